@@ -0,0 +1,64 @@
+// Compares hashing the scripts of a large block one at a time with the batched path used by the
+// indexer. Run with `cargo bench --bench script_hash`.
+
+use std::time::{Duration, Instant};
+
+use electrs::chain::Script;
+use electrs::new_index::{compute_script_hash, compute_script_hashes};
+
+// About as many as the outputs and spent prevouts of a full block
+const SCRIPT_COUNT: usize = 20_000;
+const ROUNDS: u32 = 50;
+
+fn measure(name: &str, mut f: impl FnMut() -> usize) -> Duration {
+    // warm up the thread pool and the caches
+    f();
+    let started = Instant::now();
+    let mut hashed = 0;
+    for _ in 0..ROUNDS {
+        hashed += f();
+    }
+    let elapsed = started.elapsed() / ROUNDS;
+    assert_eq!(hashed, SCRIPT_COUNT * ROUNDS as usize);
+    println!(
+        "{:<12} {:>10.3?} per block, {:>6} ns per script",
+        name,
+        elapsed,
+        elapsed.as_nanos() / SCRIPT_COUNT as u128
+    );
+    elapsed
+}
+
+fn main() {
+    // p2wpkh-like scripts with distinct programs
+    let scripts: Vec<Script> = (0..SCRIPT_COUNT as u32)
+        .map(|i| {
+            let mut script = vec![0x00, 0x14];
+            script.extend_from_slice(&[0u8; 16]);
+            script.extend_from_slice(&i.to_le_bytes());
+            Script::from(script)
+        })
+        .collect();
+    let scripts: Vec<&Script> = scripts.iter().collect();
+
+    assert_eq!(
+        compute_script_hashes(&scripts),
+        scripts
+            .iter()
+            .map(|script| compute_script_hash(script))
+            .collect::<Vec<_>>()
+    );
+
+    let sequential = measure("sequential", || {
+        scripts
+            .iter()
+            .map(|script| compute_script_hash(script))
+            .collect::<Vec<_>>()
+            .len()
+    });
+    let batched = measure("batched", || compute_script_hashes(&scripts).len());
+    println!(
+        "speedup: {:.2}x",
+        sequential.as_secs_f64() / batched.as_secs_f64()
+    );
+}
@@ -0,0 +1,9 @@
+fn main() {
+    // requires protoc, see https://github.com/hyperium/tonic#dependencies
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        // the client is used by the tests
+        .build_client(true)
+        .compile(&["proto/electrs.proto"], &["proto"])
+        .expect("failed compiling the gRPC protos");
+}
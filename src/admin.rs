@@ -0,0 +1,187 @@
+// Loopback-only HTTP listener for maintenance operations, enabled with --admin-addr:
+//
+//      POST /compact/{txstore,history,cache,meta}  run a full compaction of the db
+//      POST /caches/clear                          drop the cached scripthash aggregates
+//      POST /indexing/pause
+//      POST /indexing/resume
+//      GET  /state                                 internal state, as json
+//
+// And with --metadata, to maintain the metadata store (see new_index::meta):
+//
+//      PUT    /meta/:namespace/:key    set the value of the key, any json value as the body
+//      GET    /meta/:namespace/:key
+//      DELETE /meta/:namespace/:key
+
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use serde_json::Value;
+use tiny_http::{Header, Method, Request, Response};
+
+use crate::electrum::ClientStats;
+use crate::metrics::Gauge;
+use crate::new_index::{meta, Query};
+use crate::util::spawn_thread;
+
+struct AdminState {
+    query: Arc<Query>,
+    pending_blocks: Gauge,
+    electrum: ClientStats,
+    indexing_paused: Arc<AtomicBool>,
+}
+
+pub struct Admin {
+    indexing_paused: Arc<AtomicBool>,
+}
+
+impl Admin {
+    pub fn start(
+        addr: SocketAddr,
+        query: Arc<Query>,
+        pending_blocks: Gauge,
+        electrum: ClientStats,
+    ) -> Admin {
+        assert!(
+            addr.ip().is_loopback(),
+            "the admin server must listen on a loopback address"
+        );
+        let server = tiny_http::Server::http(addr)
+            .unwrap_or_else(|_| panic!("failed to start admin HTTP server at {}", addr));
+        info!("admin server running on {}", addr);
+
+        let indexing_paused = Arc::new(AtomicBool::new(false));
+        let state = AdminState {
+            query,
+            pending_blocks,
+            electrum,
+            indexing_paused: Arc::clone(&indexing_paused),
+        };
+        spawn_thread("admin", move || {
+            for request in server.incoming_requests() {
+                handle_request(&state, request);
+            }
+        });
+        Admin { indexing_paused }
+    }
+
+    // Set via POST /indexing/pause, new blocks are not indexed until resumed
+    pub fn indexing_paused(&self) -> bool {
+        self.indexing_paused.load(Ordering::Relaxed)
+    }
+}
+
+fn handle_request(state: &AdminState, mut request: Request) {
+    let method = request.method().clone();
+    let url = request.url().to_string();
+    let path: Vec<&str> = url.split('?').next().unwrap().split('/').skip(1).collect();
+    let (status, value) = match (&method, &path[..]) {
+        (Method::Post, ["compact", name]) => {
+            let store = state.query.chain().store();
+            let db = match *name {
+                "txstore" => Some(store.txstore_db()),
+                "history" => Some(store.history_db()),
+                "cache" => Some(store.cache_db()),
+                "meta" => Some(store.meta_db()),
+                _ => None,
+            };
+            match db {
+                Some(db) => {
+                    info!("admin: compacting the {} db", name);
+                    let started = Instant::now();
+                    db.full_compaction();
+                    (
+                        200,
+                        json!({ "duration_ms": started.elapsed().as_millis() as u64 }),
+                    )
+                }
+                None => (404, json!({ "error": "unknown db" })),
+            }
+        }
+        (Method::Post, ["caches", "clear"]) => {
+            info!("admin: clearing the caches");
+            let deleted = state.query.chain().store().clear_caches();
+            (200, json!({ "deleted_rows": deleted }))
+        }
+        (Method::Post, ["indexing", action @ ("pause" | "resume")]) => {
+            info!("admin: indexing {}d", action);
+            let paused = *action == "pause";
+            state.indexing_paused.store(paused, Ordering::Relaxed);
+            (200, json!({ "indexing_paused": paused }))
+        }
+        (Method::Get, ["state"]) => (200, dump_state(state)),
+        (_, ["meta", _, _]) if !state.query.config().metadata => {
+            (404, json!({ "error": "metadata disabled" }))
+        }
+        (Method::Put, ["meta", namespace, key]) => {
+            let meta_db = state.query.chain().store().meta_db();
+            let mut body = String::new();
+            let value = request
+                .as_reader()
+                .read_to_string(&mut body)
+                .map_err(|e| e.to_string())
+                .and_then(|_| serde_json::from_str(&body).map_err(|e| e.to_string()))
+                .and_then(|value| {
+                    meta::set(meta_db, namespace, key, &value)
+                        .map(|_| value)
+                        .map_err(|e| e.to_string())
+                });
+            match value {
+                Ok(value) => (200, value),
+                Err(e) => (400, json!({ "error": e })),
+            }
+        }
+        (Method::Get, ["meta", namespace, key]) => {
+            match meta::get(state.query.chain().store().meta_db(), namespace, key) {
+                Some(value) => (200, value),
+                None => (404, json!({ "error": "not found" })),
+            }
+        }
+        (Method::Delete, ["meta", namespace, key]) => {
+            if meta::delete(state.query.chain().store().meta_db(), namespace, key) {
+                (200, json!({ "deleted": true }))
+            } else {
+                (404, json!({ "error": "not found" }))
+            }
+        }
+        _ => (404, json!({ "error": "not found" })),
+    };
+
+    let response = Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header("Content-Type: application/json".parse::<Header>().unwrap());
+    if let Err(e) = request.respond(response) {
+        warn!("admin: failed responding to {} {:?}: {}", method, path, e);
+    }
+}
+
+fn dump_state(state: &AdminState) -> Value {
+    let chain = state.query.chain();
+    let mempool = state.query.mempool();
+    let db_stats: serde_json::Map<String, Value> = chain
+        .store()
+        .db_stats()
+        .into_iter()
+        .map(|(name, stats)| (name.to_string(), json!(stats)))
+        .collect();
+    json!({
+        "tip": {
+            "height": chain.best_height(),
+            "hash": chain.best_hash(),
+        },
+        "indexing": {
+            "paused": state.indexing_paused.load(Ordering::Relaxed),
+            "pending_blocks": state.pending_blocks.get(),
+        },
+        "mempool": {
+            "count": mempool.backlog_stats().count,
+            "vsize": mempool.backlog_stats().vsize,
+        },
+        "electrum": {
+            "clients": state.electrum.clients.get(),
+            "subscriptions": state.electrum.subscriptions.get(),
+        },
+        "db": db_stats,
+    })
+}
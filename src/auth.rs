@@ -0,0 +1,127 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::errors::*;
+
+const AUTH_KEYS_ENV: &str = "ELECTRS_AUTH_KEYS";
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    RateLimited,
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "missing API key"),
+            AuthError::Invalid => write!(f, "invalid API key"),
+            AuthError::RateLimited => write!(f, "API key rate limit exceeded"),
+        }
+    }
+}
+
+// The set of API keys allowed to access the REST and Electrum servers, along with their
+// optional rate limits (in requests per minute).
+pub struct ApiKeys {
+    limits: HashMap<String, Option<u32>>,
+    // key -> (start of the current window, # of requests made within it)
+    usage: Mutex<HashMap<String, (Instant, u32)>>,
+}
+
+impl ApiKeys {
+    // Load the API keys from `--auth-keys-file` and the ELECTRS_AUTH_KEYS env var.
+    // Returns None if no keys are configured, which disables authentication.
+    pub fn from_config(config: &Config) -> Result<Option<ApiKeys>> {
+        let mut entries = vec![];
+        if let Some(ref path) = config.auth_keys_file {
+            let contents = fs::read_to_string(path)
+                .chain_err(|| format!("failed reading auth keys file {:?}", path))?;
+            entries.extend(contents.lines().map(str::to_string));
+        }
+        if let Ok(keys) = env::var(AUTH_KEYS_ENV) {
+            entries.extend(keys.split(',').map(str::to_string));
+        }
+
+        let mut limits = HashMap::new();
+        for entry in entries {
+            let entry = entry.trim();
+            if entry.is_empty() || entry.starts_with('#') {
+                continue;
+            }
+            let (key, limit) = parse_entry(entry)?;
+            limits.insert(key, limit);
+        }
+
+        if limits.is_empty() {
+            return Ok(None);
+        }
+        info!("API key authentication enabled with {} keys", limits.len());
+        Ok(Some(ApiKeys {
+            limits,
+            usage: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // Verify that the key is valid and within its rate limit, counting this request against it
+    pub fn check(&self, key: Option<&str>) -> std::result::Result<(), AuthError> {
+        let key = key.ok_or(AuthError::Missing)?;
+        let limit = match self.limits.get(key) {
+            None => return Err(AuthError::Invalid),
+            Some(None) => return Ok(()),
+            Some(Some(limit)) => *limit,
+        };
+
+        let mut usage = self.usage.lock().unwrap();
+        let now = Instant::now();
+        let (window_start, count) = usage.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(*window_start) >= RATE_LIMIT_WINDOW {
+            *window_start = now;
+            *count = 0;
+        }
+        if *count >= limit {
+            return Err(AuthError::RateLimited);
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    // Carry over the rate limit usage of the keys that are still configured, so that reloading
+    // them doesn't reset their limits
+    pub fn inherit_usage(&self, previous: &ApiKeys) {
+        let previous = previous.usage.lock().unwrap();
+        let mut usage = self.usage.lock().unwrap();
+        usage.extend(
+            previous
+                .iter()
+                .filter(|(key, _)| self.limits.contains_key(*key))
+                .map(|(key, window)| (key.clone(), *window)),
+        );
+    }
+
+    pub fn is_valid(&self, key: &str) -> bool {
+        self.limits.contains_key(key)
+    }
+}
+
+// Entries are formatted as `KEY` or `KEY:REQUESTS_PER_MINUTE`
+fn parse_entry(entry: &str) -> Result<(String, Option<u32>)> {
+    let mut parts = entry.splitn(2, ':');
+    let key = parts.next().unwrap().trim().to_string();
+    let limit = parts
+        .next()
+        .map(|limit| {
+            limit
+                .trim()
+                .parse::<u32>()
+                .chain_err(|| "invalid rate limit in API key entry")
+        })
+        .transpose()?;
+    Ok((key, limit))
+}
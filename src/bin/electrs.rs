@@ -6,14 +6,19 @@ extern crate electrs;
 
 use error_chain::ChainedError;
 use std::process;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::thread;
 use std::time::Duration;
 
 use electrs::{
+    admin::Admin,
+    auth::ApiKeys,
+    chain::BlockHash,
     config::Config,
     daemon::Daemon,
     electrum::RPC as ElectrumRPC,
     errors::*,
+    events::EventPublisher,
     metrics::Metrics,
     new_index::{precache, ChainQuery, FetchFrom, Indexer, Mempool, Query, Store},
     rest,
@@ -22,10 +27,13 @@ use electrs::{
 
 #[cfg(feature = "liquid")]
 use electrs::elements::AssetRegistry;
+#[cfg(feature = "grpc")]
+use electrs::grpc;
 use electrs::metrics::MetricOpts;
 
 fn fetch_from(config: &Config, store: &Store) -> FetchFrom {
-    let mut jsonrpc_import = config.jsonrpc_import;
+    // the blk*.dat files of a pruned node are incomplete
+    let mut jsonrpc_import = config.jsonrpc_import || config.allow_pruned;
     if !jsonrpc_import {
         // switch over to jsonrpc after the initial sync is done
         jsonrpc_import = store.done_initial_sync();
@@ -40,115 +48,305 @@ fn fetch_from(config: &Config, store: &Store) -> FetchFrom {
     }
 }
 
-fn run_server(config: Arc<Config>) -> Result<()> {
-    let signal = Waiter::start();
-    let metrics = Metrics::new(config.monitoring_addr);
-    metrics.start();
-
-    let daemon = Arc::new(Daemon::new(
-        &config.daemon_dir,
-        &config.blocks_dir,
-        config.daemon_rpc_addr,
-        config.cookie_getter(),
-        config.network_type,
-        signal.clone(),
-        &metrics,
-    )?);
-    let store = Arc::new(Store::open(&config.db_path.join("newindex"), &config));
-    let mut indexer = Indexer::open(
-        Arc::clone(&store),
-        fetch_from(&config, &store),
-        &config,
-        &metrics,
-    );
-    let mut tip = indexer.update(&daemon)?;
+// The index, mempool and electrum server of one of the served networks
+struct NetworkServer {
+    config: Arc<Config>,
+    daemon: Arc<Daemon>,
+    indexer: Indexer,
+    mempool: Arc<RwLock<Mempool>>,
+    query: Arc<Query>,
+    electrum_server: ElectrumRPC,
+    metrics: Metrics,
+    tip: BlockHash,
+}
 
-    let chain = Arc::new(ChainQuery::new(
-        Arc::clone(&store),
-        Arc::clone(&daemon),
-        &config,
-        &metrics,
-    ));
+impl NetworkServer {
+    fn start(config: Arc<Config>, signal: &Waiter) -> Result<NetworkServer> {
+        // fail early on a malformed keys file, before spending time on the initial sync
+        let api_keys = ApiKeys::from_config(&config).chain_err(|| "failed loading API keys")?;
+
+        let metrics = Metrics::new(config.monitoring_addr);
+        metrics.start();
+
+        let daemon = Arc::new(Daemon::new(
+            &config.daemon_dir,
+            &config.blocks_dir,
+            config.daemon_rpc_addr,
+            config.cookie_getter(),
+            config.network_type,
+            config.allow_pruned,
+            signal.clone(),
+            &metrics,
+        )?);
+        let store = Arc::new(Store::open(&config.db_path.join("newindex"), &config));
+        let mut indexer = Indexer::open(
+            Arc::clone(&store),
+            fetch_from(&config, &store),
+            &config,
+            &metrics,
+        );
+        let tip = indexer.update(&daemon)?;
+
+        let chain = Arc::new(ChainQuery::new(
+            Arc::clone(&store),
+            Arc::clone(&daemon),
+            &config,
+            &metrics,
+        ));
+
+        if let Some(ref precache_file) = config.precache_scripts {
+            let precache_scripthashes = precache::scripthashes_from_file(precache_file.to_string())
+                .expect("cannot load scripts to precache");
+            precache::precache(&chain, precache_scripthashes);
+        }
+
+        let mempool = Arc::new(RwLock::new(Mempool::new(
+            Arc::clone(&chain),
+            &metrics,
+            Arc::clone(&config),
+        )));
+        loop {
+            match Mempool::update(&mempool, &daemon) {
+                Ok(_) => break,
+                Err(e) => {
+                    warn!(
+                        "Error performing initial mempool update, trying again in 5 seconds: {}",
+                        e.display_chain()
+                    );
+                    signal.wait(Duration::from_secs(5), false)?;
+                }
+            }
+        }
+
+        #[cfg(feature = "liquid")]
+        let asset_db = config.asset_db_path.as_ref().map(|db_dir| {
+            let asset_db = Arc::new(RwLock::new(AssetRegistry::new(db_dir.clone())));
+            AssetRegistry::spawn_sync(asset_db.clone());
+            asset_db
+        });
 
-    if let Some(ref precache_file) = config.precache_scripts {
-        let precache_scripthashes = precache::scripthashes_from_file(precache_file.to_string())
-            .expect("cannot load scripts to precache");
-        precache::precache(&chain, precache_scripthashes);
+        let query = Arc::new(Query::new(
+            Arc::clone(&chain),
+            Arc::clone(&mempool),
+            Arc::clone(&daemon),
+            Arc::clone(&config),
+            api_keys,
+            #[cfg(feature = "liquid")]
+            asset_db,
+        ));
+
+        let electrum_server = ElectrumRPC::start(Arc::clone(&config), Arc::clone(&query), &metrics);
+
+        Ok(NetworkServer {
+            config,
+            daemon,
+            indexer,
+            mempool,
+            query,
+            electrum_server,
+            metrics,
+            tip,
+        })
     }
 
-    let mempool = Arc::new(RwLock::new(Mempool::new(
-        Arc::clone(&chain),
-        &metrics,
-        Arc::clone(&config),
-    )));
-    loop {
-        match Mempool::update(&mempool, &daemon) {
-            Ok(_) => break,
-            Err(e) => {
-                warn!("Error performing initial mempool update, trying again in 5 seconds: {}", e.display_chain());
-                signal.wait(Duration::from_secs(5), false)?;
-            },
+    fn update(&mut self, index: bool) -> Result<()> {
+        // Index new blocks
+        if index {
+            let current_tip = self.daemon.getbestblockhash()?;
+            if current_tip != self.tip {
+                self.indexer.update(&self.daemon)?;
+                self.tip = current_tip;
+            };
+        }
+        self.indexer.update_db_stats();
+
+        // Update mempool
+        if let Err(e) = Mempool::update(&self.mempool, &self.daemon) {
+            // Log the error if the result is an Err
+            warn!(
+                "Error updating mempool, skipping mempool update: {}",
+                e.display_chain()
+            );
         }
+
+        // Update subscribed clients
+        self.electrum_server.notify();
+        Ok(())
     }
+}
 
-    #[cfg(feature = "liquid")]
-    let asset_db = config.asset_db_path.as_ref().map(|db_dir| {
-        let asset_db = Arc::new(RwLock::new(AssetRegistry::new(db_dir.clone())));
-        AssetRegistry::spawn_sync(asset_db.clone());
-        asset_db
-    });
+// An additional network going through its initial sync
+struct PendingNetwork {
+    config: Arc<Config>,
+    query: Arc<OnceLock<Arc<Query>>>,
+    handle: thread::JoinHandle<Result<NetworkServer>>,
+}
 
-    let query = Arc::new(Query::new(
-        Arc::clone(&chain),
-        Arc::clone(&mempool),
-        Arc::clone(&daemon),
-        Arc::clone(&config),
-        #[cfg(feature = "liquid")]
-        asset_db,
-    ));
+impl PendingNetwork {
+    fn start(config: Arc<Config>, query: Arc<OnceLock<Arc<Query>>>, signal: &Waiter) -> Self {
+        info!("starting additional network {:?}", config.network_type);
+        let handle = {
+            let config = Arc::clone(&config);
+            let signal = signal.clone();
+            thread::spawn(move || NetworkServer::start(config, &signal))
+        };
+        PendingNetwork {
+            config,
+            query,
+            handle,
+        }
+    }
+}
+
+fn run_server(config: Arc<Config>) -> Result<()> {
+    let signal = Waiter::start();
+    let mut server = NetworkServer::start(Arc::clone(&config), &signal)?;
+    let query = Arc::clone(&server.query);
+
+    // The additional networks run their initial sync in the background, so that it doesn't hold
+    // back the servers of the main network. Once synced, they're updated by this thread too, one
+    // after the other.
+    let mut network_servers: Vec<NetworkServer> = vec![];
+    let mut pending_networks = vec![];
+    let mut network_routes = vec![];
+    for network in &config.networks {
+        let network_config = Arc::new(config.network_config(network));
+        let query = Arc::new(OnceLock::new());
+        network_routes.push(rest::NetworkRoute {
+            prefix: network.http_prefix.clone(),
+            config: Arc::clone(&network_config),
+            query: Arc::clone(&query),
+        });
+        pending_networks.push(PendingNetwork::start(network_config, query, &signal));
+    }
 
     // TODO: configuration for which servers to start
-    let rest_server = rest::start(Arc::clone(&config), Arc::clone(&query));
-    let electrum_server = ElectrumRPC::start(Arc::clone(&config), Arc::clone(&query), &metrics);
+    let rest_server =
+        rest::start_with_networks(Arc::clone(&config), Arc::clone(&query), network_routes);
+    let event_publisher = EventPublisher::start(&config, Arc::clone(&query), &server.metrics)?;
+    #[cfg(feature = "grpc")]
+    let grpc_server = grpc::start(Arc::clone(&config), Arc::clone(&query));
+    let admin = config.admin_addr.map(|addr| {
+        Admin::start(
+            addr,
+            Arc::clone(&query),
+            server.indexer.pending_blocks(),
+            server.electrum_server.client_stats(),
+        )
+    });
 
-    let main_loop_count = metrics.gauge(MetricOpts::new(
+    let main_loop_count = server.metrics.gauge(MetricOpts::new(
         "electrs_main_loop_count",
         "count of iterations of electrs main loop each 5 seconds or after interrupts",
     ));
 
     loop {
-
         main_loop_count.inc();
 
         if let Err(err) = signal.wait(Duration::from_secs(5), true) {
             info!("stopping server: {}", err);
             rest_server.stop();
-            // the electrum server is stopped when dropped
+            #[cfg(feature = "grpc")]
+            if let Some(grpc_server) = grpc_server {
+                grpc_server.stop();
+            }
+            // the electrum servers are stopped when dropped
             break;
         }
 
-        // Index new blocks
-        let current_tip = daemon.getbestblockhash()?;
-        if current_tip != tip {
-            indexer.update(&daemon)?;
-            tip = current_tip;
-        };
+        if signal.reload_requested() {
+            if let Err(e) = config.reload() {
+                warn!("failed reloading config: {}", e.display_chain());
+            }
+            let network_queries = network_servers.iter().map(|network| &network.query);
+            for query in std::iter::once(&query).chain(network_queries) {
+                if let Err(e) = query.reload_api_keys() {
+                    warn!("failed reloading API keys: {}", e.display_chain());
+                }
+            }
+        }
 
-        // Update mempool
-        if let Err(e) = Mempool::update(&mempool, &daemon) {
-            // Log the error if the result is an Err
-            warn!("Error updating mempool, skipping mempool update: {}", e.display_chain());
+        let (started, pending): (Vec<_>, Vec<_>) = pending_networks
+            .into_iter()
+            .partition(|network| network.handle.is_finished());
+        pending_networks = pending;
+        for network in started {
+            match network.handle.join().expect("network sync thread panicked") {
+                Ok(network_server) => {
+                    info!("network {:?} synced", network.config.network_type);
+                    network
+                        .query
+                        .set(Arc::clone(&network_server.query))
+                        .unwrap_or_else(|_| panic!("network query already set"));
+                    network_servers.push(network_server);
+                }
+                Err(e) => {
+                    warn!(
+                        "failed starting network {:?}, retrying: {}",
+                        network.config.network_type,
+                        e.display_chain()
+                    );
+                    pending_networks.push(PendingNetwork::start(
+                        network.config,
+                        network.query,
+                        &signal,
+                    ));
+                }
+            }
         }
 
-        // Update subscribed clients
-        electrum_server.notify();
+        let index = !admin
+            .as_ref()
+            .map_or(false, |admin| admin.indexing_paused());
+        server.update(index)?;
+        // a failing additional network doesn't bring the others down, it's retried next time
+        for network_server in &mut network_servers {
+            if let Err(e) = network_server.update(index) {
+                warn!(
+                    "failed updating network {:?}: {}",
+                    network_server.config.network_type,
+                    e.display_chain()
+                );
+            }
+        }
+
+        if let Some(ref event_publisher) = event_publisher {
+            event_publisher.notify();
+        }
     }
     info!("server stopped");
     Ok(())
 }
 
+fn verify_index(config: &Config) -> bool {
+    let store = Store::open(&config.db_path.join("newindex"), config);
+    let report = store.verify(config.light_mode, config.repair_index);
+    info!(
+        "verified {} blocks, {} history rows and {} edge rows",
+        report.checked_blocks, report.checked_history_rows, report.checked_edge_rows
+    );
+    if report.is_consistent() {
+        info!("index is consistent");
+        return true;
+    }
+    warn!(
+        "index is inconsistent: {} broken blocks, {} dangling history rows, {} dangling edge rows",
+        report.broken_blocks.len(),
+        report.dangling_history_rows,
+        report.dangling_edge_rows
+    );
+    if report.repaired {
+        info!("inconsistencies repaired, broken blocks will be re-indexed on the next start");
+        return true;
+    }
+    false
+}
+
 fn main() {
     let config = Arc::new(Config::from_args());
+    if config.verify_index {
+        process::exit(if verify_index(&config) { 0 } else { 1 });
+    }
     if let Err(e) = run_server(config) {
         error!("server failed: {}", e.display_chain());
         process::exit(1);
@@ -35,6 +35,7 @@ fn main() {
             config.daemon_rpc_addr,
             config.cookie_getter(),
             config.network_type,
+            config.allow_pruned,
             signal,
             &metrics,
         )
@@ -2,7 +2,7 @@
 pub use bitcoin::{
     address, blockdata::block::Header as BlockHeader, blockdata::script, consensus::deserialize,
     hash_types::TxMerkleNode, Address, Block, BlockHash, OutPoint, ScriptBuf as Script, Sequence,
-    Transaction, TxIn, TxOut, Txid,
+    Transaction, TxIn, TxOut, Txid, Wtxid,
 };
 
 #[cfg(feature = "liquid")]
@@ -11,12 +11,24 @@ pub use {
     elements::{
         address, confidential, encode::deserialize, script, Address, AssetId, Block, BlockHash,
         BlockHeader, OutPoint, Script, Sequence, Transaction, TxIn, TxMerkleNode, TxOut, Txid,
+        Wtxid,
     },
 };
 
 use bitcoin::blockdata::constants::genesis_block;
 pub use bitcoin::network::Network as BNetwork;
 
+#[cfg(not(feature = "liquid"))]
+use {
+    crate::errors::*,
+    bitcoin::bech32::{self, Hrp},
+    bitcoin::hashes::{sha256d, Hash},
+    bitcoin::{base58, PubkeyHash, ScriptHash, WitnessProgram, WitnessVersion},
+    hex::FromHex,
+    std::convert::{TryFrom, TryInto},
+    std::sync::OnceLock,
+};
+
 #[cfg(not(feature = "liquid"))]
 pub type Value = u64;
 #[cfg(feature = "liquid")]
@@ -32,6 +44,9 @@ pub enum Network {
     Regtest,
     #[cfg(not(feature = "liquid"))]
     Signet,
+    // A private signet or regtest-style chain, described by the `ChainParams` loaded at startup
+    #[cfg(not(feature = "liquid"))]
+    Custom,
 
     #[cfg(feature = "liquid")]
     Liquid,
@@ -44,6 +59,9 @@ pub enum Network {
 impl Network {
     #[cfg(not(feature = "liquid"))]
     pub fn magic(self) -> u32 {
+        if self == Network::Custom {
+            return u32::from_le_bytes(custom_chain().magic());
+        }
         u32::from_le_bytes(BNetwork::from(self).magic().to_bytes())
     }
 
@@ -55,10 +73,22 @@ impl Network {
         }
     }
 
+    // The built-in network that this network is derived from, used for its default ports and
+    // the daemon's data directory layout
+    pub fn base(self) -> Network {
+        #[cfg(not(feature = "liquid"))]
+        if self == Network::Custom {
+            return custom_chain().base;
+        }
+        self
+    }
+
     pub fn is_regtest(self) -> bool {
         match self {
             #[cfg(not(feature = "liquid"))]
             Network::Regtest => true,
+            #[cfg(not(feature = "liquid"))]
+            Network::Custom => custom_chain().base == Network::Regtest,
             #[cfg(feature = "liquid")]
             Network::LiquidRegtest => true,
             _ => false,
@@ -92,6 +122,17 @@ impl Network {
         }
     }
 
+    // The (height, blockhash) of blocks known to be part of the chain, from Bitcoin Core
+    pub fn checkpoints(self) -> &'static [(usize, &'static str)] {
+        match self {
+            #[cfg(not(feature = "liquid"))]
+            Network::Bitcoin => &BITCOIN_CHECKPOINTS,
+            #[cfg(not(feature = "liquid"))]
+            Network::Testnet => &TESTNET_CHECKPOINTS,
+            _ => &[],
+        }
+    }
+
     pub fn names() -> Vec<String> {
         #[cfg(not(feature = "liquid"))]
         return vec![
@@ -99,6 +140,7 @@ impl Network {
             "testnet".to_string(),
             "regtest".to_string(),
             "signet".to_string(),
+            "custom".to_string(),
         ];
 
         #[cfg(feature = "liquid")]
@@ -110,7 +152,73 @@ impl Network {
     }
 }
 
+#[cfg(not(feature = "liquid"))]
+const BITCOIN_CHECKPOINTS: [(usize, &str); 13] = [
+    (
+        11111,
+        "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+    ),
+    (
+        33333,
+        "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6",
+    ),
+    (
+        74000,
+        "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20",
+    ),
+    (
+        105000,
+        "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97",
+    ),
+    (
+        134444,
+        "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe",
+    ),
+    (
+        168000,
+        "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763",
+    ),
+    (
+        193000,
+        "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317",
+    ),
+    (
+        210000,
+        "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e",
+    ),
+    (
+        216116,
+        "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e",
+    ),
+    (
+        225430,
+        "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932",
+    ),
+    (
+        250000,
+        "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214",
+    ),
+    (
+        279000,
+        "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40",
+    ),
+    (
+        295000,
+        "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983",
+    ),
+];
+
+#[cfg(not(feature = "liquid"))]
+const TESTNET_CHECKPOINTS: [(usize, &str); 1] = [(
+    546,
+    "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70",
+)];
+
 pub fn genesis_hash(network: Network) -> BlockHash {
+    #[cfg(not(feature = "liquid"))]
+    if network == Network::Custom {
+        return custom_chain().genesis_hash();
+    }
     #[cfg(not(feature = "liquid"))]
     return bitcoin_genesis_hash(network.into());
     #[cfg(feature = "liquid")]
@@ -168,6 +276,8 @@ impl From<&str> for Network {
             "regtest" => Network::Regtest,
             #[cfg(not(feature = "liquid"))]
             "signet" => Network::Signet,
+            #[cfg(not(feature = "liquid"))]
+            "custom" => Network::Custom,
 
             #[cfg(feature = "liquid")]
             "liquid" => Network::Liquid,
@@ -189,6 +299,7 @@ impl From<Network> for BNetwork {
             Network::Testnet => BNetwork::Testnet,
             Network::Regtest => BNetwork::Regtest,
             Network::Signet => BNetwork::Signet,
+            Network::Custom => BNetwork::from(custom_chain().base),
         }
     }
 }
@@ -205,3 +316,282 @@ impl From<BNetwork> for Network {
         }
     }
 }
+
+/// The parameters of a custom chain, loaded from the TOML file given with `--chain-params`.
+/// Anything left unset is inherited from the `base` network.
+///
+/// ```toml
+/// base = "signet"
+/// signet_challenge = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be43051ae"
+/// bech32_hrp = "tb"
+/// ```
+#[cfg(not(feature = "liquid"))]
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChainParams {
+    #[serde(deserialize_with = "deserialize_base")]
+    pub base: Network,
+    pub genesis_hash: Option<BlockHash>,
+    /// The network magic as it appears on the wire, hex encoded (e.g. "fabfb5da")
+    pub magic: Option<String>,
+    /// The signet challenge script, hex encoded. Used to derive the magic if it isn't set.
+    pub signet_challenge: Option<String>,
+    pub bech32_hrp: Option<String>,
+    pub p2pkh_prefix: Option<u8>,
+    pub p2sh_prefix: Option<u8>,
+}
+
+#[cfg(not(feature = "liquid"))]
+fn deserialize_base<'de, D>(deserializer: D) -> std::result::Result<Network, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    match String::deserialize(deserializer)?.as_str() {
+        "testnet" => Ok(Network::Testnet),
+        "regtest" => Ok(Network::Regtest),
+        "signet" => Ok(Network::Signet),
+        base => Err(serde::de::Error::custom(format!(
+            "unsupported base network {:?}, expected testnet, regtest or signet",
+            base
+        ))),
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+static CUSTOM_CHAIN: OnceLock<ChainParams> = OnceLock::new();
+
+#[cfg(not(feature = "liquid"))]
+fn custom_chain() -> &'static ChainParams {
+    CUSTOM_CHAIN
+        .get()
+        .expect("custom chain parameters were not loaded")
+}
+
+#[cfg(not(feature = "liquid"))]
+impl ChainParams {
+    pub fn from_file(path: &std::path::Path) -> Result<ChainParams> {
+        let contents = std::fs::read_to_string(path)
+            .chain_err(|| format!("failed reading chain params {:?}", path))?;
+        Self::from_toml(&contents).chain_err(|| format!("invalid chain params {:?}", path))
+    }
+
+    fn from_toml(contents: &str) -> Result<ChainParams> {
+        let params: ChainParams = toml::from_str(contents).chain_err(|| "failed parsing")?;
+        // validate the encoded fields upfront rather than failing when they're first used
+        params.magic_checked()?;
+        params.hrp_checked()?;
+        Ok(params)
+    }
+
+    /// Make these the parameters of `Network::Custom`. Can only be called once.
+    pub fn install(self) {
+        if CUSTOM_CHAIN.set(self).is_err() {
+            panic!("custom chain parameters were already loaded");
+        }
+    }
+
+    fn magic_checked(&self) -> Result<[u8; 4]> {
+        if let Some(ref magic) = self.magic {
+            let bytes = Vec::<u8>::from_hex(magic).chain_err(|| "invalid magic")?;
+            return bytes.try_into().map_err(|_| "magic must be 4 bytes".into());
+        }
+        if let Some(ref challenge) = self.signet_challenge {
+            // like bitcoind, use the first 4 bytes of the hash of the serialized challenge script
+            let challenge =
+                Vec::<u8>::from_hex(challenge).chain_err(|| "invalid signet challenge")?;
+            let hash = sha256d::Hash::hash(&bitcoin::consensus::serialize(&challenge));
+            return Ok(hash[..4].try_into().unwrap());
+        }
+        Ok(BNetwork::from(self.base).magic().to_bytes())
+    }
+
+    fn hrp_checked(&self) -> Result<Hrp> {
+        let hrp = match self.bech32_hrp {
+            Some(ref hrp) => hrp.as_str(),
+            None => match self.base {
+                Network::Regtest => "bcrt",
+                _ => "tb",
+            },
+        };
+        Hrp::parse(hrp).chain_err(|| format!("invalid bech32 hrp {:?}", hrp))
+    }
+
+    fn magic(&self) -> [u8; 4] {
+        self.magic_checked().unwrap()
+    }
+
+    fn genesis_hash(&self) -> BlockHash {
+        self.genesis_hash
+            .unwrap_or_else(|| bitcoin_genesis_hash(self.base.into()))
+    }
+
+    // Testnet, regtest and signet all share the same base58 prefixes
+    fn p2pkh_prefix(&self) -> u8 {
+        self.p2pkh_prefix.unwrap_or(111)
+    }
+
+    fn p2sh_prefix(&self) -> u8 {
+        self.p2sh_prefix.unwrap_or(196)
+    }
+
+    /// Encode the script as an address using these prefixes
+    pub fn script_to_address(&self, script: &bitcoin::Script) -> Option<String> {
+        let bytes = script.as_bytes();
+        if script.is_p2pkh() {
+            Some(base58::encode_check(
+                &[&[self.p2pkh_prefix()], &bytes[3..23]].concat(),
+            ))
+        } else if script.is_p2sh() {
+            Some(base58::encode_check(
+                &[&[self.p2sh_prefix()], &bytes[2..22]].concat(),
+            ))
+        } else {
+            let version = script.witness_version()?;
+            bech32::segwit::encode(&self.hrp_checked().ok()?, version.to_fe(), &bytes[2..]).ok()
+        }
+    }
+
+    /// Decode an address encoded with these prefixes into its script
+    pub fn address_to_script(&self, addr: &str) -> Result<Script> {
+        if let Ok((hrp, version, program)) = bech32::segwit::decode(addr) {
+            if hrp != self.hrp_checked()? {
+                bail!("address on invalid network");
+            }
+            let version =
+                WitnessVersion::try_from(version).chain_err(|| "invalid witness version")?;
+            let program =
+                WitnessProgram::new(version, program).chain_err(|| "invalid witness program")?;
+            return Ok(Script::new_witness_program(&program));
+        }
+
+        let data = base58::decode_check(addr).chain_err(|| "invalid address")?;
+        if data.len() != 21 {
+            bail!("invalid address length");
+        }
+        let hash: [u8; 20] = data[1..].try_into().unwrap();
+        match data[0] {
+            prefix if prefix == self.p2pkh_prefix() => {
+                Ok(Script::new_p2pkh(&PubkeyHash::from_byte_array(hash)))
+            }
+            prefix if prefix == self.p2sh_prefix() => {
+                Ok(Script::new_p2sh(&ScriptHash::from_byte_array(hash)))
+            }
+            _ => bail!("address on invalid network"),
+        }
+    }
+}
+
+/// Encode the script as an address using the custom chain's prefixes
+#[cfg(not(feature = "liquid"))]
+pub fn custom_script_to_address(script: &bitcoin::Script) -> Option<String> {
+    custom_chain().script_to_address(script)
+}
+
+/// Decode an address encoded with the custom chain's prefixes into its script
+#[cfg(not(feature = "liquid"))]
+pub fn custom_address_to_script(addr: &str) -> Result<Script> {
+    custom_chain().address_to_script(addr)
+}
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::*;
+
+    fn test_scripts() -> Vec<Script> {
+        vec![
+            Script::new_p2pkh(&PubkeyHash::from_byte_array([1; 20])),
+            Script::new_p2sh(&ScriptHash::from_byte_array([2; 20])),
+            Script::new_witness_program(
+                &WitnessProgram::new(WitnessVersion::V0, vec![3; 20]).unwrap(),
+            ),
+            Script::new_witness_program(
+                &WitnessProgram::new(WitnessVersion::V0, vec![4; 32]).unwrap(),
+            ),
+            Script::new_witness_program(
+                &WitnessProgram::new(WitnessVersion::V1, vec![5; 32]).unwrap(),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_chain_params_defaults() {
+        let params = ChainParams::from_toml("base = \"regtest\"").unwrap();
+        assert_eq!(params.magic(), BNetwork::Regtest.magic().to_bytes());
+        assert_eq!(
+            params.genesis_hash(),
+            bitcoin_genesis_hash(BNetwork::Regtest)
+        );
+        // without overrides, the addresses are the same as the base network's
+        for script in test_scripts() {
+            let addr = params.script_to_address(&script).unwrap();
+            assert_eq!(
+                addr,
+                Address::from_script(&script, BNetwork::Regtest)
+                    .unwrap()
+                    .to_string()
+            );
+            assert_eq!(params.address_to_script(&addr).unwrap(), script);
+        }
+    }
+
+    #[test]
+    fn test_chain_params_custom_prefixes() {
+        let params = ChainParams::from_toml(
+            r#"
+            base = "signet"
+            magic = "fabfb5da"
+            bech32_hrp = "sb"
+            p2pkh_prefix = 63
+            p2sh_prefix = 125
+            "#,
+        )
+        .unwrap();
+        assert_eq!(params.magic(), [0xfa, 0xbf, 0xb5, 0xda]);
+
+        let addrs: Vec<String> = test_scripts()
+            .iter()
+            .map(|script| params.script_to_address(script).unwrap())
+            .collect();
+        assert!(addrs[0].starts_with('S'));
+        assert!(addrs[1].starts_with('s'));
+        assert!(addrs[2..].iter().all(|addr| addr.starts_with("sb1")));
+        for (addr, script) in addrs.iter().zip(test_scripts()) {
+            assert_eq!(params.address_to_script(addr).unwrap(), script);
+        }
+
+        // the addresses of the base network are rejected
+        for script in test_scripts() {
+            let addr = Address::from_script(&script, BNetwork::Signet).unwrap();
+            let err = params.address_to_script(&addr.to_string()).unwrap_err();
+            assert_eq!(err.to_string(), "address on invalid network");
+        }
+    }
+
+    #[test]
+    fn test_chain_params_signet_challenge() {
+        // the challenge of the default signet derives its magic
+        let params = ChainParams::from_toml(
+            r#"
+            base = "signet"
+            signet_challenge = "512103ad5e0edad18cb1f0fc0d28a3d4f1f3e445640337489abb10404f2d1e086be430210359ef5021964fe22d6f8e05b2463c9540ce96883fe3b278760f048f5189f2e6c452ae"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(params.magic(), BNetwork::Signet.magic().to_bytes());
+    }
+
+    #[test]
+    fn test_chain_params_invalid() {
+        for contents in &[
+            "base = \"bitcoin\"",
+            "base = \"regtest\"\nmagic = \"fabfb5\"",
+            "base = \"regtest\"\nmagic = \"zz\"",
+            "base = \"regtest\"\nbech32_hrp = \"\"",
+            "base = \"regtest\"\nsignet_challenge = \"0g\"",
+            "base = \"regtest\"\nunknown = 1",
+        ] {
+            assert!(ChainParams::from_toml(contents).is_err(), "{}", contents);
+        }
+    }
+}
@@ -1,11 +1,14 @@
+use std::cmp;
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::fs;
 use std::io::{BufRead, BufReader, Lines, Write};
 use std::net::{SocketAddr, TcpStream};
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use base64::prelude::{Engine, BASE64_STANDARD};
 use hex::FromHex;
@@ -18,9 +21,9 @@ use bitcoin::consensus::encode::{deserialize, serialize_hex};
 use elements::encode::{deserialize, serialize_hex};
 
 use crate::chain::{Block, BlockHash, BlockHeader, Network, Transaction, Txid};
-use crate::metrics::{HistogramOpts, HistogramVec, Metrics};
+use crate::metrics::{CounterVec, Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::signal::Waiter;
-use crate::util::{HeaderList, DEFAULT_BLOCKHASH};
+use crate::util::{cost, HeaderList, DEFAULT_BLOCKHASH};
 
 use crate::errors::*;
 
@@ -34,8 +37,20 @@ lazy_static! {
     static ref DAEMON_WRITE_TIMEOUT: Duration = Duration::from_secs(
         env::var("DAEMON_WRITE_TIMEOUT").map_or(10 * 60, |s| s.parse().unwrap())
     );
+    // The number of query calls (see Daemon::try_request) that can be waiting on the daemon at once
+    static ref DAEMON_MAX_PENDING_CALLS: usize =
+        env::var("DAEMON_MAX_PENDING_CALLS").map_or(32, |s| s.parse().unwrap());
+    // The consecutive connection failures after which the query calls fail fast, for the cooldown
+    static ref DAEMON_BREAKER_THRESHOLD: u32 =
+        env::var("DAEMON_BREAKER_THRESHOLD").map_or(3, |s| s.parse().unwrap());
+    static ref DAEMON_BREAKER_COOLDOWN: Duration = Duration::from_secs(
+        env::var("DAEMON_BREAKER_COOLDOWN").map_or(30, |s| s.parse().unwrap())
+    );
 }
 
+const MIN_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(60);
+
 fn parse_hash<T>(value: &Value) -> Result<T>
 where
     T: FromStr,
@@ -117,6 +132,7 @@ pub struct BlockchainInfo {
     pub headers: u32,
     pub bestblockhash: String,
     pub pruned: bool,
+    pub pruneheight: Option<u32>,
     pub verificationprogress: f32,
     pub initialblockdownload: Option<bool>,
 }
@@ -130,6 +146,9 @@ struct NetworkInfo {
 
 pub trait CookieGetter: Send + Sync {
     fn get(&self) -> Result<Vec<u8>>;
+
+    // Called when the daemon rejects the credentials, for them to be read again
+    fn refresh(&self) {}
 }
 
 struct Connection {
@@ -140,15 +159,18 @@ struct Connection {
     signal: Waiter,
 }
 
+fn tcp_connect_once(addr: SocketAddr) -> std::io::Result<TcpStream> {
+    let conn = TcpStream::connect_timeout(&addr, *DAEMON_CONNECTION_TIMEOUT)?;
+    // can only fail if DAEMON_TIMEOUT is 0
+    conn.set_read_timeout(Some(*DAEMON_READ_TIMEOUT)).unwrap();
+    conn.set_write_timeout(Some(*DAEMON_WRITE_TIMEOUT)).unwrap();
+    Ok(conn)
+}
+
 fn tcp_connect(addr: SocketAddr, signal: &Waiter) -> Result<TcpStream> {
     loop {
-        match TcpStream::connect_timeout(&addr, *DAEMON_CONNECTION_TIMEOUT) {
-            Ok(conn) => {
-                // can only fail if DAEMON_TIMEOUT is 0
-                conn.set_read_timeout(Some(*DAEMON_READ_TIMEOUT)).unwrap();
-                conn.set_write_timeout(Some(*DAEMON_WRITE_TIMEOUT)).unwrap();
-                return Ok(conn);
-            }
+        match tcp_connect_once(addr) {
+            Ok(conn) => return Ok(conn),
             Err(err) => {
                 warn!(
                     "failed to connect daemon at {}: {} (backoff 3 seconds)",
@@ -168,6 +190,15 @@ impl Connection {
         signal: Waiter,
     ) -> Result<Connection> {
         let conn = tcp_connect(addr, &signal)?;
+        Connection::from_stream(conn, cookie_getter, addr, signal)
+    }
+
+    fn from_stream(
+        conn: TcpStream,
+        cookie_getter: Arc<dyn CookieGetter>,
+        addr: SocketAddr,
+        signal: Waiter,
+    ) -> Result<Connection> {
         let reader = BufReader::new(
             conn.try_clone()
                 .chain_err(|| format!("failed to clone {:?}", conn))?,
@@ -185,6 +216,19 @@ impl Connection {
         Connection::new(self.addr, self.cookie_getter.clone(), self.signal.clone())
     }
 
+    // Unlike reconnect(), fails instead of waiting for the daemon to be reachable
+    fn try_reconnect(&self) -> Result<Connection> {
+        let conn = tcp_connect_once(self.addr).chain_err(|| {
+            ErrorKind::Connection(format!("failed to connect daemon at {}", self.addr))
+        })?;
+        Connection::from_stream(
+            conn,
+            self.cookie_getter.clone(),
+            self.addr,
+            self.signal.clone(),
+        )
+    }
+
     fn send(&mut self, request: &str) -> Result<()> {
         let cookie = &self.cookie_getter.get()?;
         let msg = format!(
@@ -209,6 +253,13 @@ impl Connection {
                 ErrorKind::Connection("disconnected from daemon while receiving".to_owned())
             })?
             .chain_err(|| ErrorKind::Connection("failed to read status".to_owned()))?;
+        if status.starts_with("HTTP/1.1 401") {
+            // retried by reconnecting, with the refreshed credentials
+            self.cookie_getter.refresh();
+            bail!(ErrorKind::Connection(
+                "daemon rejected the credentials".to_owned()
+            ));
+        }
         let mut headers = HashMap::new();
         for line in iter {
             let line = line.chain_err(|| ErrorKind::Connection("failed to read".to_owned()))?;
@@ -279,6 +330,94 @@ impl Counter {
     }
 }
 
+#[derive(Default)]
+struct Breaker {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+// Keeps the query calls from piling up while the daemon is unreachable or overloaded, shared by
+// the reconnected instances of the Daemon. After DAEMON_BREAKER_THRESHOLD consecutive connection
+// failures the breaker opens, and the query calls fail right away until the cooldown is over. The
+// calls made after that close it again when they succeed, or re-open it for another cooldown.
+struct RpcGuard {
+    pending: Mutex<usize>,
+    breaker: Mutex<Breaker>,
+    errors: CounterVec,
+    breaker_open: Gauge,
+}
+
+struct PendingCall<'a>(&'a RpcGuard);
+
+impl Drop for PendingCall<'_> {
+    fn drop(&mut self) {
+        *self.0.pending.lock().unwrap() -= 1;
+    }
+}
+
+impl RpcGuard {
+    fn new(metrics: &Metrics) -> Self {
+        RpcGuard {
+            pending: Mutex::new(0),
+            breaker: Mutex::new(Breaker::default()),
+            errors: metrics.counter_vec(
+                MetricOpts::new("daemon_rpc_errors", "Bitcoind RPC errors"),
+                &["method", "kind"],
+            ),
+            breaker_open: metrics.gauge(MetricOpts::new(
+                "daemon_rpc_breaker_open",
+                "Whether the query calls to bitcoind are failing fast (1) or not (0)",
+            )),
+        }
+    }
+
+    fn enter(&self, method: &str) -> Result<PendingCall<'_>> {
+        let open_until = self.breaker.lock().unwrap().open_until;
+        if open_until.map_or(false, |open_until| Instant::now() < open_until) {
+            self.errors
+                .with_label_values(&[method, "breaker_open"])
+                .inc();
+            bail!(ErrorKind::Connection(
+                "daemon unavailable, waiting for it to recover".to_owned()
+            ));
+        }
+        let mut pending = self.pending.lock().unwrap();
+        if *pending >= *DAEMON_MAX_PENDING_CALLS {
+            self.errors.with_label_values(&[method, "overloaded"]).inc();
+            bail!("too many pending daemon calls");
+        }
+        *pending += 1;
+        Ok(PendingCall(self))
+    }
+
+    fn record_failure(&self, method: &str) {
+        self.errors.with_label_values(&[method, "connection"]).inc();
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.failures += 1;
+        if breaker.failures >= *DAEMON_BREAKER_THRESHOLD {
+            if breaker.open_until.is_none() {
+                warn!(
+                    "daemon unreachable after {} attempts, failing the query calls for {:?}",
+                    breaker.failures, *DAEMON_BREAKER_COOLDOWN
+                );
+            }
+            breaker.open_until = Some(Instant::now() + *DAEMON_BREAKER_COOLDOWN);
+            self.breaker_open.set(1);
+        }
+    }
+
+    fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.failures > 0 {
+            if breaker.open_until.is_some() {
+                info!("daemon recovered");
+                self.breaker_open.set(0);
+            }
+            *breaker = Breaker::default();
+        }
+    }
+}
+
 pub struct Daemon {
     daemon_dir: PathBuf,
     blocks_dir: PathBuf,
@@ -286,6 +425,10 @@ pub struct Daemon {
     conn: Mutex<Connection>,
     message_id: Counter, // for monotonic JSONRPC 'id'
     signal: Waiter,
+    guard: Arc<RpcGuard>,
+    pruned: bool,
+    // The height of the lowest block a pruned node still has, refreshed by getblockchaininfo()
+    prune_height: Arc<AtomicUsize>,
 
     // monitoring
     latency: HistogramVec,
@@ -299,10 +442,11 @@ impl Daemon {
         daemon_rpc_addr: SocketAddr,
         cookie_getter: Arc<dyn CookieGetter>,
         network: Network,
+        allow_pruned: bool,
         signal: Waiter,
         metrics: &Metrics,
     ) -> Result<Daemon> {
-        let daemon = Daemon {
+        let mut daemon = Daemon {
             daemon_dir: daemon_dir.clone(),
             blocks_dir: blocks_dir.clone(),
             network,
@@ -313,6 +457,9 @@ impl Daemon {
             )?),
             message_id: Counter::new(),
             signal: signal.clone(),
+            guard: Arc::new(RpcGuard::new(metrics)),
+            pruned: false,
+            prune_height: Arc::new(AtomicUsize::new(0)),
             latency: metrics.histogram_vec(
                 HistogramOpts::new("daemon_rpc", "Bitcoind RPC latency (in seconds)"),
                 &["method"],
@@ -333,7 +480,15 @@ impl Daemon {
         let blockchain_info = daemon.getblockchaininfo()?;
         info!("{:?}", blockchain_info);
         if blockchain_info.pruned {
-            bail!("pruned node is not supported (use '-prune=0' bitcoind flag)".to_owned())
+            if !allow_pruned {
+                bail!(
+                    "pruned node is not supported (use '-prune=0' bitcoind flag, or --allow-pruned)"
+                )
+            }
+            // blocks are indexed while bitcoind downloads them, before they get pruned
+            info!("pruned node, not waiting for its initial sync to finish");
+            daemon.pruned = true;
+            return Ok(daemon);
         }
         loop {
             let info = daemon.getblockchaininfo()?;
@@ -361,6 +516,9 @@ impl Daemon {
             conn: Mutex::new(self.conn.lock().unwrap().reconnect()?),
             message_id: Counter::new(),
             signal: self.signal.clone(),
+            guard: Arc::clone(&self.guard),
+            pruned: self.pruned,
+            prune_height: Arc::clone(&self.prune_height),
             latency: self.latency.clone(),
             size: self.size.clone(),
         })
@@ -377,6 +535,18 @@ impl Daemon {
         Ok(paths)
     }
 
+    // The key of bitcoind's -blocksxor obfuscation of the blk*.dat files (v28+), None if they're
+    // written in the clear
+    pub fn blocks_xor_key(&self) -> Result<Option<Vec<u8>>> {
+        let path = self.blocks_dir.join("xor.dat");
+        if !path.exists() {
+            return Ok(None);
+        }
+        let key = fs::read(&path).chain_err(|| format!("failed to read {:?}", path))?;
+        ensure!(!key.is_empty(), "empty blocks XOR key in {:?}", path);
+        Ok(Some(key).filter(|key| key.iter().any(|&byte| byte != 0)))
+    }
+
     pub fn magic(&self) -> u32 {
         self.network.magic()
     }
@@ -384,6 +554,7 @@ impl Daemon {
     fn call_jsonrpc(&self, method: &str, request: &Value) -> Result<Value> {
         let mut conn = self.conn.lock().unwrap();
         let timer = self.latency.with_label_values(&[method]).start_timer();
+        cost::add_daemon_rpc();
         let request = request.to_string();
         conn.send(&request)?;
         self.size
@@ -421,16 +592,43 @@ impl Daemon {
     }
 
     fn retry_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        let mut backoff = MIN_RETRY_BACKOFF;
         loop {
             match self.handle_request_batch(method, params_list) {
                 Err(Error(ErrorKind::Connection(msg), _)) => {
-                    warn!("reconnecting to bitcoind: {}", msg);
-                    self.signal.wait(Duration::from_secs(3), false)?;
+                    self.guard.record_failure(method);
+                    warn!("reconnecting to bitcoind: {} (backoff {:?})", msg, backoff);
+                    self.signal.wait(backoff, false)?;
+                    backoff = cmp::min(backoff * 2, MAX_RETRY_BACKOFF);
                     let mut conn = self.conn.lock().unwrap();
                     *conn = conn.reconnect()?;
                     continue;
                 }
-                result => return result,
+                result => {
+                    self.guard.record_success();
+                    return result;
+                }
+            }
+        }
+    }
+
+    // Used by the query paths (light mode lookups, broadcasting and fee estimates): makes a single
+    // attempt, and fails right away while the daemon is unreachable or too many calls are pending
+    fn try_request_batch(&self, method: &str, params_list: &[Value]) -> Result<Vec<Value>> {
+        let _pending = self.guard.enter(method)?;
+        match self.handle_request_batch(method, params_list) {
+            Err(Error(ErrorKind::Connection(msg), _)) => {
+                self.guard.record_failure(method);
+                // replace the broken connection for the next calls, if the daemon is back
+                let mut conn = self.conn.lock().unwrap();
+                if let Ok(new_conn) = conn.try_reconnect() {
+                    *conn = new_conn;
+                }
+                bail!(ErrorKind::Connection(msg))
+            }
+            result => {
+                self.guard.record_success();
+                result
             }
         }
     }
@@ -445,11 +643,31 @@ impl Daemon {
         self.retry_request_batch(method, params_list)
     }
 
+    fn try_request(&self, method: &str, params: Value) -> Result<Value> {
+        let mut values = self.try_request_batch(method, &[params])?;
+        assert_eq!(values.len(), 1);
+        Ok(values.remove(0))
+    }
+
     // bitcoind JSONRPC API:
 
     pub fn getblockchaininfo(&self) -> Result<BlockchainInfo> {
         let info: Value = self.request("getblockchaininfo", json!([]))?;
-        Ok(from_value(info).chain_err(|| "invalid blockchain info")?)
+        let info: BlockchainInfo = from_value(info).chain_err(|| "invalid blockchain info")?;
+        if let Some(prune_height) = info.pruneheight {
+            self.prune_height
+                .store(prune_height as usize, Ordering::Relaxed);
+        }
+        Ok(info)
+    }
+
+    pub fn is_pruned(&self) -> bool {
+        self.pruned
+    }
+
+    // The blocks below this height are no longer available, as of the last getblockchaininfo()
+    pub fn prune_height(&self) -> Option<usize> {
+        Some(self.prune_height.load(Ordering::Relaxed)).filter(|_| self.pruned)
     }
 
     fn getnetworkinfo(&self) -> Result<NetworkInfo> {
@@ -457,6 +675,14 @@ impl Daemon {
         Ok(from_value(info).chain_err(|| "invalid network info")?)
     }
 
+    pub fn try_getblockcount(&self) -> Result<usize> {
+        let count = self.try_request("getblockcount", json!([]))?;
+        match count.as_u64() {
+            Some(count) => Ok(count as usize),
+            None => bail!("invalid block count: {}", count),
+        }
+    }
+
     pub fn getbestblockhash(&self) -> Result<BlockHash> {
         parse_hash(&self.request("getbestblockhash", json!([]))?)
     }
@@ -487,7 +713,7 @@ impl Daemon {
     }
 
     pub fn getblock_raw(&self, blockhash: &BlockHash, verbose: u32) -> Result<Value> {
-        self.request("getblock", json!([blockhash, verbose]))
+        self.try_request("getblock", json!([blockhash, verbose]))
     }
 
     pub fn getblocks(&self, blockhashes: &[BlockHash]) -> Result<Vec<Block>> {
@@ -503,6 +729,22 @@ impl Daemon {
         Ok(blocks)
     }
 
+    // The serialized blocks, left for the caller to deserialize
+    pub fn getblocks_raw(&self, blockhashes: &[BlockHash]) -> Result<Vec<Vec<u8>>> {
+        let params_list: Vec<Value> = blockhashes
+            .iter()
+            .map(|hash| json!([hash, /*verbose=*/ false]))
+            .collect();
+        let values = self.requests("getblock", &params_list)?;
+        values
+            .into_iter()
+            .map(|value| {
+                let block_hex = value.as_str().chain_err(|| "non-string block")?;
+                Vec::from_hex(block_hex).chain_err(|| "non-hex block")
+            })
+            .collect()
+    }
+
     pub fn gettransactions(&self, txhashes: &[&Txid]) -> Result<Vec<Transaction>> {
         let params_list: Vec<Value> = txhashes
             .iter()
@@ -524,7 +766,7 @@ impl Daemon {
         blockhash: &BlockHash,
         verbose: bool,
     ) -> Result<Value> {
-        self.request("getrawtransaction", json!([txid, verbose, blockhash]))
+        self.try_request("getrawtransaction", json!([txid, verbose, blockhash]))
     }
 
     pub fn getmempooltx(&self, txhash: &Txid) -> Result<Transaction> {
@@ -542,7 +784,7 @@ impl Daemon {
     }
 
     pub fn broadcast_raw(&self, txhex: &str) -> Result<Txid> {
-        let txid = self.request("sendrawtransaction", json!([txhex]))?;
+        let txid = self.try_request("sendrawtransaction", json!([txhex]))?;
         Ok(
             Txid::from_str(txid.as_str().chain_err(|| "non-string txid")?)
                 .chain_err(|| "failed to parse txid")?,
@@ -556,7 +798,7 @@ impl Daemon {
         let params_list: Vec<Value> = conf_targets.iter().map(|t| json!([t, "ECONOMICAL"])).collect();
 
         Ok(self
-            .requests("estimatesmartfee", &params_list)?
+            .try_request_batch("estimatesmartfee", &params_list)?
             .iter()
             .zip(conf_targets)
             .filter_map(|(reply, target)| {
@@ -649,3 +891,34 @@ impl Daemon {
         Ok(relayfee * 100_000f64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rpc_guard() {
+        let metrics = Metrics::new("127.0.0.1:0".parse().unwrap());
+        let guard = RpcGuard::new(&metrics);
+
+        for _ in 0..*DAEMON_BREAKER_THRESHOLD - 1 {
+            guard.record_failure("getblock");
+        }
+        assert!(guard.enter("getblock").is_ok());
+        guard.record_failure("getblock");
+        assert!(matches!(
+            guard.enter("getblock"),
+            Err(Error(ErrorKind::Connection(_), _))
+        ));
+        assert_eq!(guard.breaker_open.get(), 1);
+
+        guard.record_success();
+        assert_eq!(guard.breaker_open.get(), 0);
+        let pending: Vec<PendingCall> = (0..*DAEMON_MAX_PENDING_CALLS)
+            .map(|_| guard.enter("getblock").unwrap())
+            .collect();
+        assert!(guard.enter("getblock").is_err());
+        drop(pending);
+        assert!(guard.enter("getblock").is_ok());
+    }
+}
@@ -45,6 +45,9 @@ pub struct DiscoveryManager {
     /// Whether we should announce ourselves to the servers we're connecting to
     announce: bool,
 
+    /// If set, only these hostnames can be added via `server.add_peer`
+    allowlist: Option<HashSet<Hostname>>,
+
     /// Optional, will not support onion hosts without this
     tor_proxy: Option<SocketAddr>,
 }
@@ -96,6 +99,7 @@ impl DiscoveryManager {
         our_features: ServerFeatures,
         our_version: ProtocolVersion,
         announce: bool,
+        allowlist: Option<HashSet<Hostname>>,
         tor_proxy: Option<SocketAddr>,
     ) -> Self {
         let our_addrs = our_features
@@ -112,6 +116,7 @@ impl DiscoveryManager {
             our_version,
             our_features,
             announce,
+            allowlist,
             tor_proxy,
             healthy: Default::default(),
             queue: Default::default(),
@@ -148,6 +153,12 @@ impl DiscoveryManager {
                     warn!("skipping invalid hostname");
                     return None;
                 }
+                if let Some(allowlist) = &self.allowlist {
+                    if !allowlist.contains(&hostname) {
+                        warn!("skipping server not in the allowlist ({})", hostname);
+                        return None;
+                    }
+                }
                 let addr = match ServerAddr::resolve(&hostname) {
                     Ok(addr) => addr,
                     Err(e) => {
@@ -546,6 +557,7 @@ mod tests {
             PROTOCOL_VERSION,
             false,
             None,
+            None,
         ));
         discovery.add_default_server(
             "electrum.blockstream.info".into(),
@@ -581,4 +593,44 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_add_peer_allowlist() {
+        let features = |hosts: &str| ServerFeatures {
+            hosts: serde_json::from_str(hosts).unwrap(),
+            server_version: "electrs-esplora 9".into(),
+            genesis_hash: genesis_hash(Network::Regtest),
+            protocol_min: PROTOCOL_VERSION,
+            protocol_max: PROTOCOL_VERSION,
+            hash_function: "sha256".into(),
+            pruning: None,
+        };
+        let allowlist = vec!["allowed.onion".to_string()].into_iter().collect();
+        let discovery = DiscoveryManager::new(
+            Network::Regtest,
+            features("{}"),
+            PROTOCOL_VERSION,
+            false,
+            Some(allowlist),
+            None,
+        );
+
+        // hostnames are compared case-insensitively, the others are skipped
+        discovery
+            .add_server_request(
+                "203.0.113.1".parse().unwrap(),
+                features(
+                    "{\"Allowed.onion\":{\"tcp_port\":50001},\"other.onion\":{\"tcp_port\":50001}}",
+                ),
+            )
+            .unwrap();
+        let queued: Vec<Hostname> = discovery
+            .queue
+            .read()
+            .unwrap()
+            .iter()
+            .map(|job| job.hostname.clone())
+            .collect();
+        assert_eq!(queued, vec!["allowed.onion".to_string()]);
+    }
 }
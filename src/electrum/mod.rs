@@ -1,5 +1,5 @@
 mod server;
-pub use server::RPC;
+pub use server::{ClientStats, RPC};
 
 #[cfg(feature = "electrum-discovery")]
 mod client;
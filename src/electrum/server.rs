@@ -1,14 +1,13 @@
 use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{self, BufRead, BufReader, Write};
 use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
 use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
 use error_chain::ChainedError;
 use hex::{self, DisplayHex};
 use serde_json::{from_str, Value};
@@ -19,17 +18,20 @@ use bitcoin::consensus::encode::serialize_hex;
 use elements::encode::serialize_hex;
 
 use crate::chain::Txid;
-use crate::config::{Config, RpcLogging};
+use crate::config::{Config, DisabledRoute, ElectrumListener, RpcLogging};
 use crate::electrum::{get_electrum_height, ProtocolVersion};
 use crate::errors::*;
 use crate::metrics::{Gauge, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::new_index::{Query, Utxo};
 use crate::util::electrum_merkle::{get_header_merkle_proof, get_id_from_pos, get_tx_merkle_proof};
-use crate::util::{create_socket, spawn_thread, BlockId, BoolThen, Channel, FullHash, HeaderEntry};
+use crate::util::{
+    cost, create_socket, spawn_thread, BlockId, BoolThen, Channel, FullHash, HeaderEntry,
+};
 
 const ELECTRS_VERSION: &str = env!("CARGO_PKG_VERSION");
 const PROTOCOL_VERSION: ProtocolVersion = ProtocolVersion::new(1, 4);
 const MAX_HEADERS: usize = 2016;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
 
 #[cfg(feature = "electrum-discovery")]
 use crate::electrum::{DiscoveryManager, ServerFeatures};
@@ -70,23 +72,29 @@ fn bool_from_value_or(val: Option<&Value>, name: &str, default: bool) -> Result<
 
 // TODO: implement caching and delta updates
 fn get_status_hash(txs: Vec<(Txid, Option<BlockId>)>, query: &Query) -> Option<FullHash> {
-    if txs.is_empty() {
-        None
-    } else {
-        let mut hash = FullHash::default();
-        let mut sha2 = Sha256::new();
-        for (txid, blockid) in txs {
+    let history: Vec<(Txid, isize)> = txs
+        .into_iter()
+        .map(|(txid, blockid)| {
             let is_mempool = blockid.is_none();
             let has_unconfirmed_parents = is_mempool
                 .and_then(|| Some(query.has_unconfirmed_parents(&txid)))
                 .unwrap_or(false);
-            let height = get_electrum_height(blockid, has_unconfirmed_parents);
-            let part = format!("{}:{}:", txid, height);
-            sha2.input(part.as_bytes());
-        }
-        sha2.result(&mut hash);
-        Some(hash)
+            (txid, get_electrum_height(blockid, has_unconfirmed_parents))
+        })
+        .collect();
+    status_hash(&history)
+}
+
+// The sha256 of the concatenated "{txid}:{height}:" entries, or None without any history
+fn status_hash(history: &[(Txid, isize)]) -> Option<FullHash> {
+    if history.is_empty() {
+        return None;
+    }
+    let mut engine = sha256::Hash::engine();
+    for (txid, height) in history {
+        engine.input(format!("{}:{}:", txid, height).as_bytes());
     }
+    Some(sha256::Hash::from_engine(engine).to_byte_array())
 }
 
 macro_rules! conditionally_log_rpc_event {
@@ -109,6 +117,10 @@ struct Connection {
     #[cfg(feature = "electrum-discovery")]
     discovery: Option<Arc<DiscoveryManager>>,
     rpc_logging: Option<RpcLogging>,
+    api_key: Option<String>,
+    listener: Arc<ElectrumListener>,
+    // start of the current rate limit window, # of requests made within it
+    requests: (Instant, u32),
 }
 
 impl Connection {
@@ -120,9 +132,10 @@ impl Connection {
         stats: Arc<Stats>,
         txs_limit: usize,
         #[cfg(feature = "electrum-discovery")] discovery: Option<Arc<DiscoveryManager>>,
-        rpc_logging: Option<RpcLogging>,
+        listener: Arc<ElectrumListener>,
     ) -> Connection {
         Connection {
+            rpc_logging: query.config().electrum_rpc_logging.clone(),
             query,
             last_header_entry: None, // disable header subscription for now
             status_hashes: HashMap::new(),
@@ -133,14 +146,25 @@ impl Connection {
             txs_limit,
             #[cfg(feature = "electrum-discovery")]
             discovery,
-            rpc_logging,
+            api_key: None,
+            listener,
+            requests: (Instant::now(), 0),
         }
     }
 
-    fn blockchain_headers_subscribe(&mut self) -> Result<Value> {
+    fn blockchain_headers_subscribe(&mut self, params: &[Value]) -> Result<Value> {
+        let cp_height = usize_from_value_or(params.get(0), "cp_height", 0)?;
         let entry = self.query.chain().best_header();
         let hex_header = serialize_hex(entry.header());
-        let result = json!({"hex": hex_header, "height": entry.height()});
+        let mut result = json!({"hex": hex_header, "height": entry.height()});
+        // the tip can only be proven against a checkpoint at or above it. a checkpoint above our
+        // tip means we're lagging behind the client and is reported as an error.
+        if cp_height > 0 && cp_height >= entry.height() {
+            let (branch, root) =
+                get_header_merkle_proof(self.query.chain(), entry.height(), cp_height)?;
+            result["root"] = json!(root);
+            result["branch"] = json!(branch);
+        }
         self.last_header_entry = Some(entry);
         Ok(result)
     }
@@ -199,6 +223,51 @@ impl Connection {
         Ok(json!(true))
     }
 
+    fn server_auth(&mut self, params: &[Value]) -> Result<Value> {
+        let key = params.get(0).chain_err(|| "missing key")?;
+        let key = key.as_str().chain_err(|| "non-string key")?;
+        if let Some(api_keys) = self.query.api_keys() {
+            ensure!(api_keys.is_valid(key), "invalid API key");
+        }
+        self.api_key = Some(key.to_string());
+        Ok(json!(true))
+    }
+
+    // Methods that can be called before authenticating with server.auth
+    fn is_auth_exempt(method: &str) -> bool {
+        match method {
+            "server.auth" | "server.version" | "server.ping" => true,
+            _ => false,
+        }
+    }
+
+    // The restrictions of the listener the client connected through
+    fn check_listener_policy(&mut self, method: &str) -> Result<()> {
+        if let Some(route) = DisabledRoute::from_electrum_method(method) {
+            ensure!(
+                !self.query.config().disabled_routes.contains(&route),
+                "route-disabled: {} is disabled on this server",
+                route.name()
+            );
+        }
+        ensure!(
+            self.listener.allows_method(method),
+            "method {} is not allowed",
+            method
+        );
+        if let Some(limit) = self.listener.rate_limit {
+            let now = Instant::now();
+            let (window_start, count) = &mut self.requests;
+            if now.duration_since(*window_start) >= RATE_LIMIT_WINDOW {
+                *window_start = now;
+                *count = 0;
+            }
+            ensure!(*count < limit, "rate limit exceeded");
+            *count += 1;
+        }
+        Ok(())
+    }
+
     fn mempool_get_fee_histogram(&self) -> Result<Value> {
         Ok(json!(&self.query.mempool().backlog_stats().fee_histogram))
     }
@@ -280,6 +349,14 @@ impl Connection {
     fn blockchain_scripthash_subscribe(&mut self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
 
+        let max_subscriptions = self.query.config().electrum_max_subscriptions;
+        if max_subscriptions > 0
+            && self.status_hashes.len() >= max_subscriptions
+            && !self.status_hashes.contains_key(&script_hash)
+        {
+            bail!("too many subscriptions (limit is {})", max_subscriptions);
+        }
+
         let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
         let status_hash = get_status_hash(history_txids, &self.query)
             .map_or(Value::Null, |h| json!(h.to_lower_hex_string()));
@@ -296,8 +373,8 @@ impl Connection {
         let (chain_stats, mempool_stats) = self.query.stats(&script_hash[..]);
 
         Ok(json!({
-            "confirmed": chain_stats.funded_txo_sum - chain_stats.spent_txo_sum,
-            "unconfirmed": mempool_stats.funded_txo_sum as i64 - mempool_stats.spent_txo_sum as i64,
+            "confirmed": chain_stats.balance,
+            "unconfirmed": mempool_stats.balance,
         }))
     }
 
@@ -319,6 +396,40 @@ impl Connection {
             .collect::<Vec<_>>()))
     }
 
+    // Non-standard extension of get_history that also includes the net balance change and the
+    // fee of each transaction, so that wallets can render the history without fetching every
+    // transaction along with its prevouts
+    #[cfg(not(feature = "liquid"))]
+    fn blockchain_scripthash_get_history_ex(&self, params: &[Value]) -> Result<Value> {
+        let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
+        let history = self
+            .query
+            .history_txids_with_delta(&script_hash[..], self.txs_limit + 1);
+        ensure!(history.len() <= self.txs_limit, ErrorKind::TooPopular);
+
+        Ok(json!(history
+            .into_iter()
+            .map(|(txid, blockid, delta)| {
+                let is_mempool = blockid.is_none();
+                let fee = if is_mempool {
+                    self.query.get_mempool_tx_fee(&txid)
+                } else {
+                    self.query.chain().get_tx_fee(&txid)
+                };
+                let has_unconfirmed_parents = is_mempool
+                    .and_then(|| Some(self.query.has_unconfirmed_parents(&txid)))
+                    .unwrap_or(false);
+                let height = get_electrum_height(blockid, has_unconfirmed_parents);
+                json!({
+                    "tx_hash": txid,
+                    "height": height,
+                    "delta": delta,
+                    "fee": fee,
+                })
+            })
+            .collect::<Vec<_>>()))
+    }
+
     fn blockchain_scripthash_listunspent(&self, params: &[Value]) -> Result<Value> {
         let script_hash = hash_from_value(params.get(0)).chain_err(|| "bad script_hash")?;
         let utxos = self.query.utxo(&script_hash[..])?;
@@ -419,15 +530,31 @@ impl Connection {
             .latency
             .with_label_values(&[method])
             .start_timer();
-        let result = match method {
+        let started = Instant::now();
+        cost::reset();
+        let auth = self
+            .check_listener_policy(method)
+            .and_then(|_| match self.query.api_keys() {
+                Some(api_keys) if self.listener.auth && !Connection::is_auth_exempt(method) => {
+                    api_keys
+                        .check(self.api_key.as_deref())
+                        .map_err(|e| Error::from(e.to_string()))
+                }
+                _ => Ok(()),
+            });
+        let result = auth.and_then(|_| match method {
             "blockchain.block.header" => self.blockchain_block_header(&params),
             "blockchain.block.headers" => self.blockchain_block_headers(&params),
             "blockchain.estimatefee" => self.blockchain_estimatefee(&params),
-            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(),
+            "blockchain.headers.subscribe" => self.blockchain_headers_subscribe(&params),
             "blockchain.relayfee" => self.blockchain_relayfee(),
             #[cfg(not(feature = "liquid"))]
             "blockchain.scripthash.get_balance" => self.blockchain_scripthash_get_balance(&params),
             "blockchain.scripthash.get_history" => self.blockchain_scripthash_get_history(&params),
+            #[cfg(not(feature = "liquid"))]
+            "blockchain.scripthash.get_history_ex" => {
+                self.blockchain_scripthash_get_history_ex(&params)
+            }
             "blockchain.scripthash.listunspent" => self.blockchain_scripthash_listunspent(&params),
             "blockchain.scripthash.subscribe" => self.blockchain_scripthash_subscribe(&params),
             "blockchain.transaction.broadcast" => self.blockchain_transaction_broadcast(&params),
@@ -440,6 +567,7 @@ impl Connection {
             "server.banner" => self.server_banner(),
             "server.donation_address" => self.server_donation_address(),
             "server.peers.subscribe" => self.server_peers_subscribe(),
+            "server.auth" => self.server_auth(&params),
             "server.ping" => Ok(Value::Null),
             "server.version" => self.server_version(),
 
@@ -449,13 +577,29 @@ impl Connection {
             "server.add_peer" => self.server_add_peer(&params),
 
             &_ => bail!("unknown method {} {:?}", method, params),
-        };
+        });
         timer.observe_duration();
+        let elapsed = started.elapsed();
+        let query_cost = cost::take();
+        if cost::is_slow_query(elapsed, &query_cost, self.query.config()) {
+            tracing::warn!(
+                target: "electrs::electrum",
+                "[{}] slow rpc {} {:?}: took {}ms, scanned {} rows, deserialized {} txs, made {} daemon rpcs",
+                self.addr,
+                method,
+                params,
+                elapsed.as_millis(),
+                query_cost.rows_scanned,
+                query_cost.txs_deserialized,
+                query_cost.daemon_rpcs
+            );
+        }
         // TODO: return application errors should be sent to the client
         Ok(match result {
             Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
             Err(e) => {
-                warn!(
+                tracing::warn!(
+                    target: "electrs::electrum",
                     "rpc #{} {} {:?} failed: {}",
                     id,
                     method,
@@ -486,8 +630,13 @@ impl Connection {
                     "params": [header]}));
             }
         }
-        for (script_hash, status_hash) in self.status_hashes.iter_mut() {
-            let history_txids = get_history(&self.query, &script_hash[..], self.txs_limit)?;
+        // the histories of all the subscriptions are read at once, see history_txids_batch()
+        let script_hashes: Vec<Sha256dHash> = self.status_hashes.keys().copied().collect();
+        let keys: Vec<&[u8]> = script_hashes.iter().map(|h| &h[..]).collect();
+        let histories = self.query.history_txids_batch(&keys, self.txs_limit + 1);
+        for (script_hash, history_txids) in script_hashes.into_iter().zip(histories) {
+            ensure!(history_txids.len() <= self.txs_limit, ErrorKind::TooPopular);
+            let status_hash = self.status_hashes.get_mut(&script_hash).unwrap();
             let new_status_hash = get_status_hash(history_txids, &self.query)
                 .map_or(Value::Null, |h| json!(h.to_lower_hex_string()));
             if new_status_hash == *status_hash {
@@ -529,7 +678,7 @@ impl Connection {
         loop {
             let msg = receiver.recv().chain_err(|| "channel closed")?;
             let start_time = Instant::now();
-            trace!("RPC {:?}", msg);
+            tracing::trace!(target: "electrs::electrum", "RPC {:?}", msg);
             match msg {
                 Message::Request(line) => {
                     let cmd: Value = from_str(&line).chain_err(|| "invalid JSON format")?;
@@ -543,6 +692,16 @@ impl Connection {
                             &Value::Array(ref params),
                             Some(ref id),
                         ) => {
+                            // the records logged while handling the request are attached to
+                            // this span
+                            let _span = tracing::debug_span!(
+                                target: "electrs::electrum",
+                                "request",
+                                request_id = %id,
+                                %method,
+                                client = %self.addr
+                            )
+                            .entered();
                             conditionally_log_rpc_event!(
                                 self,
                                 json!({
@@ -569,6 +728,15 @@ impl Connection {
                                     "id": id,
                                 })
                             );
+                            tracing::debug!(
+                                target: "electrs::electrum",
+                                request_id = %id,
+                                %method,
+                                status = if reply.get("error").is_some() { "error" } else { "ok" },
+                                duration_ms = start_time.elapsed().as_millis() as u64,
+                                client = %self.addr,
+                                "request served"
+                            );
 
                             self.send_values(&[reply])?
                         }
@@ -588,12 +756,29 @@ impl Connection {
         }
     }
 
-    fn parse_requests(mut reader: BufReader<TcpStream>, tx: &SyncSender<Message>) -> Result<()> {
+    // With `reject_when_full` set, clients that have too many pending requests are disconnected
+    // instead of waiting for them to be processed
+    fn parse_requests(
+        mut reader: BufReader<TcpStream>,
+        tx: &SyncSender<Message>,
+        reject_when_full: bool,
+    ) -> Result<()> {
         loop {
             let mut line = Vec::<u8>::new();
-            reader
-                .read_until(b'\n', &mut line)
-                .chain_err(|| "failed to read a request")?;
+            match reader.read_until(b'\n', &mut line) {
+                Ok(_) => (),
+                // the read timeout is set to the idle timeout
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    debug!("closing idle connection");
+                    return Ok(());
+                }
+                Err(e) => return Err(e).chain_err(|| "failed to read a request"),
+            }
             if line.is_empty() {
                 return Ok(());
             } else {
@@ -602,6 +787,11 @@ impl Connection {
                     bail!("invalid request - maybe SSL-encrypted data?: {:?}", line)
                 }
                 match String::from_utf8(line) {
+                    Ok(req) if reject_when_full => match tx.try_send(Message::Request(req)) {
+                        Ok(()) => (),
+                        Err(TrySendError::Full(_)) => bail!("too many pending requests"),
+                        Err(TrySendError::Disconnected(_)) => bail!("channel closed"),
+                    },
                     Ok(req) => tx
                         .send(Message::Request(req))
                         .chain_err(|| "channel closed")?,
@@ -613,8 +803,12 @@ impl Connection {
         }
     }
 
-    fn reader_thread(reader: BufReader<TcpStream>, tx: SyncSender<Message>) -> Result<()> {
-        let result = Connection::parse_requests(reader, &tx);
+    fn reader_thread(
+        reader: BufReader<TcpStream>,
+        tx: SyncSender<Message>,
+        reject_when_full: bool,
+    ) -> Result<()> {
+        let result = Connection::parse_requests(reader, &tx, reject_when_full);
         if let Err(e) = tx.send(Message::Done) {
             warn!("failed closing channel: {}", e);
         }
@@ -625,9 +819,18 @@ impl Connection {
         self.stats.clients.inc();
         conditionally_log_rpc_event!(self, json!({ "event": "connection established" }));
 
+        let idle_timeout = self.query.config().electrum_idle_timeout;
+        if idle_timeout > 0 {
+            self.stream
+                .set_read_timeout(Some(Duration::from_secs(idle_timeout)))
+                .expect("failed to set read timeout");
+        }
         let reader = BufReader::new(self.stream.try_clone().expect("failed to clone TcpStream"));
         let sender = self.sender.clone();
-        let child = spawn_thread("reader", || Connection::reader_thread(reader, sender));
+        let reject_when_full = self.query.config().electrum_max_pending > 0;
+        let child = spawn_thread("reader", move || {
+            Connection::reader_thread(reader, sender, reject_when_full)
+        });
         if let Err(e) = self.handle_replies(receiver) {
             error!(
                 "[{}] connection handling failed: {}",
@@ -670,6 +873,8 @@ struct GetHistoryResult {
     fee: Option<u64>,
 }
 
+type Accepted = (TcpStream, SocketAddr, usize);
+
 #[derive(Debug)]
 pub enum Message {
     Request(String),
@@ -685,6 +890,14 @@ pub enum Notification {
 pub struct RPC {
     notification: Sender<Notification>,
     server: Option<thread::JoinHandle<()>>, // so we can join the server while dropping this ojbect
+    client_stats: ClientStats,
+}
+
+// The number of connected clients and of their subscriptions
+#[derive(Clone)]
+pub struct ClientStats {
+    pub clients: Gauge,
+    pub subscriptions: Gauge,
 }
 
 struct Stats {
@@ -697,7 +910,7 @@ impl RPC {
     fn start_notifier(
         notification: Channel<Notification>,
         senders: Arc<Mutex<Vec<SyncSender<Message>>>>,
-        acceptor: Sender<Option<(TcpStream, SocketAddr)>>,
+        acceptor: Sender<Option<Accepted>>,
     ) {
         spawn_thread("notification", move || {
             for msg in notification.receiver().iter() {
@@ -720,9 +933,7 @@ impl RPC {
         });
     }
 
-    fn start_acceptor(addr: SocketAddr) -> Channel<Option<(TcpStream, SocketAddr)>> {
-        let chan = Channel::unbounded();
-        let acceptor = chan.sender();
+    fn start_acceptor(addr: SocketAddr, index: usize, acceptor: Sender<Option<Accepted>>) {
         spawn_thread("acceptor", move || {
             let socket = create_socket(&addr);
             socket.listen(511).expect("setting backlog failed");
@@ -737,10 +948,11 @@ impl RPC {
                 stream
                     .set_nonblocking(false)
                     .expect("failed to set connection as blocking");
-                acceptor.send(Some((stream, addr))).expect("send failed");
+                acceptor
+                    .send(Some((stream, addr, index)))
+                    .expect("send failed");
             }
         });
-        chan
     }
 
     pub fn start(config: Arc<Config>, query: Arc<Query>, metrics: &Metrics) -> RPC {
@@ -758,6 +970,11 @@ impl RPC {
         stats.clients.set(0);
         stats.subscriptions.set(0);
 
+        let client_stats = ClientStats {
+            clients: stats.clients.clone(),
+            subscriptions: stats.subscriptions.clone(),
+        };
+
         let notification = Channel::unbounded();
 
         // Discovery is enabled when electrum-public-hosts is set
@@ -778,36 +995,71 @@ impl RPC {
                 features,
                 PROTOCOL_VERSION,
                 config.electrum_announce,
+                config.electrum_peers_allowlist.clone(),
                 config.tor_proxy,
             ));
             DiscoveryManager::spawn_jobs_thread(Arc::clone(&discovery));
             discovery
         });
 
-        let rpc_addr = config.electrum_rpc_addr;
+        let listeners: Vec<Arc<ElectrumListener>> = std::iter::once(ElectrumListener::new(
+            config.electrum_rpc_addr,
+            config.electrum_max_connections,
+        ))
+        .chain(config.electrum_listeners.iter().cloned())
+        .map(Arc::new)
+        .collect();
         let txs_limit = config.electrum_txs_limit;
+        let max_pending = config.electrum_max_pending;
 
         RPC {
             notification: notification.sender(),
             server: Some(spawn_thread("rpc", move || {
                 let senders = Arc::new(Mutex::new(Vec::<SyncSender<Message>>::new()));
 
-                let acceptor = RPC::start_acceptor(rpc_addr);
+                // the connections accepted by all the listeners, along with the listener's index
+                let acceptor = Channel::unbounded();
+                for (index, listener) in listeners.iter().enumerate() {
+                    RPC::start_acceptor(listener.addr, index, acceptor.sender());
+                }
                 RPC::start_notifier(notification, senders.clone(), acceptor.sender());
 
-                let mut threads = HashMap::new();
+                let mut threads: HashMap<thread::ThreadId, (usize, thread::JoinHandle<()>)> =
+                    HashMap::new();
                 let (garbage_sender, garbage_receiver) = crossbeam_channel::unbounded();
 
-                while let Some((stream, addr)) = acceptor.receiver().recv().unwrap() {
+                while let Some((mut stream, addr, index)) = acceptor.receiver().recv().unwrap() {
+                    while let Ok(id) = garbage_receiver.try_recv() {
+                        if let Some((_, thread)) = threads.remove(&id) {
+                            trace!("[{}] joining {:?}", addr, id);
+                            if let Err(error) = thread.join() {
+                                error!("failed to join {:?}: {:?}", id, error);
+                            }
+                        }
+                    }
+                    let listener = Arc::clone(&listeners[index]);
+                    let connections = threads.values().filter(|(i, _)| *i == index).count();
+                    if listener.max_connections > 0 && connections >= listener.max_connections {
+                        warn!("[{}] rejecting connection, too many clients", addr);
+                        let reply =
+                            json!({"jsonrpc": "2.0", "id": null, "error": "too many connections"});
+                        let _ = stream.write_all((reply.to_string() + "\n").as_bytes());
+                        let _ = stream.shutdown(Shutdown::Both);
+                        continue;
+                    }
+
                     // explicitly scope the shadowed variables for the new thread
                     let query = Arc::clone(&query);
                     let stats = Arc::clone(&stats);
                     let garbage_sender = garbage_sender.clone();
-                    let rpc_logging = config.electrum_rpc_logging.clone();
                     #[cfg(feature = "electrum-discovery")]
                     let discovery = discovery.clone();
 
-                    let (sender, receiver) = mpsc::sync_channel(10);
+                    let (sender, receiver) = match max_pending {
+                        0 => mpsc::sync_channel(10),
+                        // leave room for the periodic updates
+                        max_pending => mpsc::sync_channel(max_pending + 1),
+                    };
                     senders.lock().unwrap().push(sender.clone());
 
                     let spawned = spawn_thread("peer", move || {
@@ -821,7 +1073,7 @@ impl RPC {
                             txs_limit,
                             #[cfg(feature = "electrum-discovery")]
                             discovery,
-                            rpc_logging,
+                            listener,
                         );
                         conn.run(receiver);
                         info!("[{}] disconnected peer", addr);
@@ -829,15 +1081,7 @@ impl RPC {
                     });
 
                     trace!("[{}] spawned {:?}", addr, spawned.thread().id());
-                    threads.insert(spawned.thread().id(), spawned);
-                    while let Ok(id) = garbage_receiver.try_recv() {
-                        if let Some(thread) = threads.remove(&id) {
-                            trace!("[{}] joining {:?}", addr, id);
-                            if let Err(error) = thread.join() {
-                                error!("failed to join {:?}: {:?}", id, error);
-                            }
-                        }
-                    }
+                    threads.insert(spawned.thread().id(), (index, spawned));
                 }
 
                 trace!("closing {} RPC connections", senders.lock().unwrap().len());
@@ -845,7 +1089,7 @@ impl RPC {
                     let _ = sender.send(Message::Done);
                 }
 
-                for (id, thread) in threads {
+                for (id, (_, thread)) in threads {
                     trace!("joining {:?}", id);
                     if let Err(error) = thread.join() {
                         error!("failed to join {:?}: {:?}", id, error);
@@ -854,12 +1098,17 @@ impl RPC {
 
                 trace!("RPC connections are closed");
             })),
+            client_stats,
         }
     }
 
     pub fn notify(&self) {
         self.notification.send(Notification::Periodic).unwrap();
     }
+
+    pub fn client_stats(&self) -> ClientStats {
+        self.client_stats.clone()
+    }
 }
 
 impl Drop for RPC {
@@ -872,3 +1121,33 @@ impl Drop for RPC {
         trace!("RPC server is stopped");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_status_hash() {
+        let txid = |hex: &str| Txid::from_str(hex).unwrap();
+        let genesis = txid("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b");
+        let confirmed = txid("0e3e2357e806b6cdb1f70b54c3a3a17b6714ee1f0e68bebb44a74b1efd512098");
+        let unconfirmed = txid("f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16");
+        let hex = |history: &[(Txid, isize)]| status_hash(history).map(|h| h.to_lower_hex_string());
+
+        assert_eq!(hex(&[]), None);
+        assert_eq!(
+            hex(&[(genesis, 0)]).as_deref(),
+            Some("29beb5f7aa420d38725efea2ca01053de004f20816024af33a7a80a6b5a95a5b")
+        );
+        assert_eq!(
+            hex(&[(genesis, 0), (confirmed, 1), (unconfirmed, 0)]).as_deref(),
+            Some("f375178e4c203777c3ca3c571cdadd239dbd617f007807824af9852b35879cd3")
+        );
+        // mempool transactions with unconfirmed parents are at height -1
+        assert_eq!(
+            hex(&[(genesis, 0), (confirmed, 1), (unconfirmed, -1)]).as_deref(),
+            Some("fa267efefdb2b48b8933927ed1422b7568c9b4e3cda2847f3994ba1e2c4c3bf1")
+        );
+    }
+}
@@ -5,6 +5,7 @@ use elements::{issuance::ContractHash, AssetId, TxIn};
 pub mod asset;
 pub mod peg;
 mod registry;
+pub mod unblind;
 
 use asset::get_issuance_entropy;
 pub use asset::{lookup_asset, LiquidAsset};
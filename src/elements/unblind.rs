@@ -0,0 +1,178 @@
+// Unblinding of confidential outputs on behalf of wallet backends. The blinding keys are only used
+// for the duration of the request, and are never logged or persisted.
+
+use std::str::FromStr;
+
+use elements::secp256k1_zkp::{All, Secp256k1, SecretKey};
+use elements::{AssetId, Transaction};
+
+use crate::errors::*;
+
+pub const MAX_BLINDING_KEYS: usize = 20;
+
+lazy_static! {
+    static ref SECP: Secp256k1<All> = Secp256k1::new();
+}
+
+#[derive(Serialize)]
+pub struct UnblindedOutput {
+    pub vout: u32,
+    pub value: u64,
+    pub asset: AssetId,
+    pub value_blinder: String,
+    pub asset_blinder: String,
+}
+
+pub fn parse_blinding_keys(keys: &[String]) -> Result<Vec<SecretKey>> {
+    ensure!(
+        !keys.is_empty() && keys.len() <= MAX_BLINDING_KEYS,
+        "expected between 1 and {} blinding keys",
+        MAX_BLINDING_KEYS
+    );
+    keys.iter()
+        .map(|key| SecretKey::from_str(key).chain_err(|| "invalid blinding key"))
+        .collect()
+}
+
+// Tries each of the keys on the confidential outputs, returning those that could be unblinded
+pub fn unblind_outputs(tx: &Transaction, keys: &[SecretKey]) -> Vec<UnblindedOutput> {
+    tx.output
+        .iter()
+        .enumerate()
+        .filter(|(_, txout)| txout.value.is_confidential() || txout.asset.is_confidential())
+        .filter_map(|(vout, txout)| {
+            let secrets = keys
+                .iter()
+                .find_map(|key| txout.unblind(&SECP, *key).ok())?;
+            Some(UnblindedOutput {
+                vout: vout as u32,
+                value: secrets.value,
+                asset: secrets.asset,
+                value_blinder: secrets.value_bf.to_string(),
+                asset_blinder: secrets.asset_bf.to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use elements::bitcoin;
+    use elements::secp256k1_zkp::rand::{self, CryptoRng, RngCore};
+    use elements::secp256k1_zkp::PublicKey;
+    use elements::{Address, AddressParams, LockTime, TxOut, TxOutSecrets};
+    use elements::{AssetBlindingFactor, ValueBlindingFactor};
+
+    // Deterministic stand-in for a CSPRNG, so that the blinded output is the same on every run
+    struct TestRng(u64);
+
+    impl RngCore for TestRng {
+        fn next_u32(&mut self) -> u32 {
+            self.next_u64() as u32
+        }
+        fn next_u64(&mut self) -> u64 {
+            // xorshift64
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for chunk in dest.chunks_mut(8) {
+                let bytes = self.next_u64().to_le_bytes();
+                chunk.copy_from_slice(&bytes[..chunk.len()]);
+            }
+        }
+        fn try_fill_bytes(&mut self, dest: &mut [u8]) -> std::result::Result<(), rand::Error> {
+            self.fill_bytes(dest);
+            Ok(())
+        }
+    }
+
+    impl CryptoRng for TestRng {}
+
+    fn secret_key(byte: u8) -> SecretKey {
+        SecretKey::from_slice(&[byte; 32]).unwrap()
+    }
+
+    // A transaction paying `value` to a confidential address under `blinding_key`, followed by an
+    // explicit fee output. Returns the blinders that were used for the confidential output.
+    fn blinded_tx(
+        value: u64,
+        asset: AssetId,
+        blinding_key: &SecretKey,
+    ) -> (Transaction, AssetBlindingFactor, ValueBlindingFactor) {
+        let spk = PublicKey::from_secret_key(&SECP, &secret_key(2));
+        let address = Address::p2wpkh(
+            &bitcoin::PublicKey::new(spk),
+            Some(PublicKey::from_secret_key(&SECP, blinding_key)),
+            &AddressParams::ELEMENTS,
+        );
+        let spent = [TxOutSecrets::new(
+            asset,
+            AssetBlindingFactor::zero(),
+            value + 500,
+            ValueBlindingFactor::zero(),
+        )];
+        let (txout, abf, vbf, _) = TxOut::new_not_last_confidential(
+            &mut TestRng(0x5eed),
+            &SECP,
+            value,
+            address,
+            asset,
+            &spent,
+        )
+        .unwrap();
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![],
+            output: vec![txout, TxOut::new_fee(500, asset)],
+        };
+        (tx, abf, vbf)
+    }
+
+    #[test]
+    fn test_unblind_outputs() {
+        let asset = AssetId::LIQUID_BTC;
+        let blinding_key = secret_key(1);
+        let (tx, abf, vbf) = blinded_tx(123_456, asset, &blinding_key);
+        assert!(tx.output[0].value.is_confidential());
+
+        // The explicit fee output is skipped, the confidential one is opened with the right key
+        // even when it isn't the first one given
+        let unblinded = unblind_outputs(&tx, &[secret_key(3), blinding_key]);
+        assert_eq!(unblinded.len(), 1);
+        assert_eq!(unblinded[0].vout, 0);
+        assert_eq!(unblinded[0].value, 123_456);
+        assert_eq!(unblinded[0].asset, asset);
+        assert_eq!(unblinded[0].value_blinder, vbf.to_string());
+        assert_eq!(unblinded[0].asset_blinder, abf.to_string());
+
+        // A key the output wasn't blinded to opens nothing
+        assert!(unblind_outputs(&tx, &[secret_key(3)]).is_empty());
+    }
+
+    #[test]
+    fn test_parse_blinding_keys() {
+        let key = "01".repeat(32);
+        assert_eq!(
+            parse_blinding_keys(&[key.clone()]).unwrap(),
+            vec![secret_key(1)]
+        );
+        assert_eq!(
+            parse_blinding_keys(&vec![key.clone(); MAX_BLINDING_KEYS])
+                .unwrap()
+                .len(),
+            MAX_BLINDING_KEYS
+        );
+
+        assert!(parse_blinding_keys(&[]).is_err());
+        assert!(parse_blinding_keys(&vec![key; MAX_BLINDING_KEYS + 1]).is_err());
+        assert!(parse_blinding_keys(&["00".repeat(32)]).is_err());
+        assert!(parse_blinding_keys(&["not hex".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use url::Url;
+
+use crate::errors::*;
+use crate::events::{Event, EventSink};
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Publishes events to a NATS server or to Redis pub/sub channels. Delivery is at-most-once:
+// events that fail to publish are dropped, and the connection is re-established on the next
+// update.
+pub struct MessageBus {
+    url: Url,
+    prefix: String,
+    conn: Option<Box<dyn BusConnection>>,
+}
+
+trait BusConnection: Send {
+    fn publish(&mut self, subject: &str, payload: &str) -> Result<()>;
+    fn keepalive(&mut self) -> Result<()>;
+}
+
+impl MessageBus {
+    pub fn new(url: &str, prefix: &str) -> Result<Self> {
+        let url = Url::parse(url).chain_err(|| format!("invalid event bus url {}", url))?;
+        match url.scheme() {
+            "nats" | "redis" => (),
+            scheme => bail!(
+                "unsupported event bus {} (expected nats:// or redis://)",
+                scheme
+            ),
+        }
+        Ok(MessageBus {
+            url,
+            prefix: prefix.to_string(),
+            conn: None,
+        })
+    }
+
+    fn connect(&self) -> Result<Box<dyn BusConnection>> {
+        let host = self.url.host_str().chain_err(|| "missing event bus host")?;
+        let port = match self.url.scheme() {
+            "nats" => self.url.port().unwrap_or(4222),
+            _ => self.url.port().unwrap_or(6379),
+        };
+        let stream = TcpStream::connect((host, port))
+            .chain_err(|| format!("failed connecting to event bus at {}:{}", host, port))?;
+        stream
+            .set_read_timeout(Some(IO_TIMEOUT))
+            .and_then(|_| stream.set_write_timeout(Some(IO_TIMEOUT)))
+            .chain_err(|| "failed setting event bus socket timeouts")?;
+        let user = Some(self.url.username()).filter(|user| !user.is_empty());
+        let password = self.url.password();
+
+        Ok(match self.url.scheme() {
+            "nats" => Box::new(NatsConnection::new(stream, user, password)?),
+            _ => Box::new(RedisConnection::new(stream, user, password)?),
+        })
+    }
+
+    // Runs f with an open connection, which gets dropped on failure
+    fn with_connection<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn BusConnection) -> Result<()>,
+    {
+        if self.conn.is_none() {
+            self.conn = Some(self.connect()?);
+        }
+        let result = f(self.conn.as_mut().unwrap().as_mut());
+        if result.is_err() {
+            self.conn = None;
+        }
+        result
+    }
+}
+
+impl EventSink for MessageBus {
+    fn name(&self) -> &'static str {
+        "bus"
+    }
+
+    fn publish(&mut self, events: &[Event]) -> Result<()> {
+        let prefix = self.prefix.clone();
+        self.with_connection(|conn| {
+            for event in events {
+                for (subject, payload) in event.messages(&prefix) {
+                    conn.publish(&subject, &payload)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn keepalive(&mut self) -> Result<()> {
+        self.with_connection(|conn| conn.keepalive())
+    }
+}
+
+// Speaks the NATS text protocol: https://docs.nats.io/reference/reference-protocols/nats-protocol
+struct NatsConnection {
+    stream: TcpStream,
+}
+
+impl NatsConnection {
+    fn new(stream: TcpStream, user: Option<&str>, password: Option<&str>) -> Result<Self> {
+        let mut info = String::new();
+        BufReader::new(&stream)
+            .read_line(&mut info)
+            .chain_err(|| "failed reading NATS greeting")?;
+        ensure!(
+            info.starts_with("INFO "),
+            "unexpected NATS greeting: {}",
+            info.trim()
+        );
+
+        let mut conn = NatsConnection { stream };
+        let options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "name": "electrs",
+            "user": user,
+            "pass": password,
+        });
+        write!(conn.stream, "CONNECT {}\r\n", options).chain_err(|| "failed connecting to NATS")?;
+        Ok(conn)
+    }
+}
+
+impl BusConnection for NatsConnection {
+    fn publish(&mut self, subject: &str, payload: &str) -> Result<()> {
+        write!(
+            self.stream,
+            "PUB {} {}\r\n{}\r\n",
+            subject,
+            payload.len(),
+            payload
+        )
+        .chain_err(|| "failed publishing to NATS")
+    }
+
+    // The server periodically PINGs its clients and drops the ones that don't reply
+    fn keepalive(&mut self) -> Result<()> {
+        self.stream
+            .set_nonblocking(true)
+            .chain_err(|| "failed setting NATS socket to non-blocking")?;
+        let mut received = vec![];
+        let mut buf = [0u8; 1024];
+        // whether the server closed the connection
+        let read_result = loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break Ok(true),
+                Ok(n) => received.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(false),
+                Err(e) => break Err(Error::with_chain(e, "failed reading from NATS")),
+            }
+        };
+        self.stream
+            .set_nonblocking(false)
+            .chain_err(|| "failed setting NATS socket to blocking")?;
+        let closed = read_result?;
+
+        // the server closes the connection right after sending an error
+        let received = String::from_utf8_lossy(&received);
+        if let Some(err) = received.lines().find(|line| line.starts_with("-ERR")) {
+            bail!("NATS server error: {}", err);
+        }
+        ensure!(!closed, "connection closed by the NATS server");
+        for _ in received.matches("PING\r\n") {
+            self.stream
+                .write_all(b"PONG\r\n")
+                .chain_err(|| "failed replying to NATS PING")?;
+        }
+        Ok(())
+    }
+}
+
+// Publishes to Redis pub/sub channels named after the subjects
+struct RedisConnection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl RedisConnection {
+    fn new(stream: TcpStream, user: Option<&str>, password: Option<&str>) -> Result<Self> {
+        let reader = BufReader::new(stream.try_clone().chain_err(|| "failed cloning socket")?);
+        let mut conn = RedisConnection { stream, reader };
+        match (user, password) {
+            (Some(user), Some(password)) => conn.command(&["AUTH", user, password])?,
+            (None, Some(password)) => conn.command(&["AUTH", password])?,
+            _ => String::new(),
+        };
+        Ok(conn)
+    }
+
+    // Sends a command and returns its (single line) reply
+    fn command(&mut self, args: &[&str]) -> Result<String> {
+        let mut request = format!("*{}\r\n", args.len());
+        for arg in args {
+            request.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        self.stream
+            .write_all(request.as_bytes())
+            .chain_err(|| "failed sending Redis command")?;
+
+        let mut reply = String::new();
+        self.reader
+            .read_line(&mut reply)
+            .chain_err(|| "failed reading Redis reply")?;
+        ensure!(!reply.is_empty(), "connection closed by the Redis server");
+        ensure!(!reply.starts_with('-'), "Redis error: {}", reply.trim());
+        Ok(reply)
+    }
+}
+
+impl BusConnection for RedisConnection {
+    fn publish(&mut self, subject: &str, payload: &str) -> Result<()> {
+        self.command(&["PUBLISH", subject, payload])?;
+        Ok(())
+    }
+
+    fn keepalive(&mut self) -> Result<()> {
+        self.command(&["PING"])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain::Txid;
+    use std::net::TcpListener;
+    use std::str::FromStr;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn test_event() -> Event {
+        Event::Transaction {
+            txid: Txid::from_str(
+                "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b",
+            )
+            .unwrap(),
+            blockid: None,
+            scripthashes: vec![[1u8; 32]],
+        }
+    }
+
+    // Serves a single connection with the given mock server
+    fn mock_server<F>(scheme: &str, serve: F) -> (MessageBus, thread::JoinHandle<()>)
+    where
+        F: FnOnce(TcpStream) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "{}://user:pass@127.0.0.1:{}",
+            scheme,
+            listener.local_addr().unwrap().port()
+        );
+        let server = thread::spawn(move || serve(listener.accept().unwrap().0));
+        (MessageBus::new(&url, "test").unwrap(), server)
+    }
+
+    fn read_line(reader: &mut impl BufRead) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    }
+
+    #[test]
+    fn test_nats_framing() {
+        let (pinged_tx, pinged_rx) = mpsc::channel();
+        let (ponged_tx, ponged_rx) = mpsc::channel();
+        let (mut bus, server) = mock_server("nats", move |mut stream| {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            stream
+                .write_all(b"INFO {\"max_payload\":1048576}\r\n")
+                .unwrap();
+
+            let connect = read_line(&mut reader);
+            assert!(connect.starts_with("CONNECT ") && connect.ends_with("\r\n"));
+            let options: serde_json::Value = serde_json::from_str(&connect[8..]).unwrap();
+            assert_eq!(options["user"], "user");
+            assert_eq!(options["pass"], "pass");
+            assert_eq!(options["verbose"], false);
+
+            for (subject, payload) in test_event().messages("test") {
+                assert_eq!(
+                    read_line(&mut reader),
+                    format!("PUB {} {}\r\n", subject, payload.len())
+                );
+                assert_eq!(read_line(&mut reader), format!("{}\r\n", payload));
+            }
+
+            stream.write_all(b"PING\r\n").unwrap();
+            pinged_tx.send(()).unwrap();
+            assert_eq!(read_line(&mut reader), "PONG\r\n");
+            ponged_tx.send(()).unwrap();
+
+            stream
+                .write_all(b"-ERR 'Unknown Protocol Operation'\r\n")
+                .unwrap();
+        });
+
+        bus.publish(&[test_event()]).unwrap();
+
+        // the PINGs received in between are answered
+        pinged_rx.recv().unwrap();
+        let ponged = (0..100).any(|_| {
+            bus.keepalive().unwrap();
+            thread::sleep(Duration::from_millis(20));
+            ponged_rx.try_recv().is_ok()
+        });
+        assert!(ponged);
+
+        // server errors fail the keepalive and drop the connection
+        server.join().unwrap();
+        let err = (0..100)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(20));
+                bus.keepalive().err()
+            })
+            .expect("missing NATS error");
+        assert_eq!(
+            err.to_string(),
+            "NATS server error: -ERR 'Unknown Protocol Operation'"
+        );
+        assert!(bus.conn.is_none());
+    }
+
+    #[test]
+    fn test_redis_framing() {
+        let (mut bus, server) = mock_server("redis", |mut stream| {
+            let mut expect = |request: &[&str], reply: &[u8]| {
+                let mut expected = format!("*{}\r\n", request.len());
+                for arg in request {
+                    expected.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+                }
+                let mut received = vec![0; expected.len()];
+                stream.read_exact(&mut received).unwrap();
+                assert_eq!(String::from_utf8(received).unwrap(), expected);
+                stream.write_all(reply).unwrap();
+            };
+
+            expect(&["AUTH", "user", "pass"], b"+OK\r\n");
+            for (subject, payload) in test_event().messages("test") {
+                expect(&["PUBLISH", &subject, &payload], b":1\r\n");
+            }
+            expect(&["PING"], b"+PONG\r\n");
+            expect(&["PING"], b"-ERR unknown command\r\n");
+        });
+
+        bus.publish(&[test_event()]).unwrap();
+        bus.keepalive().unwrap();
+
+        // error replies fail the command and drop the connection
+        let err = bus.keepalive().unwrap_err();
+        assert_eq!(err.to_string(), "Redis error: -ERR unknown command");
+        assert!(bus.conn.is_none());
+        server.join().unwrap();
+    }
+}
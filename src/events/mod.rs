@@ -0,0 +1,357 @@
+// Publishing of index events (new blocks, and transactions along with the scripthashes they
+// touch) to external consumers, so that downstream services don't have to poll the REST API.
+
+mod bus;
+mod webhook;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+
+use error_chain::ChainedError;
+use hex::DisplayHex;
+
+use crate::chain::{BlockHash, OutPoint, Transaction, TxOut, Txid};
+use crate::config::Config;
+use crate::errors::*;
+use crate::metrics::{CounterVec, MetricOpts, Metrics};
+use crate::new_index::{compute_script_hash, Query};
+use crate::util::{has_prevout, is_spendable, spawn_thread, BlockId, FullHash, TransactionStatus};
+
+pub use self::bus::MessageBus;
+pub use self::webhook::{
+    ConfirmationWatch, Webhook, WebhookRegistry, WebhookSink, DEFAULT_CONFIRMATION_TARGETS,
+    MAX_WEBHOOK_SCRIPTHASHES,
+};
+
+// Number of recently published blocks that are remembered to detect reorgs
+const RECENT_BLOCKS: usize = 100;
+
+#[derive(Debug)]
+pub enum Event {
+    Block(BlockId),
+    Transaction {
+        txid: Txid,
+        blockid: Option<BlockId>,
+        scripthashes: Vec<FullHash>,
+    },
+}
+
+impl Event {
+    // The messages to publish for this event, as (subject, JSON payload) pairs. Subjects are
+    // dot-separated and start with the configured prefix.
+    pub fn messages(&self, prefix: &str) -> Vec<(String, String)> {
+        match self {
+            Event::Block(blockid) => vec![(
+                format!("{}.block", prefix),
+                json!({
+                    "height": blockid.height,
+                    "hash": blockid.hash,
+                    "time": blockid.time,
+                })
+                .to_string(),
+            )],
+            Event::Transaction {
+                txid,
+                blockid,
+                scripthashes,
+            } => {
+                let status = TransactionStatus::from(blockid.clone());
+                let scripthashes: Vec<String> = scripthashes
+                    .iter()
+                    .map(|scripthash| scripthash.to_lower_hex_string())
+                    .collect();
+
+                let mut messages = vec![(
+                    format!("{}.tx", prefix),
+                    json!({
+                        "txid": txid,
+                        "status": status,
+                        "scripthashes": scripthashes,
+                    })
+                    .to_string(),
+                )];
+                messages.extend(scripthashes.iter().map(|scripthash| {
+                    (
+                        format!("{}.scripthash.{}", prefix, scripthash),
+                        json!({
+                            "scripthash": scripthash,
+                            "txid": txid,
+                            "status": status,
+                        })
+                        .to_string(),
+                    )
+                }));
+                messages
+            }
+        }
+    }
+}
+
+pub trait EventSink: Send {
+    fn name(&self) -> &'static str;
+
+    fn publish(&mut self, events: &[Event]) -> Result<()>;
+
+    // Called on every update, even when there are no events to publish
+    fn keepalive(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Keeps track of what was already published, to emit events for new blocks and new mempool
+// transactions only
+struct EventTracker {
+    recent_blocks: BTreeMap<usize, BlockHash>,
+    mempool_txids: HashSet<Txid>,
+}
+
+impl EventTracker {
+    fn new(query: &Query) -> Self {
+        let tip = query.chain().best_header();
+        let mut recent_blocks = BTreeMap::new();
+        recent_blocks.insert(tip.height(), *tip.hash());
+        EventTracker {
+            recent_blocks,
+            mempool_txids: query.mempool().old_txids(),
+        }
+    }
+
+    fn poll(&mut self, query: &Query) -> Vec<Event> {
+        let mut events = self.poll_blocks(query);
+        events.extend(self.poll_mempool(query));
+        events
+    }
+
+    fn poll_blocks(&mut self, query: &Query) -> Vec<Event> {
+        let chain = query.chain();
+        let tip_height = chain.best_height();
+
+        // resume after the highest published block that is still part of the best chain. blocks
+        // above it were reorged out (or are new), and get published (again).
+        let fork_height = self
+            .recent_blocks
+            .iter()
+            .rev()
+            .find(|(height, hash)| chain.hash_by_height(**height).as_ref() == Some(*hash))
+            .map(|(height, _)| *height);
+        let start_height = fork_height
+            .map_or(tip_height, |height| height + 1)
+            .max(tip_height.saturating_sub(RECENT_BLOCKS - 1));
+        self.recent_blocks.split_off(&start_height);
+
+        let mut events = vec![];
+        for height in start_height..=tip_height {
+            let blockid = match chain.blockid_by_height(height) {
+                Some(blockid) => blockid,
+                None => break,
+            };
+            let txids = chain.get_block_txids(&blockid.hash).unwrap_or_default();
+            let txids: Vec<(Txid, BlockId)> = txids
+                .into_iter()
+                .map(|txid| (txid, blockid.clone()))
+                .collect();
+            let txs = match chain.lookup_txns(&txids) {
+                Ok(txs) => txs,
+                Err(e) => {
+                    warn!(
+                        "failed loading the transactions of block {}: {}",
+                        blockid.hash, e
+                    );
+                    vec![]
+                }
+            };
+            let prevouts = chain.lookup_avail_txos(&get_prevouts(&txs));
+
+            events.push(Event::Block(blockid.clone()));
+            events.extend(
+                txs.iter()
+                    .map(|tx| tx_event(tx, Some(blockid.clone()), &prevouts)),
+            );
+
+            self.recent_blocks.insert(height, blockid.hash);
+            while self.recent_blocks.len() > RECENT_BLOCKS {
+                let lowest = *self.recent_blocks.keys().next().unwrap();
+                self.recent_blocks.remove(&lowest);
+            }
+        }
+        events
+    }
+
+    fn poll_mempool(&mut self, query: &Query) -> Vec<Event> {
+        let mempool = query.mempool();
+        let mempool_txids = mempool.old_txids();
+        let txs: Vec<Transaction> = mempool_txids
+            .difference(&self.mempool_txids)
+            .filter_map(|txid| mempool.lookup_txn(txid))
+            .collect();
+        // if some parent got evicted in the meanwhile, the spent scripthashes are left out
+        let prevouts = mempool.lookup_txos(&get_prevouts(&txs)).unwrap_or_default();
+        self.mempool_txids = mempool_txids;
+
+        txs.iter().map(|tx| tx_event(tx, None, &prevouts)).collect()
+    }
+}
+
+fn get_prevouts(txs: &[Transaction]) -> BTreeSet<OutPoint> {
+    txs.iter()
+        .flat_map(|tx| tx.input.iter())
+        .filter(|txin| has_prevout(txin))
+        .map(|txin| txin.previous_output)
+        .collect()
+}
+
+fn tx_event(
+    tx: &Transaction,
+    blockid: Option<BlockId>,
+    prevouts: &HashMap<OutPoint, TxOut>,
+) -> Event {
+    let funded = tx.output.iter().filter(|txout| is_spendable(txout));
+    let spent = tx
+        .input
+        .iter()
+        .filter_map(|txin| prevouts.get(&txin.previous_output));
+    let scripthashes: BTreeSet<FullHash> = funded
+        .chain(spent)
+        .map(|txout| compute_script_hash(&txout.script_pubkey))
+        .collect();
+
+    Event::Transaction {
+        txid: tx.txid(),
+        blockid,
+        scripthashes: scripthashes.into_iter().collect(),
+    }
+}
+
+pub struct EventPublisher {
+    notification: Option<SyncSender<()>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl EventPublisher {
+    // Returns None if no event sinks are configured
+    pub fn start(
+        config: &Config,
+        query: Arc<Query>,
+        metrics: &Metrics,
+    ) -> Result<Option<EventPublisher>> {
+        let mut sinks: Vec<Box<dyn EventSink>> = vec![];
+        if let Some(url) = &config.event_bus_url {
+            sinks.push(Box::new(MessageBus::new(url, &config.event_bus_prefix)?));
+        }
+        if config.webhooks {
+            sinks.push(Box::new(WebhookSink::new(Arc::clone(&query))));
+        }
+        if sinks.is_empty() {
+            return Ok(None);
+        }
+
+        let published = metrics.counter_vec(
+            MetricOpts::new(
+                "electrs_events_published",
+                "# of index events published, by sink and result",
+            ),
+            &["sink", "result"],
+        );
+
+        // notifications are coalesced while the publisher is busy
+        let (notification, receiver) = sync_channel(1);
+        let thread = spawn_thread("events", move || {
+            run_publisher(query, sinks, receiver, published)
+        });
+
+        Ok(Some(EventPublisher {
+            notification: Some(notification),
+            thread: Some(thread),
+        }))
+    }
+
+    // Signal that the index or the mempool may have been updated
+    pub fn notify(&self) {
+        if let Some(notification) = &self.notification {
+            match notification.try_send(()) {
+                Ok(()) | Err(TrySendError::Full(())) => (),
+                Err(TrySendError::Disconnected(())) => warn!("event publisher is not running"),
+            }
+        }
+    }
+}
+
+impl Drop for EventPublisher {
+    fn drop(&mut self) {
+        self.notification.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().expect("event publisher panicked");
+        }
+    }
+}
+
+fn run_publisher(
+    query: Arc<Query>,
+    mut sinks: Vec<Box<dyn EventSink>>,
+    notifications: Receiver<()>,
+    published: CounterVec,
+) {
+    let mut tracker = EventTracker::new(&query);
+    while notifications.recv().is_ok() {
+        let events = tracker.poll(&query);
+        for sink in sinks.iter_mut() {
+            let result = if events.is_empty() {
+                sink.keepalive()
+            } else {
+                sink.publish(&events)
+            };
+            match result {
+                Ok(()) => published
+                    .with_label_values(&[sink.name(), "ok"])
+                    .inc_by(events.len() as u64),
+                Err(e) => {
+                    warn!(
+                        "failed publishing {} events to {}: {}",
+                        events.len(),
+                        sink.name(),
+                        e.display_chain()
+                    );
+                    published
+                        .with_label_values(&[sink.name(), "failed"])
+                        .inc_by(events.len() as u64);
+                }
+            }
+        }
+    }
+    debug!("event publisher stopped");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_event_messages() {
+        let txid =
+            Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+                .unwrap();
+        let event = Event::Transaction {
+            txid,
+            blockid: None,
+            scripthashes: vec![[1u8; 32], [2u8; 32]],
+        };
+        let messages = event.messages("test");
+        let subjects: Vec<&str> = messages.iter().map(|(s, _)| s.as_str()).collect();
+        assert_eq!(
+            subjects,
+            vec![
+                "test.tx".to_string(),
+                format!("test.scripthash.{}", "01".repeat(32)),
+                format!("test.scripthash.{}", "02".repeat(32)),
+            ]
+        );
+
+        let payload: serde_json::Value = serde_json::from_str(&messages[1].1).unwrap();
+        assert_eq!(payload["txid"], txid.to_string());
+        assert_eq!(payload["scripthash"], "01".repeat(32));
+        assert_eq!(payload["status"]["confirmed"], false);
+    }
+}
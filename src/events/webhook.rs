@@ -0,0 +1,714 @@
+// Webhook subscriptions: clients register a callback URL along with the scripthashes to watch,
+// and get a JSON notification POSTed whenever a transaction funding or spending one of them
+// enters the mempool or gets confirmed. Clients can also watch a single txid until it reaches
+// some target confirmation counts (1, 3 and 6 by default), and get notified once per target as
+// new blocks get connected. Subscriptions are kept in the cache db:
+//
+//      W{hook-id} → {url}{scripthashes}{secret}
+//      C{watch-id} → {url}{txid}{targets}{secret}{notified}
+//
+// Confirmation watches are deleted once their highest target is notified. The subscriptions are
+// loaded into memory on startup, and the registry keeps both in sync as they get registered and
+// deleted.
+//
+// Registering requires API key authentication, since the notifications are sent on the server's
+// behalf. Callbacks to addresses that aren't publicly routable (loopback, private networks, etc.)
+// are refused, both when registering and when connecting, so that the server can't be used to
+// reach internal services.
+//
+// Pending deliveries are only kept in memory, and are retried with an exponential backoff.
+// Deliveries run concurrently, and notifications are dropped when too many are pending.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use hex::DisplayHex;
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::{Body, Client, Method, Request};
+use tokio::runtime;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::Semaphore;
+
+use crate::chain::Txid;
+use crate::errors::*;
+use crate::events::{Event, EventSink};
+use crate::new_index::db::{DBFlush, DBRow, DB};
+use crate::new_index::Query;
+use crate::util::{bincode, spawn_thread, Bytes, FullHash, TransactionStatus};
+
+pub const MAX_WEBHOOK_SCRIPTHASHES: usize = 1000;
+pub const DEFAULT_CONFIRMATION_TARGETS: [u32; 3] = [1, 3, 6];
+pub const MAX_CONFIRMATION_TARGETS: usize = 10;
+pub const MAX_CONFIRMATION_TARGET: u32 = 1000;
+
+const MAX_WEBHOOKS: usize = 10_000;
+const MAX_CONFIRMATION_WATCHES: usize = 100_000;
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const DELIVERY_ATTEMPTS: u32 = 8;
+// Delay before retrying a failed delivery, doubled after every failed attempt
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+// Deliveries being attempted or waiting for a retry, beyond which new ones get queued
+const MAX_PENDING_DELIVERIES: usize = 10_000;
+// Queued deliveries, beyond which new ones get dropped
+const DELIVERY_QUEUE_SIZE: usize = 10_000;
+const MAX_CONCURRENT_DELIVERIES: usize = 64;
+
+#[derive(Serialize, Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    pub scripthashes: Vec<FullHash>,
+    pub secret: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ConfirmationWatch {
+    pub url: String,
+    pub txid: Txid,
+    // sorted and deduplicated
+    pub targets: Vec<u32>,
+    pub secret: Option<String>,
+    // the highest target already notified, 0 if none
+    pub notified: u32,
+}
+
+fn hook_key(id: &[u8]) -> Bytes {
+    [b"W", id].concat()
+}
+
+fn watch_key(id: &[u8]) -> Bytes {
+    [b"C", id].concat()
+}
+
+fn validate_url(url: &str) -> Result<()> {
+    let url = url::Url::parse(url).chain_err(|| "invalid callback url")?;
+    // TLS is not supported by the http client, use a TLS-terminating proxy for https
+    ensure!(
+        url.scheme() == "http",
+        "only http:// callback urls are supported"
+    );
+    let addrs = url
+        .socket_addrs(|| None)
+        .chain_err(|| "failed resolving the callback host")?;
+    ensure!(
+        addrs.iter().all(|addr| is_public(&addr.ip())),
+        "callback urls must point to a public address"
+    );
+    Ok(())
+}
+
+// Whether the address is publicly routable, as opposed to the loopback, private, link-local and
+// other special-purpose ranges
+fn is_public(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, _, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                || a == 0 // "this network"
+                || (a == 100 && b & 0xc0 == 64) // carrier-grade NAT
+                || (a == 198 && b & 0xfe == 18) // benchmarking
+                || a >= 240) // reserved
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(&IpAddr::V4(ip));
+            }
+            // NAT64, which maps to an IPv4 address
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b, c, d] = ip.octets()[12..].try_into().unwrap();
+                return is_public(&IpAddr::V4([a, b, c, d].into()));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || segments[0] & 0xfe00 == 0xfc00 // unique local
+                || segments[0] & 0xffc0 == 0xfe80) // link-local
+        }
+    }
+}
+
+fn new_id(url: &str, data: &[u8]) -> FullHash {
+    let nonce = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let mut engine = sha256::Hash::engine();
+    engine.input(url.as_bytes());
+    engine.input(data);
+    engine.input(&nonce.to_le_bytes());
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+impl Webhook {
+    pub fn validate(&self) -> Result<()> {
+        validate_url(&self.url)?;
+        ensure!(
+            !self.scripthashes.is_empty() && self.scripthashes.len() <= MAX_WEBHOOK_SCRIPTHASHES,
+            "a webhook must watch between 1 and {} scripthashes",
+            MAX_WEBHOOK_SCRIPTHASHES
+        );
+        Ok(())
+    }
+
+    fn save(&self, db: &DB, id: &[u8]) {
+        db.write(
+            vec![DBRow {
+                key: hook_key(id),
+                value: bincode::serialize_little(self).unwrap(),
+            }],
+            DBFlush::Enable,
+        );
+    }
+
+    fn load_all(db: &DB) -> Vec<(FullHash, Webhook)> {
+        db.iter_scan(b"W")
+            .filter(|row| row.key.len() == 33)
+            .map(|row| {
+                let id: FullHash = row.key[1..].try_into().unwrap();
+                let hook =
+                    bincode::deserialize_little(&row.value).expect("failed to parse Webhook");
+                (id, hook)
+            })
+            .collect()
+    }
+}
+
+impl ConfirmationWatch {
+    pub fn new(url: String, txid: Txid, targets: Vec<u32>, secret: Option<String>) -> Self {
+        let mut targets = targets;
+        targets.sort_unstable();
+        targets.dedup();
+        ConfirmationWatch {
+            url,
+            txid,
+            targets,
+            secret,
+            notified: 0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        validate_url(&self.url)?;
+        ensure!(
+            !self.targets.is_empty() && self.targets.len() <= MAX_CONFIRMATION_TARGETS,
+            "a confirmation watch must have between 1 and {} targets",
+            MAX_CONFIRMATION_TARGETS
+        );
+        ensure!(
+            self.targets
+                .iter()
+                .all(|target| (1..=MAX_CONFIRMATION_TARGET).contains(target)),
+            "confirmation targets must be between 1 and {}",
+            MAX_CONFIRMATION_TARGET
+        );
+        Ok(())
+    }
+
+    fn save(&self, db: &DB, id: &[u8]) {
+        db.write(
+            vec![DBRow {
+                key: watch_key(id),
+                value: bincode::serialize_little(self).unwrap(),
+            }],
+            DBFlush::Enable,
+        );
+    }
+
+    fn load_all(db: &DB) -> Vec<(FullHash, ConfirmationWatch)> {
+        db.iter_scan(b"C")
+            .filter(|row| row.key.len() == 33)
+            .map(|row| {
+                let id: FullHash = row.key[1..].try_into().unwrap();
+                let watch = bincode::deserialize_little(&row.value)
+                    .expect("failed to parse ConfirmationWatch");
+                (id, watch)
+            })
+            .collect()
+    }
+
+    // Moves the notified target to the highest one reached, and returns the targets that were
+    // newly reached. After a reorg the targets that are no longer reached get notified again.
+    fn update(&mut self, confirmations: u32) -> Vec<u32> {
+        let reached = self
+            .targets
+            .iter()
+            .copied()
+            .filter(|target| *target <= confirmations)
+            .max()
+            .unwrap_or(0);
+        let notify = self
+            .targets
+            .iter()
+            .copied()
+            .filter(|target| *target > self.notified && *target <= reached)
+            .collect();
+        self.notified = reached;
+        notify
+    }
+
+    fn is_done(&self) -> bool {
+        self.targets
+            .last()
+            .map_or(true, |last| self.notified >= *last)
+    }
+}
+
+#[derive(Default)]
+struct Hooks {
+    by_id: HashMap<FullHash, Arc<Webhook>>,
+    // scripthash → the ids of the hooks watching it
+    by_scripthash: HashMap<FullHash, Vec<FullHash>>,
+}
+
+// The registered webhooks and confirmation watches, kept in memory and persisted to the cache db
+pub struct WebhookRegistry {
+    hooks: RwLock<Hooks>,
+    watches: RwLock<HashMap<FullHash, ConfirmationWatch>>,
+}
+
+impl WebhookRegistry {
+    pub fn load(db: &DB) -> Self {
+        let mut hooks = Hooks::default();
+        for (id, hook) in Webhook::load_all(db) {
+            for scripthash in &hook.scripthashes {
+                hooks.by_scripthash.entry(*scripthash).or_default().push(id);
+            }
+            hooks.by_id.insert(id, Arc::new(hook));
+        }
+        let watches: HashMap<_, _> = ConfirmationWatch::load_all(db).into_iter().collect();
+        debug!(
+            "loaded {} webhooks and {} confirmation watches",
+            hooks.by_id.len(),
+            watches.len()
+        );
+        WebhookRegistry {
+            hooks: RwLock::new(hooks),
+            watches: RwLock::new(watches),
+        }
+    }
+
+    // Persist the webhook and return its id
+    pub fn register_hook(&self, db: &DB, hook: Webhook) -> Result<FullHash> {
+        hook.validate()?;
+        let mut hooks = self.hooks.write().unwrap();
+        ensure!(
+            hooks.by_id.len() < MAX_WEBHOOKS,
+            "too many webhooks registered"
+        );
+        let id = new_id(&hook.url, &hook.scripthashes.concat());
+        hook.save(db, &id);
+        for scripthash in &hook.scripthashes {
+            hooks.by_scripthash.entry(*scripthash).or_default().push(id);
+        }
+        hooks.by_id.insert(id, Arc::new(hook));
+        Ok(id)
+    }
+
+    pub fn hook(&self, id: &FullHash) -> Option<Arc<Webhook>> {
+        self.hooks.read().unwrap().by_id.get(id).cloned()
+    }
+
+    // Returns false if there is no such webhook
+    pub fn delete_hook(&self, db: &DB, id: &FullHash) -> bool {
+        let mut hooks = self.hooks.write().unwrap();
+        let hook = match hooks.by_id.remove(id) {
+            Some(hook) => hook,
+            None => return false,
+        };
+        for scripthash in &hook.scripthashes {
+            if let Some(ids) = hooks.by_scripthash.get_mut(scripthash) {
+                ids.retain(|hook_id| hook_id != id);
+                if ids.is_empty() {
+                    hooks.by_scripthash.remove(scripthash);
+                }
+            }
+        }
+        db.write_and_delete(vec![], vec![hook_key(id)], DBFlush::Enable);
+        true
+    }
+
+    // The hooks watching any of the scripthashes, along with the (hex) scripthashes they watch
+    fn matching_hooks(
+        &self,
+        scripthashes: &[FullHash],
+    ) -> Vec<(FullHash, Arc<Webhook>, Vec<String>)> {
+        let hooks = self.hooks.read().unwrap();
+        let mut matched: HashMap<FullHash, Vec<String>> = HashMap::new();
+        for scripthash in scripthashes {
+            for id in hooks.by_scripthash.get(scripthash).into_iter().flatten() {
+                matched
+                    .entry(*id)
+                    .or_default()
+                    .push(scripthash.to_lower_hex_string());
+            }
+        }
+        matched
+            .into_iter()
+            .map(|(id, scripthashes)| (id, Arc::clone(&hooks.by_id[&id]), scripthashes))
+            .collect()
+    }
+
+    // Persist the watch and return its id
+    pub fn register_watch(&self, db: &DB, watch: ConfirmationWatch) -> Result<FullHash> {
+        watch.validate()?;
+        let mut watches = self.watches.write().unwrap();
+        ensure!(
+            watches.len() < MAX_CONFIRMATION_WATCHES,
+            "too many confirmation watches registered"
+        );
+        let id = new_id(&watch.url, &watch.txid[..]);
+        watch.save(db, &id);
+        watches.insert(id, watch);
+        Ok(id)
+    }
+
+    pub fn watch(&self, id: &FullHash) -> Option<ConfirmationWatch> {
+        self.watches.read().unwrap().get(id).cloned()
+    }
+
+    // Returns false if there is no such watch
+    pub fn delete_watch(&self, db: &DB, id: &FullHash) -> bool {
+        if self.watches.write().unwrap().remove(id).is_none() {
+            return false;
+        }
+        db.write_and_delete(vec![], vec![watch_key(id)], DBFlush::Enable);
+        true
+    }
+
+    // Persist the progress of a watch, unless it was deleted in the meantime. Watches that are
+    // done get deleted.
+    fn update_watch(&self, db: &DB, id: FullHash, watch: ConfirmationWatch) {
+        let mut watches = self.watches.write().unwrap();
+        if !watches.contains_key(&id) {
+            return;
+        }
+        if watch.is_done() {
+            watches.remove(&id);
+            db.write_and_delete(vec![], vec![watch_key(&id)], DBFlush::Enable);
+        } else {
+            watch.save(db, &id);
+            watches.insert(id, watch);
+        }
+    }
+}
+
+// Resolves the callback hosts to their public addresses only, so that a host that resolved to a
+// public address when registered can't be pointed at an internal one later on
+#[derive(Clone)]
+struct PublicResolver;
+
+impl Service<Name> for PublicResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(&addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} has no public address", name),
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+type WebhookClient = Client<HttpConnector<PublicResolver>>;
+
+struct Delivery {
+    url: String,
+    secret: Option<String>,
+    body: String,
+}
+
+pub struct WebhookSink {
+    query: Arc<Query>,
+    deliveries: mpsc::Sender<Delivery>,
+}
+
+impl WebhookSink {
+    pub fn new(query: Arc<Query>) -> Self {
+        let (deliveries, mut receiver) = mpsc::channel::<Delivery>(DELIVERY_QUEUE_SIZE);
+        spawn_thread("webhooks", move || {
+            let runtime = runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("failed building the webhooks runtime");
+            runtime.block_on(async move {
+                let client: WebhookClient =
+                    Client::builder().build(HttpConnector::new_with_resolver(PublicResolver));
+                let pending = Arc::new(Semaphore::new(MAX_PENDING_DELIVERIES));
+                let concurrency = Arc::new(Semaphore::new(MAX_CONCURRENT_DELIVERIES));
+                loop {
+                    // stop taking deliveries off the queue while too many are pending
+                    let permit = Arc::clone(&pending).acquire_owned().await.unwrap();
+                    let delivery = match receiver.recv().await {
+                        Some(delivery) => delivery,
+                        None => break,
+                    };
+                    let client = client.clone();
+                    let concurrency = Arc::clone(&concurrency);
+                    tokio::spawn(async move {
+                        deliver_with_retries(&client, &concurrency, &delivery).await;
+                        drop(permit);
+                    });
+                }
+            });
+            debug!("webhooks dispatcher stopped");
+        });
+        WebhookSink { query, deliveries }
+    }
+}
+
+impl EventSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    fn publish(&mut self, events: &[Event]) -> Result<()> {
+        self.publish_transactions(events)?;
+        if events.iter().any(|event| matches!(event, Event::Block(_))) {
+            self.publish_confirmations()?;
+        }
+        Ok(())
+    }
+}
+
+impl WebhookSink {
+    fn publish_transactions(&mut self, events: &[Event]) -> Result<()> {
+        let webhooks = self
+            .query
+            .webhooks()
+            .chain_err(|| "webhooks are disabled")?;
+        for event in events {
+            let (txid, blockid, scripthashes) = match event {
+                Event::Transaction {
+                    txid,
+                    blockid,
+                    scripthashes,
+                } => (txid, blockid, scripthashes),
+                Event::Block(_) => continue,
+            };
+            for (id, hook, matched_scripthashes) in webhooks.matching_hooks(scripthashes) {
+                let body = json!({
+                    "hook_id": id.to_lower_hex_string(),
+                    "txid": txid,
+                    "status": TransactionStatus::from(blockid.clone()),
+                    "scripthashes": matched_scripthashes,
+                });
+                self.send(&hook.url, &hook.secret, body.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    // Checks the confirmations of the watched transactions against the new tip
+    fn publish_confirmations(&mut self) -> Result<()> {
+        let webhooks = self
+            .query
+            .webhooks()
+            .chain_err(|| "webhooks are disabled")?;
+        let chain = self.query.chain();
+        let tip_height = chain.best_height();
+
+        // the watches are checked against a snapshot, so that they can keep being registered
+        let watches: Vec<(FullHash, ConfirmationWatch)> = webhooks
+            .watches
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(id, watch)| (*id, watch.clone()))
+            .collect();
+        for (id, mut watch) in watches {
+            let blockid = chain.tx_confirming_block(&watch.txid);
+            let confirmations = blockid
+                .as_ref()
+                .map_or(0, |b| (tip_height + 1).saturating_sub(b.height) as u32);
+            let notified = watch.notified;
+            let reached = watch.update(confirmations);
+            for target in reached {
+                let body = json!({
+                    "watch_id": id.to_lower_hex_string(),
+                    "txid": watch.txid,
+                    "confirmations": target,
+                    "status": TransactionStatus::from(blockid.clone()),
+                });
+                self.send(&watch.url, &watch.secret, body.to_string())?;
+            }
+            if watch.notified != notified {
+                webhooks.update_watch(chain.store().cache_db(), id, watch);
+            }
+        }
+        Ok(())
+    }
+
+    // Queue the notification, dropping it if the queue is full rather than blocking the publisher
+    fn send(&self, url: &str, secret: &Option<String>, body: String) -> Result<()> {
+        let delivery = Delivery {
+            url: url.to_string(),
+            secret: secret.clone(),
+            body,
+        };
+        match self.deliveries.try_send(delivery) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(delivery)) => {
+                warn!(
+                    "webhooks queue is full, dropping a notification to {}",
+                    delivery.url
+                );
+                Ok(())
+            }
+            Err(TrySendError::Closed(_)) => bail!("webhooks dispatcher is not running"),
+        }
+    }
+}
+
+async fn deliver_with_retries(
+    client: &WebhookClient,
+    concurrency: &Semaphore,
+    delivery: &Delivery,
+) {
+    for attempt in 0..DELIVERY_ATTEMPTS {
+        if attempt > 0 {
+            tokio::time::sleep(RETRY_BACKOFF * 2u32.pow(attempt - 1)).await;
+        }
+        let result = {
+            let _slot = concurrency.acquire().await.unwrap();
+            deliver(client, delivery).await
+        };
+        match result {
+            Ok(()) => return,
+            Err(e) => debug!("webhook delivery to {} failed: {}", delivery.url, e),
+        }
+    }
+    warn!(
+        "giving up webhook delivery to {} after {} attempts",
+        delivery.url, DELIVERY_ATTEMPTS
+    );
+}
+
+async fn deliver(client: &WebhookClient, delivery: &Delivery) -> Result<()> {
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(&delivery.url)
+        .header("Content-Type", "application/json");
+    // lets the receiver authenticate the notification
+    if let Some(secret) = &delivery.secret {
+        let mut engine = HmacEngine::<sha256::Hash>::new(secret.as_bytes());
+        engine.input(delivery.body.as_bytes());
+        let signature = Hmac::from_engine(engine)
+            .to_byte_array()
+            .to_lower_hex_string();
+        request = request.header("X-Electrs-Signature", format!("sha256={}", signature));
+    }
+    let request = request
+        .body(Body::from(delivery.body.clone()))
+        .chain_err(|| "invalid webhook request")?;
+
+    let response = tokio::time::timeout(DELIVERY_TIMEOUT, client.request(request))
+        .await
+        .chain_err(|| "timed out")?
+        .chain_err(|| "request failed")?;
+    ensure!(
+        response.status().is_success(),
+        "unexpected status {}",
+        response.status()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_confirmation_targets() {
+        let txid =
+            Txid::from_str("4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b")
+                .unwrap();
+        let mut watch =
+            ConfirmationWatch::new("http://127.0.0.1/".into(), txid, vec![6, 1, 3, 3], None);
+        assert_eq!(watch.targets, vec![1, 3, 6]);
+
+        assert_eq!(watch.update(0), Vec::<u32>::new());
+        assert_eq!(watch.update(1), vec![1]);
+        assert_eq!(watch.update(2), Vec::<u32>::new());
+        // skipped targets are all notified
+        assert_eq!(watch.update(4), vec![3]);
+        // reorged below the notified target, which gets notified again
+        assert_eq!(watch.update(2), Vec::<u32>::new());
+        assert_eq!(watch.notified, 1);
+        assert!(!watch.is_done());
+        assert_eq!(watch.update(7), vec![3, 6]);
+        assert!(watch.is_done());
+    }
+
+    #[test]
+    fn test_is_public() {
+        let is_public = |ip: &str| is_public(&ip.parse().unwrap());
+        for ip in &[
+            "1.1.1.1",
+            "203.0.113.7",
+            "2606:4700::1111",
+            "::ffff:8.8.8.8",
+        ] {
+            assert!(is_public(ip), "{}", ip);
+        }
+        for ip in &[
+            "0.0.0.0",
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "255.255.255.255",
+            "::",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!is_public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn test_validate_url() {
+        assert!(validate_url("http://1.1.1.1:8080/notify").is_ok());
+        assert!(validate_url("https://1.1.1.1/notify").is_err());
+        assert!(validate_url("http://127.0.0.1/notify").is_err());
+        assert!(validate_url("http://[::1]/notify").is_err());
+        assert!(validate_url("http://169.254.169.254/latest/meta-data").is_err());
+        assert!(validate_url("http://localhost/notify").is_err());
+    }
+}
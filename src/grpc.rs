@@ -0,0 +1,319 @@
+// gRPC interface mirroring the main ChainQuery lookups, for backend integrators that prefer typed
+// messages and streaming over polling the REST API. See proto/electrs.proto for the service.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot, Semaphore};
+use tokio::{runtime, task, time};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+#[cfg(not(feature = "liquid"))]
+use bitcoin::consensus::encode::{deserialize, serialize};
+#[cfg(feature = "liquid")]
+use elements::encode::{deserialize, serialize};
+
+use crate::auth::AuthError;
+use crate::chain::Txid;
+use crate::config::Config;
+use crate::new_index::{HistoryOrder, Query, Utxo};
+use crate::util::{BlockId, FullHash};
+
+pub mod proto {
+    tonic::include_proto!("electrs");
+}
+
+use proto::electrs_server::{Electrs, ElectrsServer};
+
+const HISTORY_DEFAULT_LIMIT: usize = 25;
+const HISTORY_MAX_LIMIT: usize = 100;
+
+const BLOCKS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+const BLOCKS_STREAM_BUFFER: usize = 16;
+// Number of recently streamed blocks that are remembered to detect reorgs
+const RECENT_BLOCKS: usize = 100;
+
+impl From<BlockId> for proto::Block {
+    fn from(blockid: BlockId) -> Self {
+        proto::Block {
+            hash: serialize(&blockid.hash),
+            height: blockid.height as u32,
+            time: blockid.time,
+        }
+    }
+}
+
+impl From<Utxo> for proto::Utxo {
+    fn from(utxo: Utxo) -> Self {
+        proto::Utxo {
+            txid: serialize(&utxo.txid),
+            vout: utxo.vout,
+            #[cfg(not(feature = "liquid"))]
+            value: Some(utxo.value),
+            #[cfg(feature = "liquid")]
+            value: utxo.value.explicit(),
+            block: utxo.confirmed.map(proto::Block::from),
+        }
+    }
+}
+
+// The lookups share the concurrency limit and timeout of the REST API (--http-max-concurrency
+// and --http-request-timeout), with their own pool of permits
+struct ElectrsService {
+    query: Arc<Query>,
+    permits: Arc<Semaphore>,
+    timeout: Option<Duration>,
+}
+
+impl ElectrsService {
+    // Runs index lookups on the blocking thread pool, off the async executor. Requests that can't
+    // get a permit before their timeout are rejected, and those that don't complete in time are
+    // answered with an error (the lookup itself keeps running until it's done).
+    async fn blocking<T, F>(&self, f: F) -> Result<Response<T>, Status>
+    where
+        F: FnOnce() -> Result<T, Status> + Send + 'static,
+        T: Send + 'static,
+    {
+        let deadline = self.timeout.map(|timeout| time::Instant::now() + timeout);
+        let permits = Arc::clone(&self.permits);
+        let permit = match deadline {
+            Some(deadline) => time::timeout_at(deadline, permits.acquire_owned())
+                .await
+                .map_err(|_| Status::unavailable("server is busy, try again later"))?,
+            None => permits.acquire_owned().await,
+        }
+        .expect("request semaphore closed");
+
+        let handler = task::spawn_blocking(move || {
+            let _permit = permit;
+            f()
+        });
+        let joined = match deadline {
+            Some(deadline) => time::timeout_at(deadline, handler)
+                .await
+                .map_err(|_| Status::deadline_exceeded("request timed out"))?,
+            None => handler.await,
+        };
+        joined
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map(Response::new)
+    }
+}
+
+#[tonic::async_trait]
+impl Electrs for ElectrsService {
+    async fn get_transaction(
+        &self,
+        request: Request<proto::TransactionRequest>,
+    ) -> Result<Response<proto::TransactionResponse>, Status> {
+        let txid = parse_txid(&request.get_ref().txid)?;
+        let query = Arc::clone(&self.query);
+        self.blocking(move || {
+            let raw_tx = query
+                .lookup_raw_txn(&txid)
+                .ok_or_else(|| Status::not_found("transaction not found"))?;
+            let block = query.chain().tx_confirming_block(&txid);
+            Ok(proto::TransactionResponse {
+                raw_tx,
+                block: block.map(proto::Block::from),
+            })
+        })
+        .await
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<proto::HistoryRequest>,
+    ) -> Result<Response<proto::HistoryResponse>, Status> {
+        let request = request.into_inner();
+        let scripthash = parse_scripthash(&request.scripthash)?;
+        let last_seen_txid = request
+            .last_seen_txid
+            .as_deref()
+            .map(parse_txid)
+            .transpose()?;
+        let limit = match request.limit as usize {
+            0 => HISTORY_DEFAULT_LIMIT,
+            limit => limit.min(HISTORY_MAX_LIMIT),
+        };
+
+        let query = Arc::clone(&self.query);
+        self.blocking(move || {
+            let history = query.chain().history(
+                &scripthash,
+                last_seen_txid.as_ref(),
+                limit,
+                HistoryOrder::Descending,
+            );
+            let entries = history
+                .into_iter()
+                .map(|(tx, blockid)| proto::HistoryEntry {
+                    txid: serialize(&tx.txid()),
+                    block: Some(blockid.into()),
+                    raw_tx: serialize(&tx),
+                })
+                .collect();
+            Ok(proto::HistoryResponse { entries })
+        })
+        .await
+    }
+
+    async fn get_utxos(
+        &self,
+        request: Request<proto::UtxosRequest>,
+    ) -> Result<Response<proto::UtxosResponse>, Status> {
+        let scripthash = parse_scripthash(&request.get_ref().scripthash)?;
+        let query = Arc::clone(&self.query);
+        self.blocking(move || {
+            let utxos = query
+                .utxo(&scripthash)
+                .map_err(|e| Status::failed_precondition(e.to_string()))?;
+            Ok(proto::UtxosResponse {
+                utxos: utxos.into_iter().map(proto::Utxo::from).collect(),
+            })
+        })
+        .await
+    }
+
+    type SubscribeBlocksStream = ReceiverStream<Result<proto::Block, Status>>;
+
+    async fn subscribe_blocks(
+        &self,
+        request: Request<proto::SubscribeBlocksRequest>,
+    ) -> Result<Response<Self::SubscribeBlocksStream>, Status> {
+        let start_height = request.get_ref().start_height.map(|height| height as usize);
+        let (sender, receiver) = mpsc::channel(BLOCKS_STREAM_BUFFER);
+        tokio::spawn(stream_blocks(Arc::clone(&self.query), start_height, sender));
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+async fn stream_blocks(
+    query: Arc<Query>,
+    start_height: Option<usize>,
+    sender: mpsc::Sender<Result<proto::Block, Status>>,
+) {
+    let chain = query.chain();
+    let mut next_height = start_height.unwrap_or_else(|| chain.best_height() + 1);
+    let mut recent_blocks: VecDeque<BlockId> = VecDeque::new();
+
+    while !sender.is_closed() {
+        // rewind to the fork point if some of the streamed blocks were reorged out
+        while let Some(blockid) = recent_blocks.back() {
+            if chain.hash_by_height(blockid.height) == Some(blockid.hash) {
+                break;
+            }
+            next_height = blockid.height;
+            recent_blocks.pop_back();
+        }
+
+        while let Some(blockid) = chain.blockid_by_height(next_height) {
+            if sender.send(Ok(blockid.clone().into())).await.is_err() {
+                return;
+            }
+            recent_blocks.push_back(blockid);
+            if recent_blocks.len() > RECENT_BLOCKS {
+                recent_blocks.pop_front();
+            }
+            next_height += 1;
+        }
+
+        tokio::time::sleep(BLOCKS_POLL_INTERVAL).await;
+    }
+}
+
+fn parse_txid(txid: &[u8]) -> Result<Txid, Status> {
+    deserialize(txid).map_err(|_| Status::invalid_argument("invalid txid"))
+}
+
+fn parse_scripthash(scripthash: &[u8]) -> Result<FullHash, Status> {
+    scripthash
+        .try_into()
+        .map_err(|_| Status::invalid_argument("invalid scripthash"))
+}
+
+// Applies the API key authentication and rate limits of the REST API, with the key passed in the
+// `authorization: Bearer <key>` or `x-api-key` metadata
+fn authenticate(query: &Query, request: &Request<()>) -> Result<(), Status> {
+    let api_keys = match query.api_keys() {
+        Some(api_keys) => api_keys,
+        None => return Ok(()),
+    };
+    let metadata = request.metadata();
+    let key = metadata
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            metadata
+                .get("x-api-key")
+                .and_then(|value| value.to_str().ok())
+        });
+    api_keys.check(key).map_err(|e| match e {
+        AuthError::RateLimited => Status::resource_exhausted(e.to_string()),
+        AuthError::Missing | AuthError::Invalid => Status::unauthenticated(e.to_string()),
+    })
+}
+
+async fn run_server(
+    addr: SocketAddr,
+    config: Arc<Config>,
+    query: Arc<Query>,
+    rx: oneshot::Receiver<()>,
+) {
+    let service = ElectrsService {
+        query: Arc::clone(&query),
+        permits: Arc::new(Semaphore::new(config.http_max_concurrency)),
+        timeout: match config.http_request_timeout {
+            0 => None,
+            timeout => Some(Duration::from_secs(timeout)),
+        },
+    };
+    let interceptor = move |request: Request<()>| authenticate(&query, &request).map(|()| request);
+
+    info!("gRPC server running on {}", addr);
+    let result = Server::builder()
+        .add_service(ElectrsServer::with_interceptor(service, interceptor))
+        .serve_with_shutdown(addr, async {
+            rx.await.ok();
+        })
+        .await;
+    if let Err(e) = result {
+        error!("gRPC server failed: {}", e);
+    }
+}
+
+// Returns None if the gRPC server is not enabled (no --grpc-addr)
+pub fn start(config: Arc<Config>, query: Arc<Query>) -> Option<Handle> {
+    let addr = config.grpc_addr?;
+    let (tx, rx) = oneshot::channel::<()>();
+
+    Some(Handle {
+        tx,
+        thread: thread::spawn(move || {
+            let runtime = runtime::Builder::new_multi_thread()
+                .enable_all()
+                .build()
+                .expect("failed building the gRPC server runtime");
+            runtime.block_on(run_server(addr, config, query, rx));
+        }),
+    })
+}
+
+pub struct Handle {
+    tx: oneshot::Sender<()>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Handle {
+    pub fn stop(self) {
+        self.tx.send(()).expect("failed to send shutdown signal");
+        self.thread.join().expect("gRPC server failed");
+    }
+}
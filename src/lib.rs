@@ -22,11 +22,14 @@ extern crate serde_json;
 #[macro_use]
 extern crate lazy_static;
 
+pub mod admin;
+pub mod auth;
 pub mod chain;
 pub mod config;
 pub mod daemon;
 pub mod electrum;
 pub mod errors;
+pub mod events;
 pub mod metrics;
 pub mod new_index;
 pub mod rest;
@@ -35,3 +38,9 @@ pub mod util;
 
 #[cfg(feature = "liquid")]
 pub mod elements;
+
+#[cfg(feature = "grpc")]
+pub mod grpc;
+
+#[cfg(all(feature = "runes", feature = "liquid"))]
+compile_error!("the runes feature is not supported on liquid");
@@ -0,0 +1,175 @@
+// Aggregation of the confirmed activity of scripthashes per day or month (in UTC), by the time of
+// the confirming blocks. The aggregates are cached along with the last block they account for,
+// so that repeated requests only process the history added since:
+//
+//      Y{scripthash}{granularity} → {height}{blockhash}
+//      Y{scripthash}{granularity}{period-start} → {activity}
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::str::FromStr;
+
+use time::OffsetDateTime as DateTime;
+
+use crate::chain::BlockHash;
+use crate::new_index::db::{DBFlush, DBRow, DB};
+use crate::new_index::schema::TxHistoryInfo;
+use crate::util::{bincode, Bytes};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Granularity {
+    Day,
+    Month,
+}
+
+impl Granularity {
+    fn code(self) -> u8 {
+        match self {
+            Granularity::Day => b'd',
+            Granularity::Month => b'm',
+        }
+    }
+
+    // Start timestamp of the period that contains the given timestamp
+    pub fn period_start(self, timestamp: u32) -> u32 {
+        let date = DateTime::from_unix_timestamp(timestamp as i64)
+            .unwrap()
+            .date();
+        let date = match self {
+            Granularity::Day => date,
+            Granularity::Month => date.replace_day(1).unwrap(),
+        };
+        date.midnight().assume_utc().unix_timestamp() as u32
+    }
+}
+
+impl FromStr for Granularity {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Granularity::Day),
+            "month" => Ok(Granularity::Month),
+            _ => Err("Invalid granularity, expected day or month".to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+pub struct Activity {
+    pub tx_count: usize,
+    pub funded_txo_count: usize,
+    pub spent_txo_count: usize,
+    #[cfg(not(feature = "liquid"))]
+    pub received: u64,
+    #[cfg(not(feature = "liquid"))]
+    pub sent: u64,
+}
+
+impl Activity {
+    pub fn add(&mut self, txinfo: &TxHistoryInfo) {
+        match txinfo {
+            #[cfg(not(feature = "liquid"))]
+            TxHistoryInfo::Funding(info) => {
+                self.funded_txo_count += 1;
+                self.received += info.value;
+            }
+            #[cfg(not(feature = "liquid"))]
+            TxHistoryInfo::Spending(info) => {
+                self.spent_txo_count += 1;
+                self.sent += info.value;
+            }
+
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Funding(_) => self.funded_txo_count += 1,
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Spending(_) => self.spent_txo_count += 1,
+            #[cfg(feature = "liquid")]
+            TxHistoryInfo::Issuing(_)
+            | TxHistoryInfo::Burning(_)
+            | TxHistoryInfo::Pegin(_)
+            | TxHistoryInfo::Pegout(_) => unreachable!(),
+        }
+    }
+}
+
+// period start timestamp → activity
+pub type ActivityMap = BTreeMap<u32, Activity>;
+
+fn checkpoint_key(scripthash: &[u8], granularity: Granularity) -> Bytes {
+    [b"Y", scripthash, &[granularity.code()]].concat()
+}
+
+fn period_key(scripthash: &[u8], granularity: Granularity, period_start: u32) -> Bytes {
+    [
+        &checkpoint_key(scripthash, granularity)[..],
+        &period_start.to_be_bytes(),
+    ]
+    .concat()
+}
+
+// Returns the cached periods along with the height and hash of the last block they account for
+pub fn load_cache(
+    db: &DB,
+    scripthash: &[u8],
+    granularity: Granularity,
+) -> Option<(usize, BlockHash, ActivityMap)> {
+    let prefix = checkpoint_key(scripthash, granularity);
+    let (height, blockhash): (u32, BlockHash) =
+        bincode::deserialize_little(&db.get(&prefix)?).unwrap();
+    let periods = db
+        .iter_scan(&prefix)
+        .filter(|row| row.key.len() == prefix.len() + 4)
+        .map(|row| {
+            let period_start = u32::from_be_bytes(row.key[prefix.len()..].try_into().unwrap());
+            let activity = bincode::deserialize_little(&row.value).unwrap();
+            (period_start, activity)
+        })
+        .collect();
+    Some((height as usize, blockhash, periods))
+}
+
+// Saves the given (updated) periods, now accounting for the history up to the given block
+pub fn save_cache<'a>(
+    db: &DB,
+    scripthash: &[u8],
+    granularity: Granularity,
+    height: usize,
+    blockhash: &BlockHash,
+    periods: impl Iterator<Item = (&'a u32, &'a Activity)>,
+) {
+    let mut rows: Vec<DBRow> = periods
+        .map(|(period_start, activity)| DBRow {
+            key: period_key(scripthash, granularity, *period_start),
+            value: bincode::serialize_little(activity).unwrap(),
+        })
+        .collect();
+    rows.push(DBRow {
+        key: checkpoint_key(scripthash, granularity),
+        value: bincode::serialize_little(&(height as u32, blockhash)).unwrap(),
+    });
+    db.write(rows, DBFlush::Enable);
+}
+
+// Drops the cache after the last block it accounts for was orphaned
+pub fn clear_cache(db: &DB, scripthash: &[u8], granularity: Granularity) {
+    let keys = db
+        .iter_scan(&checkpoint_key(scripthash, granularity))
+        .map(|row| row.key)
+        .collect();
+    db.write_and_delete(vec![], keys, DBFlush::Enable);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_period_start() {
+        // 2024-02-29T13:37:00Z
+        let timestamp = 1709213820;
+        assert_eq!(Granularity::Day.period_start(timestamp), 1709164800);
+        assert_eq!(Granularity::Month.period_start(timestamp), 1706745600);
+        assert_eq!(Granularity::Month.period_start(1706745600), 1706745600);
+    }
+}
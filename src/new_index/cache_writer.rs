@@ -0,0 +1,101 @@
+// Writes the aggregates cached by the queries (the stats and utxos of scripthashes) to the cache
+// db from a background thread, so that user requests don't wait for the write. The rows queued
+// while the writer is busy are coalesced by key, the latest value winning, so that the cache of a
+// popular scripthash queried repeatedly is written once per batch. When the queue is full, the
+// rows are dropped rather than blocking the queries, and get recomputed on a later request.
+
+use std::collections::HashMap;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+
+use crate::metrics::{Counter, MetricOpts, Metrics};
+use crate::new_index::db::{DBFlush, DBRow};
+use crate::new_index::Store;
+use crate::util::{spawn_thread, Bytes};
+
+// The number of rows that can be queued before new ones are dropped
+const QUEUE_SIZE: usize = 10_000;
+const MAX_BATCH_SIZE: usize = 1_000;
+
+pub struct CacheWriter {
+    sender: SyncSender<DBRow>,
+    dropped: Counter,
+}
+
+impl CacheWriter {
+    pub fn start(store: Arc<Store>, metrics: &Metrics) -> CacheWriter {
+        let (sender, receiver) = sync_channel(QUEUE_SIZE);
+        spawn_thread("cache_writer", move || run_writer(store, receiver));
+        CacheWriter {
+            sender,
+            dropped: metrics.counter(MetricOpts::new(
+                "electrs_cache_rows_dropped",
+                "# of cache rows dropped because the cache writer was busy",
+            )),
+        }
+    }
+
+    pub fn write(&self, row: DBRow) {
+        match self.sender.try_send(row) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => self.dropped.inc(),
+            Err(TrySendError::Disconnected(_)) => {
+                warn!("cache writer stopped, dropping cache row")
+            }
+        }
+    }
+}
+
+// Runs until the CacheWriter is dropped
+fn run_writer(store: Arc<Store>, receiver: Receiver<DBRow>) {
+    while let Ok(row) = receiver.recv() {
+        let mut batch: HashMap<Bytes, Bytes> = HashMap::new();
+        batch.insert(row.key, row.value);
+        while batch.len() < MAX_BATCH_SIZE {
+            match receiver.try_recv() {
+                Ok(row) => batch.insert(row.key, row.value),
+                Err(_) => break,
+            };
+        }
+        let rows = batch
+            .into_iter()
+            .map(|(key, value)| DBRow { key, value })
+            .collect();
+        store.cache_db().write(rows, DBFlush::Enable);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_drops_when_full() {
+        let (sender, receiver) = sync_channel(2);
+        let writer = CacheWriter {
+            sender,
+            dropped: Counter::new("dropped", "dropped rows").unwrap(),
+        };
+        let row = |i: u8| DBRow {
+            key: vec![i],
+            value: vec![],
+        };
+
+        // doesn't block once the queue is full
+        for i in 0..5 {
+            writer.write(row(i));
+        }
+        assert_eq!(writer.dropped.get(), 3);
+        let queued: Vec<Bytes> = receiver.try_iter().map(|row| row.key).collect();
+        assert_eq!(queued, vec![vec![0], vec![1]]);
+
+        writer.write(row(5));
+        assert_eq!(receiver.try_recv().unwrap().key, vec![5]);
+        assert_eq!(writer.dropped.get(), 3);
+
+        // not counted as dropped for being busy once the writer stopped
+        drop(receiver);
+        writer.write(row(6));
+        assert_eq!(writer.dropped.get(), 3);
+    }
+}
@@ -0,0 +1,443 @@
+// Address clustering based on the common-input-ownership heuristic: scripts that are spent
+// together as inputs of the same transaction are assumed to be controlled by the same entity.
+//
+// Clusters are kept as a union-find where every member points directly at the cluster root.
+// Merging relabels the members of the smaller cluster, so each script is relabeled at most
+// O(log n) times. Scripts that were never spent alongside another script form singleton
+// clusters and have no rows.
+//
+// Merges can't be undone in place, so the unions made by each block are also journaled. On a
+// reorg, the clusters touched by the orphaned blocks are dropped and rebuilt from the unions
+// of the blocks that remain in the best chain.
+//
+//      K{scripthash} → {root-scripthash}
+//      k{root-scripthash}{scripthash} → {script}
+//      N{root-scripthash} → {cluster-size}
+//      n{blockhash} → [({script}, {script}), ...]
+//      g{scripthash}{blockhash} → ""
+
+use std::collections::{HashMap, HashSet};
+
+use crate::chain::{OutPoint, Script, Transaction, TxOut};
+use crate::new_index::db::{DBRow, DB};
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::schema::compute_script_hash;
+use crate::util::{bincode, full_hash, has_prevout, Bytes, FullHash};
+
+pub struct ClusterMember {
+    pub scripthash: FullHash,
+    pub script: Script,
+}
+
+pub struct Cluster {
+    pub root: FullHash,
+    pub size: u64,
+}
+
+fn root_key(scripthash: &[u8]) -> Bytes {
+    [b"K", scripthash].concat()
+}
+
+fn member_key(root: &[u8], scripthash: &[u8]) -> Bytes {
+    [b"k", root, scripthash].concat()
+}
+
+fn members_prefix(root: &[u8]) -> Bytes {
+    [b"k", root].concat()
+}
+
+fn size_key(root: &[u8]) -> Bytes {
+    [b"N", root].concat()
+}
+
+fn journal_key(blockhash: &[u8]) -> Bytes {
+    [b"n", blockhash].concat()
+}
+
+// indexes the journals by the first script of their unions, which is a member of the cluster
+fn journal_index_key(scripthash: &[u8], blockhash: &[u8]) -> Bytes {
+    [b"g", scripthash, blockhash].concat()
+}
+
+fn block_unions(db: &DB, blockhash: &[u8]) -> Vec<(Script, Script)> {
+    db.get(&journal_key(blockhash))
+        .map(|journal| {
+            let unions: Vec<(Bytes, Bytes)> =
+                bincode::deserialize_big(&journal).expect("invalid cluster journal");
+            unions
+                .into_iter()
+                .map(|(a, b)| (Script::from(a), Script::from(b)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Buffers the changes made while clustering a batch of blocks, so that later reads within
+// the same batch observe them and each key is only written once.
+struct ClusterBatch<'a> {
+    db: &'a DB,
+    puts: HashMap<Bytes, Bytes>,
+    deletes: HashSet<Bytes>,
+}
+
+impl<'a> ClusterBatch<'a> {
+    fn new(db: &'a DB) -> Self {
+        ClusterBatch {
+            db,
+            puts: HashMap::new(),
+            deletes: HashSet::new(),
+        }
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Bytes> {
+        if self.deletes.contains(key) {
+            return None;
+        }
+        self.puts.get(key).cloned().or_else(|| self.db.get(key))
+    }
+
+    fn put(&mut self, key: Bytes, value: Bytes) {
+        self.deletes.remove(&key);
+        self.puts.insert(key, value);
+    }
+
+    fn delete(&mut self, key: Bytes) {
+        self.puts.remove(&key);
+        self.deletes.insert(key);
+    }
+
+    fn scan(&self, prefix: &[u8]) -> HashMap<Bytes, Bytes> {
+        let mut rows: HashMap<Bytes, Bytes> = self
+            .db
+            .iter_scan(prefix)
+            .filter(|row| !self.deletes.contains(&row.key))
+            .map(|row| (row.key, row.value))
+            .collect();
+        rows.extend(
+            self.puts
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        rows
+    }
+
+    fn root(&self, scripthash: &FullHash) -> FullHash {
+        self.get(&root_key(scripthash))
+            .map(|root| full_hash(&root))
+            .unwrap_or(*scripthash)
+    }
+
+    fn size(&self, root: &FullHash) -> u64 {
+        self.get(&size_key(root))
+            .map(|size| bincode::deserialize_big(&size).expect("invalid cluster size"))
+            .unwrap_or(1)
+    }
+
+    fn union(&mut self, a: (FullHash, &Script), b: (FullHash, &Script)) {
+        let (root_a, root_b) = (self.root(&a.0), self.root(&b.0));
+        if root_a == root_b {
+            return;
+        }
+        let (size_a, size_b) = (self.size(&root_a), self.size(&root_b));
+        // the root of a singleton cluster is the script itself, so the provided script is
+        // only used when the cluster has no member rows yet
+        let ((big, big_script), (small, small_script)) = if size_a >= size_b {
+            ((root_a, a.1), (root_b, b.1))
+        } else {
+            ((root_b, b.1), (root_a, a.1))
+        };
+
+        if self.size(&big) == 1 {
+            self.put(root_key(&big), big.to_vec());
+            self.put(member_key(&big, &big), big_script.as_bytes().to_vec());
+        }
+
+        let prefix_len = 1 + small.len();
+        let mut members: Vec<(FullHash, Bytes)> = self
+            .scan(&members_prefix(&small))
+            .into_iter()
+            .map(|(key, script)| (full_hash(&key[prefix_len..]), script))
+            .collect();
+        if members.is_empty() {
+            members.push((small, small_script.as_bytes().to_vec()));
+        }
+
+        for (member, script) in members {
+            self.delete(member_key(&small, &member));
+            self.put(root_key(&member), big.to_vec());
+            self.put(member_key(&big, &member), script);
+        }
+        self.delete(size_key(&small));
+        self.put(
+            size_key(&big),
+            bincode::serialize_big(&(size_a + size_b)).unwrap(),
+        );
+    }
+
+    // Merge the clusters of each pair of scripts, and journal the unions made by the block
+    fn apply_unions(&mut self, blockhash: &[u8], unions: &[(Script, Script)]) {
+        if unions.is_empty() {
+            return;
+        }
+        let mut firsts = HashSet::new();
+        for (a, b) in unions {
+            let a_hash = compute_script_hash(a);
+            self.union((a_hash, a), (compute_script_hash(b), b));
+            if firsts.insert(a_hash) {
+                self.put(journal_index_key(&a_hash, blockhash), vec![]);
+            }
+        }
+        let journal: Vec<(&[u8], &[u8])> = unions
+            .iter()
+            .map(|(a, b)| (a.as_bytes(), b.as_bytes()))
+            .collect();
+        self.put(
+            journal_key(blockhash),
+            bincode::serialize_big(&journal).unwrap(),
+        );
+    }
+
+    fn into_rows(self) -> (Vec<DBRow>, Vec<Bytes>) {
+        let rows = self
+            .puts
+            .into_iter()
+            .map(|(key, value)| DBRow { key, value })
+            .collect();
+        (rows, self.deletes.into_iter().collect())
+    }
+}
+
+// Merge the clusters of the scripts spent together by the transactions in these blocks.
+// Returns the rows to write and the keys to delete.
+pub fn index_clusters(
+    block_entries: &[BlockEntry],
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    db: &DB,
+) -> (Vec<DBRow>, Vec<Bytes>) {
+    let mut batch = ClusterBatch::new(db);
+    for b in block_entries {
+        let unions: Vec<(Script, Script)> = b
+            .block
+            .txdata
+            .iter()
+            .flat_map(|tx| transaction_unions(tx, previous_txos_map))
+            .collect();
+        batch.apply_unions(&b.entry.hash()[..], &unions);
+    }
+    batch.into_rows()
+}
+
+// Rebuild the clusters touched by these blocks (the orphaned ones and the ones that replaced
+// them) from the journaled unions of the blocks that are in the best chain.
+// Returns the rows to write and the keys to delete.
+pub fn rebuild_clusters<F>(
+    db: &DB,
+    blockhashes: &[Bytes],
+    in_best_chain: F,
+) -> (Vec<DBRow>, Vec<Bytes>)
+where
+    F: Fn(&[u8]) -> bool,
+{
+    let mut batch = ClusterBatch::new(db);
+
+    let roots: HashSet<FullHash> = blockhashes
+        .iter()
+        .flat_map(|blockhash| block_unions(db, blockhash))
+        .flat_map(|(a, b)| vec![compute_script_hash(&a), compute_script_hash(&b)])
+        .map(|scripthash| batch.root(&scripthash))
+        .collect();
+
+    // every member of the touched clusters starts over as a singleton
+    let mut members = vec![];
+    for root in roots {
+        let prefix = members_prefix(&root);
+        let cluster: Vec<FullHash> = db
+            .iter_scan(&prefix)
+            .map(|row| full_hash(&row.key[prefix.len()..]))
+            .collect();
+        for member in &cluster {
+            batch.delete(root_key(member));
+            batch.delete(member_key(&root, member));
+        }
+        batch.delete(size_key(&root));
+        if cluster.is_empty() {
+            members.push(root);
+        } else {
+            members.extend(cluster);
+        }
+    }
+
+    let mut journals: HashMap<Bytes, Vec<(Script, Script)>> = HashMap::new();
+    for member in members {
+        let prefix = journal_index_key(&member, &[]);
+        for row in db.iter_scan(&prefix) {
+            let blockhash = &row.key[prefix.len()..];
+            if !in_best_chain(blockhash) {
+                continue;
+            }
+            let unions = journals
+                .entry(blockhash.to_vec())
+                .or_insert_with(|| block_unions(db, blockhash));
+            for (a, b) in unions.iter() {
+                if compute_script_hash(a) == member {
+                    batch.union((member, a), (compute_script_hash(b), b));
+                }
+            }
+        }
+    }
+    batch.into_rows()
+}
+
+// The pairs of scripts spent together by the transaction, each input paired with the first one
+fn transaction_unions(
+    tx: &Transaction,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+) -> Vec<(Script, Script)> {
+    let mut seen = HashSet::new();
+    let inputs: Vec<&Script> = tx
+        .input
+        .iter()
+        .filter(|txi| has_prevout(txi))
+        .map(|txi| {
+            let prev_txo = previous_txos_map
+                .get(&txi.previous_output)
+                .unwrap_or_else(|| panic!("missing previous txo {}", txi.previous_output));
+            &prev_txo.script_pubkey
+        })
+        .filter(|script| seen.insert(compute_script_hash(script)))
+        .collect();
+
+    match inputs.split_first() {
+        Some((first, rest)) => rest
+            .iter()
+            .map(|input| ((*first).clone(), (*input).clone()))
+            .collect(),
+        None => vec![],
+    }
+}
+
+// Returns None if the script belongs to a singleton cluster
+pub fn lookup_cluster(db: &DB, scripthash: &[u8]) -> Option<Cluster> {
+    let root = full_hash(&db.get(&root_key(scripthash))?);
+    let size = db
+        .get(&size_key(&root))
+        .map(|size| bincode::deserialize_big(&size).expect("invalid cluster size"))
+        .unwrap_or(1);
+    Some(Cluster { root, size })
+}
+
+// List the members of the cluster, ordered by scripthash
+pub fn cluster_members(
+    db: &DB,
+    root: &FullHash,
+    last_seen: Option<&FullHash>,
+    limit: usize,
+) -> Vec<ClusterMember> {
+    let prefix = members_prefix(root);
+    let iter = match last_seen {
+        Some(last_seen) => db.iter_scan_from(&prefix, &member_key(root, last_seen)),
+        None => db.iter_scan(&prefix),
+    };
+    iter.map(|row| ClusterMember {
+        scripthash: full_hash(&row.key[prefix.len()..]),
+        script: Script::from(row.value),
+    })
+    .filter(|member| Some(&member.scripthash) != last_seen)
+    .take(limit)
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_index::db::DBFlush;
+
+    fn script(i: u8) -> Script {
+        Script::from(vec![0x51, i])
+    }
+
+    fn apply(db: &DB, blockhash: &[u8], unions: &[(u8, u8)]) {
+        let mut batch = ClusterBatch::new(db);
+        let unions: Vec<(Script, Script)> = unions
+            .iter()
+            .map(|(a, b)| (script(*a), script(*b)))
+            .collect();
+        batch.apply_unions(blockhash, &unions);
+        let (rows, deletes) = batch.into_rows();
+        db.write_and_delete(rows, deletes, DBFlush::Enable);
+    }
+
+    fn cluster_of(db: &DB, i: u8) -> Option<(FullHash, u64)> {
+        lookup_cluster(db, &compute_script_hash(&script(i))).map(|c| (c.root, c.size))
+    }
+
+    #[test]
+    fn test_union_and_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open_for_tests(dir.path());
+
+        apply(&db, b"block1", &[(1, 2), (3, 4), (1, 2)]);
+        let (root12, size12) = cluster_of(&db, 1).unwrap();
+        assert_eq!(size12, 2);
+        assert_eq!(cluster_of(&db, 2), Some((root12, 2)));
+        let (root34, _) = cluster_of(&db, 3).unwrap();
+        assert_ne!(root12, root34);
+        assert_eq!(cluster_of(&db, 5), None);
+
+        // merging into the bigger cluster keeps its root
+        apply(&db, b"block2", &[(5, 4), (2, 4)]);
+        let (root, size) = cluster_of(&db, 5).unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(root, root34);
+        for i in 1..=5 {
+            assert_eq!(cluster_of(&db, i), Some((root, 5)));
+        }
+
+        let members = cluster_members(&db, &root, None, 10);
+        let mut scripthashes: Vec<FullHash> =
+            (1..=5).map(|i| compute_script_hash(&script(i))).collect();
+        scripthashes.sort();
+        let listed: Vec<FullHash> = members.iter().map(|m| m.scripthash).collect();
+        assert_eq!(listed, scripthashes);
+        assert!(members
+            .iter()
+            .all(|m| compute_script_hash(&m.script) == m.scripthash));
+
+        let rest = cluster_members(&db, &root, Some(&scripthashes[1]), 10);
+        assert_eq!(rest.len(), 3);
+        assert_eq!(rest[0].scripthash, scripthashes[2]);
+    }
+
+    #[test]
+    fn test_rebuild_on_reorg() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = DB::open_for_tests(dir.path());
+
+        apply(&db, b"block1", &[(1, 2)]);
+        apply(&db, b"block2a", &[(2, 3), (4, 5)]);
+        apply(&db, b"block2b", &[(6, 1)]);
+        assert_eq!(cluster_of(&db, 6).unwrap().1, 4);
+
+        // block2a is orphaned by block2b, only block1 and block2b remain in the best chain
+        let blockhashes = vec![b"block2a".to_vec(), b"block2b".to_vec()];
+        let (rows, deletes) =
+            rebuild_clusters(&db, &blockhashes, |blockhash| blockhash != b"block2a");
+        db.write_and_delete(rows, deletes, DBFlush::Enable);
+
+        let (root, size) = cluster_of(&db, 1).unwrap();
+        assert_eq!(size, 3);
+        assert_eq!(cluster_of(&db, 2), Some((root, 3)));
+        assert_eq!(cluster_of(&db, 6), Some((root, 3)));
+        assert_eq!(cluster_of(&db, 3), None);
+        assert_eq!(cluster_of(&db, 4), None);
+        assert_eq!(cluster_of(&db, 5), None);
+        assert_eq!(cluster_members(&db, &root, None, 10).len(), 3);
+
+        // reconnecting block2a merges its clusters again
+        let (rows, deletes) = rebuild_clusters(&db, &blockhashes, |_| true);
+        db.write_and_delete(rows, deletes, DBFlush::Enable);
+        assert_eq!(cluster_of(&db, 3).unwrap().1, 4);
+        assert_eq!(cluster_of(&db, 4).unwrap().1, 2);
+    }
+}
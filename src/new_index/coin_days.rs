@@ -0,0 +1,135 @@
+// Coin days destroyed: the value of the outputs spent by a transaction, weighted by the number of
+// days since they were created (based on the timestamps of the funding and spending blocks). The
+// block time of every transaction is kept in the txstore, to know the age of its outputs once
+// spent:
+//
+//      txstore: j{txid} → {block-time}
+//      history: J{txid} → {coin-days-destroyed}
+//      history: y{blockhash} → {coin-days-destroyed}
+//
+// Coin days destroyed are stored in satoshi-days, and are unavailable for the transactions (and
+// blocks) spending outputs created before --coin-days-destroyed was enabled.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use crate::chain::{OutPoint, TxOut};
+use crate::new_index::db::{DBRow, DB};
+use crate::new_index::fetch::BlockEntry;
+use crate::util::{has_prevout, Bytes};
+
+const SECONDS_PER_DAY: u128 = 24 * 60 * 60;
+const SATS_PER_BTC: f64 = 100_000_000.0;
+
+fn time_key(txid: &[u8]) -> Bytes {
+    [b"j", txid].concat()
+}
+
+fn tx_key(txid: &[u8]) -> Bytes {
+    [b"J", txid].concat()
+}
+
+fn block_key(blockhash: &[u8]) -> Bytes {
+    [b"y", blockhash].concat()
+}
+
+pub fn add_tx_times(b: &BlockEntry, rows: &mut Vec<DBRow>) {
+    let time = b.entry.header().time;
+    for tx in &b.block.txdata {
+        rows.push(DBRow {
+            key: time_key(&tx.txid()[..]),
+            value: time.to_le_bytes().to_vec(),
+        });
+    }
+}
+
+pub fn lookup_tx_time(txstore_db: &DB, outpoint: &OutPoint) -> Option<u32> {
+    txstore_db
+        .get(&time_key(&outpoint.txid[..]))
+        .map(|value| u32::from_le_bytes(value[..].try_into().expect("invalid tx time")))
+}
+
+// The value of the output in satoshis, times its age in days
+fn coin_days(txout: &TxOut, funding_time: u32, spending_time: u32) -> u64 {
+    let age = spending_time.saturating_sub(funding_time) as u128;
+    (txout.value.to_sat() as u128 * age / SECONDS_PER_DAY) as u64
+}
+
+pub fn index_block(
+    b: &BlockEntry,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    tx_times: &HashMap<OutPoint, u32>,
+) -> Vec<DBRow> {
+    let spending_time = b.entry.header().time;
+    let mut rows = vec![];
+    let mut block_total = Some(0u64);
+    for tx in b.block.txdata.iter().filter(|tx| !tx.is_coinbase()) {
+        let tx_total = tx
+            .input
+            .iter()
+            .filter(|txin| has_prevout(txin))
+            .map(|txin| {
+                let outpoint = &txin.previous_output;
+                let funding_time = *tx_times.get(outpoint)?;
+                let txout = previous_txos_map
+                    .get(outpoint)
+                    .unwrap_or_else(|| panic!("missing previous txo {}", outpoint));
+                Some(coin_days(txout, funding_time, spending_time))
+            })
+            .sum::<Option<u64>>();
+        match tx_total {
+            Some(tx_total) => {
+                rows.push(DBRow {
+                    key: tx_key(&tx.txid()[..]),
+                    value: tx_total.to_le_bytes().to_vec(),
+                });
+                block_total = block_total.map(|total| total.saturating_add(tx_total));
+            }
+            None => block_total = None,
+        }
+    }
+    if let Some(block_total) = block_total {
+        rows.push(DBRow {
+            key: block_key(&b.entry.hash()[..]),
+            value: block_total.to_le_bytes().to_vec(),
+        });
+    }
+    rows
+}
+
+fn parse_coin_days(value: Bytes) -> u64 {
+    u64::from_le_bytes(value[..].try_into().expect("invalid coin days destroyed"))
+}
+
+pub fn tx_coin_days_destroyed(history_db: &DB, txid: &[u8]) -> Option<u64> {
+    history_db.get(&tx_key(txid)).map(parse_coin_days)
+}
+
+pub fn block_coin_days_destroyed(history_db: &DB, blockhash: &[u8]) -> Option<u64> {
+    history_db.get(&block_key(blockhash)).map(parse_coin_days)
+}
+
+// Satoshi-days to coin-days, as exposed by the API
+pub fn to_coin_days(sat_days: u64) -> f64 {
+    sat_days as f64 / SATS_PER_BTC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{Amount, ScriptBuf};
+
+    #[test]
+    fn test_coin_days() {
+        let txout = TxOut {
+            value: Amount::from_sat(150_000_000),
+            script_pubkey: ScriptBuf::new(),
+        };
+        let day = SECONDS_PER_DAY as u32;
+        assert_eq!(coin_days(&txout, 1000, 1000 + 2 * day), 300_000_000);
+        assert_eq!(coin_days(&txout, 1000, 1000 + day / 2), 75_000_000);
+        // block timestamps are not monotonic
+        assert_eq!(coin_days(&txout, 1000, 900), 0);
+        assert_eq!(to_coin_days(300_000_000), 3.0);
+    }
+}
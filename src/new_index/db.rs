@@ -3,9 +3,10 @@ use rocksdb;
 use std::path::Path;
 
 use crate::config::Config;
-use crate::util::{bincode, Bytes};
+use crate::errors::*;
+use crate::util::{bincode, cost, Bytes};
 
-static DB_VERSION: u32 = 1;
+static DB_VERSION: u32 = 2;
 
 #[derive(Debug, Eq, PartialEq)]
 pub struct DBRow {
@@ -13,6 +14,15 @@ pub struct DBRow {
     pub value: Vec<u8>,
 }
 
+// Disk usage and size estimates reported by RocksDB
+#[derive(Serialize, Debug, Default)]
+pub struct DBStats {
+    pub sst_files_size: u64,
+    pub live_data_size: u64,
+    pub mem_tables_size: u64,
+    pub estimated_keys: u64,
+}
+
 pub struct ScanIterator<'a> {
     prefix: Vec<u8>,
     iter: rocksdb::DBIterator<'a>,
@@ -31,6 +41,7 @@ impl<'a> Iterator for ScanIterator<'a> {
             self.done = true;
             return None;
         }
+        cost::add_rows_scanned(1);
         Some(DBRow {
             key: key.to_vec(),
             value: value.to_vec(),
@@ -62,6 +73,7 @@ impl<'a> Iterator for ReverseScanIterator<'a> {
             key: key.into(),
             value: self.iter.value().unwrap().into(),
         };
+        cost::add_rows_scanned(1);
 
         self.iter.prev();
 
@@ -74,6 +86,11 @@ pub struct DB {
     db: rocksdb::DB,
 }
 
+// A point-in-time view of the DB. Reads made through it are not affected by concurrent writes.
+pub struct DBSnapshot<'a> {
+    snapshot: rocksdb::Snapshot<'a>,
+}
+
 #[derive(Copy, Clone, Debug)]
 pub enum DBFlush {
     Disable,
@@ -96,8 +113,11 @@ impl DB {
         db_opts.set_compaction_readahead_size(1 << 20);
         db_opts.increase_parallelism(2);
 
-        // let mut block_opts = rocksdb::BlockBasedOptions::default();
+        let mut block_opts = rocksdb::BlockBasedOptions::default();
         // block_opts.set_block_size(???);
+        // lookups of missing keys are mostly answered by the filters, without reading data blocks
+        block_opts.set_bloom_filter(10.0, false);
+        db_opts.set_block_based_table_factory(&block_opts);
 
         let db = DB {
             db: rocksdb::DB::open(&db_opts, path).expect("failed to open RocksDB"),
@@ -106,6 +126,16 @@ impl DB {
         db
     }
 
+    // A plain database without the compatibility check, for the unit tests
+    #[cfg(test)]
+    pub fn open_for_tests(path: &Path) -> DB {
+        let mut db_opts = rocksdb::Options::default();
+        db_opts.create_if_missing(true);
+        DB {
+            db: rocksdb::DB::open(&db_opts, path).expect("failed to open RocksDB"),
+        }
+    }
+
     pub fn full_compaction(&self) {
         // TODO: make sure this doesn't fail silently
         debug!("starting full compaction on {:?}", self.db);
@@ -153,10 +183,22 @@ impl DB {
         }
     }
 
-    pub fn write(&self, mut rows: Vec<DBRow>, flush: DBFlush) {
+    pub fn snapshot(&self) -> DBSnapshot {
+        DBSnapshot {
+            snapshot: self.db.snapshot(),
+        }
+    }
+
+    pub fn write(&self, rows: Vec<DBRow>, flush: DBFlush) {
+        self.write_and_delete(rows, vec![], flush)
+    }
+
+    // Atomically write the rows and delete the keys, which must not overlap
+    pub fn write_and_delete(&self, mut rows: Vec<DBRow>, deletes: Vec<Bytes>, flush: DBFlush) {
         debug!(
-            "writing {} rows to {:?}, flush={:?}",
+            "writing {} rows and deleting {} keys to {:?}, flush={:?}",
             rows.len(),
+            deletes.len(),
             self.db,
             flush
         );
@@ -168,6 +210,12 @@ impl DB {
             #[cfg(feature = "oldcpu")]
             batch.put(&row.key, &row.value).unwrap();
         }
+        for key in deletes {
+            #[cfg(not(feature = "oldcpu"))]
+            batch.delete(&key);
+            #[cfg(feature = "oldcpu")]
+            batch.delete(&key).unwrap();
+        }
         let do_flush = match flush {
             DBFlush::Enable => true,
             DBFlush::Disable => false,
@@ -196,6 +244,24 @@ impl DB {
         self.db.get(key).unwrap().map(|v| v.to_vec())
     }
 
+    pub fn stats(&self) -> DBStats {
+        let property = |name: &str| self.db.property_int_value(name).ok().flatten().unwrap_or(0);
+        DBStats {
+            sst_files_size: property("rocksdb.total-sst-files-size"),
+            live_data_size: property("rocksdb.estimate-live-data-size"),
+            mem_tables_size: property("rocksdb.cur-size-all-mem-tables"),
+            estimated_keys: property("rocksdb.estimate-num-keys"),
+        }
+    }
+
+    // Like get(), but reports read failures instead of panicking
+    pub fn try_get(&self, key: &[u8]) -> Result<Option<Bytes>> {
+        self.db
+            .get(key)
+            .map(|v| v.map(|v| v.to_vec()))
+            .chain_err(|| "failed reading from db")
+    }
+
     fn verify_compatibility(&self, config: &Config) {
         let mut compatibility_bytes = bincode::serialize_little(&DB_VERSION).unwrap();
 
@@ -216,3 +282,36 @@ impl DB {
         }
     }
 }
+
+impl<'a> DBSnapshot<'a> {
+    pub fn iter_scan(&self, prefix: &[u8]) -> ScanIterator {
+        self.iter_scan_from(prefix, prefix)
+    }
+
+    pub fn iter_scan_from(&self, prefix: &[u8], start_at: &[u8]) -> ScanIterator {
+        let iter = self.snapshot.iterator(rocksdb::IteratorMode::From(
+            start_at,
+            rocksdb::Direction::Forward,
+        ));
+        ScanIterator {
+            prefix: prefix.to_vec(),
+            iter,
+            done: false,
+        }
+    }
+
+    pub fn iter_scan_reverse(&self, prefix: &[u8], prefix_max: &[u8]) -> ReverseScanIterator {
+        let mut iter = self.snapshot.raw_iterator();
+        iter.seek_for_prev(prefix_max);
+
+        ReverseScanIterator {
+            prefix: prefix.to_vec(),
+            iter,
+            done: false,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Bytes> {
+        self.snapshot.get(key).unwrap()
+    }
+}
@@ -10,7 +10,7 @@ use elements::encode::{deserialize, Decodable};
 use std::collections::HashMap;
 use std::fs;
 use std::io::Cursor;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::Receiver;
 use std::thread;
 
@@ -76,39 +76,64 @@ fn bitcoind_fetcher(
     let daemon = daemon.reconnect()?;
     let chan = SyncChannel::new(1);
     let sender = chan.sender();
+
+    // the blocks of the next chunk are requested while the previous ones are being deserialized
+    let reader = bitcoind_reader(daemon, new_headers);
     Ok(Fetcher::from(
         chan.into_receiver(),
         spawn_thread("bitcoind_fetcher", move || {
-            for entries in new_headers.chunks(100) {
-                let blockhashes: Vec<BlockHash> = entries.iter().map(|e| *e.hash()).collect();
-                let blocks = daemon
-                    .getblocks(&blockhashes)
-                    .expect("failed to get blocks from bitcoind");
-                assert_eq!(blocks.len(), entries.len());
-                let block_entries: Vec<BlockEntry> = blocks
-                    .into_iter()
+            reader.map(|(entries, blobs)| {
+                let block_entries: Vec<BlockEntry> = blobs
+                    .into_par_iter()
                     .zip(entries)
-                    .map(|(block, entry)| BlockEntry {
-                        entry: entry.clone(), // TODO: remove this clone()
-                        size: block.total_size() as u32,
-                        block,
+                    .map(|(blob, entry)| BlockEntry {
+                        block: deserialize(&blob).expect("failed to parse Block"),
+                        size: blob.len() as u32,
+                        entry,
                     })
                     .collect();
-                assert_eq!(block_entries.len(), entries.len());
                 sender
                     .send(block_entries)
                     .expect("failed to send fetched blocks");
-            }
+            });
         }),
     ))
 }
 
+fn bitcoind_reader(
+    daemon: Daemon,
+    new_headers: Vec<HeaderEntry>,
+) -> Fetcher<(Vec<HeaderEntry>, Vec<Vec<u8>>)> {
+    let chan = SyncChannel::new(1);
+    let sender = chan.sender();
+
+    Fetcher::from(
+        chan.into_receiver(),
+        spawn_thread("bitcoind_reader", move || {
+            for entries in new_headers.chunks(100) {
+                let blockhashes: Vec<BlockHash> = entries.iter().map(|e| *e.hash()).collect();
+                let blobs = daemon
+                    .getblocks_raw(&blockhashes)
+                    .expect("failed to get blocks from bitcoind");
+                assert_eq!(blobs.len(), entries.len());
+                sender
+                    .send((entries.to_vec(), blobs))
+                    .expect("failed to send fetched blocks");
+            }
+        }),
+    )
+}
+
 fn blkfiles_fetcher(
     daemon: &Daemon,
     new_headers: Vec<HeaderEntry>,
 ) -> Result<Fetcher<Vec<BlockEntry>>> {
     let magic = daemon.magic();
     let blk_files = daemon.list_blk_files()?;
+    let xor_key = daemon.blocks_xor_key()?;
+    if xor_key.is_some() {
+        info!("de-obfuscating the blk*.dat files with the key from xor.dat");
+    }
 
     let chan = SyncChannel::new(1);
     let sender = chan.sender();
@@ -116,7 +141,8 @@ fn blkfiles_fetcher(
     let mut entry_map: HashMap<BlockHash, HeaderEntry> =
         new_headers.into_iter().map(|h| (*h.hash(), h)).collect();
 
-    let parser = blkfiles_parser(blkfiles_reader(blk_files), magic);
+    let files_count = blk_files.len();
+    let parser = blkfiles_parser(blkfiles_reader(blk_files, xor_key), magic, files_count);
     Ok(Fetcher::from(
         chan.into_receiver(),
         spawn_thread("blkfiles_fetcher", move || {
@@ -149,7 +175,10 @@ fn blkfiles_fetcher(
     ))
 }
 
-fn blkfiles_reader(blk_files: Vec<PathBuf>) -> Fetcher<Vec<u8>> {
+fn blkfiles_reader(
+    blk_files: Vec<PathBuf>,
+    xor_key: Option<Vec<u8>>,
+) -> Fetcher<(PathBuf, Vec<u8>)> {
     let chan = SyncChannel::new(1);
     let sender = chan.sender();
 
@@ -158,26 +187,54 @@ fn blkfiles_reader(blk_files: Vec<PathBuf>) -> Fetcher<Vec<u8>> {
         spawn_thread("blkfiles_reader", move || {
             for path in blk_files {
                 trace!("reading {:?}", path);
-                let blob = fs::read(&path)
+                let mut blob = fs::read(&path)
                     .unwrap_or_else(|e| panic!("failed to read {:?}: {:?}", path, e));
+                if let Some(xor_key) = &xor_key {
+                    xor_blob(&mut blob, xor_key);
+                }
                 sender
-                    .send(blob)
+                    .send((path.clone(), blob))
                     .unwrap_or_else(|_| panic!("failed to send {:?} contents", path));
             }
         }),
     )
 }
 
-fn blkfiles_parser(blobs: Fetcher<Vec<u8>>, magic: u32) -> Fetcher<Vec<SizedBlock>> {
+// The key is applied from the start of the file, repeated
+fn xor_blob(blob: &mut [u8], key: &[u8]) {
+    for (byte, key_byte) in blob.iter_mut().zip(key.iter().cycle()) {
+        *byte ^= key_byte;
+    }
+}
+
+fn blkfiles_parser(
+    blobs: Fetcher<(PathBuf, Vec<u8>)>,
+    magic: u32,
+    files_count: usize,
+) -> Fetcher<Vec<SizedBlock>> {
     let chan = SyncChannel::new(1);
     let sender = chan.sender();
 
     Fetcher::from(
         chan.into_receiver(),
         spawn_thread("blkfiles_parser", move || {
-            blobs.map(|blob| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(0) // CPU-bound
+                .thread_name(|i| format!("parse-blocks-{}", i))
+                .build()
+                .unwrap();
+            let mut files_done = 0;
+            blobs.map(|(path, blob)| {
                 trace!("parsing {} bytes", blob.len());
-                let blocks = parse_blocks(blob, magic).expect("failed to parse blk*.dat file");
+                let blocks = parse_blocks(&path, &blob, magic, &pool);
+                files_done += 1;
+                info!(
+                    "parsed {} blocks from {:?} ({}/{} files)",
+                    blocks.len(),
+                    path,
+                    files_done,
+                    files_count
+                );
                 sender
                     .send(blocks)
                     .expect("failed to send blocks from blk*.dat file");
@@ -186,8 +243,10 @@ fn blkfiles_parser(blobs: Fetcher<Vec<u8>>, magic: u32) -> Fetcher<Vec<SizedBloc
     )
 }
 
-fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<SizedBlock>> {
-    let mut cursor = Cursor::new(&blob);
+// Corrupted or partially written records (e.g. at the end of the file the node was writing to when
+// it crashed) are skipped, the blocks they contain are expected to be found later on
+fn parse_blocks(path: &Path, blob: &[u8], magic: u32, pool: &rayon::ThreadPool) -> Vec<SizedBlock> {
+    let mut cursor = Cursor::new(blob);
     let mut slices = vec![];
     let max_pos = blob.len() as u64;
 
@@ -202,9 +261,24 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<SizedBlock>> {
             }
             Err(_) => break, // EOF
         };
-        let block_size = u32::consensus_decode(&mut cursor).chain_err(|| "no block size")?;
+        let block_size = match u32::consensus_decode(&mut cursor) {
+            Ok(block_size) => block_size,
+            Err(_) => {
+                warn!("{:?}: skipping truncated record at offset {}", path, offset);
+                break;
+            }
+        };
         let start = cursor.position();
         let end = start + block_size as u64;
+        if end > max_pos {
+            warn!(
+                "{:?}: skipping truncated record at offset {} ({} bytes missing)",
+                path,
+                offset,
+                end - max_pos
+            );
+            break;
+        }
 
         // If Core's WriteBlockToDisk ftell fails, only the magic bytes and size will be written
         // and the block body won't be written to the blk*.dat file.
@@ -219,19 +293,73 @@ fn parse_blocks(blob: Vec<u8>, magic: u32) -> Result<Vec<SizedBlock>> {
             }
             Err(_) => break, // EOF
         }
-        slices.push((&blob[start as usize..end as usize], block_size));
+        slices.push((offset, &blob[start as usize..end as usize], block_size));
         cursor.set_position(end as u64);
     }
 
-    let pool = rayon::ThreadPoolBuilder::new()
-        .num_threads(0) // CPU-bound
-        .thread_name(|i| format!("parse-blocks-{}", i))
-        .build()
-        .unwrap();
-    Ok(pool.install(|| {
+    pool.install(|| {
         slices
             .into_par_iter()
-            .map(|(slice, size)| (deserialize(slice).expect("failed to parse Block"), size))
+            .filter_map(|(offset, slice, size)| match deserialize(slice) {
+                Ok(block) => Some((block, size)),
+                Err(e) => {
+                    warn!(
+                        "{:?}: skipping corrupted block at offset {}: {}",
+                        path, offset, e
+                    );
+                    None
+                }
+            })
             .collect()
-    }))
+    })
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "liquid"))]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::serialize;
+    use bitcoin::Network;
+
+    #[test]
+    fn test_parse_blocks() {
+        let magic: u32 = 0xd9b4bef9;
+        let genesis = genesis_block(Network::Bitcoin);
+        let block = serialize(&genesis);
+        let record = |body: &[u8], size: usize| {
+            [&magic.to_le_bytes()[..], &(size as u32).to_le_bytes(), body].concat()
+        };
+        let blob = [
+            record(&block, block.len()),
+            record(&[0xff; 100], 100), // corrupted
+            vec![0; 16],
+            record(&block, block.len()),
+            record(&block[..50], block.len()), // partially written
+        ]
+        .concat();
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .unwrap();
+        let blocks = parse_blocks(Path::new("blk00000.dat"), &blob, magic, &pool);
+        assert_eq!(blocks.len(), 2);
+        for (parsed, size) in blocks {
+            assert_eq!(parsed.block_hash(), genesis.block_hash());
+            assert_eq!(size as usize, block.len());
+        }
+    }
+
+    #[test]
+    fn test_xor_blob() {
+        let key = [0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0];
+        let blob: Vec<u8> = (0..20).collect();
+        let mut obfuscated = blob.clone();
+        xor_blob(&mut obfuscated, &key);
+        assert_eq!(obfuscated[..3], [0x12, 0x35, 0x54]);
+        assert_eq!(obfuscated[8], 8 ^ 0x12);
+        xor_blob(&mut obfuscated, &key);
+        assert_eq!(obfuscated, blob);
+    }
 }
@@ -0,0 +1,65 @@
+// The wall-clock time at which blocks and mempool transactions were first seen by electrs, to tell
+// their propagation delay (e.g. versus the timestamp claimed by the block header). Kept in the
+// cache db, when --first-seen is enabled:
+//
+//      s{txid} → {unix-time}
+//      S{blockhash} → {unix-time}
+//
+// The earliest time is kept if a block or transaction is seen again (e.g. after a reorg). Blocks
+// added by the initial sync and transactions that were confirmed without being seen in the
+// mempool have none.
+
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::new_index::db::{DBFlush, DBRow, DB};
+use crate::util::Bytes;
+
+fn tx_key(txid: &[u8]) -> Bytes {
+    [b"s", txid].concat()
+}
+
+fn block_key(blockhash: &[u8]) -> Bytes {
+    [b"S", blockhash].concat()
+}
+
+fn now() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as u32
+}
+
+fn record(db: &DB, keys: impl Iterator<Item = Bytes>) {
+    let value = now().to_le_bytes();
+    let rows: Vec<DBRow> = keys
+        .filter(|key| db.get(key).is_none())
+        .map(|key| DBRow {
+            key,
+            value: value.to_vec(),
+        })
+        .collect();
+    if !rows.is_empty() {
+        db.write(rows, DBFlush::Enable);
+    }
+}
+
+pub fn record_txs<'a>(db: &DB, txids: impl Iterator<Item = &'a [u8]>) {
+    record(db, txids.map(tx_key))
+}
+
+pub fn record_blocks<'a>(db: &DB, blockhashes: impl Iterator<Item = &'a [u8]>) {
+    record(db, blockhashes.map(block_key))
+}
+
+fn parse_time(value: Bytes) -> u32 {
+    u32::from_le_bytes(value[..].try_into().expect("invalid first seen time"))
+}
+
+pub fn tx_seen_at(db: &DB, txid: &[u8]) -> Option<u32> {
+    db.get(&tx_key(txid)).map(parse_time)
+}
+
+pub fn block_seen_at(db: &DB, blockhash: &[u8]) -> Option<u32> {
+    db.get(&block_key(blockhash)).map(parse_time)
+}
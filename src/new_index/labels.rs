@@ -0,0 +1,89 @@
+// User-assigned labels for scripthashes, letting self-hosters annotate their own addresses. Labels
+// are local to the instance and kept in the cache db:
+//
+//      L{scripthash} → {name}{color}
+
+use std::convert::TryInto;
+
+use crate::errors::*;
+use crate::new_index::db::{DBFlush, DBRow, DB};
+use crate::util::{bincode, Bytes, FullHash};
+
+pub const MAX_LABEL_NAME_LEN: usize = 100;
+// The size of the label as submitted, in json
+pub const MAX_LABEL_SIZE: usize = 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Label {
+    pub name: String,
+    pub color: Option<String>,
+}
+
+fn label_key(scripthash: &[u8]) -> Bytes {
+    [b"L", scripthash].concat()
+}
+
+impl Label {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.name.trim().is_empty() && self.name.chars().count() <= MAX_LABEL_NAME_LEN,
+            "label name must be between 1 and {} characters",
+            MAX_LABEL_NAME_LEN
+        );
+        if let Some(color) = &self.color {
+            // #rrggbb
+            ensure!(
+                color.len() == 7
+                    && color.starts_with('#')
+                    && color[1..].chars().all(|c| c.is_ascii_hexdigit()),
+                "label color must be formatted as #rrggbb"
+            );
+        }
+        Ok(())
+    }
+
+    // Add the label, or replace the existing one
+    pub fn set(&self, db: &DB, scripthash: &[u8]) -> Result<()> {
+        self.validate()?;
+        db.write(
+            vec![DBRow {
+                key: label_key(scripthash),
+                value: bincode::serialize_little(self).unwrap(),
+            }],
+            DBFlush::Enable,
+        );
+        Ok(())
+    }
+
+    pub fn get(db: &DB, scripthash: &[u8]) -> Option<Label> {
+        db.get(&label_key(scripthash))
+            .map(|value| bincode::deserialize_little(&value).expect("failed to parse Label"))
+    }
+
+    // Returns false if there is no such label
+    pub fn delete(db: &DB, scripthash: &[u8]) -> bool {
+        if db.get(&label_key(scripthash)).is_none() {
+            return false;
+        }
+        db.write_and_delete(vec![], vec![label_key(scripthash)], DBFlush::Enable);
+        true
+    }
+
+    // Labels whose name contains the search term (case-insensitive), or all labels
+    pub fn search(db: &DB, search: Option<&str>) -> Vec<(FullHash, Label)> {
+        let search = search.map(str::to_lowercase);
+        db.iter_scan(b"L")
+            .filter(|row| row.key.len() == 33)
+            .map(|row| {
+                let scripthash: FullHash = row.key[1..].try_into().unwrap();
+                let label: Label =
+                    bincode::deserialize_little(&row.value).expect("failed to parse Label");
+                (scripthash, label)
+            })
+            .filter(|(_, label)| match &search {
+                Some(search) => label.name.to_lowercase().contains(search),
+                None => true,
+            })
+            .collect()
+    }
+}
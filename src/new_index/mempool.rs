@@ -6,19 +6,20 @@ use bitcoin::consensus::encode::serialize;
 #[cfg(feature = "liquid")]
 use elements::{encode::serialize, AssetId};
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::iter::FromIterator;
 use std::sync::{Arc, RwLock};
 use std::time::{Duration, Instant};
 
-use crate::chain::{deserialize, Network, OutPoint, Transaction, TxOut, Txid};
+use crate::chain::{deserialize, Network, OutPoint, Transaction, TxOut, Txid, Wtxid};
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
 use crate::metrics::{GaugeVec, HistogramOpts, HistogramVec, MetricOpts, Metrics};
 use crate::new_index::{
-    compute_script_hash, schema::FullHash, ChainQuery, FundingInfo, GetAmountVal, ScriptStats,
-    SpendingInfo, SpendingInput, TxHistoryInfo, Utxo,
+    compute_script_hash, first_seen, schema::FullHash, ChainQuery, FundingInfo, GetAmountVal,
+    ScriptStats, SpendingInfo, SpendingInput, TxHistoryInfo, Utxo,
 };
 use crate::util::fees::{make_fee_histogram, TxFeeInfo};
 use crate::util::{extract_tx_prevouts, full_hash, has_prevout, is_spendable, Bytes};
@@ -27,17 +28,29 @@ use crate::util::{extract_tx_prevouts, full_hash, has_prevout, is_spendable, Byt
 use crate::elements::asset;
 
 const RECENT_TXS_SIZE: usize = 10;
+// Number of spends of transactions removed from the mempool kept, to report conflicts
+const REMOVED_SPENDS_SIZE: usize = 50_000;
 const BACKLOG_STATS_TTL: u64 = 10;
+// default -blockmaxweight, leaving room for the coinbase
+const BLOCK_TEMPLATE_VSIZE: u64 = 999_000;
+// Number of previous block templates kept, to diff the current one against
+const PREVIOUS_TEMPLATES_SIZE: usize = 10;
 
 pub struct Mempool {
     chain: Arc<ChainQuery>,
     config: Arc<Config>,
     txstore: HashMap<Txid, Transaction>,
+    wtxids: HashMap<FullHash, Txid>, // only for transactions with witnesses
     feeinfo: HashMap<Txid, TxFeeInfo>,
     history: HashMap<FullHash, Vec<TxHistoryInfo>>, // ScriptHash -> {history_entries}
     edges: HashMap<OutPoint, (Txid, u32)>,          // OutPoint -> (spending_txid, spending_vin)
+    removed_spends: VecDeque<(OutPoint, Txid, u32)>, // the inputs of the latest removed txs
     recent: ArrayDeque<TxOverview, RECENT_TXS_SIZE, Wrapping>, // The N most recent txs to enter the mempool
     backlog_stats: (BacklogStats, Instant),
+    by_feerate: BTreeSet<FeerateKey>, // (fee rate in msat/vB, txid), highest first
+    vsize_ahead: Vec<(FeerateKey, u64)>, // by_feerate with the vsize of the txs ahead of each
+    template: BlockTemplate,          // the txs projected to be included in the next block
+    previous_templates: VecDeque<BlockTemplate>, // the latest ones before it, oldest first
 
     // monitoring
     latency: HistogramVec, // mempool requests latency
@@ -61,20 +74,50 @@ pub struct TxOverview {
     value: u64,
 }
 
+type FeerateKey = (Reverse<u64>, Txid);
+
+// Where a transaction would land if blocks were built by including transactions in fee rate order
+#[derive(Serialize)]
+pub struct MempoolPosition {
+    pub block: u64, // 0 for the next block
+    pub vsize_ahead: u64,
+    pub fee_per_vbyte: f64,
+}
+
+// The transactions projected to be included in the next block, in fee rate order. The id gets
+// bumped every time they change.
+#[derive(Default)]
+pub struct BlockTemplate {
+    pub id: u64,
+    pub txids: Vec<Txid>,
+}
+
+// The changes of the current block template from a previous one
+pub struct BlockTemplateDiff {
+    pub added: Vec<Txid>,
+    pub removed: Vec<Txid>,
+}
+
 impl Mempool {
     pub fn new(chain: Arc<ChainQuery>, metrics: &Metrics, config: Arc<Config>) -> Self {
         Mempool {
             chain,
             config,
             txstore: HashMap::new(),
+            wtxids: HashMap::new(),
             feeinfo: HashMap::new(),
             history: HashMap::new(),
             edges: HashMap::new(),
+            removed_spends: VecDeque::new(),
             recent: ArrayDeque::new(),
             backlog_stats: (
                 BacklogStats::default(),
                 Instant::now() - Duration::from_secs(BACKLOG_STATS_TTL),
             ),
+            by_feerate: BTreeSet::new(),
+            vsize_ahead: vec![],
+            template: BlockTemplate::default(),
+            previous_templates: VecDeque::with_capacity(PREVIOUS_TEMPLATES_SIZE),
             latency: metrics.histogram_vec(
                 HistogramOpts::new("mempool_latency", "Mempool requests latency (in seconds)"),
                 &["part"],
@@ -107,6 +150,16 @@ impl Mempool {
         self.txstore.get(txid).map(serialize)
     }
 
+    pub fn lookup_txid_by_wtxid(&self, wtxid: &Wtxid) -> Option<Txid> {
+        self.wtxids
+            .get(&full_hash(&wtxid[..]))
+            .copied()
+            .or_else(|| {
+                let txid = deserialize(&wtxid[..]).unwrap();
+                self.txstore.contains_key(&txid).then(|| txid)
+            })
+    }
+
     pub fn lookup_spend(&self, outpoint: &OutPoint) -> Option<SpendingInput> {
         self.edges.get(outpoint).map(|(txid, vin)| SpendingInput {
             txid: *txid,
@@ -119,6 +172,17 @@ impl Mempool {
         self.edges.contains_key(outpoint)
     }
 
+    // The spends of the outpoint by transactions that left the mempool (confirmed, replaced or
+    // evicted), as far as the rolling index of removed spends goes
+    pub fn removed_spends(&self, outpoint: &OutPoint) -> Vec<(Txid, u32)> {
+        self.removed_spends
+            .iter()
+            .filter(|(spent, _, _)| spent == outpoint)
+            .map(|(_, txid, vin)| (*txid, *vin))
+            .unique()
+            .collect()
+    }
+
     pub fn get_tx_fee(&self, txid: &Txid) -> Option<u64> {
         Some(self.feeinfo.get(txid)?.fee)
     }
@@ -151,6 +215,57 @@ impl Mempool {
             .collect()
     }
 
+    // Get the unconfirmed transactions of a scripthash, along with the net effect each one has
+    // on its balance (funded minus spent)
+    #[cfg(not(feature = "liquid"))]
+    pub fn history_with_delta(&self, scripthash: &[u8], limit: usize) -> Vec<(Transaction, i64)> {
+        let _timer = self
+            .latency
+            .with_label_values(&["history_with_delta"])
+            .start_timer();
+        self._history_txids_with_delta(scripthash, limit)
+            .into_iter()
+            .map(|(txid, delta)| {
+                let tx = self.txstore.get(&txid).expect("missing mempool tx");
+                (tx.clone(), delta)
+            })
+            .collect()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn history_txids_with_delta(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, i64)> {
+        let _timer = self
+            .latency
+            .with_label_values(&["history_txids_with_delta"])
+            .start_timer();
+        self._history_txids_with_delta(scripthash, limit)
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn _history_txids_with_delta(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, i64)> {
+        let entries = match self.history.get(scripthash) {
+            None => return vec![],
+            Some(entries) => entries,
+        };
+
+        let mut deltas: HashMap<Txid, i64> = HashMap::new();
+        for entry in entries {
+            let delta = deltas.entry(entry.get_txid()).or_insert(0);
+            match entry {
+                TxHistoryInfo::Funding(info) => *delta += info.value as i64,
+                TxHistoryInfo::Spending(info) => *delta -= info.value as i64,
+            }
+        }
+
+        entries
+            .iter()
+            .map(|e| e.get_txid())
+            .unique()
+            .take(limit)
+            .map(|txid| (txid, deltas[&txid]))
+            .collect()
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<Txid> {
         let _timer = self
             .latency
@@ -230,12 +345,14 @@ impl Mempool {
                 TxHistoryInfo::Funding(info) => {
                     stats.funded_txo_count += 1;
                     stats.funded_txo_sum += info.value;
+                    stats.balance += info.value as i64;
                 }
 
                 #[cfg(not(feature = "liquid"))]
                 TxHistoryInfo::Spending(info) => {
                     stats.spent_txo_count += 1;
                     stats.spent_txo_sum += info.value;
+                    stats.balance -= info.value as i64;
                 }
 
                 // Elements
@@ -258,6 +375,18 @@ impl Mempool {
         stats
     }
 
+    // The number of distinct mempool transactions funding the scripthash
+    pub fn funding_tx_count(&self, scripthash: &[u8]) -> usize {
+        self.history.get(scripthash).map_or(0, |entries| {
+            entries
+                .iter()
+                .filter(|entry| matches!(entry, TxHistoryInfo::Funding(_)))
+                .map(TxHistoryInfo::get_txid)
+                .collect::<HashSet<Txid>>()
+                .len()
+        })
+    }
+
     // Get all txids in the mempool
     pub fn txids(&self) -> Vec<&Txid> {
         let _timer = self.latency.with_label_values(&["txids"]).start_timer();
@@ -280,6 +409,96 @@ impl Mempool {
         return HashSet::from_iter(self.txstore.keys().cloned());
     }
 
+    // Project the position of the transaction in the upcoming blocks. This ignores package
+    // relationships, so transactions boosted by CPFP are placed according to their own fee rate.
+    pub fn position(&self, txid: &Txid) -> Option<MempoolPosition> {
+        let _timer = self.latency.with_label_values(&["position"]).start_timer();
+        let feeinfo = self.feeinfo.get(txid)?;
+        let key = (Reverse(feerate_key(feeinfo)), *txid);
+        let index = self
+            .vsize_ahead
+            .binary_search_by_key(&&key, |(key, _)| key)
+            .ok()?;
+        let vsize_ahead = self.vsize_ahead[index].1;
+        Some(MempoolPosition {
+            block: (vsize_ahead + feeinfo.vsize).saturating_sub(1) / BLOCK_TEMPLATE_VSIZE,
+            vsize_ahead,
+            fee_per_vbyte: feeinfo.fee_per_vbyte,
+        })
+    }
+
+    pub fn block_template(&self) -> &BlockTemplate {
+        &self.template
+    }
+
+    // The changes of the current template from the one with the given id, or None if it is not
+    // among the latest ones kept
+    pub fn block_template_diff(&self, base_id: u64) -> Option<BlockTemplateDiff> {
+        let base = if base_id == self.template.id {
+            &self.template
+        } else {
+            self.previous_templates
+                .iter()
+                .find(|template| template.id == base_id)?
+        };
+        let old_txids: HashSet<&Txid> = base.txids.iter().collect();
+        let new_txids: HashSet<&Txid> = self.template.txids.iter().collect();
+        Some(BlockTemplateDiff {
+            added: self
+                .template
+                .txids
+                .iter()
+                .filter(|txid| !old_txids.contains(txid))
+                .copied()
+                .collect(),
+            removed: base
+                .txids
+                .iter()
+                .filter(|txid| !new_txids.contains(txid))
+                .copied()
+                .collect(),
+        })
+    }
+
+    // Recompute the vsize ahead of each transaction in fee rate order, and the template of the
+    // next block. This takes O(|mempool|) time, but only once per update instead of per lookup.
+    fn update_projection(&mut self) {
+        let _timer = self
+            .latency
+            .with_label_values(&["update_projection"])
+            .start_timer();
+        let feeinfo = &self.feeinfo;
+        let mut total_vsize = 0;
+        self.vsize_ahead = self
+            .by_feerate
+            .iter()
+            .map(|key| {
+                let vsize_ahead = total_vsize;
+                total_vsize += feeinfo[&key.1].vsize;
+                (*key, vsize_ahead)
+            })
+            .collect();
+
+        let txids: Vec<Txid> = self
+            .vsize_ahead
+            .iter()
+            .take_while(|((_, txid), vsize_ahead)| {
+                vsize_ahead + feeinfo[txid].vsize <= BLOCK_TEMPLATE_VSIZE
+            })
+            .map(|((_, txid), _)| *txid)
+            .collect();
+        let old_txids: HashSet<&Txid> = self.template.txids.iter().collect();
+        let new_txids: HashSet<&Txid> = txids.iter().collect();
+        if old_txids != new_txids {
+            let id = self.template.id + 1;
+            let previous = std::mem::replace(&mut self.template, BlockTemplate { id, txids });
+            if self.previous_templates.len() == PREVIOUS_TEMPLATES_SIZE {
+                self.previous_templates.pop_front();
+            }
+            self.previous_templates.push_back(previous);
+        }
+    }
+
     pub fn update_backlog_stats(&mut self) {
         let _timer = self
             .latency
@@ -306,9 +525,19 @@ impl Mempool {
         // Phase 1: add to txstore
         for tx in txs {
             let txid = tx.txid();
+            let wtxid = full_hash(&tx.wtxid()[..]);
+            if wtxid[..] != txid[..] {
+                self.wtxids.insert(wtxid, txid);
+            }
             txids.push(txid);
             self.txstore.insert(txid, tx);
         }
+        if self.config.first_seen {
+            first_seen::record_txs(
+                self.chain.store().cache_db(),
+                txids.iter().map(|txid| &txid[..]),
+            );
+        }
         // Phase 2: index history and spend edges (can fail if some txos cannot be found)
         let txos = match self.lookup_txos(&self.get_prevouts(&txids)) {
             Ok(txos) => txos,
@@ -338,6 +567,8 @@ impl Mempool {
                     .sum(),
             });
 
+            self.by_feerate
+                .insert((Reverse(feerate_key(&feeinfo)), txid));
             self.feeinfo.insert(txid, feeinfo);
 
             // An iterator over (ScriptHash, TxHistoryInfo)
@@ -395,6 +626,8 @@ impl Mempool {
                 &mut self.asset_issuance,
             );
         }
+
+        self.update_projection();
     }
 
     pub fn lookup_txo(&self, outpoint: &OutPoint) -> Result<TxOut> {
@@ -453,14 +686,25 @@ impl Mempool {
         let _timer = self.latency.with_label_values(&["remove"]).start_timer();
 
         for txid in &to_remove {
-            self.txstore
+            let tx = self
+                .txstore
                 .remove(*txid)
                 .unwrap_or_else(|| panic!("missing mempool tx {}", txid));
+            self.wtxids.remove(&full_hash(&tx.wtxid()[..]));
+            for (vin, txin) in tx.input.iter().enumerate() {
+                if has_prevout(txin) {
+                    self.removed_spends
+                        .push_back((txin.previous_output, **txid, vin as u32));
+                }
+            }
 
-            self.feeinfo.remove(*txid).or_else(|| {
-                warn!("missing mempool tx feeinfo {}", txid);
-                None
-            });
+            match self.feeinfo.remove(*txid) {
+                Some(feeinfo) => {
+                    self.by_feerate
+                        .remove(&(Reverse(feerate_key(&feeinfo)), **txid));
+                }
+                None => warn!("missing mempool tx feeinfo {}", txid),
+            }
         }
 
         // TODO: make it more efficient (currently it takes O(|mempool|) time)
@@ -478,6 +722,14 @@ impl Mempool {
 
         self.edges
             .retain(|_outpoint, (txid, _vin)| !to_remove.contains(txid));
+
+        let excess = self
+            .removed_spends
+            .len()
+            .saturating_sub(REMOVED_SPENDS_SIZE);
+        self.removed_spends.drain(..excess);
+
+        self.update_projection();
     }
 
     #[cfg(feature = "liquid")]
@@ -492,7 +744,12 @@ impl Mempool {
     }
 
     pub fn update(mempool: &Arc<RwLock<Mempool>>, daemon: &Daemon) -> Result<()> {
-        let _timer = mempool.read().unwrap().latency.with_label_values(&["update"]).start_timer();
+        let _timer = mempool
+            .read()
+            .unwrap()
+            .latency
+            .with_label_values(&["update"])
+            .start_timer();
 
         // 1. Determine which transactions are no longer in the daemon's mempool and which ones have newly entered it
         let old_txids = mempool.read().unwrap().old_txids();
@@ -532,6 +789,11 @@ impl Mempool {
     }
 }
 
+// Fee rate in msat/vB, for ordering
+fn feerate_key(feeinfo: &TxFeeInfo) -> u64 {
+    feeinfo.fee * 1000 / feeinfo.vsize.max(1)
+}
+
 #[derive(Serialize)]
 pub struct BacklogStats {
     pub count: u32,
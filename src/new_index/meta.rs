@@ -0,0 +1,89 @@
+// Arbitrary metadata that external pipelines attach to scripthashes, txids or any other key (e.g.
+// exchange tags or known-script labels), grouped by namespace. It is set via the admin listener
+// and kept in its own db, separate from the index:
+//
+//      M{key}/{namespace} → {json-value}
+//
+// The REST responses of scripthashes and transactions include the metadata of their (lowercase
+// hex) scripthash or txid, when --metadata is enabled.
+
+use serde_json::{Map, Value};
+
+use crate::errors::*;
+use crate::new_index::db::{DBFlush, DBRow, DB};
+use crate::util::Bytes;
+
+pub const MAX_NAMESPACE_LEN: usize = 32;
+pub const MAX_KEY_LEN: usize = 128;
+pub const MAX_VALUE_SIZE: usize = 16 * 1024;
+
+fn meta_key(key: &str, namespace: &str) -> Bytes {
+    [b"M", key.as_bytes(), b"/", namespace.as_bytes()].concat()
+}
+
+fn meta_prefix(key: &str) -> Bytes {
+    [b"M", key.as_bytes(), b"/"].concat()
+}
+
+pub fn validate(namespace: &str, key: &str) -> Result<()> {
+    ensure!(
+        !namespace.is_empty()
+            && namespace.len() <= MAX_NAMESPACE_LEN
+            && namespace
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'),
+        "metadata namespace must be 1 to {} alphanumeric, '-' or '_' characters",
+        MAX_NAMESPACE_LEN
+    );
+    ensure!(
+        !key.is_empty() && key.len() <= MAX_KEY_LEN && !key.contains('/'),
+        "metadata key must be 1 to {} characters, without '/'",
+        MAX_KEY_LEN
+    );
+    Ok(())
+}
+
+// Add the value, or replace the existing one
+pub fn set(db: &DB, namespace: &str, key: &str, value: &Value) -> Result<()> {
+    validate(namespace, key)?;
+    let value = serde_json::to_vec(value).unwrap();
+    ensure!(
+        value.len() <= MAX_VALUE_SIZE,
+        "metadata value must be at most {} bytes",
+        MAX_VALUE_SIZE
+    );
+    db.write(
+        vec![DBRow {
+            key: meta_key(key, namespace),
+            value,
+        }],
+        DBFlush::Enable,
+    );
+    Ok(())
+}
+
+pub fn get(db: &DB, namespace: &str, key: &str) -> Option<Value> {
+    db.get(&meta_key(key, namespace))
+        .map(|value| serde_json::from_slice(&value).expect("failed to parse metadata"))
+}
+
+// Returns false if there is no such value
+pub fn delete(db: &DB, namespace: &str, key: &str) -> bool {
+    if db.get(&meta_key(key, namespace)).is_none() {
+        return false;
+    }
+    db.write_and_delete(vec![], vec![meta_key(key, namespace)], DBFlush::Enable);
+    true
+}
+
+// The metadata of the key in all namespaces, keyed by namespace
+pub fn get_all(db: &DB, key: &str) -> Map<String, Value> {
+    let prefix = meta_prefix(key);
+    db.iter_scan(&prefix)
+        .map(|row| {
+            let namespace = std::str::from_utf8(&row.key[prefix.len()..]).unwrap();
+            let value = serde_json::from_slice(&row.value).expect("failed to parse metadata");
+            (namespace.to_string(), value)
+        })
+        .collect()
+}
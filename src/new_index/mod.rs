@@ -1,15 +1,29 @@
+pub mod activity;
+mod cache_writer;
+pub mod cluster;
+#[cfg(not(feature = "liquid"))]
+pub mod coin_days;
 pub mod db;
 mod fetch;
+pub mod first_seen;
+pub mod labels;
 mod mempool;
+pub mod meta;
 pub mod precache;
 mod query;
+#[cfg(feature = "runes")]
+pub mod runes;
 pub mod schema;
+mod script_dict;
+#[cfg(not(feature = "liquid"))]
+mod utxo_hash;
 
 pub use self::db::{DBRow, DB};
 pub use self::fetch::{BlockEntry, FetchFrom};
 pub use self::mempool::Mempool;
 pub use self::query::Query;
 pub use self::schema::{
-    compute_script_hash, parse_hash, ChainQuery, FundingInfo, GetAmountVal, Indexer, ScriptStats,
-    SpendingInfo, SpendingInput, Store, TxHistoryInfo, TxHistoryKey, TxHistoryRow, Utxo,
+    compute_script_hash, compute_script_hashes, parse_hash, ChainQuery, DailyChainStats,
+    FundingInfo, GetAmountVal, HistoryOrder, Indexer, ScriptStats, SpendingInfo, SpendingInput,
+    Store, TxHistoryInfo, TxHistoryKey, TxHistoryRow, Utxo, VerifyReport,
 };
@@ -3,8 +3,7 @@ use crate::errors::*;
 use crate::new_index::ChainQuery;
 use crate::util::FullHash;
 
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
+use bitcoin::hashes::{sha256, Hash};
 use rayon::prelude::*;
 
 use hex::FromHex;
@@ -70,9 +69,5 @@ fn address_to_scripthash(addr: &str) -> Result<FullHash> {
 }
 
 pub fn compute_script_hash(data: &[u8]) -> FullHash {
-    let mut hash = FullHash::default();
-    let mut sha2 = Sha256::new();
-    sha2.input(data);
-    sha2.result(&mut hash);
-    hash
+    sha256::Hash::hash(data).to_byte_array()
 }
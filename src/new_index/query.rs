@@ -4,10 +4,12 @@ use std::collections::{BTreeSet, HashMap};
 use std::sync::{Arc, RwLock, RwLockReadGuard};
 use std::time::{Duration, Instant};
 
-use crate::chain::{Network, OutPoint, Transaction, TxOut, Txid};
+use crate::auth::ApiKeys;
+use crate::chain::{Network, OutPoint, Transaction, TxOut, Txid, Wtxid};
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
+use crate::events::WebhookRegistry;
 use crate::new_index::{ChainQuery, Mempool, ScriptStats, SpendingInput, Utxo};
 use crate::util::{is_spendable, BlockId, Bytes, TransactionStatus};
 
@@ -31,6 +33,9 @@ pub struct Query {
     config: Arc<Config>,
     cached_estimates: RwLock<(HashMap<u16, f64>, Option<Instant>)>,
     cached_relayfee: RwLock<Option<f64>>,
+    // swapped on SIGHUP, see reload_api_keys()
+    api_keys: RwLock<Option<Arc<ApiKeys>>>,
+    webhooks: Option<WebhookRegistry>,
     #[cfg(feature = "liquid")]
     asset_db: Option<Arc<RwLock<AssetRegistry>>>,
 }
@@ -42,7 +47,11 @@ impl Query {
         mempool: Arc<RwLock<Mempool>>,
         daemon: Arc<Daemon>,
         config: Arc<Config>,
+        api_keys: Option<ApiKeys>,
     ) -> Self {
+        let webhooks = config
+            .webhooks
+            .then(|| WebhookRegistry::load(chain.store().cache_db()));
         Query {
             chain,
             mempool,
@@ -50,6 +59,8 @@ impl Query {
             config,
             cached_estimates: RwLock::new((HashMap::new(), None)),
             cached_relayfee: RwLock::new(None),
+            api_keys: RwLock::new(api_keys.map(Arc::new)),
+            webhooks,
         }
     }
 
@@ -61,6 +72,28 @@ impl Query {
         &self.config
     }
 
+    // Returns None when authentication is disabled
+    pub fn api_keys(&self) -> Option<Arc<ApiKeys>> {
+        self.api_keys.read().unwrap().clone()
+    }
+
+    // Re-reads the API keys from `--auth-keys-file` and the environment. A malformed keys file
+    // keeps the current keys in place.
+    pub fn reload_api_keys(&self) -> Result<()> {
+        let api_keys = ApiKeys::from_config(&self.config)?;
+        let mut current = self.api_keys.write().unwrap();
+        if let (Some(api_keys), Some(previous)) = (&api_keys, current.as_ref()) {
+            api_keys.inherit_usage(previous);
+        }
+        *current = api_keys.map(Arc::new);
+        Ok(())
+    }
+
+    // Returns None unless --webhooks is enabled
+    pub fn webhooks(&self) -> Option<&WebhookRegistry> {
+        self.webhooks.as_ref()
+    }
+
     pub fn network(&self) -> Network {
         self.config.network_type
     }
@@ -69,6 +102,21 @@ impl Query {
         self.mempool.read().unwrap()
     }
 
+    // Verify that the database can be read from, for health checks
+    pub fn check_db(&self) -> Result<()> {
+        self.chain.store().txstore_db().try_get(b"t")?;
+        Ok(())
+    }
+
+    pub fn daemon_height(&self) -> Result<usize> {
+        self.daemon.try_getblockcount()
+    }
+
+    // None unless the daemon is a pruned node
+    pub fn daemon_prune_height(&self) -> Option<usize> {
+        self.daemon.prune_height()
+    }
+
     pub fn broadcast_raw(&self, txhex: &str) -> Result<Txid> {
         let txid = self.daemon.broadcast_raw(txhex)?;
         self.mempool
@@ -79,7 +127,9 @@ impl Query {
     }
 
     pub fn utxo(&self, scripthash: &[u8]) -> Result<Vec<Utxo>> {
-        let mut utxos = self.chain.utxo(scripthash, self.config.utxos_limit)?;
+        let mut utxos = self
+            .chain
+            .utxo(scripthash, self.config.runtime().utxos_limit)?;
         let mempool = self.mempool();
         utxos.retain(|utxo| !mempool.has_spend(&OutPoint::from(utxo)));
         utxos.extend(mempool.utxo(scripthash));
@@ -100,6 +150,48 @@ impl Query {
         confirmed_txids.chain(mempool_txids).collect()
     }
 
+    // Like history_txids() for many scripthashes, see ChainQuery::history_txids_batch()
+    pub fn history_txids_batch(
+        &self,
+        scripthashes: &[&[u8]],
+        limit: usize,
+    ) -> Vec<Vec<(Txid, Option<BlockId>)>> {
+        let confirmed = self.chain.history_txids_batch(scripthashes, limit);
+        let mempool = self.mempool();
+        confirmed
+            .into_iter()
+            .zip(scripthashes)
+            .map(|(confirmed_txids, scripthash)| {
+                let mempool_txids =
+                    mempool.history_txids(scripthash, limit - confirmed_txids.len());
+                confirmed_txids
+                    .into_iter()
+                    .map(|(txid, b)| (txid, Some(b)))
+                    .chain(mempool_txids.into_iter().map(|txid| (txid, None)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn history_txids_with_delta(
+        &self,
+        scripthash: &[u8],
+        limit: usize,
+    ) -> Vec<(Txid, Option<BlockId>, i64)> {
+        let confirmed = self.chain.history_txids_with_delta(scripthash, limit);
+        let confirmed_len = confirmed.len();
+        let confirmed = confirmed.into_iter().map(|(tx, b, d)| (tx, Some(b), d));
+
+        let mempool = self
+            .mempool()
+            .history_txids_with_delta(scripthash, limit - confirmed_len)
+            .into_iter()
+            .map(|(tx, d)| (tx, None, d));
+
+        confirmed.chain(mempool).collect()
+    }
+
     pub fn stats(&self, scripthash: &[u8]) -> (ScriptStats, ScriptStats) {
         (
             self.chain.stats(scripthash),
@@ -107,6 +199,13 @@ impl Query {
         )
     }
 
+    // None if address reuse isn't indexed
+    pub fn funding_tx_count(&self, scripthash: &[u8]) -> Option<usize> {
+        self.chain
+            .funding_tx_count(scripthash)
+            .map(|count| count + self.mempool().funding_tx_count(scripthash))
+    }
+
     pub fn lookup_txn(&self, txid: &Txid) -> Option<Transaction> {
         self.chain
             .lookup_txn(txid, None)
@@ -118,6 +217,12 @@ impl Query {
             .or_else(|| self.mempool().lookup_raw_txn(txid))
     }
 
+    pub fn lookup_txid_by_wtxid(&self, wtxid: &Wtxid) -> Option<Txid> {
+        self.chain
+            .lookup_txid_by_wtxid(wtxid)
+            .or_else(|| self.mempool().lookup_txid_by_wtxid(wtxid))
+    }
+
     pub fn lookup_txos(&self, outpoints: &BTreeSet<OutPoint>) -> HashMap<OutPoint, TxOut> {
         // the mempool lookup_txos() internally looks up confirmed txos as well
         self.mempool()
@@ -131,6 +236,21 @@ impl Query {
             .or_else(|| self.mempool().lookup_spend(outpoint))
     }
 
+    // Other spends of the outpoint than its current one, by transactions that were seen in the
+    // mempool but were not confirmed (e.g. replaced by RBF or double-spent)
+    pub fn lookup_conflicting_spends(
+        &self,
+        outpoint: &OutPoint,
+        spend: Option<&SpendingInput>,
+    ) -> Vec<(Txid, u32)> {
+        let removed_spends = self.mempool().removed_spends(outpoint);
+        removed_spends
+            .into_iter()
+            .filter(|(txid, _)| spend.map_or(true, |spend| spend.txid != *txid))
+            .filter(|(txid, _)| self.chain.tx_confirming_block(txid).is_none())
+            .collect()
+    }
+
     pub fn lookup_tx_spends(&self, tx: Transaction) -> Vec<Option<SpendingInput>> {
         let txid = tx.txid();
 
@@ -219,8 +339,12 @@ impl Query {
         mempool: Arc<RwLock<Mempool>>,
         daemon: Arc<Daemon>,
         config: Arc<Config>,
+        api_keys: Option<ApiKeys>,
         asset_db: Option<Arc<RwLock<AssetRegistry>>>,
     ) -> Self {
+        let webhooks = config
+            .webhooks
+            .then(|| WebhookRegistry::load(chain.store().cache_db()));
         Query {
             chain,
             mempool,
@@ -229,6 +353,8 @@ impl Query {
             asset_db,
             cached_estimates: RwLock::new((HashMap::new(), None)),
             cached_relayfee: RwLock::new(None),
+            api_keys: RwLock::new(api_keys.map(Arc::new)),
+            webhooks,
         }
     }
 
@@ -0,0 +1,516 @@
+// Recognition of Runes protocol messages ("runestones"), which are carried in an OP_RETURN
+// output starting with OP_13, followed by data pushes encoding a sequence of LEB128 integers.
+//
+// This only deciphers the messages and tracks which transactions reference each rune. It does
+// not track rune balances, which would require replaying the protocol's allocation rules.
+//
+//      U{rune-block}{rune-tx}{height}{txid} → ""
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::blockdata::opcodes::all::{OP_PUSHNUM_13, OP_RETURN};
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::hashes::Hash;
+use serde::{Serialize, Serializer};
+
+use crate::chain::{Transaction, Txid};
+use crate::new_index::db::{DBRow, DBSnapshot};
+use crate::new_index::fetch::BlockEntry;
+use crate::util::{bincode, full_hash, FullHash};
+
+const TAG_BODY: u128 = 0;
+const TAG_DIVISIBILITY: u128 = 1;
+const TAG_FLAGS: u128 = 2;
+const TAG_SPACERS: u128 = 3;
+const TAG_RUNE: u128 = 4;
+const TAG_SYMBOL: u128 = 5;
+const TAG_PREMINE: u128 = 6;
+const TAG_CAP: u128 = 8;
+const TAG_AMOUNT: u128 = 10;
+const TAG_HEIGHT_START: u128 = 12;
+const TAG_HEIGHT_END: u128 = 14;
+const TAG_OFFSET_START: u128 = 16;
+const TAG_OFFSET_END: u128 = 18;
+const TAG_MINT: u128 = 20;
+const TAG_POINTER: u128 = 22;
+
+const FLAG_ETCHING: u128 = 1 << 0;
+const FLAG_TERMS: u128 = 1 << 1;
+const FLAG_TURBO: u128 = 1 << 2;
+
+const MAX_DIVISIBILITY: u8 = 38;
+const MAX_SPACERS: u32 = 0b0000_0111_1111_1111_1111_1111_1111_1111;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RuneId {
+    pub block: u64,
+    pub tx: u32,
+}
+
+impl RuneId {
+    // Edict ids are delta-encoded relative to the previous edict's id
+    fn next(self, block_delta: u128, tx_delta: u128) -> Option<RuneId> {
+        let block = self.block.checked_add(u64::try_from(block_delta).ok()?)?;
+        let tx = if block_delta == 0 {
+            self.tx.checked_add(u32::try_from(tx_delta).ok()?)?
+        } else {
+            u32::try_from(tx_delta).ok()?
+        };
+        Some(RuneId { block, tx })
+    }
+}
+
+impl fmt::Display for RuneId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.block, self.tx)
+    }
+}
+
+impl FromStr for RuneId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (block, tx) = s.split_once(':').ok_or("invalid rune id")?;
+        Ok(RuneId {
+            block: block.parse().map_err(|_| "invalid rune id block")?,
+            tx: tx.parse().map_err(|_| "invalid rune id tx")?,
+        })
+    }
+}
+
+impl Serialize for RuneId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Amounts are u128 and are serialized as strings, since JSON numbers can't represent them
+fn serialize_amount<S: Serializer>(amount: &u128, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(amount)
+}
+
+fn serialize_opt_amount<S: Serializer>(
+    amount: &Option<u128>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => serializer.collect_str(amount),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Edict {
+    pub id: RuneId,
+    #[serde(serialize_with = "serialize_amount")]
+    pub amount: u128,
+    pub output: u32,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Terms {
+    #[serde(serialize_with = "serialize_opt_amount")]
+    pub amount: Option<u128>,
+    #[serde(serialize_with = "serialize_opt_amount")]
+    pub cap: Option<u128>,
+    pub height: (Option<u64>, Option<u64>),
+    pub offset: (Option<u64>, Option<u64>),
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Etching {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rune: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub divisibility: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<char>,
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_opt_amount"
+    )]
+    pub premine: Option<u128>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub terms: Option<Terms>,
+    pub turbo: bool,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct Runestone {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub edicts: Vec<Edict>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub etching: Option<Etching>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mint: Option<RuneId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pointer: Option<u32>,
+    // A malformed runestone. Cenotaphs burn the runes of the transaction's inputs, and only
+    // their etched rune name and mint are retained.
+    pub cenotaph: bool,
+}
+
+impl Runestone {
+    fn cenotaph() -> Self {
+        Runestone {
+            cenotaph: true,
+            ..Default::default()
+        }
+    }
+
+    // The runes referenced by this runestone, given the id a rune etched by it would get
+    fn rune_ids(&self, etched_id: RuneId) -> HashSet<RuneId> {
+        let mut ids: HashSet<RuneId> = self
+            .edicts
+            .iter()
+            .map(|edict| match edict.id {
+                // an id of 0:0 refers to the rune etched by the transaction itself
+                RuneId { block: 0, tx: 0 } => etched_id,
+                id => id,
+            })
+            .chain(self.mint)
+            .collect();
+        if self.etching.is_some() {
+            ids.insert(etched_id);
+        }
+        ids
+    }
+}
+
+/// Decipher the runestone carried by the transaction, if any
+pub fn decipher(tx: &Transaction) -> Option<Runestone> {
+    let payload = match payload(tx)? {
+        Some(payload) => payload,
+        None => return Some(Runestone::cenotaph()),
+    };
+    let integers = match integers(&payload) {
+        Some(integers) => integers,
+        None => return Some(Runestone::cenotaph()),
+    };
+    Some(parse_message(tx, &integers))
+}
+
+// Returns None if the tx has no runestone output, or Some(None) if its payload is malformed
+fn payload(tx: &Transaction) -> Option<Option<Vec<u8>>> {
+    for txout in &tx.output {
+        let mut instructions = txout.script_pubkey.instructions();
+        if instructions.next() != Some(Ok(Instruction::Op(OP_RETURN)))
+            || instructions.next() != Some(Ok(Instruction::Op(OP_PUSHNUM_13)))
+        {
+            continue;
+        }
+        let mut payload = vec![];
+        for instruction in instructions {
+            match instruction {
+                Ok(Instruction::PushBytes(push)) => payload.extend_from_slice(push.as_bytes()),
+                // opcodes and invalid pushes make the runestone a cenotaph
+                Ok(Instruction::Op(_)) | Err(_) => return Some(None),
+            }
+        }
+        return Some(Some(payload));
+    }
+    None
+}
+
+fn integers(payload: &[u8]) -> Option<Vec<u128>> {
+    let mut integers = vec![];
+    let mut i = 0;
+    while i < payload.len() {
+        let (integer, length) = decode_varint(&payload[i..])?;
+        integers.push(integer);
+        i += length;
+    }
+    Some(integers)
+}
+
+// Decode a LEB128 integer, returning it along with its encoded length. Overlong, overflowing
+// and unterminated encodings are rejected.
+fn decode_varint(buf: &[u8]) -> Option<(u128, usize)> {
+    let mut n: u128 = 0;
+    for (i, &byte) in buf.iter().enumerate() {
+        if i > 18 {
+            return None;
+        }
+        let value = u128::from(byte & 0x7f);
+        if i == 18 && value & 0x7c != 0 {
+            return None;
+        }
+        n |= value << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((n, i + 1));
+        }
+    }
+    None
+}
+
+fn parse_message(tx: &Transaction, integers: &[u128]) -> Runestone {
+    let mut fields: HashMap<u128, VecDeque<u128>> = HashMap::new();
+    let mut edicts = vec![];
+    let mut flaw = false;
+
+    let mut i = 0;
+    while i < integers.len() {
+        let tag = integers[i];
+        if tag == TAG_BODY {
+            let mut id = RuneId::default();
+            for chunk in integers[i + 1..].chunks(4) {
+                if chunk.len() != 4 {
+                    flaw = true;
+                    break;
+                }
+                let next = match id.next(chunk[0], chunk[1]) {
+                    Some(next) => next,
+                    None => {
+                        flaw = true;
+                        break;
+                    }
+                };
+                // an output equal to the number of outputs splits the amount between all of them
+                match u32::try_from(chunk[3]) {
+                    Ok(output) if output as usize <= tx.output.len() => edicts.push(Edict {
+                        id: next,
+                        amount: chunk[2],
+                        output,
+                    }),
+                    _ => {
+                        flaw = true;
+                        break;
+                    }
+                }
+                id = next;
+            }
+            break;
+        }
+        match integers.get(i + 1) {
+            Some(value) => fields.entry(tag).or_default().push_back(*value),
+            None => {
+                flaw = true;
+                break;
+            }
+        }
+        i += 2;
+    }
+
+    let mut take = |tag: u128| -> Option<u128> {
+        let values = fields.get_mut(&tag)?;
+        let value = values.pop_front();
+        if values.is_empty() {
+            fields.remove(&tag);
+        }
+        value
+    };
+
+    let mut flags = take(TAG_FLAGS).unwrap_or(0);
+    let etching = if flags & FLAG_ETCHING != 0 {
+        let spacers = take(TAG_SPACERS)
+            .and_then(|spacers| u32::try_from(spacers).ok())
+            .filter(|spacers| *spacers <= MAX_SPACERS);
+        Some(Etching {
+            divisibility: take(TAG_DIVISIBILITY)
+                .and_then(|d| u8::try_from(d).ok())
+                .filter(|d| *d <= MAX_DIVISIBILITY),
+            premine: take(TAG_PREMINE),
+            rune: take(TAG_RUNE).map(|rune| rune_name(rune, spacers.unwrap_or(0))),
+            symbol: take(TAG_SYMBOL)
+                .and_then(|symbol| u32::try_from(symbol).ok())
+                .and_then(char::from_u32),
+            terms: if flags & FLAG_TERMS != 0 {
+                Some(Terms {
+                    cap: take(TAG_CAP),
+                    amount: take(TAG_AMOUNT),
+                    height: (
+                        take(TAG_HEIGHT_START).and_then(|h| u64::try_from(h).ok()),
+                        take(TAG_HEIGHT_END).and_then(|h| u64::try_from(h).ok()),
+                    ),
+                    offset: (
+                        take(TAG_OFFSET_START).and_then(|o| u64::try_from(o).ok()),
+                        take(TAG_OFFSET_END).and_then(|o| u64::try_from(o).ok()),
+                    ),
+                })
+            } else {
+                None
+            },
+            turbo: flags & FLAG_TURBO != 0,
+        })
+    } else {
+        None
+    };
+    flags &= !(FLAG_ETCHING | FLAG_TERMS | FLAG_TURBO);
+
+    let mint = match (take(TAG_MINT), take(TAG_MINT)) {
+        (Some(block), Some(tx)) => Some(RuneId {
+            block: u64::try_from(block).unwrap_or(u64::MAX),
+            tx: u32::try_from(tx).unwrap_or(u32::MAX),
+        }),
+        _ => None,
+    };
+    let pointer = take(TAG_POINTER)
+        .and_then(|pointer| u32::try_from(pointer).ok())
+        .filter(|pointer| (*pointer as usize) < tx.output.len());
+
+    // unknown flags and even tags are reserved for future protocol changes that a reader
+    // unaware of them could misinterpret
+    let supply_overflows = etching.as_ref().map_or(false, |etching| {
+        let terms = etching.terms.as_ref();
+        let minted = terms
+            .map(|t| t.cap.unwrap_or(0).checked_mul(t.amount.unwrap_or(0)))
+            .unwrap_or(Some(0));
+        minted
+            .and_then(|minted| minted.checked_add(etching.premine.unwrap_or(0)))
+            .is_none()
+    });
+    if flaw || flags != 0 || fields.keys().any(|tag| tag % 2 == 0) || supply_overflows {
+        return Runestone {
+            etching: etching.map(|etching| Etching {
+                rune: etching.rune,
+                ..Default::default()
+            }),
+            mint,
+            ..Runestone::cenotaph()
+        };
+    }
+
+    Runestone {
+        edicts,
+        etching,
+        mint,
+        pointer,
+        cenotaph: false,
+    }
+}
+
+// Rune names are encoded as modified base-26 integers, with spacers marking the positions
+// after which a bullet is displayed
+fn rune_name(mut n: u128, spacers: u32) -> String {
+    let mut letters = vec![];
+    if n == u128::MAX {
+        letters = "BCGDENLQRQWDSLRUGSNLBTMFIJAV".chars().collect();
+    } else {
+        n += 1;
+        while n > 0 {
+            letters.push((b'A' + ((n - 1) % 26) as u8) as char);
+            n = (n - 1) / 26;
+        }
+        letters.reverse();
+    }
+
+    let mut name = String::new();
+    for (i, letter) in letters.iter().enumerate() {
+        name.push(*letter);
+        if i < letters.len() - 1 && spacers & (1 << i) != 0 {
+            name.push('•');
+        }
+    }
+    name
+}
+
+#[derive(Serialize, Deserialize)]
+struct RuneHistoryKey {
+    code: u8,
+    block: u64,
+    tx: u32,
+    height: u32,
+    txid: FullHash,
+}
+
+fn history_prefix(id: &RuneId) -> Vec<u8> {
+    bincode::serialize_big(&(b'U', id.block, id.tx)).unwrap()
+}
+
+fn history_prefix_end(id: &RuneId) -> Vec<u8> {
+    bincode::serialize_big(&(b'U', id.block, id.tx, u32::MAX)).unwrap()
+}
+
+// Index the transactions referencing each rune in these blocks
+pub fn index_block_runes(block_entry: &BlockEntry, rows: &mut Vec<DBRow>) {
+    let height = block_entry.entry.height() as u32;
+    for (tx_index, tx) in block_entry.block.txdata.iter().enumerate() {
+        let runestone = match decipher(tx) {
+            Some(runestone) => runestone,
+            None => continue,
+        };
+        let etched_id = RuneId {
+            block: height as u64,
+            tx: tx_index as u32,
+        };
+        let txid = full_hash(&tx.txid()[..]);
+        for id in runestone.rune_ids(etched_id) {
+            let key = RuneHistoryKey {
+                code: b'U',
+                block: id.block,
+                tx: id.tx,
+                height,
+                txid,
+            };
+            rows.push(DBRow {
+                key: bincode::serialize_big(&key).unwrap(),
+                value: vec![],
+            });
+        }
+    }
+}
+
+// The txids of the transactions referencing the rune, most recent first
+pub fn history_txids<'a>(db: &'a DBSnapshot, id: &RuneId) -> impl Iterator<Item = Txid> + 'a {
+    db.iter_scan_reverse(&history_prefix(id), &history_prefix_end(id))
+        .map(|row| {
+            let key: RuneHistoryKey =
+                bincode::deserialize_big(&row.key).expect("failed to parse RuneHistoryKey");
+            Txid::from_byte_array(key.txid)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_varint() {
+        assert_eq!(decode_varint(&[0x00]), Some((0, 1)));
+        assert_eq!(decode_varint(&[0x7f]), Some((127, 1)));
+        assert_eq!(decode_varint(&[0x80, 0x01]), Some((128, 2)));
+        // unterminated
+        assert_eq!(decode_varint(&[0x80]), None);
+        // overlong
+        assert_eq!(decode_varint(&[0x80; 19]), None);
+    }
+
+    #[test]
+    fn test_rune_name() {
+        assert_eq!(rune_name(0, 0), "A");
+        assert_eq!(rune_name(25, 0), "Z");
+        assert_eq!(rune_name(26, 0), "AA");
+        assert_eq!(rune_name(26, 0b1), "A•A");
+    }
+
+    #[test]
+    fn test_rune_id() {
+        let id = RuneId::from_str("840000:3").unwrap();
+        assert_eq!(
+            id,
+            RuneId {
+                block: 840000,
+                tx: 3
+            }
+        );
+        assert_eq!(id.to_string(), "840000:3");
+        assert_eq!(
+            id.next(0, 2),
+            Some(RuneId {
+                block: 840000,
+                tx: 5
+            })
+        );
+        assert_eq!(
+            id.next(1, 2),
+            Some(RuneId {
+                block: 840001,
+                tx: 2
+            })
+        );
+        assert!(RuneId::from_str("840000").is_err());
+    }
+}
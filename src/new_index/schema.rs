@@ -1,15 +1,14 @@
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::hashes::{sha256, Hash};
 #[cfg(not(feature = "liquid"))]
 use bitcoin::merkle_tree::MerkleBlock;
 use bitcoin::VarInt;
-use crypto::digest::Digest;
-use crypto::sha2::Sha256;
 use hex::FromHex;
 use itertools::Itertools;
 use rayon::prelude::*;
 
 #[cfg(not(feature = "liquid"))]
-use bitcoin::consensus::encode::{deserialize, serialize};
+use bitcoin::consensus::encode::{deserialize, deserialize_partial, serialize};
 #[cfg(feature = "liquid")]
 use elements::{
     confidential,
@@ -17,61 +16,115 @@ use elements::{
     AssetId,
 };
 
-use std::collections::{BTreeSet, HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryInto;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Instant;
 
+#[cfg(not(feature = "liquid"))]
+use crate::chain::Block;
 use crate::chain::{
-    BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxOut, Txid, Value,
+    BlockHash, BlockHeader, Network, OutPoint, Script, Transaction, TxOut, Txid, Value, Wtxid,
 };
 use crate::config::Config;
 use crate::daemon::Daemon;
 use crate::errors::*;
-use crate::metrics::{Gauge, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics};
+use crate::metrics::{
+    Gauge, GaugeVec, HistogramOpts, HistogramTimer, HistogramVec, MetricOpts, Metrics,
+};
+use crate::util::electrum_merkle::MerkleTree;
 use crate::util::{
-    bincode, full_hash, has_prevout, is_spendable, BlockHeaderMeta, BlockId, BlockMeta,
-    BlockStatus, Bytes, HeaderEntry, HeaderList, ScriptToAddr,
+    bincode, cost, full_hash, has_prevout, is_spendable, BlockCounters, BlockHeaderMeta, BlockId,
+    BlockMeta, BlockStatus, Bytes, HeaderEntry, HeaderList, ScriptToAddr, ScriptTypeCounts,
+    SyncChannel, TxStats, DEFAULT_BLOCKHASH,
 };
+#[cfg(not(feature = "liquid"))]
+use crate::util::{BlockReward, DifficultyEpoch};
 
-use crate::new_index::db::{DBFlush, DBRow, ReverseScanIterator, ScanIterator, DB};
+use crate::new_index::activity::{self, ActivityMap, Granularity};
+use crate::new_index::cache_writer::CacheWriter;
+use crate::new_index::cluster::{self, Cluster, ClusterMember};
+use crate::new_index::db::{DBFlush, DBRow, DBSnapshot, DBStats, ScanIterator, DB};
 use crate::new_index::fetch::{start_fetcher, BlockEntry, FetchFrom};
+use crate::new_index::first_seen;
+#[cfg(feature = "runes")]
+use crate::new_index::runes::{self, RuneId};
+use crate::new_index::script_dict;
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::{coin_days, utxo_hash};
 
 #[cfg(feature = "liquid")]
 use crate::elements::{asset, peg};
 
 const MIN_HISTORY_ITEMS_TO_CACHE: usize = 100;
+// Number of headers per row of the persisted header chain
+const HEADERS_CHUNK_SIZE: usize = 2016;
+// Interval between the cached stats checkpoints used for point-in-time queries
+const STATS_CHECKPOINT_INTERVAL: usize = 1000;
+// Checkpoints closer than this to the tip are not used to resume from after a re-org
+const CHECKPOINT_MIN_DEPTH: usize = 10;
+const MISSING_TXIDS_CACHE_SIZE: usize = 10_000;
+// Number of recently requested blocks whose merkle tree is kept, for the electrum merkle proofs
+const MERKLE_TREE_CACHE_SIZE: usize = 64;
+// Below this, hashing scripts in parallel costs more than it saves
+const SCRIPT_HASH_BATCH_SIZE: usize = 4096;
+// The total serialized size of the fetched blocks that can be kept waiting for the blocks below
+// them to be added, before being deferred to a second indexing pass (they take about twice as
+// much memory once deserialized)
+const MAX_WAITING_BYTES: usize = 512 << 20;
+const BECH32_CHARSET: &str = "qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+// Flags the history db as built with --history-bloom
+const HISTORY_BLOOM_KEY: &[u8] = b"E";
+// The cache db rows that are derived from the index (unlike the webhooks and labels)
+#[cfg(not(feature = "liquid"))]
+const CACHE_PREFIXES: &[&[u8]] = &[b"A", b"U", b"o", b"Y", b"P", b"q"];
+#[cfg(feature = "liquid")]
+const CACHE_PREFIXES: &[&[u8]] = &[b"A", b"U", b"o", b"Y", b"P", b"q", b"z"];
 
 pub struct Store {
     // TODO: should be column families
     txstore_db: DB,
     history_db: DB,
     cache_db: DB,
-    added_blockhashes: RwLock<HashSet<BlockHash>>,
-    indexed_blockhashes: RwLock<HashSet<BlockHash>>,
+    meta_db: DB,
     indexed_headers: RwLock<HeaderList>,
 }
 
 impl Store {
     pub fn open(path: &Path, config: &Config) -> Self {
         let txstore_db = DB::open(&path.join("txstore"), config);
-        let added_blockhashes = load_blockhashes(&txstore_db, &BlockRow::done_filter());
-        debug!("{} blocks were added", added_blockhashes.len());
-
         let history_db = DB::open(&path.join("history"), config);
-        let indexed_blockhashes = load_blockhashes(&history_db, &BlockRow::done_filter());
-        debug!("{} blocks were indexed", indexed_blockhashes.len());
-
+        verify_history_bloom(&history_db, config.history_bloom);
         let cache_db = DB::open(&path.join("cache"), config);
+        let meta_db = DB::open(&path.join("meta"), config);
+        // unlike the index dbs, it is never bulk loaded
+        meta_db.enable_auto_compaction();
 
         let headers = if let Some(tip_hash) = txstore_db.get(b"t") {
             let tip_hash = deserialize(&tip_hash).expect("invalid chain tip in `t`");
-            let headers_map = load_blockheaders(&txstore_db);
-            debug!(
-                "{} headers were loaded, tip at {:?}",
-                headers_map.len(),
-                tip_hash
-            );
-            HeaderList::new(headers_map, tip_hash)
+            match load_headers_chain(&txstore_db).filter(|headers| *headers.tip() == tip_hash) {
+                Some(headers) => {
+                    debug!(
+                        "{} headers were restored, tip at {:?}",
+                        headers.len(),
+                        tip_hash
+                    );
+                    headers
+                }
+                None => {
+                    let headers_map = load_blockheaders(&txstore_db);
+                    debug!(
+                        "{} headers were loaded, tip at {:?}",
+                        headers_map.len(),
+                        tip_hash
+                    );
+                    let headers = HeaderList::new(headers_map, tip_hash);
+                    save_headers_chain(&txstore_db, &headers, 0);
+                    headers
+                }
+            }
         } else {
             HeaderList::empty()
         };
@@ -80,8 +133,7 @@ impl Store {
             txstore_db,
             history_db,
             cache_db,
-            added_blockhashes: RwLock::new(added_blockhashes),
-            indexed_blockhashes: RwLock::new(indexed_blockhashes),
+            meta_db,
             indexed_headers: RwLock::new(headers),
         }
     }
@@ -98,9 +150,190 @@ impl Store {
         &self.cache_db
     }
 
+    pub fn meta_db(&self) -> &DB {
+        &self.meta_db
+    }
+
+    pub fn db_stats(&self) -> Vec<(&'static str, DBStats)> {
+        vec![
+            ("txstore", self.txstore_db.stats()),
+            ("history", self.history_db.stats()),
+            ("cache", self.cache_db.stats()),
+            ("meta", self.meta_db.stats()),
+        ]
+    }
+
+    // Drop the cached aggregates of all scripthashes, to be re-computed on demand. Returns the
+    // number of deleted rows.
+    pub fn clear_caches(&self) -> usize {
+        let keys: Vec<Bytes> = CACHE_PREFIXES
+            .iter()
+            .flat_map(|prefix| self.cache_db.iter_scan(prefix).map(|row| row.key))
+            .collect();
+        let count = keys.len();
+        self.cache_db
+            .write_and_delete(vec![], keys, DBFlush::Enable);
+        count
+    }
+
     pub fn done_initial_sync(&self) -> bool {
         self.txstore_db.get(b"t").is_some()
     }
+
+    pub fn snapshot(&self) -> StoreSnapshot {
+        // the txs are added before being indexed, so the history snapshot is taken first for
+        // every tx it references to be in the txstore snapshot
+        let history_db = self.history_db.snapshot();
+        StoreSnapshot {
+            txstore_db: self.txstore_db.snapshot(),
+            history_db,
+        }
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct VerifyReport {
+    pub checked_blocks: usize,
+    pub checked_history_rows: usize,
+    pub checked_edge_rows: usize,
+    // blocks marked as added/indexed with some of their rows missing
+    pub broken_blocks: Vec<BlockHash>,
+    // history and edge rows referencing transactions missing from the txstore
+    pub dangling_history_rows: usize,
+    pub dangling_edge_rows: usize,
+    pub repaired: bool,
+}
+
+impl VerifyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.broken_blocks.is_empty()
+            && self.dangling_history_rows == 0
+            && self.dangling_edge_rows == 0
+    }
+}
+
+impl Store {
+    // Cross-check the txstore and history DBs against each other. When `repair` is set, dangling
+    // history/edge rows are deleted, and the broken blocks have their done markers removed and the
+    // synced tip moved back below them, so that they get added and indexed again the next time
+    // the indexer runs.
+    pub fn verify(&self, light_mode: bool, repair: bool) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        let mut txstore_deletes: Vec<Bytes> = vec![];
+        let mut history_deletes: Vec<Bytes> = vec![];
+
+        // every added block must have its header, and unless running in light mode,
+        // its txids and metadata
+        let added_blockhashes = load_blockhashes(&self.txstore_db, &BlockRow::done_filter());
+        for blockhash in &added_blockhashes {
+            let hash = full_hash(&blockhash[..]);
+            report.checked_blocks += 1;
+            let complete = self.txstore_db.get(&BlockRow::header_key(hash)).is_some()
+                && (light_mode
+                    || (self.txstore_db.get(&BlockRow::txids_key(hash)).is_some()
+                        && self.txstore_db.get(&BlockRow::meta_key(hash)).is_some()));
+            if !complete {
+                warn!(
+                    "block {} is marked as added but has missing rows",
+                    blockhash
+                );
+                report.broken_blocks.push(*blockhash);
+                txstore_deletes.push(BlockRow::done_key(hash));
+                history_deletes.push(BlockRow::done_key(hash));
+            }
+        }
+
+        // every indexed block must have been added first
+        for blockhash in load_blockhashes(&self.history_db, &BlockRow::done_filter()) {
+            if !added_blockhashes.contains(&blockhash) {
+                warn!(
+                    "block {} is marked as indexed but was never added",
+                    blockhash
+                );
+                report.broken_blocks.push(blockhash);
+                history_deletes.push(BlockRow::done_key(full_hash(&blockhash[..])));
+            }
+        }
+
+        let tx_exists = |txid: &[u8]| {
+            self.txstore_db
+                .iter_scan(&TxConfRow::filter(txid))
+                .next()
+                .is_some()
+        };
+
+        for row in self.history_db.iter_scan(b"H") {
+            report.checked_history_rows += 1;
+            let key = row.key.clone();
+            let txid = TxHistoryRow::from_row(row).get_txid();
+            if !tx_exists(&txid[..]) {
+                debug!("history row references missing tx {}", txid);
+                report.dangling_history_rows += 1;
+                history_deletes.push(key);
+            }
+        }
+
+        for row in self.history_db.iter_scan(b"S") {
+            report.checked_edge_rows += 1;
+            let key = row.key.clone();
+            let edge = TxEdgeRow::from_row(row).key;
+            if !tx_exists(&edge.funding_txid) || !tx_exists(&edge.spending_txid) {
+                debug!(
+                    "edge row references missing tx {} or {}",
+                    parse_hash(&edge.funding_txid),
+                    parse_hash(&edge.spending_txid)
+                );
+                report.dangling_edge_rows += 1;
+                history_deletes.push(key);
+            }
+        }
+
+        if repair && !report.is_consistent() {
+            history_deletes.sort();
+            history_deletes.dedup();
+            self.txstore_db
+                .write_and_delete(vec![], txstore_deletes, DBFlush::Enable);
+            self.history_db
+                .write_and_delete(vec![], history_deletes, DBFlush::Enable);
+            // only the blocks above the synced tip are looked at when updating, so the tip is
+            // moved back below the lowest broken block (on disk and for the running indexer) and
+            // the phase cursors are reset
+            let mut headers = self.indexed_headers.write().unwrap();
+            let lowest_broken = report
+                .broken_blocks
+                .iter()
+                .filter_map(|blockhash| headers.header_by_blockhash(blockhash))
+                .map(HeaderEntry::height)
+                .min();
+            if let Some(height) = lowest_broken {
+                let chain = headers
+                    .iter()
+                    .take(height)
+                    .map(|entry| (*entry.hash(), entry.header().clone()))
+                    .collect();
+                *headers = HeaderList::from_chain(chain).expect("indexed headers don't link up");
+                if headers.is_empty() {
+                    self.txstore_db
+                        .write_and_delete(vec![], vec![b"t".to_vec()], DBFlush::Enable);
+                } else {
+                    self.txstore_db.put_sync(b"t", &serialize(headers.tip()));
+                }
+                for db in [&self.txstore_db, &self.history_db] {
+                    db.write_and_delete(vec![], vec![PhaseCursor::key()], DBFlush::Enable);
+                }
+            }
+            report.repaired = true;
+        }
+
+        report
+    }
+}
+
+// A consistent view of the txstore and history DBs, used to serve queries that span several
+// lookups without observing rows written by the indexer in between them.
+pub struct StoreSnapshot<'a> {
+    txstore_db: DBSnapshot<'a>,
+    history_db: DBSnapshot<'a>,
 }
 
 type UtxoMap = HashMap<OutPoint, (BlockId, Value)>;
@@ -136,6 +369,13 @@ pub struct SpendingInput {
     pub confirmed: Option<BlockId>,
 }
 
+// The order in which confirmed history is returned: oldest or newest confirmations first
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryOrder {
+    Ascending,
+    Descending,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ScriptStats {
     pub tx_count: usize,
@@ -145,6 +385,17 @@ pub struct ScriptStats {
     pub funded_txo_sum: u64,
     #[cfg(not(feature = "liquid"))]
     pub spent_txo_sum: u64,
+    // funded_txo_sum minus spent_txo_sum, negative for the mempool when spending confirmed outputs
+    #[cfg(not(feature = "liquid"))]
+    pub balance: i64,
+}
+
+#[cfg(not(feature = "liquid"))]
+pub struct HistoryDelta {
+    pub txid: Txid,
+    pub blockid: BlockId,
+    pub funded: u64,
+    pub spent: u64,
 }
 
 impl ScriptStats {
@@ -157,10 +408,32 @@ impl ScriptStats {
             funded_txo_sum: 0,
             #[cfg(not(feature = "liquid"))]
             spent_txo_sum: 0,
+            #[cfg(not(feature = "liquid"))]
+            balance: 0,
         }
     }
 }
 
+#[derive(Serialize, Debug, Default)]
+pub struct DailyChainStats {
+    // start timestamp of the day (UTC)
+    pub day: u32,
+    pub block_count: u64,
+    pub tx_count: u64,
+    pub input_count: u64,
+    pub output_count: u64,
+    #[cfg(not(feature = "liquid"))]
+    pub fees: u64,
+    pub utxo_count: u64,
+    // of the blocks that have them indexed
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub coin_days_destroyed: Option<f64>,
+    // the outputs created by the blocks that have script type stats
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub script_types: Option<ScriptTypeCounts>,
+}
+
 pub struct Indexer {
     store: Arc<Store>,
     flush: DBFlush,
@@ -168,12 +441,28 @@ pub struct Indexer {
     iconfig: IndexerConfig,
     duration: HistogramVec,
     tip_metric: Gauge,
+    pending_blocks: Gauge,
+    db_stats_metric: GaugeVec,
+    // with --script-dictionary
+    seen_scripts: Option<Mutex<script_dict::SeenScripts>>,
 }
 
 struct IndexerConfig {
     light_mode: bool,
     address_search: bool,
+    address_clustering: bool,
     index_unspendables: bool,
+    script_type_stats: bool,
+    address_reuse: bool,
+    history_bloom: bool,
+    #[cfg(not(feature = "liquid"))]
+    index_pubkeys: bool,
+    #[cfg(not(feature = "liquid"))]
+    utxo_muhash: bool,
+    #[cfg(not(feature = "liquid"))]
+    coin_days_destroyed: bool,
+    checkpoints: bool,
+    first_seen: bool,
     network: Network,
     #[cfg(feature = "liquid")]
     parent_network: crate::chain::BNetwork,
@@ -184,7 +473,19 @@ impl From<&Config> for IndexerConfig {
         IndexerConfig {
             light_mode: config.light_mode,
             address_search: config.address_search,
+            address_clustering: config.address_clustering,
             index_unspendables: config.index_unspendables,
+            script_type_stats: config.script_type_stats,
+            address_reuse: config.address_reuse,
+            history_bloom: config.history_bloom,
+            #[cfg(not(feature = "liquid"))]
+            index_pubkeys: config.index_pubkeys,
+            #[cfg(not(feature = "liquid"))]
+            utxo_muhash: config.utxo_muhash,
+            #[cfg(not(feature = "liquid"))]
+            coin_days_destroyed: config.coin_days_destroyed,
+            checkpoints: config.checkpoints,
+            first_seen: config.first_seen,
             network: config.network_type,
             #[cfg(feature = "liquid")]
             parent_network: config.parent_network,
@@ -196,8 +497,47 @@ pub struct ChainQuery {
     store: Arc<Store>, // TODO: should be used as read-only
     daemon: Arc<Daemon>,
     light_mode: bool,
+    script_type_stats: bool,
+    address_reuse: bool,
+    history_bloom: bool,
+    #[cfg(not(feature = "liquid"))]
+    coin_days_destroyed: bool,
+    first_seen: bool,
     duration: HistogramVec,
     network: Network,
+    // unknown txids and txids not confirmed in the current chain
+    missing_txs: Mutex<MissingTxids>,
+    unconfirmed_txids: Mutex<MissingTxids>,
+    merkle_trees: Mutex<VecDeque<(BlockHash, Arc<MerkleTree>)>>,
+    cache_writer: CacheWriter,
+}
+
+// Txids that were not found, as of the tip they were looked up at. Keeps repeated lookups of
+// unknown txids from hitting the db until the next block.
+struct MissingTxids {
+    tip: BlockHash,
+    txids: HashSet<Txid>,
+}
+
+impl MissingTxids {
+    fn new() -> Mutex<Self> {
+        Mutex::new(MissingTxids {
+            tip: *DEFAULT_BLOCKHASH,
+            txids: HashSet::new(),
+        })
+    }
+
+    fn contains(&self, txid: &Txid, tip: &BlockHash) -> bool {
+        self.tip == *tip && self.txids.contains(txid)
+    }
+
+    fn insert(&mut self, txid: &Txid, tip: &BlockHash) {
+        if self.tip != *tip || self.txids.len() >= MISSING_TXIDS_CACHE_SIZE {
+            self.tip = *tip;
+            self.txids.clear();
+        }
+        self.txids.insert(*txid);
+    }
 }
 
 // TODO: &[Block] should be an iterator / a queue.
@@ -213,6 +553,39 @@ impl Indexer {
                 &["step"],
             ),
             tip_metric: metrics.gauge(MetricOpts::new("tip_height", "Current chain tip height")),
+            pending_blocks: metrics.gauge(MetricOpts::new(
+                "index_pending_blocks",
+                "# of blocks left to fetch and process in the current indexing phase",
+            )),
+            db_stats_metric: metrics.gauge_vec(
+                MetricOpts::new(
+                    "db_stats",
+                    "RocksDB disk usage and estimated number of keys",
+                ),
+                &["db", "stat"],
+            ),
+            seen_scripts: config
+                .script_dictionary
+                .then(|| Mutex::new(script_dict::SeenScripts::new())),
+        }
+    }
+
+    // The number of blocks left in the current indexing phase
+    pub fn pending_blocks(&self) -> Gauge {
+        self.pending_blocks.clone()
+    }
+
+    pub fn update_db_stats(&self) {
+        for (db, stats) in self.store.db_stats() {
+            let set = |stat: &str, value: u64| {
+                self.db_stats_metric
+                    .with_label_values(&[db, stat])
+                    .set(value as f64)
+            };
+            set("sst_files_size", stats.sst_files_size);
+            set("live_data_size", stats.live_data_size);
+            set("mem_tables_size", stats.mem_tables_size);
+            set("estimated_keys", stats.estimated_keys);
         }
     }
 
@@ -221,19 +594,36 @@ impl Indexer {
     }
 
     fn headers_to_add(&self, new_headers: &[HeaderEntry]) -> Vec<HeaderEntry> {
-        let added_blockhashes = self.store.added_blockhashes.read().unwrap();
-        new_headers
-            .iter()
-            .filter(|e| !added_blockhashes.contains(e.hash()))
-            .cloned()
-            .collect()
+        self.headers_not_done(&self.store.txstore_db, new_headers)
     }
 
     fn headers_to_index(&self, new_headers: &[HeaderEntry]) -> Vec<HeaderEntry> {
-        let indexed_blockhashes = self.store.indexed_blockhashes.read().unwrap();
+        self.headers_not_done(&self.store.history_db, new_headers)
+    }
+
+    // The blocks above the cursor of the phase that have no done marker. The whole range is checked
+    // if the cursor is missing or was re-orged out of the chain.
+    fn headers_not_done(&self, db: &DB, new_headers: &[HeaderEntry]) -> Vec<HeaderEntry> {
+        let cursor = PhaseCursor::load(db).filter(|cursor| {
+            let height = cursor.height as usize;
+            let entry = match new_headers.first() {
+                Some(first) if height >= first.height() => {
+                    new_headers.get(height - first.height()).cloned()
+                }
+                _ => self
+                    .store
+                    .indexed_headers
+                    .read()
+                    .unwrap()
+                    .header_by_height(height)
+                    .cloned(),
+            };
+            entry.map_or(false, |entry| *entry.hash() == cursor.blockhash)
+        });
         new_headers
             .iter()
-            .filter(|e| !indexed_blockhashes.contains(e.hash()))
+            .filter(|e| cursor.map_or(true, |cursor| e.height() > cursor.height as usize))
+            .filter(|e| !is_done(db, e.hash()))
             .cloned()
             .collect()
     }
@@ -252,6 +642,12 @@ impl Indexer {
         let headers = self.store.indexed_headers.read().unwrap();
         let new_headers = daemon.get_new_headers(&headers, &tip)?;
         let result = headers.order(new_headers);
+        // checked before indexing them, so that a misbehaving daemon can't get its chain indexed
+        if self.iconfig.checkpoints {
+            headers
+                .validate(&result, self.iconfig.network.checkpoints())
+                .chain_err(|| "refusing to follow the daemon's chain (see --no-checkpoints)")?;
+        }
 
         if let Some(tip) = result.last() {
             info!("{:?} ({} left to index)", tip, result.len());
@@ -263,39 +659,86 @@ impl Indexer {
         let daemon = daemon.reconnect()?;
         let tip = daemon.getbestblockhash()?;
         let new_headers = self.get_new_headers(&daemon, &tip)?;
+        // the records logged while updating (including by the add and index threads) are attached
+        // to this span
+        let _span = tracing::info_span!(
+            target: "electrs::indexer",
+            "update",
+            %tip,
+            new_blocks = new_headers.len()
+        )
+        .entered();
+        if self.iconfig.first_seen && self.store.done_initial_sync() {
+            first_seen::record_blocks(
+                &self.store.cache_db,
+                new_headers.iter().map(|entry| &entry.hash()[..]),
+            );
+        }
 
         let to_add = self.headers_to_add(&new_headers);
-        debug!(
-            "adding transactions from {} blocks using {:?}",
-            to_add.len(),
-            self.from
-        );
-        start_fetcher(self.from, &daemon, to_add)?.map(|blocks| self.add(&blocks));
-        self.start_auto_compactions(&self.store.txstore_db);
-
         let to_index = self.headers_to_index(&new_headers);
-        debug!(
-            "indexing history from {} blocks using {:?}",
+        if daemon.is_pruned() {
+            // also refreshes the prune height seen by the light mode queries
+            let prune_height = daemon.getblockchaininfo()?.pruneheight.unwrap_or(0) as usize;
+            // the blocks added before a restart but not indexed yet are fetched again too
+            let pruned = to_add
+                .iter()
+                .chain(&to_index)
+                .find(|h| h.height() < prune_height);
+            if let (Some(pruned), false) = (pruned, self.iconfig.light_mode) {
+                bail!(
+                    "block {} was pruned by bitcoind before being indexed (pruned below height {}), \
+                     it must keep more blocks (-prune) or be resynced",
+                    pruned.hash(),
+                    prune_height
+                );
+            }
+        }
+        tracing::debug!(
+            target: "electrs::indexer",
+            "adding transactions from {} blocks and indexing history from {} blocks using {:?}",
+            to_add.len(),
             to_index.len(),
             self.from
         );
-        start_fetcher(self.from, &daemon, to_index)?.map(|blocks| self.index(&blocks));
+        let (blocks_count, started) = (to_index.len(), Instant::now());
+        self.add_and_index(&daemon, &new_headers, to_add, to_index)?;
+        #[cfg(not(feature = "liquid"))]
+        if self.iconfig.utxo_muhash {
+            let _timer = self.start_timer("index_utxo_hash");
+            utxo_hash::apply_deltas(&self.store.history_db, &new_headers, self.flush);
+        }
+        self.start_auto_compactions(&self.store.txstore_db);
         self.start_auto_compactions(&self.store.history_db);
+        log_phase("add_index", blocks_count, started);
 
         if let DBFlush::Disable = self.flush {
-            debug!("flushing to disk");
+            tracing::debug!(target: "electrs::indexer", "flushing to disk");
             self.store.txstore_db.flush();
             self.store.history_db.flush();
             self.flush = DBFlush::Enable;
         }
 
+        if let Some(cursor) = PhaseCursor::below(&new_headers, None) {
+            cursor.save(&self.store.txstore_db, DBFlush::Enable);
+            cursor.save(&self.store.history_db, DBFlush::Enable);
+        }
+
+        if self.iconfig.address_clustering {
+            self.rebuild_reorged_clusters(&new_headers);
+        }
+
         // update the synced tip *after* the new data is flushed to disk
-        debug!("updating synced tip to {:?}", tip);
+        tracing::debug!(target: "electrs::indexer", "updating synced tip to {:?}", tip);
         self.store.txstore_db.put_sync(b"t", &serialize(&tip));
 
         let mut headers = self.store.indexed_headers.write().unwrap();
+        let changed_height = new_headers.first().map(HeaderEntry::height);
         headers.apply(new_headers);
         assert_eq!(tip, *headers.tip());
+        if let Some(changed_height) = changed_height {
+            save_headers_chain(&self.store.txstore_db, &headers, changed_height);
+        }
 
         if let FetchFrom::BlkFiles = self.from {
             self.from = FetchFrom::Bitcoind;
@@ -306,22 +749,177 @@ impl Indexer {
         Ok(tip)
     }
 
+    // The merges made by orphaned blocks can't be undone in place, so the clusters touched by a
+    // reorg are rebuilt. This happens before the synced tip is updated, so that it's done again
+    // if interrupted.
+    fn rebuild_reorged_clusters(&self, new_headers: &[HeaderEntry]) {
+        let headers = self.store.indexed_headers.read().unwrap();
+        let fork_height = match new_headers.first() {
+            Some(entry) if entry.height() < headers.len() => entry.height(),
+            _ => return,
+        };
+        let blockhashes: Vec<Bytes> = headers
+            .iter()
+            .skip(fork_height)
+            .chain(new_headers)
+            .map(|entry| entry.hash()[..].to_vec())
+            .collect();
+        let in_best_chain = |blockhash: &[u8]| {
+            let blockhash: BlockHash = deserialize(blockhash).expect("invalid blockhash");
+            new_headers.iter().any(|entry| *entry.hash() == blockhash)
+                || headers
+                    .header_by_blockhash(&blockhash)
+                    .map_or(false, |entry| entry.height() < fork_height)
+        };
+        let _timer = self.start_timer("rebuild_clusters");
+        let (rows, deletes) =
+            cluster::rebuild_clusters(&self.store.history_db, &blockhashes, in_best_chain);
+        self.store
+            .history_db
+            .write_and_delete(rows, deletes, DBFlush::Enable);
+    }
+
+    // Fetches the blocks once and pipelines them through add() and index(), which run in their own
+    // threads over bounded channels. As the spent txos are looked up from the txstore, a block is
+    // only indexed once all the blocks below it were added. The blk*.dat files are not ordered by
+    // height, so the blocks waiting for that are buffered, and deferred to a second pass if there
+    // are too many of them. The cursor of each phase is moved up as the blocks below it get done.
+    fn add_and_index(
+        &self,
+        daemon: &Daemon,
+        new_headers: &[HeaderEntry],
+        to_add: Vec<HeaderEntry>,
+        to_index: Vec<HeaderEntry>,
+    ) -> Result<()> {
+        let add_heights: BTreeSet<usize> = to_add.iter().map(HeaderEntry::height).collect();
+        let index_heights: BTreeSet<usize> = to_index.iter().map(HeaderEntry::height).collect();
+        let mut to_fetch: Vec<HeaderEntry> = to_index
+            .into_iter()
+            .filter(|entry| !add_heights.contains(&entry.height()))
+            .chain(to_add)
+            .collect();
+        if to_fetch.is_empty() {
+            return Ok(());
+        }
+        to_fetch.sort_by_key(HeaderEntry::height);
+        self.pending_blocks.set(to_fetch.len() as i64);
+        let fetcher = start_fetcher(self.from, daemon, to_fetch)?;
+
+        let chan = SyncChannel::new(1);
+        let sender = chan.sender();
+        let add_span = tracing::debug_span!(
+            target: "electrs::indexer",
+            "add",
+            blocks = add_heights.len()
+        );
+        let index_span = tracing::debug_span!(
+            target: "electrs::indexer",
+            "index",
+            blocks = index_heights.len()
+        );
+
+        let mut deferred = thread::scope(|scope| {
+            scope.spawn(move || {
+                let _span = add_span.entered();
+                let mut pending_add = add_heights;
+                let mut add_cursor = PhaseCursor::below(new_headers, pending_add.first().copied());
+                fetcher.map(|blocks| {
+                    let (added, rest): (Vec<BlockEntry>, Vec<BlockEntry>) = blocks
+                        .into_iter()
+                        .partition(|b| pending_add.contains(&b.entry.height()));
+                    if !added.is_empty() {
+                        self.add(&added);
+                    }
+                    for b in &added {
+                        pending_add.remove(&b.entry.height());
+                    }
+                    self.advance_cursor(
+                        &self.store.txstore_db,
+                        &mut add_cursor,
+                        PhaseCursor::below(new_headers, pending_add.first().copied()),
+                    );
+                    // the blocks below this height can be indexed
+                    let added_below = pending_add.first().copied().unwrap_or(usize::MAX);
+                    sender
+                        .send((added.into_iter().chain(rest), added_below))
+                        .expect("failed to send added blocks");
+                });
+            });
+
+            let _span = index_span.enter();
+            let mut pending_index = index_heights.clone();
+            let mut index_cursor = PhaseCursor::below(new_headers, pending_index.first().copied());
+            let mut waiting = WaitingBlocks::new(MAX_WAITING_BYTES);
+            for (blocks, added_below) in chan.into_receiver() {
+                let blocks = blocks.filter(|b| {
+                    let to_index = index_heights.contains(&b.entry.height());
+                    if !to_index {
+                        self.pending_blocks.dec();
+                    }
+                    to_index
+                });
+                let ready = waiting.push(blocks, added_below);
+                if !ready.is_empty() {
+                    self.index(&ready);
+                    self.pending_blocks.sub(ready.len() as i64);
+                    for b in &ready {
+                        pending_index.remove(&b.entry.height());
+                    }
+                    self.advance_cursor(
+                        &self.store.history_db,
+                        &mut index_cursor,
+                        PhaseCursor::below(new_headers, pending_index.first().copied()),
+                    );
+                }
+            }
+            assert!(waiting.blocks.is_empty(), "blocks left unindexed");
+            waiting.deferred
+        });
+
+        if !deferred.is_empty() {
+            let _span = index_span.enter();
+            tracing::debug!(
+                target: "electrs::indexer",
+                "indexing {} deferred blocks",
+                deferred.len()
+            );
+            deferred.sort_by_key(HeaderEntry::height);
+            start_fetcher(self.from, daemon, deferred)?.map(|blocks| {
+                self.index(&blocks);
+                self.pending_blocks.sub(blocks.len() as i64);
+            });
+        }
+        Ok(())
+    }
+
+    // Saves the new cursor of a phase if it moved up
+    fn advance_cursor(&self, db: &DB, cursor: &mut Option<PhaseCursor>, new: Option<PhaseCursor>) {
+        if let Some(new) = new {
+            if cursor.map_or(true, |cursor| new.height > cursor.height) {
+                new.save(db, self.flush);
+                *cursor = Some(new);
+            }
+        }
+    }
+
     fn add(&self, blocks: &[BlockEntry]) {
         // TODO: skip orphaned blocks?
         let rows = {
             let _timer = self.start_timer("add_process");
-            add_blocks(blocks, &self.iconfig)
+            let (script_ids, mut rows) = match &self.seen_scripts {
+                Some(seen) => {
+                    let mut seen = seen.lock().unwrap();
+                    script_dict::assign_ids(&self.store.txstore_db, &mut seen, blocks)
+                }
+                None => (HashMap::new(), vec![]),
+            };
+            rows.extend(add_blocks(blocks, &self.iconfig, &script_ids));
+            rows
         };
         {
             let _timer = self.start_timer("add_write");
             self.store.txstore_db.write(rows, self.flush);
         }
-
-        self.store
-            .added_blockhashes
-            .write()
-            .unwrap()
-            .extend(blocks.iter().map(|b| b.entry.hash()));
     }
 
     fn index(&self, blocks: &[BlockEntry]) {
@@ -331,17 +929,63 @@ impl Indexer {
         };
         let rows = {
             let _timer = self.start_timer("index_process");
-            let added_blockhashes = self.store.added_blockhashes.read().unwrap();
             for b in blocks {
                 let blockhash = b.entry.hash();
-                // TODO: replace by lookup into txstore_db?
-                if !added_blockhashes.contains(blockhash) {
+                if !is_done(&self.store.txstore_db, blockhash) {
                     panic!("cannot index block {} (missing from store)", blockhash);
                 }
             }
             index_blocks(blocks, &previous_txos_map, &self.iconfig)
         };
         self.store.history_db.write(rows, self.flush);
+
+        #[cfg(not(feature = "liquid"))]
+        if self.iconfig.utxo_muhash {
+            let rows = {
+                let _timer = self.start_timer("index_utxo_hash");
+                let outpoints = previous_txos_map.keys().copied().collect();
+                let coin_codes = lookup_txos_with(
+                    &self.store.txstore_db,
+                    &outpoints,
+                    true,
+                    utxo_hash::lookup_coin_code,
+                );
+                blocks
+                    .par_iter()
+                    .filter_map(|b| utxo_hash::block_delta(b, &previous_txos_map, &coin_codes))
+                    .collect()
+            };
+            self.store.history_db.write(rows, self.flush);
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        if self.iconfig.coin_days_destroyed {
+            let rows = {
+                let _timer = self.start_timer("index_coin_days");
+                let outpoints = previous_txos_map.keys().copied().collect();
+                let tx_times = lookup_txos_with(
+                    &self.store.txstore_db,
+                    &outpoints,
+                    true,
+                    coin_days::lookup_tx_time,
+                );
+                blocks
+                    .par_iter()
+                    .flat_map(|b| coin_days::index_block(b, &previous_txos_map, &tx_times))
+                    .collect()
+            };
+            self.store.history_db.write(rows, self.flush);
+        }
+
+        if self.iconfig.address_clustering {
+            let (rows, deletes) = {
+                let _timer = self.start_timer("index_clusters");
+                cluster::index_clusters(blocks, &previous_txos_map, &self.store.history_db)
+            };
+            self.store
+                .history_db
+                .write_and_delete(rows, deletes, self.flush);
+        }
     }
 
     pub fn fetch_from(&mut self, from: FetchFrom) {
@@ -352,15 +996,44 @@ impl Indexer {
 impl ChainQuery {
     pub fn new(store: Arc<Store>, daemon: Arc<Daemon>, config: &Config, metrics: &Metrics) -> Self {
         ChainQuery {
+            cache_writer: CacheWriter::start(Arc::clone(&store), metrics),
             store,
             daemon,
             light_mode: config.light_mode,
+            script_type_stats: config.script_type_stats,
+            address_reuse: config.address_reuse,
+            history_bloom: config.history_bloom,
+            #[cfg(not(feature = "liquid"))]
+            coin_days_destroyed: config.coin_days_destroyed,
+            first_seen: config.first_seen,
             network: config.network_type,
             duration: metrics.histogram_vec(
                 HistogramOpts::new("query_duration", "Index query duration (in seconds)"),
                 &["name"],
             ),
+            missing_txs: MissingTxids::new(),
+            unconfirmed_txids: MissingTxids::new(),
+            merkle_trees: Mutex::new(VecDeque::with_capacity(MERKLE_TREE_CACHE_SIZE)),
+        }
+    }
+
+    // Runs the lookup unless the txid is known to be missing as of the current tip. Misses are only
+    // recorded if the tip didn't change during the lookup.
+    fn lookup_unless_missing<T>(
+        &self,
+        missing: &Mutex<MissingTxids>,
+        txid: &Txid,
+        lookup: impl FnOnce() -> Option<T>,
+    ) -> Option<T> {
+        let tip = self.best_hash();
+        if missing.lock().unwrap().contains(txid, &tip) {
+            return None;
+        }
+        let result = lookup();
+        if result.is_none() && self.best_hash() == tip {
+            missing.lock().unwrap().insert(txid, &tip);
         }
+        result
     }
 
     pub fn network(&self) -> Network {
@@ -390,6 +1063,46 @@ impl ChainQuery {
         }
     }
 
+    // The merkle tree of the block txids, kept for the most recently requested blocks
+    pub fn get_block_merkle_tree(&self, hash: &BlockHash) -> Option<Arc<MerkleTree>> {
+        let _timer = self.start_timer("get_block_merkle_tree");
+        {
+            let mut trees = self.merkle_trees.lock().unwrap();
+            if let Some(index) = trees.iter().position(|(blockhash, _)| blockhash == hash) {
+                let entry = trees.remove(index).unwrap();
+                let tree = Arc::clone(&entry.1);
+                trees.push_front(entry);
+                return Some(tree);
+            }
+        }
+
+        let txids = self.get_block_txids(hash)?;
+        let tree = Arc::new(MerkleTree::new(
+            txids.into_iter().map(Sha256dHash::from).collect(),
+        ));
+        let mut trees = self.merkle_trees.lock().unwrap();
+        if !trees.iter().any(|(blockhash, _)| blockhash == hash) {
+            trees.truncate(MERKLE_TREE_CACHE_SIZE - 1);
+            trees.push_front((*hash, Arc::clone(&tree)));
+        }
+        Some(tree)
+    }
+
+    // The txid of the block's coinbase, read without deserializing all of the block txids
+    pub fn get_block_coinbase_txid(&self, hash: &BlockHash) -> Option<Txid> {
+        if self.light_mode {
+            return self.get_block_txids(hash)?.into_iter().next();
+        }
+        let val = self
+            .store
+            .txstore_db
+            .get(&BlockRow::txids_key(full_hash(&hash[..])))?;
+        // the txids are prefixed by their count
+        let (_count, txid): (u64, Txid) =
+            bincode::deserialize_little(&val).expect("failed to parse block txids");
+        Some(txid)
+    }
+
     pub fn get_block_meta(&self, hash: &BlockHash) -> Option<BlockMeta> {
         let _timer = self.start_timer("get_block_meta");
 
@@ -400,8 +1113,74 @@ impl ChainQuery {
             self.store
                 .txstore_db
                 .get(&BlockRow::meta_key(full_hash(&hash[..])))
-                .map(|val| bincode::deserialize_little(&val).expect("failed to parse BlockMeta"))
+                .map(|val| BlockMeta::from_db_value(&val))
+        }
+    }
+
+    // Returns None for coinbase transactions. Transactions indexed before fees were stored get
+    // their fee computed from the prevout values.
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_tx_fee(&self, txid: &Txid) -> Option<u64> {
+        let _timer = self.start_timer("get_tx_fee");
+        self.store
+            .history_db
+            .get(&TxFeeRow::key(&txid[..]))
+            .map(|val| bincode::deserialize_little(&val).expect("failed to parse tx fee"))
+            .or_else(|| self.compute_tx_fee(txid))
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn compute_tx_fee(&self, txid: &Txid) -> Option<u64> {
+        let tx: Transaction = deserialize(&self.lookup_raw_txn(txid, None)?).ok()?;
+        if tx.is_coinbase() {
+            return None;
         }
+        let outpoints = tx.input.iter().map(|txin| txin.previous_output).collect();
+        let values = self.lookup_avail_txo_values(&outpoints);
+        if values.len() != outpoints.len() {
+            return None;
+        }
+        let total_in: u64 = values.values().sum();
+        let total_out: u64 = tx.output.iter().map(|txout| txout.value.to_sat()).sum();
+        total_in.checked_sub(total_out)
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_block_reward(&self, hash: &BlockHash) -> Option<BlockReward> {
+        let _timer = self.start_timer("get_block_reward");
+        self.store
+            .history_db
+            .get(&BlockRow::reward_key(full_hash(&hash[..])))
+            .map(|val| BlockReward::from_db_value(&val))
+    }
+
+    // Unavailable for transactions indexed before they were introduced
+    pub fn get_tx_stats(&self, txid: &Txid) -> Option<TxStats> {
+        let _timer = self.start_timer("get_tx_stats");
+        self.store
+            .history_db
+            .get(&TxStatsRow::key(&txid[..]))
+            .map(|val| bincode::deserialize_little(&val).expect("failed to parse TxStats"))
+    }
+
+    // Unavailable without --utxo-muhash
+    #[cfg(not(feature = "liquid"))]
+    pub fn utxo_muhash(&self, hash: &BlockHash) -> Option<sha256::Hash> {
+        let _timer = self.start_timer("utxo_muhash");
+        utxo_hash::utxo_muhash(&self.store.history_db, hash)
+    }
+
+    // In satoshi-days, unavailable without --coin-days-destroyed
+    #[cfg(not(feature = "liquid"))]
+    pub fn tx_coin_days_destroyed(&self, txid: &Txid) -> Option<u64> {
+        let _timer = self.start_timer("tx_coin_days_destroyed");
+        coin_days::tx_coin_days_destroyed(&self.store.history_db, &txid[..])
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn block_coin_days_destroyed(&self, hash: &BlockHash) -> Option<u64> {
+        let _timer = self.start_timer("block_coin_days_destroyed");
+        coin_days::block_coin_days_destroyed(&self.store.history_db, &hash[..])
     }
 
     pub fn get_block_raw(&self, hash: &BlockHash) -> Option<Vec<u8>> {
@@ -442,37 +1221,141 @@ impl ChainQuery {
         self.store.indexed_headers.read().unwrap().get_mtp(height)
     }
 
+    #[cfg(not(feature = "liquid"))]
+    pub fn get_difficulty_epoch(&self, height: usize) -> Option<DifficultyEpoch> {
+        let _timer = self.start_timer("get_difficulty_epoch");
+        self.store
+            .indexed_headers
+            .read()
+            .unwrap()
+            .difficulty_epoch(height)
+    }
+
+    pub fn tx_seen_at(&self, txid: &Txid) -> Option<u32> {
+        if !self.first_seen {
+            return None;
+        }
+        let _timer = self.start_timer("tx_seen_at");
+        first_seen::tx_seen_at(&self.store.cache_db, &txid[..])
+    }
+
+    pub fn block_seen_at(&self, hash: &BlockHash) -> Option<u32> {
+        if !self.first_seen {
+            return None;
+        }
+        let _timer = self.start_timer("block_seen_at");
+        first_seen::block_seen_at(&self.store.cache_db, &hash[..])
+    }
+
     pub fn get_block_with_meta(&self, hash: &BlockHash) -> Option<BlockHeaderMeta> {
         let _timer = self.start_timer("get_block_with_meta");
         let header_entry = self.header_by_hash(hash)?;
         Some(BlockHeaderMeta {
             meta: self.get_block_meta(hash)?,
             mtp: self.get_mtp(header_entry.height()),
+            #[cfg(not(feature = "liquid"))]
+            reward: self.get_block_reward(hash),
+            #[cfg(not(feature = "liquid"))]
+            coin_days_destroyed: self.block_coin_days_destroyed(hash),
+            seen_at: self.block_seen_at(hash),
+            #[cfg(not(feature = "liquid"))]
+            epoch: self.get_difficulty_epoch(header_entry.height())?,
             header_entry,
         })
     }
 
+    // The confirmed history of the scripthash in ascending order, with the value it received and
+    // sent in each transaction. Read lazily, one block at a time.
+    #[cfg(not(feature = "liquid"))]
+    pub fn history_deltas<'a>(
+        &'a self,
+        scripthash: &[u8],
+    ) -> impl Iterator<Item = HistoryDelta> + 'a {
+        let mut rows = self
+            .history_iter_scan(b'H', scripthash, 0)
+            .map(TxHistoryRow::from_row)
+            .peekable();
+        std::iter::from_fn(move || {
+            let height = rows.peek()?.key.confirmed_height;
+            let mut deltas: Vec<(Txid, u64, u64)> = vec![];
+            let mut positions: HashMap<Txid, usize> = HashMap::new();
+            while let Some(row) = rows.next_if(|row| row.key.confirmed_height == height) {
+                let txid = row.get_txid();
+                let position = *positions.entry(txid).or_insert_with(|| {
+                    deltas.push((txid, 0, 0));
+                    deltas.len() - 1
+                });
+                match row.key.txinfo {
+                    TxHistoryInfo::Funding(info) => deltas[position].1 += info.value,
+                    TxHistoryInfo::Spending(info) => deltas[position].2 += info.value,
+                }
+            }
+            Some((height, deltas))
+        })
+        .flat_map(|(height, deltas)| deltas.into_iter().map(move |delta| (height, delta)))
+        .filter_map(move |(height, (txid, funded, spent))| {
+            // skip the rows of transactions that were re-orged and confirmed again elsewhere
+            let blockid = self
+                .tx_confirming_block(&txid)
+                .filter(|blockid| blockid.height == height as usize)?;
+            Some(HistoryDelta {
+                txid,
+                blockid,
+                funded,
+                spent,
+            })
+        })
+    }
+
     pub fn history_iter_scan(&self, code: u8, hash: &[u8], start_height: usize) -> ScanIterator {
         self.store.history_db.iter_scan_from(
             &TxHistoryRow::filter(code, &hash[..]),
             &TxHistoryRow::prefix_height(code, &hash[..], start_height as u32),
         )
     }
-    fn history_iter_scan_reverse(&self, code: u8, hash: &[u8]) -> ReverseScanIterator {
-        self.store.history_db.iter_scan_reverse(
+    fn snapshot_history_iter_scan<'a>(
+        &self,
+        snapshot: &'a StoreSnapshot,
+        code: u8,
+        hash: &[u8],
+        start_height: usize,
+    ) -> ScanIterator<'a> {
+        snapshot.history_db.iter_scan_from(
             &TxHistoryRow::filter(code, &hash[..]),
-            &TxHistoryRow::prefix_end(code, &hash[..]),
+            &TxHistoryRow::prefix_height(code, &hash[..], start_height as u32),
         )
     }
 
+    // The position in the chain of the confirmed transaction, from which the history pages that
+    // follow it are scanned
+    fn snapshot_history_cursor(
+        &self,
+        snapshot: &StoreSnapshot,
+        txid: &Txid,
+    ) -> Option<HistoryCursor> {
+        let blockid = self.snapshot_tx_confirming_block(snapshot, txid)?;
+        let tx_position = self
+            .get_block_txids(&blockid.hash)
+            .and_then(|txids| txids.iter().position(|t| t == txid))
+            .map(|position| position as u32);
+        Some(HistoryCursor {
+            height: blockid.height as u32,
+            tx_position,
+        })
+    }
+
     pub fn history(
         &self,
         scripthash: &[u8],
         last_seen_txid: Option<&Txid>,
         limit: usize,
+        order: HistoryOrder,
     ) -> Vec<(Transaction, BlockId)> {
+        if !self.may_have_history(scripthash) {
+            return vec![];
+        }
         // scripthash lookup
-        self._history(b'H', scripthash, last_seen_txid, limit)
+        self._history(b'H', scripthash, last_seen_txid, limit, order)
     }
 
     fn _history(
@@ -481,14 +1364,54 @@ impl ChainQuery {
         hash: &[u8],
         last_seen_txid: Option<&Txid>,
         limit: usize,
+        order: HistoryOrder,
     ) -> Vec<(Transaction, BlockId)> {
         let _timer_scan = self.start_timer("history");
-        let txs_conf = self
-            .history_iter_scan_reverse(code, hash)
-            .map(|row| TxHistoryRow::from_row(row).get_txid())
-            // XXX: unique() requires keeping an in-memory list of all txids, can we avoid that?
-            .unique()
-            // TODO seek directly to last seen tx without reading earlier rows
+        let snapshot = self.store.snapshot();
+
+        // seek the scan to the rows of the last seen tx, so that the rows of earlier pages don't
+        // have to be read again
+        let cursor = last_seen_txid.and_then(|txid| self.snapshot_history_cursor(&snapshot, txid));
+        let filter = TxHistoryRow::filter(code, hash);
+        let rows: Box<dyn Iterator<Item = DBRow>> = match order {
+            HistoryOrder::Ascending => {
+                let start = cursor.map_or(0, |c| c.height);
+                let tx_position = cursor.and_then(|c| c.tx_position).unwrap_or(0);
+                let prefix_min = TxHistoryRow::prefix_position(code, hash, start, tx_position);
+                Box::new(snapshot.history_db.iter_scan_from(&filter, &prefix_min))
+            }
+            HistoryOrder::Descending => {
+                // seek past the last row of the last seen tx
+                let prefix_max = match cursor {
+                    Some(HistoryCursor {
+                        height,
+                        tx_position: Some(tx_position),
+                    }) => TxHistoryRow::prefix_position(code, hash, height, tx_position + 1),
+                    Some(HistoryCursor { height, .. }) => {
+                        TxHistoryRow::prefix_height(code, hash, height + 1)
+                    }
+                    None => TxHistoryRow::prefix_end(code, hash),
+                };
+                Box::new(snapshot.history_db.iter_scan_reverse(&filter, &prefix_max))
+            }
+        };
+
+        let txids = rows
+            .map(|row| TxHistoryRow::from_row(row).get_txid())
+            // XXX: unique() requires keeping an in-memory list of all txids, can we avoid that?
+            .unique();
+        self.snapshot_history_page(&snapshot, txids, last_seen_txid, limit)
+    }
+
+    // Look up the confirmed transactions following last_seen_txid in the txids iterator
+    fn snapshot_history_page(
+        &self,
+        snapshot: &StoreSnapshot,
+        txids: impl Iterator<Item = Txid>,
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Vec<(Transaction, BlockId)> {
+        let txs_conf = txids
             .skip_while(|txid| {
                 // skip until we reach the last_seen_txid
                 last_seen_txid.map_or(false, |last_seen_txid| last_seen_txid != txid)
@@ -497,11 +1420,14 @@ impl ChainQuery {
                 Some(_) => 1, // skip the last_seen_txid itself
                 None => 0,
             })
-            .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
+            .filter_map(|txid| {
+                self.snapshot_tx_confirming_block(snapshot, &txid)
+                    .map(|b| (txid, b))
+            })
             .take(limit)
             .collect::<Vec<(Txid, BlockId)>>();
 
-        self.lookup_txns(&txs_conf)
+        self.snapshot_lookup_txns(snapshot, &txs_conf)
             .expect("failed looking up txs in history index")
             .into_iter()
             .zip(txs_conf)
@@ -509,23 +1435,145 @@ impl ChainQuery {
             .collect()
     }
 
+    #[cfg(feature = "runes")]
+    pub fn rune_history(
+        &self,
+        id: &RuneId,
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+    ) -> Vec<(Transaction, BlockId)> {
+        let _timer_scan = self.start_timer("rune_history");
+        let snapshot = self.store.snapshot();
+        let txids = runes::history_txids(&snapshot.history_db, id);
+        self.snapshot_history_page(&snapshot, txids, last_seen_txid, limit)
+    }
+
     pub fn history_txids(&self, scripthash: &[u8], limit: usize) -> Vec<(Txid, BlockId)> {
+        if !self.may_have_history(scripthash) {
+            return vec![];
+        }
         // scripthash lookup
         self._history_txids(b'H', scripthash, limit)
     }
 
+    // False if the scripthash was never funded, as told by its marker row with --history-bloom.
+    // Unused scripthashes are mostly ruled out by the bloom filters, without reading from disk.
+    pub fn may_have_history(&self, scripthash: &[u8]) -> bool {
+        if !self.history_bloom {
+            return true;
+        }
+        let _timer = self.start_timer("may_have_history");
+        self.store
+            .history_db
+            .get(&TxHistoryRow::marker_key(scripthash))
+            .is_some()
+    }
+
     fn _history_txids(&self, code: u8, hash: &[u8], limit: usize) -> Vec<(Txid, BlockId)> {
         let _timer = self.start_timer("history_txids");
-        self.history_iter_scan(code, hash, 0)
+        let snapshot = self.store.snapshot();
+        self.snapshot_history_txids(&snapshot, code, hash, limit)
+    }
+
+    // Like history_txids() for many scripthashes at once, e.g. to refresh the status of the
+    // subscriptions after a new block. They are all read from the same snapshot, in the order of
+    // their keys so that consecutive scans hit nearby blocks. Returned in the original order.
+    pub fn history_txids_batch(
+        &self,
+        scripthashes: &[&[u8]],
+        limit: usize,
+    ) -> Vec<Vec<(Txid, BlockId)>> {
+        let _timer = self.start_timer("history_txids_batch");
+        let snapshot = self.store.snapshot();
+        let mut order: Vec<usize> = (0..scripthashes.len()).collect();
+        order.sort_unstable_by_key(|&i| scripthashes[i]);
+
+        let mut results = vec![vec![]; scripthashes.len()];
+        for i in order {
+            if self.may_have_history(scripthashes[i]) {
+                results[i] = self.snapshot_history_txids(&snapshot, b'H', scripthashes[i], limit);
+            }
+        }
+        results
+    }
+
+    fn snapshot_history_txids(
+        &self,
+        snapshot: &StoreSnapshot,
+        code: u8,
+        hash: &[u8],
+        limit: usize,
+    ) -> Vec<(Txid, BlockId)> {
+        self.snapshot_history_iter_scan(snapshot, code, hash, 0)
             .map(|row| TxHistoryRow::from_row(row).get_txid())
             .unique()
-            .filter_map(|txid| self.tx_confirming_block(&txid).map(|b| (txid, b)))
+            .filter_map(|txid| {
+                self.snapshot_tx_confirming_block(snapshot, &txid)
+                    .map(|b| (txid, b))
+            })
             .take(limit)
             .collect()
     }
 
+    // Like history_txids(), along with the net effect each transaction has on the scripthash
+    // balance (funded minus spent)
+    #[cfg(not(feature = "liquid"))]
+    pub fn history_txids_with_delta(
+        &self,
+        scripthash: &[u8],
+        limit: usize,
+    ) -> Vec<(Txid, BlockId, i64)> {
+        if !self.may_have_history(scripthash) {
+            return vec![];
+        }
+        let _timer = self.start_timer("history_txids_with_delta");
+        let snapshot = self.store.snapshot();
+        let mut deltas: Vec<(Txid, BlockId, i64)> = vec![];
+        let mut positions: HashMap<Txid, usize> = HashMap::new();
+        let mut confirmations: HashMap<Txid, Option<BlockId>> = HashMap::new();
+
+        for row in self.snapshot_history_iter_scan(&snapshot, b'H', scripthash, 0) {
+            let history = TxHistoryRow::from_row(row);
+            let height = history.key.confirmed_height as usize;
+            // all the entries of a transaction share its height, so once the limit is
+            // reached and we moved past the last included height, nothing else can be added
+            if deltas.len() == limit && deltas.last().map_or(true, |(_, b, _)| height > b.height) {
+                break;
+            }
+            let txid = history.get_txid();
+            let value = match history.key.txinfo {
+                TxHistoryInfo::Funding(ref info) => info.value as i64,
+                TxHistoryInfo::Spending(ref info) => -(info.value as i64),
+            };
+            if let Some(&pos) = positions.get(&txid) {
+                // entries left over from orphaned blocks are at a different height
+                if deltas[pos].1.height == height {
+                    deltas[pos].2 += value;
+                }
+                continue;
+            }
+            if deltas.len() == limit {
+                continue;
+            }
+            let blockid = confirmations
+                .entry(txid)
+                .or_insert_with(|| self.snapshot_tx_confirming_block(&snapshot, &txid));
+            match blockid {
+                Some(blockid) if blockid.height == height => {
+                    positions.insert(txid, deltas.len());
+                    deltas.push((txid, blockid.clone(), value));
+                }
+                _ => (),
+            }
+        }
+        deltas
+    }
+
     // TODO: avoid duplication with stats/stats_delta?
     pub fn utxo(&self, scripthash: &[u8], limit: usize) -> Result<Vec<Utxo>> {
+        if !self.may_have_history(scripthash) {
+            return Ok(vec![]);
+        }
         let _timer = self.start_timer("utxo");
 
         // get the last known utxo set and the blockhash it was updated for.
@@ -540,21 +1588,29 @@ impl ChainQuery {
                     .map(|height| (utxos_cache, height))
             })
             .map(|(utxos_cache, height)| (from_utxo_cache(utxos_cache, self), height));
-        let had_cache = cache.is_some();
+        let snapshot = self.store.snapshot();
+        let (oldutxos, start_height, had_cache, checkpoint_items) = match cache {
+            Some((oldutxos, blockheight)) => (oldutxos, blockheight + 1, true, 0),
+            // resume from the checkpoint rather than from scratch, e.g. after a re-org
+            None => self.utxo_checkpoint(&snapshot, scripthash, limit)?,
+        };
 
-        // update utxo set with new transactions since
-        let (newutxos, lastblock, processed_items) = cache.map_or_else(
-            || self.utxo_delta(scripthash, HashMap::new(), 0, limit),
-            |(oldutxos, blockheight)| self.utxo_delta(scripthash, oldutxos, blockheight + 1, limit),
+        // update utxo set with new transactions since, within what's left of the limit
+        let remaining = limit.saturating_sub(checkpoint_items);
+        let (newutxos, lastblock, processed_items) = self.utxo_delta(
+            &snapshot,
+            scripthash,
+            oldutxos,
+            start_height,
+            None,
+            remaining,
         )?;
 
         // save updated utxo set to cache
         if let Some(lastblock) = lastblock {
             if had_cache || processed_items > MIN_HISTORY_ITEMS_TO_CACHE {
-                self.store.cache_db.write(
-                    vec![UtxoCacheRow::new(scripthash, &newutxos, &lastblock).into_row()],
-                    DBFlush::Enable,
-                );
+                self.cache_writer
+                    .write(UtxoCacheRow::new(scripthash, &newutxos, &lastblock).into_row());
             }
         }
 
@@ -585,19 +1641,78 @@ impl ChainQuery {
             .collect())
     }
 
+    // The utxo set as of the last checkpoint deep enough not to be re-orged, the height to resume
+    // from, whether there is a saved checkpoint and the number of history items replayed to get
+    // there. The checkpoint is advanced and saved in the cache along the way, for large sets:
+    //      o{scripthash} → {utxos}{height}{blockhash}
+    fn utxo_checkpoint(
+        &self,
+        snapshot: &StoreSnapshot,
+        scripthash: &[u8],
+        limit: usize,
+    ) -> Result<(UtxoMap, usize, bool, usize)> {
+        let height = self.best_height().saturating_sub(CHECKPOINT_MIN_DEPTH);
+        let checkpoint_height = height - height % STATS_CHECKPOINT_INTERVAL;
+
+        let checkpoint: Option<(CachedUtxoMap, u32, BlockHash)> = self
+            .store
+            .cache_db
+            .get(&UtxoCheckpointRow::key(scripthash))
+            .map(|c| bincode::deserialize_little(&c).unwrap())
+            .filter(|(_, height, blockhash)| {
+                *height as usize <= checkpoint_height
+                    && self.hash_by_height(*height as usize) == Some(*blockhash)
+            });
+        let (utxos, start_height) = match checkpoint {
+            Some((utxos_cache, height, _)) => {
+                (from_utxo_cache(utxos_cache, self), height as usize + 1)
+            }
+            None => (HashMap::new(), 0),
+        };
+        if checkpoint_height == 0 || start_height > checkpoint_height {
+            return Ok((utxos, start_height, start_height > 0, 0));
+        }
+
+        let (utxos, _, processed_items) = self.utxo_delta(
+            snapshot,
+            scripthash,
+            utxos,
+            start_height,
+            Some(checkpoint_height),
+            limit,
+        )?;
+        let mut saved = false;
+        if start_height > 0 || processed_items > MIN_HISTORY_ITEMS_TO_CACHE {
+            if let Some(blockhash) = self.hash_by_height(checkpoint_height) {
+                let row = UtxoCheckpointRow::row(scripthash, &utxos, checkpoint_height, &blockhash);
+                self.cache_writer.write(row);
+                saved = true;
+            }
+        }
+        Ok((utxos, checkpoint_height + 1, saved, processed_items))
+    }
+
+    // Replays the history rows from start_height up to end_height (inclusive, or to the tip if None)
     fn utxo_delta(
         &self,
+        snapshot: &StoreSnapshot,
         scripthash: &[u8],
         init_utxos: UtxoMap,
         start_height: usize,
+        end_height: Option<usize>,
         limit: usize,
     ) -> Result<(UtxoMap, Option<BlockHash>, usize)> {
         let _timer = self.start_timer("utxo_delta");
         let history_iter = self
-            .history_iter_scan(b'H', scripthash, start_height)
+            .snapshot_history_iter_scan(snapshot, b'H', scripthash, start_height)
             .map(TxHistoryRow::from_row)
+            .take_while(|history| {
+                end_height.map_or(true, |end_height| {
+                    history.key.confirmed_height as usize <= end_height
+                })
+            })
             .filter_map(|history| {
-                self.tx_confirming_block(&history.get_txid())
+                self.snapshot_tx_confirming_block(snapshot, &history.get_txid())
                     .map(|b| (history, b))
             });
 
@@ -631,51 +1746,126 @@ impl ChainQuery {
     }
 
     pub fn stats(&self, scripthash: &[u8]) -> ScriptStats {
+        if !self.may_have_history(scripthash) {
+            return ScriptStats::default();
+        }
         let _timer = self.start_timer("stats");
 
-        // get the last known stats and the blockhash they are updated for.
-        // invalidates the cache if the block was orphaned.
-        let cache: Option<(ScriptStats, usize)> = self
-            .store
+        let (oldstats, start_height) = match self.cached_stats(scripthash) {
+            Some((oldstats, blockheight)) => (oldstats, blockheight + 1),
+            // resume from the checkpoint rather than from scratch, e.g. after a re-org
+            None => self.stats_checkpoint(scripthash),
+        };
+
+        // update stats with new transactions since
+        let snapshot = self.store.snapshot();
+        let (newstats, lastblock) =
+            self.stats_delta(&snapshot, scripthash, oldstats, start_height, None);
+
+        // save updated stats to cache
+        if let Some(lastblock) = lastblock {
+            if newstats.funded_txo_count + newstats.spent_txo_count > MIN_HISTORY_ITEMS_TO_CACHE {
+                self.cache_writer
+                    .write(StatsCacheRow::new(scripthash, &newstats, &lastblock).into_row());
+            }
+        }
+
+        newstats
+    }
+
+    // The last known stats and the height of the block they are updated for, without the blocks
+    // added since. None if they weren't cached, or if the block was orphaned.
+    pub fn cached_stats(&self, scripthash: &[u8]) -> Option<(ScriptStats, usize)> {
+        self.store
             .cache_db
             .get(&StatsCacheRow::key(scripthash))
-            .map(|c| bincode::deserialize_little(&c).unwrap())
+            // rows cached before the balance was added fail to parse, and get recomputed
+            .and_then(|c| bincode::deserialize_little(&c).ok())
             .and_then(|(stats, blockhash)| {
                 self.height_by_hash(&blockhash)
                     .map(|height| (stats, height))
-            });
+            })
+    }
 
-        // update stats with new transactions since
-        let (newstats, lastblock) = cache.map_or_else(
-            || self.stats_delta(scripthash, ScriptStats::default(), 0),
-            |(oldstats, blockheight)| self.stats_delta(scripthash, oldstats, blockheight + 1),
+    // The stats as of the last checkpoint deep enough not to be re-orged, and the height to resume from
+    fn stats_checkpoint(&self, scripthash: &[u8]) -> (ScriptStats, usize) {
+        let height = self.best_height().saturating_sub(CHECKPOINT_MIN_DEPTH);
+        let checkpoint_height = height - height % STATS_CHECKPOINT_INTERVAL;
+        if checkpoint_height == 0 {
+            return (ScriptStats::default(), 0);
+        }
+        (
+            self.stats_at_height(scripthash, checkpoint_height),
+            checkpoint_height + 1,
+        )
+    }
+
+    // Stats of the scripthash as of the given height (inclusive), replayed from the closest cached
+    // checkpoint. Checkpoints are saved every STATS_CHECKPOINT_INTERVAL blocks, along with the hash
+    // of their block to detect re-orgs.
+    pub fn stats_at_height(&self, scripthash: &[u8], height: usize) -> ScriptStats {
+        let _timer = self.start_timer("stats_at_height");
+        let snapshot = self.store.snapshot();
+
+        let checkpoint = self
+            .store
+            .cache_db
+            .iter_scan(&StatsCheckpointRow::filter(scripthash))
+            .filter_map(StatsCheckpointRow::from_row)
+            .filter(|row| row.height <= height)
+            .filter(|row| self.hash_by_height(row.height) == Some(row.blockhash))
+            .last();
+        let (mut stats, mut start_height) = checkpoint.map_or_else(
+            || (ScriptStats::default(), 0),
+            |row| (row.stats, row.height + 1),
         );
 
-        // save updated stats to cache
-        if let Some(lastblock) = lastblock {
-            if newstats.funded_txo_count + newstats.spent_txo_count > MIN_HISTORY_ITEMS_TO_CACHE {
-                self.store.cache_db.write(
-                    vec![StatsCacheRow::new(scripthash, &newstats, &lastblock).into_row()],
-                    DBFlush::Enable,
-                );
+        // save a new checkpoint if the closest one is missing
+        let checkpoint_height = height - height % STATS_CHECKPOINT_INTERVAL;
+        if checkpoint_height >= start_height && checkpoint_height > 0 {
+            stats = self
+                .stats_delta(
+                    &snapshot,
+                    scripthash,
+                    stats,
+                    start_height,
+                    Some(checkpoint_height),
+                )
+                .0;
+            start_height = checkpoint_height + 1;
+            if stats.funded_txo_count + stats.spent_txo_count > MIN_HISTORY_ITEMS_TO_CACHE {
+                if let Some(blockhash) = self.hash_by_height(checkpoint_height) {
+                    let row =
+                        StatsCheckpointRow::row(scripthash, checkpoint_height, &stats, blockhash);
+                    self.cache_writer.write(row);
+                }
             }
         }
 
-        newstats
+        self.stats_delta(&snapshot, scripthash, stats, start_height, Some(height))
+            .0
     }
 
+    // Replays the history rows from start_height up to end_height (inclusive, or to the tip if None)
     fn stats_delta(
         &self,
+        snapshot: &StoreSnapshot,
         scripthash: &[u8],
         init_stats: ScriptStats,
         start_height: usize,
+        end_height: Option<usize>,
     ) -> (ScriptStats, Option<BlockHash>) {
         let _timer = self.start_timer("stats_delta"); // TODO: measure also the number of txns processed.
         let history_iter = self
-            .history_iter_scan(b'H', scripthash, start_height)
+            .snapshot_history_iter_scan(snapshot, b'H', scripthash, start_height)
             .map(TxHistoryRow::from_row)
+            .take_while(|history| {
+                end_height.map_or(true, |end_height| {
+                    history.key.confirmed_height as usize <= end_height
+                })
+            })
             .filter_map(|history| {
-                self.tx_confirming_block(&history.get_txid())
+                self.snapshot_tx_confirming_block(snapshot, &history.get_txid())
                     // drop history entries that were previously confirmed in a re-orged block and later
                     // confirmed again at a different height
                     .filter(|blockid| blockid.height == history.key.confirmed_height as usize)
@@ -700,12 +1890,14 @@ impl ChainQuery {
                 TxHistoryInfo::Funding(ref info) => {
                     stats.funded_txo_count += 1;
                     stats.funded_txo_sum += info.value;
+                    stats.balance += info.value as i64;
                 }
 
                 #[cfg(not(feature = "liquid"))]
                 TxHistoryInfo::Spending(ref info) => {
                     stats.spent_txo_count += 1;
                     stats.spent_txo_sum += info.value;
+                    stats.balance -= info.value as i64;
                 }
 
                 #[cfg(feature = "liquid")]
@@ -731,14 +1923,170 @@ impl ChainQuery {
         (stats, lastblock)
     }
 
+    // Confirmed activity of the scripthash per period, updated from the cached periods
+    pub fn activity(&self, scripthash: &[u8], granularity: Granularity) -> ActivityMap {
+        let _timer = self.start_timer("activity");
+        let cache_db = &self.store.cache_db;
+
+        let cache = activity::load_cache(cache_db, scripthash, granularity);
+        let had_cache = cache.is_some();
+        let (mut periods, start_height) = match cache {
+            Some((height, blockhash, periods))
+                if self.hash_by_height(height) == Some(blockhash) =>
+            {
+                (periods, height + 1)
+            }
+            Some(_) => {
+                activity::clear_cache(cache_db, scripthash, granularity);
+                (ActivityMap::new(), 0)
+            }
+            None => (ActivityMap::new(), 0),
+        };
+
+        let snapshot = self.store.snapshot();
+        let history_iter = self
+            .snapshot_history_iter_scan(&snapshot, b'H', scripthash, start_height)
+            .map(TxHistoryRow::from_row)
+            .filter_map(|history| {
+                self.snapshot_tx_confirming_block(&snapshot, &history.get_txid())
+                    .filter(|blockid| blockid.height == history.key.confirmed_height as usize)
+                    .map(|blockid| (history, blockid))
+            });
+
+        let mut updated_periods = BTreeSet::new();
+        let mut seen_txids = HashSet::new();
+        let mut lastblock: Option<BlockId> = None;
+        let mut processed_items = 0;
+
+        for (history, blockid) in history_iter {
+            if lastblock.as_ref().map(|b| b.hash) != Some(blockid.hash) {
+                seen_txids.clear();
+            }
+            let period_start = granularity.period_start(blockid.time);
+            let activity = periods.entry(period_start).or_default();
+            if seen_txids.insert(history.get_txid()) {
+                activity.tx_count += 1;
+            }
+            activity.add(&history.key.txinfo);
+
+            updated_periods.insert(period_start);
+            processed_items += 1;
+            lastblock = Some(blockid);
+        }
+
+        if let Some(lastblock) = lastblock {
+            if had_cache || processed_items > MIN_HISTORY_ITEMS_TO_CACHE {
+                activity::save_cache(
+                    cache_db,
+                    scripthash,
+                    granularity,
+                    lastblock.height,
+                    &lastblock.hash,
+                    periods
+                        .iter()
+                        .filter(|(period_start, _)| updated_periods.contains(*period_start)),
+                );
+            }
+        }
+
+        periods
+    }
+
+    // Per-day aggregates of the chain-wide block counters for the blocks timestamped within
+    // [from, to], along with the size of the UTXO set at the end of each day
+    pub fn chain_stats_per_day(&self, from: u32, to: u32) -> Vec<DailyChainStats> {
+        let _timer = self.start_timer("chain_stats_per_day");
+        let mut days: BTreeMap<u32, DailyChainStats> = BTreeMap::new();
+        let mut utxo_count: i64 = 0;
+
+        for row in self.store.history_db.iter_scan(&BlockCountersRow::filter()) {
+            let row = BlockCountersRow::from_row(row);
+            // skip leftovers of orphaned blocks
+            let header = match self.header_by_height(row.height as usize) {
+                Some(header) if *header.hash() == row.blockhash => header,
+                _ => continue,
+            };
+            // the size of the UTXO set is accumulated from genesis, regardless of the range
+            utxo_count += row.counters.utxo_delta;
+
+            let time = header.header().time;
+            if time < from || time > to {
+                continue;
+            }
+            let day = Granularity::Day.period_start(time);
+            let stats = days.entry(day).or_insert_with(|| DailyChainStats {
+                day,
+                ..Default::default()
+            });
+            stats.block_count += 1;
+            stats.tx_count += row.counters.tx_count as u64;
+            stats.input_count += row.counters.input_count as u64;
+            stats.output_count += row.counters.output_count as u64;
+            #[cfg(not(feature = "liquid"))]
+            {
+                stats.fees += row.counters.fees;
+            }
+            stats.utxo_count = utxo_count.max(0) as u64;
+
+            #[cfg(not(feature = "liquid"))]
+            if self.coin_days_destroyed {
+                if let Some(sat_days) = self.block_coin_days_destroyed(&row.blockhash) {
+                    *stats.coin_days_destroyed.get_or_insert(0.0) +=
+                        coin_days::to_coin_days(sat_days);
+                }
+            }
+
+            if self.script_type_stats && !self.light_mode {
+                let script_types = self
+                    .get_block_meta(&row.blockhash)
+                    .and_then(|meta| meta.script_types);
+                if let Some(script_types) = script_types {
+                    stats
+                        .script_types
+                        .get_or_insert_with(ScriptTypeCounts::default)
+                        .add(&script_types);
+                }
+            }
+        }
+        days.into_values().collect()
+    }
+
     pub fn address_search(&self, prefix: &str, limit: usize) -> Vec<String> {
         let _timer_scan = self.start_timer("address_search");
-        self.store
-            .history_db
+        let history_db = &self.store.history_db;
+        let exact = history_db
             .iter_scan(&addr_search_filter(prefix))
-            .take(limit)
-            .map(|row| std::str::from_utf8(&row.key[1..]).unwrap().to_string())
-            .collect()
+            .map(|row| std::str::from_utf8(&row.key[1..]).unwrap().to_string());
+
+        // bech32 addresses are case-insensitive, and can be searched for with or without their HRP
+        let normalized = prefix.to_ascii_lowercase();
+        let (hrp, data) = match normalized.split_once('1') {
+            Some((hrp, data)) => (Some(hrp), data),
+            None => (None, normalized.as_str()),
+        };
+        let is_bech32_data =
+            data.chars().all(|c| BECH32_CHARSET.contains(c)) && (hrp.is_some() || !data.is_empty());
+        let bech32 = Some(data)
+            .filter(|_| is_bech32_data)
+            .into_iter()
+            .flat_map(|data| history_db.iter_scan(&bech32_search_filter(data)))
+            .filter_map(|row| {
+                let row_hrp = std::str::from_utf8(&row.value).unwrap();
+                let row_data = std::str::from_utf8(&row.key[1..]).unwrap();
+                Some(format!("{}1{}", row_hrp, row_data))
+                    .filter(|_| hrp.map_or(true, |hrp| hrp == row_hrp))
+            });
+
+        let mut results = vec![];
+        for address in exact.chain(bech32) {
+            if results.len() == limit {
+                break;
+            }
+            if !results.contains(&address) {
+                results.push(address);
+            }
+        }
+        results
     }
 
     fn header_by_hash(&self, hash: &BlockHash) -> Option<HeaderEntry> {
@@ -769,6 +2117,15 @@ impl ChainQuery {
             .cloned()
     }
 
+    // Returns up to `count` consecutive headers starting at `start_height`
+    pub fn headers_range(&self, start_height: usize, count: usize) -> Vec<BlockHeader> {
+        let headers = self.store.indexed_headers.read().unwrap();
+        (start_height..start_height.saturating_add(count))
+            .map_while(|height| headers.header_by_height(height))
+            .map(|entry| entry.header().clone())
+            .collect()
+    }
+
     pub fn hash_by_height(&self, height: usize) -> Option<BlockHash> {
         self.store
             .indexed_headers
@@ -778,6 +2135,22 @@ impl ChainQuery {
             .map(|entry| *entry.hash())
     }
 
+    // Returns the hashes of all the blocks up to and including `height`, read under a single lock
+    // so that they're consistent with each other. Returns None if `height` is above the tip.
+    pub fn hashes_up_to(&self, height: usize) -> Option<Vec<BlockHash>> {
+        let headers = self.store.indexed_headers.read().unwrap();
+        if height >= headers.len() {
+            return None;
+        }
+        Some(
+            headers
+                .iter()
+                .take(height + 1)
+                .map(|entry| *entry.hash())
+                .collect(),
+        )
+    }
+
     pub fn blockid_by_height(&self, height: usize) -> Option<BlockId> {
         self.store
             .indexed_headers
@@ -797,6 +2170,21 @@ impl ChainQuery {
             .map(BlockId::from)
     }
 
+    pub fn address_cluster(&self, scripthash: &[u8]) -> Option<Cluster> {
+        let _timer = self.start_timer("address_cluster");
+        cluster::lookup_cluster(&self.store.history_db, scripthash)
+    }
+
+    pub fn address_cluster_members(
+        &self,
+        root: &FullHash,
+        last_seen: Option<&FullHash>,
+        limit: usize,
+    ) -> Vec<ClusterMember> {
+        let _timer = self.start_timer("address_cluster_members");
+        cluster::cluster_members(&self.store.history_db, root, last_seen, limit)
+    }
+
     pub fn best_height(&self) -> usize {
         self.store.indexed_headers.read().unwrap().len() - 1
     }
@@ -817,6 +2205,8 @@ impl ChainQuery {
     // TODO: should also use a custom ThreadPoolBuilder?
     pub fn lookup_txns(&self, txids: &[(Txid, BlockId)]) -> Result<Vec<Transaction>> {
         let _timer = self.start_timer("lookup_txns");
+        // accounted here, since the lookups run on the rayon thread pool
+        cost::add_txs_deserialized(txids.len() as u64);
         txids
             .par_iter()
             .map(|(txid, blockid)| {
@@ -826,9 +2216,35 @@ impl ChainQuery {
             .collect::<Result<Vec<Transaction>>>()
     }
 
+    fn snapshot_lookup_txns(
+        &self,
+        snapshot: &StoreSnapshot,
+        txids: &[(Txid, BlockId)],
+    ) -> Result<Vec<Transaction>> {
+        if self.light_mode {
+            // transactions are fetched from the daemon and are not affected by the snapshot
+            return self.lookup_txns(txids);
+        }
+        let _timer = self.start_timer("lookup_txns");
+        cost::add_txs_deserialized(txids.len() as u64);
+        txids
+            .par_iter()
+            .map(|(txid, _)| {
+                let rawtx = snapshot
+                    .txstore_db
+                    .get(&TxRow::key(&txid[..]))
+                    .chain_err(|| "missing tx")?;
+                let txn: Transaction = deserialize(&rawtx).expect("failed to parse Transaction");
+                assert_eq!(*txid, txn.txid());
+                Ok(txn)
+            })
+            .collect::<Result<Vec<Transaction>>>()
+    }
+
     pub fn lookup_txn(&self, txid: &Txid, blockhash: Option<&BlockHash>) -> Option<Transaction> {
         let _timer = self.start_timer("lookup_txn");
         self.lookup_raw_txn(txid, blockhash).map(|rawtx| {
+            cost::add_txs_deserialized(1);
             let txn: Transaction = deserialize(&rawtx).expect("failed to parse Transaction");
             assert_eq!(*txid, txn.txid());
             txn
@@ -837,7 +2253,15 @@ impl ChainQuery {
 
     pub fn lookup_raw_txn(&self, txid: &Txid, blockhash: Option<&BlockHash>) -> Option<Bytes> {
         let _timer = self.start_timer("lookup_raw_txn");
+        if blockhash.is_some() {
+            return self.lookup_raw_txn_uncached(txid, blockhash);
+        }
+        self.lookup_unless_missing(&self.missing_txs, txid, || {
+            self.lookup_raw_txn_uncached(txid, None)
+        })
+    }
 
+    fn lookup_raw_txn_uncached(&self, txid: &Txid, blockhash: Option<&BlockHash>) -> Option<Bytes> {
         if self.light_mode {
             let queried_blockhash =
                 blockhash.map_or_else(|| self.tx_confirming_block(txid).map(|b| b.hash), |_| None);
@@ -869,6 +2293,15 @@ impl ChainQuery {
         lookup_txos(&self.store.txstore_db, outpoints, true)
     }
 
+    #[cfg(not(feature = "liquid"))]
+    pub fn lookup_avail_txo_values(
+        &self,
+        outpoints: &BTreeSet<OutPoint>,
+    ) -> HashMap<OutPoint, Value> {
+        let _timer = self.start_timer("lookup_available_txo_values");
+        lookup_txo_values(&self.store.txstore_db, outpoints, true)
+    }
+
     pub fn lookup_spend(&self, outpoint: &OutPoint) -> Option<SpendingInput> {
         let _timer = self.start_timer("lookup_spend");
         self.store
@@ -884,12 +2317,118 @@ impl ChainQuery {
                 })
             })
     }
+
+    // The confirmed transactions funding or spending the x-only pubkey, when indexed
+    #[cfg(not(feature = "liquid"))]
+    pub fn pubkey_history(
+        &self,
+        pubkey: &[u8],
+        last_seen_txid: Option<&Txid>,
+        limit: usize,
+        order: HistoryOrder,
+    ) -> Vec<(Transaction, BlockId)> {
+        self._history(b'x', pubkey, last_seen_txid, limit, order)
+    }
+
+    // The number of distinct confirmed transactions funding the scripthash, or None if address
+    // reuse isn't indexed. The count is cached along with the last block it covers, and only the
+    // rows of the blocks added since are checked:
+    //      q{scripthash} → {count}{blockhash}
+    pub fn funding_tx_count(&self, scripthash: &[u8]) -> Option<usize> {
+        if !self.address_reuse {
+            return None;
+        }
+        let _timer = self.start_timer("funding_tx_count");
+
+        // invalidated if the block was orphaned
+        let (mut count, start_height) = self
+            .store
+            .cache_db
+            .get(&FundingTxCountRow::key(scripthash))
+            .map(|c| bincode::deserialize_little(&c).unwrap())
+            .and_then(|(count, blockhash): (u64, BlockHash)| {
+                self.height_by_hash(&blockhash)
+                    .map(|height| (count as usize, height + 1))
+            })
+            .unwrap_or((0, 0));
+
+        let mut lastblock = None;
+        let mut processed_items = 0;
+        let rows = self.store.history_db.iter_scan_from(
+            &FundingTxRow::filter(scripthash),
+            &FundingTxRow::filter_from(scripthash, start_height as u32),
+        );
+        for row in rows.map(FundingTxRow::from_row) {
+            processed_items += 1;
+            let txid = deserialize(&row.txid).expect("cannot parse Txid");
+            // skip leftovers of orphaned blocks
+            match self.tx_confirming_block(&txid) {
+                Some(blockid) if blockid.height == row.height as usize => {
+                    count += 1;
+                    lastblock = Some(blockid.hash);
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(lastblock) = lastblock {
+            if start_height > 0 || processed_items > MIN_HISTORY_ITEMS_TO_CACHE {
+                self.cache_writer
+                    .write(FundingTxCountRow::row(scripthash, count, &lastblock));
+            }
+        }
+        Some(count)
+    }
+
     pub fn tx_confirming_block(&self, txid: &Txid) -> Option<BlockId> {
         let _timer = self.start_timer("tx_confirming_block");
-        let headers = self.store.indexed_headers.read().unwrap();
-        self.store
+        self.lookup_unless_missing(&self.unconfirmed_txids, txid, || {
+            self.confirming_block(
+                self.store
+                    .txstore_db
+                    .iter_scan(&TxConfRow::filter(&txid[..])),
+            )
+        })
+    }
+
+    // Looked up in parallel on a single snapshot, for the statuses to be consistent with each other
+    pub fn txs_confirming_blocks(&self, txids: &[Txid]) -> Vec<Option<BlockId>> {
+        let snapshot = self.store.snapshot();
+        txids
+            .par_iter()
+            .map(|txid| self.snapshot_tx_confirming_block(&snapshot, txid))
+            .collect()
+    }
+
+    // Only transactions with witnesses have a wtxid → txid row, the others are looked up by txid
+    pub fn lookup_txid_by_wtxid(&self, wtxid: &Wtxid) -> Option<Txid> {
+        let _timer = self.start_timer("lookup_txid_by_wtxid");
+        let row = self
+            .store
             .txstore_db
-            .iter_scan(&TxConfRow::filter(&txid[..]))
+            .iter_scan(&TxWtxidRow::filter(&wtxid[..]))
+            .next();
+        match row {
+            Some(row) => Some(deserialize(&TxWtxidRow::from_row(row).key.txid).unwrap()),
+            None => {
+                let txid = deserialize(&wtxid[..]).unwrap();
+                self.tx_confirming_block(&txid).map(|_| txid)
+            }
+        }
+    }
+
+    fn snapshot_tx_confirming_block(
+        &self,
+        snapshot: &StoreSnapshot,
+        txid: &Txid,
+    ) -> Option<BlockId> {
+        let _timer = self.start_timer("tx_confirming_block");
+        self.confirming_block(snapshot.txstore_db.iter_scan(&TxConfRow::filter(&txid[..])))
+    }
+
+    fn confirming_block(&self, conf_rows: ScanIterator) -> Option<BlockId> {
+        let headers = self.store.indexed_headers.read().unwrap();
+        conf_rows
             .map(TxConfRow::from_row)
             // header_by_blockhash only returns blocks that are part of the best chain,
             // or None for orphaned blocks.
@@ -941,7 +2480,13 @@ impl ChainQuery {
         last_seen_txid: Option<&Txid>,
         limit: usize,
     ) -> Vec<(Transaction, BlockId)> {
-        self._history(b'I', &asset_id.into_inner()[..], last_seen_txid, limit)
+        self._history(
+            b'I',
+            &asset_id.into_inner()[..],
+            last_seen_txid,
+            limit,
+            HistoryOrder::Descending,
+        )
     }
 
     #[cfg(feature = "liquid")]
@@ -950,6 +2495,21 @@ impl ChainQuery {
     }
 }
 
+fn log_phase(phase: &str, blocks: usize, started: Instant) {
+    tracing::debug!(
+        target: "electrs::indexer",
+        phase,
+        blocks,
+        duration_ms = started.elapsed().as_millis() as u64,
+        "indexer phase completed"
+    );
+}
+
+fn is_done(db: &DB, blockhash: &BlockHash) -> bool {
+    db.get(&BlockRow::done_key(full_hash(&blockhash[..])))
+        .is_some()
+}
+
 fn load_blockhashes(db: &DB, prefix: &[u8]) -> HashSet<BlockHash> {
     db.iter_scan(prefix)
         .map(BlockRow::from_row)
@@ -968,15 +2528,72 @@ fn load_blockheaders(db: &DB) -> HashMap<BlockHash, BlockHeader> {
         .collect()
 }
 
-fn add_blocks(block_entries: &[BlockEntry], iconfig: &IndexerConfig) -> Vec<DBRow> {
+// The chain of indexed headers is also persisted in chunks, so that it can be restored on startup
+// without going through every B row and linking the headers up again:
+//      h{chunk-index} → {blockhash1}...{blockhashN}{header1}...{headerN}
+fn headers_chunk_key(index: usize) -> Bytes {
+    [b"h", &(index as u32).to_be_bytes()[..]].concat()
+}
+
+fn load_headers_chain(db: &DB) -> Option<HeaderList> {
+    let mut chain = vec![];
+    for (index, row) in db.iter_scan(b"h").enumerate() {
+        if row.key != headers_chunk_key(index) {
+            return None;
+        }
+        let (hashes, headers): (Vec<BlockHash>, Vec<Bytes>) =
+            bincode::deserialize_little(&row.value).ok()?;
+        for (hash, header) in hashes.into_iter().zip(headers) {
+            chain.push((hash, deserialize(&header).ok()?));
+        }
+    }
+    HeaderList::from_chain(chain)
+}
+
+// Rewrites the chunks from the one containing the given height, which is where the chain changed
+fn save_headers_chain(db: &DB, headers: &HeaderList, changed_height: usize) {
+    let first_chunk = changed_height / HEADERS_CHUNK_SIZE;
+    let entries: Vec<&HeaderEntry> = headers
+        .iter()
+        .skip(first_chunk * HEADERS_CHUNK_SIZE)
+        .collect();
+    let rows: Vec<DBRow> = entries
+        .chunks(HEADERS_CHUNK_SIZE)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hashes: Vec<BlockHash> = chunk.iter().map(|entry| *entry.hash()).collect();
+            let headers: Vec<Bytes> = chunk
+                .iter()
+                .map(|entry| serialize(entry.header()))
+                .collect();
+            DBRow {
+                key: headers_chunk_key(first_chunk + i),
+                value: bincode::serialize_little(&(hashes, headers)).unwrap(),
+            }
+        })
+        .collect();
+    // drop the chunks past the tip, left behind by a reorg to a shorter chain
+    let next_chunk = (headers.len() + HEADERS_CHUNK_SIZE - 1) / HEADERS_CHUNK_SIZE;
+    let deletes = db
+        .iter_scan_from(b"h", &headers_chunk_key(next_chunk))
+        .map(|row| row.key)
+        .collect();
+    db.write_and_delete(rows, deletes, DBFlush::Enable);
+}
+
+fn add_blocks(
+    block_entries: &[BlockEntry],
+    iconfig: &IndexerConfig,
+    script_ids: &HashMap<&Script, u64>,
+) -> Vec<DBRow> {
     // persist individual transactions:
     //      T{txid} → {rawtx}
     //      C{txid}{blockhash}{height} →
-    //      O{txid}{index} → {txout}
+    //      O{txid}{index} → {txout}, or with its script replaced by an id (see script_dict)
     // persist block headers', block txids' and metadata rows:
     //      B{blockhash} → {header}
     //      X{blockhash} → {txid1}...{txidN}
-    //      M{blockhash} → {tx_count}{size}{weight}
+    //      M{blockhash} → {tx_count}{size}{weight}{script_types}
     block_entries
         .par_iter() // serialization is CPU-intensive
         .map(|b| {
@@ -984,12 +2601,24 @@ fn add_blocks(block_entries: &[BlockEntry], iconfig: &IndexerConfig) -> Vec<DBRo
             let blockhash = full_hash(&b.entry.hash()[..]);
             let txids: Vec<Txid> = b.block.txdata.iter().map(|tx| tx.txid()).collect();
             for tx in &b.block.txdata {
-                add_transaction(tx, blockhash, &mut rows, iconfig);
+                add_transaction(tx, blockhash, &mut rows, iconfig, script_ids);
+            }
+            #[cfg(not(feature = "liquid"))]
+            if iconfig.utxo_muhash {
+                utxo_hash::add_coin_codes(b, &mut rows);
+            }
+            #[cfg(not(feature = "liquid"))]
+            if iconfig.coin_days_destroyed {
+                coin_days::add_tx_times(b, &mut rows);
             }
 
             if !iconfig.light_mode {
                 rows.push(BlockRow::new_txids(blockhash, &txids).into_row());
-                rows.push(BlockRow::new_meta(blockhash, &BlockMeta::from(b)).into_row());
+                let mut meta = BlockMeta::from(b);
+                if iconfig.script_type_stats {
+                    meta.script_types = Some(ScriptTypeCounts::from_block(&b.block));
+                }
+                rows.push(BlockRow::new_meta(blockhash, &meta).into_row());
             }
 
             rows.push(BlockRow::new_header(&b).into_row());
@@ -1005,6 +2634,7 @@ fn add_transaction(
     blockhash: FullHash,
     rows: &mut Vec<DBRow>,
     iconfig: &IndexerConfig,
+    script_ids: &HashMap<&Script, u64>,
 ) {
     rows.push(TxConfRow::new(tx, blockhash).into_row());
 
@@ -1013,9 +2643,16 @@ fn add_transaction(
     }
 
     let txid = full_hash(&tx.txid()[..]);
+    let wtxid = full_hash(&tx.wtxid()[..]);
+    // the wtxid of transactions without witnesses is their txid
+    if wtxid != txid {
+        rows.push(TxWtxidRow::new(wtxid, txid).into_row());
+    }
+
     for (txo_index, txo) in tx.output.iter().enumerate() {
         if is_spendable(txo) {
-            rows.push(TxOutRow::new(&txid, txo_index, txo).into_row());
+            let script_id = script_ids.get(&txo.script_pubkey).copied();
+            rows.push(TxOutRow::new(&txid, txo_index, txo, script_id).into_row());
         }
     }
 }
@@ -1038,6 +2675,28 @@ fn lookup_txos(
     outpoints: &BTreeSet<OutPoint>,
     allow_missing: bool,
 ) -> HashMap<OutPoint, TxOut> {
+    lookup_txos_with(txstore_db, outpoints, allow_missing, lookup_txo)
+}
+
+// Like lookup_txos(), for the callers that only need the values, namely the fee computation of
+// the transactions indexed before the fee rows were stored. Indexing needs the full outputs (the
+// spending history rows need their scripts), and the utxo and stats queries read the values from
+// the history rows.
+#[cfg(not(feature = "liquid"))]
+fn lookup_txo_values(
+    txstore_db: &DB,
+    outpoints: &BTreeSet<OutPoint>,
+    allow_missing: bool,
+) -> HashMap<OutPoint, Value> {
+    lookup_txos_with(txstore_db, outpoints, allow_missing, lookup_txo_value)
+}
+
+fn lookup_txos_with<T: Send>(
+    txstore_db: &DB,
+    outpoints: &BTreeSet<OutPoint>,
+    allow_missing: bool,
+    lookup: fn(&DB, &OutPoint) -> Option<T>,
+) -> HashMap<OutPoint, T> {
     let pool = rayon::ThreadPoolBuilder::new()
         .num_threads(16) // we need to saturate SSD IOPS
         .thread_name(|i| format!("lookup-txo-{}", i))
@@ -1047,7 +2706,7 @@ fn lookup_txos(
         outpoints
             .par_iter()
             .filter_map(|outpoint| {
-                lookup_txo(&txstore_db, &outpoint)
+                lookup(&txstore_db, &outpoint)
                     .or_else(|| {
                         if !allow_missing {
                             panic!("missing txo {} in {:?}", outpoint, txstore_db);
@@ -1063,7 +2722,22 @@ fn lookup_txos(
 fn lookup_txo(txstore_db: &DB, outpoint: &OutPoint) -> Option<TxOut> {
     txstore_db
         .get(&TxOutRow::key(&outpoint))
-        .map(|val| deserialize(&val).expect("failed to parse TxOut"))
+        .map(|val| script_dict::decode_txo(txstore_db, &val))
+}
+
+// The value is the first field of the consensus-serialized TxOut, and can be decoded without
+// parsing the rest of it (most notably the scriptPubKey)
+#[cfg(not(feature = "liquid"))]
+fn lookup_txo_value(txstore_db: &DB, outpoint: &OutPoint) -> Option<Value> {
+    txstore_db
+        .get(&TxOutRow::key(&outpoint))
+        .map(|val| parse_txo_value(&val))
+}
+
+#[cfg(not(feature = "liquid"))]
+fn parse_txo_value(txo: &[u8]) -> Value {
+    let (value, _) = deserialize_partial(txo).expect("failed to parse TxOut value");
+    value
 }
 
 fn index_blocks(
@@ -1075,10 +2749,46 @@ fn index_blocks(
         .par_iter() // serialization is CPU-intensive
         .map(|b| {
             let mut rows = vec![];
-            for tx in &b.block.txdata {
+            // hash the scripts of the whole block at once, rather than one by one
+            let scripts: Vec<&Script> = b
+                .block
+                .txdata
+                .iter()
+                .flat_map(|tx| tx_scripts(tx, previous_txos_map))
+                .collect();
+            let mut scripthashes = compute_script_hashes(&scripts).into_iter();
+            for (position, tx) in b.block.txdata.iter().enumerate() {
                 let height = b.entry.height() as u32;
-                index_transaction(tx, height, previous_txos_map, &mut rows, iconfig);
+                index_transaction(
+                    tx,
+                    height,
+                    position as u32,
+                    previous_txos_map,
+                    &mut scripthashes,
+                    &mut rows,
+                    iconfig,
+                );
+            }
+            #[cfg(feature = "runes")]
+            runes::index_block_runes(b, &mut rows);
+            // persist the block's fee totals:
+            //      R{blockhash} → {fees}{subsidy}{output-value}{sigop-cost}
+            #[cfg(not(feature = "liquid"))]
+            let reward = block_reward(&b.block, previous_txos_map);
+            #[cfg(not(feature = "liquid"))]
+            rows.push(BlockRow::new_reward(full_hash(&b.entry.hash()[..]), &reward).into_row());
+
+            // persist the block's chain-wide counters, by height:
+            //      G{height} → {blockhash}{counters}
+            #[allow(unused_mut)]
+            let mut counters = block_counters(&b.block);
+            #[cfg(not(feature = "liquid"))]
+            {
+                counters.fees = reward.fees;
             }
+            rows.push(
+                BlockCountersRow::new(b.entry.height(), *b.entry.hash(), counters).into_row(),
+            );
             rows.push(BlockRow::new_done(full_hash(&b.entry.hash()[..])).into_row()); // mark block as "indexed"
             rows
         })
@@ -1086,25 +2796,225 @@ fn index_blocks(
         .collect()
 }
 
+fn block_counters(block: &Block) -> BlockCounters {
+    let mut counters = BlockCounters {
+        tx_count: block.txdata.len() as u32,
+        ..Default::default()
+    };
+    for tx in &block.txdata {
+        let spent = tx.input.iter().filter(|txin| has_prevout(txin)).count();
+        let created = tx.output.iter().filter(|txout| is_spendable(txout)).count();
+        counters.input_count += spent as u32;
+        counters.output_count += tx.output.len() as u32;
+        counters.utxo_delta += created as i64 - spent as i64;
+    }
+    counters
+}
+
+#[cfg(not(feature = "liquid"))]
+fn block_reward(block: &Block, previous_txos_map: &HashMap<OutPoint, TxOut>) -> BlockReward {
+    let (mut fees, mut coinbase_value, mut output_value, mut sigop_cost) = (0, 0, 0, 0);
+    for tx in &block.txdata {
+        let tx_output_value: u64 = tx.output.iter().map(|txo| txo.value.amount_value()).sum();
+        output_value += tx_output_value;
+        sigop_cost +=
+            tx.total_sigop_cost(|outpoint| previous_txos_map.get(outpoint).cloned()) as u64;
+        if tx.is_coinbase() {
+            coinbase_value = tx_output_value;
+        } else {
+            fees += tx_fee(tx, previous_txos_map);
+        }
+    }
+    BlockReward {
+        fees,
+        subsidy: coinbase_value.saturating_sub(fees),
+        output_value,
+        sigop_cost: Some(sigop_cost),
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+fn tx_fee(tx: &Transaction, previous_txos_map: &HashMap<OutPoint, TxOut>) -> u64 {
+    let input_value: u64 = tx
+        .input
+        .iter()
+        .filter(|txi| has_prevout(txi))
+        .map(|txi| {
+            previous_txos_map
+                .get(&txi.previous_output)
+                .unwrap_or_else(|| panic!("missing previous txo {}", txi.previous_output))
+                .value
+                .amount_value()
+        })
+        .sum();
+    let output_value: u64 = tx.output.iter().map(|txo| txo.value.amount_value()).sum();
+    input_value - output_value
+}
+
+// The scripts funded and spent by the transaction, in the order index_transaction() expects their
+// hashes: the outputs, followed by the prevouts of the inputs
+fn tx_scripts<'a>(
+    tx: &'a Transaction,
+    previous_txos_map: &'a HashMap<OutPoint, TxOut>,
+) -> impl Iterator<Item = &'a Script> + 'a {
+    let funded = tx.output.iter().map(|txo| &txo.script_pubkey);
+    let spent = tx
+        .input
+        .iter()
+        .filter(|txi| has_prevout(txi))
+        .map(move |txi| {
+            &previous_txos_map
+                .get(&txi.previous_output)
+                .unwrap_or_else(|| panic!("missing previous txo {}", txi.previous_output))
+                .script_pubkey
+        });
+    funded.chain(spent)
+}
+
 // TODO: return an iterator?
 fn index_transaction(
     tx: &Transaction,
     confirmed_height: u32,
+    tx_position: u32,
     previous_txos_map: &HashMap<OutPoint, TxOut>,
+    scripthashes: &mut impl Iterator<Item = FullHash>,
     rows: &mut Vec<DBRow>,
     iconfig: &IndexerConfig,
 ) {
+    let funded_hashes: Vec<FullHash> = scripthashes.take(tx.output.len()).collect();
+
     // persist history index:
-    //      H{funding-scripthash}{funding-height}F{funding-txid:vout} → ""
-    //      H{funding-scripthash}{spending-height}S{spending-txid:vin}{funding-txid:vout} → ""
+    //      H{funding-scripthash}{funding-height}{funding-tx-position}F{funding-txid:vout} → ""
+    //      H{funding-scripthash}{spending-height}{spending-tx-position}S{spending-txid:vin}{funding-txid:vout} → ""
     // persist "edges" for fast is-this-TXO-spent check
     //      S{funding-txid:vout}{spending-txid:vin} → ""
+    // persist a marker of the funded scripthashes, for fast negative lookups of unused ones:
+    //      E{funding-scripthash} → ""
     let txid = full_hash(&tx.txid()[..]);
     for (txo_index, txo) in tx.output.iter().enumerate() {
-        if is_spendable(txo) || iconfig.index_unspendables {
-            let history = TxHistoryRow::new(
-                &txo.script_pubkey,
+        if is_spendable(txo) || iconfig.index_unspendables {
+            let history = TxHistoryRow::new(
+                funded_hashes[txo_index],
+                confirmed_height,
+                tx_position,
+                TxHistoryInfo::Funding(FundingInfo {
+                    txid,
+                    vout: txo_index as u16,
+                    value: txo.value.amount_value(),
+                }),
+            );
+            rows.push(history.into_row());
+            if iconfig.history_bloom {
+                rows.push(TxHistoryRow::marker_row(&funded_hashes[txo_index]));
+            }
+
+            if iconfig.address_search {
+                rows.extend(addr_search_rows(&txo.script_pubkey, iconfig.network));
+            }
+        }
+    }
+    // persist the distinct transactions funding each scripthash, for address reuse detection:
+    //      Q{funding-scripthash}{funding-height}{funding-txid} → ""
+    if iconfig.address_reuse {
+        let funded: HashSet<FullHash> = tx
+            .output
+            .iter()
+            .zip(&funded_hashes)
+            .filter(|(txo, _)| is_spendable(txo) || iconfig.index_unspendables)
+            .map(|(_, scripthash)| *scripthash)
+            .collect();
+        rows.extend(
+            funded
+                .into_iter()
+                .map(|scripthash| FundingTxRow::new(scripthash, confirmed_height, txid).into_row()),
+        );
+    }
+    for (txi_index, txi) in tx.input.iter().enumerate() {
+        if !has_prevout(txi) {
+            continue;
+        }
+        let prev_txo = previous_txos_map
+            .get(&txi.previous_output)
+            .unwrap_or_else(|| panic!("missing previous txo {}", txi.previous_output));
+
+        let history = TxHistoryRow::new(
+            scripthashes.next().expect("missing prevout scripthash"),
+            confirmed_height,
+            tx_position,
+            TxHistoryInfo::Spending(SpendingInfo {
+                txid,
+                vin: txi_index as u16,
+                prev_txid: full_hash(&txi.previous_output.txid[..]),
+                prev_vout: txi.previous_output.vout as u16,
+                value: prev_txo.value.amount_value(),
+            }),
+        );
+        rows.push(history.into_row());
+
+        let edge = TxEdgeRow::new(
+            full_hash(&txi.previous_output.txid[..]),
+            txi.previous_output.vout as u16,
+            txid,
+            txi_index as u16,
+        );
+        rows.push(edge.into_row());
+    }
+
+    // persist the fee, so that it doesn't have to be recomputed from the prevouts:
+    //      f{txid} → {fee}
+    #[cfg(not(feature = "liquid"))]
+    if !tx.is_coinbase() {
+        rows.push(TxFeeRow::new(txid, tx_fee(tx, previous_txos_map)).into_row());
+    }
+
+    // persist the weight and sigop cost, so that they don't have to be recomputed:
+    //      w{txid} → {weight}{vsize}{sigop-cost}
+    rows.push(TxStatsRow::new(txid, TxStats::new(tx, previous_txos_map)).into_row());
+
+    // persist the pubkey index, in the same format as the history index:
+    //      x{funding-pubkey}{funding-height}{funding-tx-position}F{funding-txid:vout} → ""
+    //      x{spending-pubkey}{spending-height}{spending-tx-position}S{spending-txid:vin}{funding-txid:vout} → ""
+    #[cfg(not(feature = "liquid"))]
+    if iconfig.index_pubkeys {
+        index_transaction_pubkeys(
+            tx,
+            txid,
+            confirmed_height,
+            tx_position,
+            previous_txos_map,
+            rows,
+        );
+    }
+
+    // Index issued assets & native asset pegins/pegouts/burns
+    #[cfg(feature = "liquid")]
+    asset::index_confirmed_tx_assets(
+        tx,
+        confirmed_height,
+        tx_position,
+        iconfig.network,
+        iconfig.parent_network,
+        rows,
+    );
+}
+
+// Indexes the taproot output keys funded and spent by the transaction, along with the keys revealed
+// by spending P2WPKH outputs (which only commit to the hash of their key), by their x-only form
+#[cfg(not(feature = "liquid"))]
+fn index_transaction_pubkeys(
+    tx: &Transaction,
+    txid: FullHash,
+    confirmed_height: u32,
+    tx_position: u32,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    rows: &mut Vec<DBRow>,
+) {
+    for (txo_index, txo) in tx.output.iter().enumerate() {
+        if let Some(pubkey) = p2tr_output_key(&txo.script_pubkey) {
+            let history = TxHistoryRow::new_pubkey(
+                pubkey,
                 confirmed_height,
+                tx_position,
                 TxHistoryInfo::Funding(FundingInfo {
                     txid,
                     vout: txo_index as u16,
@@ -1112,12 +3022,6 @@ fn index_transaction(
                 }),
             );
             rows.push(history.into_row());
-
-            if iconfig.address_search {
-                if let Some(row) = addr_search_row(&txo.script_pubkey, iconfig.network) {
-                    rows.push(row);
-                }
-            }
         }
     }
     for (txi_index, txi) in tx.input.iter().enumerate() {
@@ -1127,60 +3031,115 @@ fn index_transaction(
         let prev_txo = previous_txos_map
             .get(&txi.previous_output)
             .unwrap_or_else(|| panic!("missing previous txo {}", txi.previous_output));
+        let pubkey = if prev_txo.script_pubkey.is_p2wpkh() {
+            p2wpkh_witness_key(&txi.witness)
+        } else {
+            p2tr_output_key(&prev_txo.script_pubkey)
+        };
+        if let Some(pubkey) = pubkey {
+            let history = TxHistoryRow::new_pubkey(
+                pubkey,
+                confirmed_height,
+                tx_position,
+                TxHistoryInfo::Spending(SpendingInfo {
+                    txid,
+                    vin: txi_index as u16,
+                    prev_txid: full_hash(&txi.previous_output.txid[..]),
+                    prev_vout: txi.previous_output.vout as u16,
+                    value: prev_txo.value.amount_value(),
+                }),
+            );
+            rows.push(history.into_row());
+        }
+    }
+}
 
-        let history = TxHistoryRow::new(
-            &prev_txo.script_pubkey,
-            confirmed_height,
-            TxHistoryInfo::Spending(SpendingInfo {
-                txid,
-                vin: txi_index as u16,
-                prev_txid: full_hash(&txi.previous_output.txid[..]),
-                prev_vout: txi.previous_output.vout as u16,
-                value: prev_txo.value.amount_value(),
-            }),
-        );
-        rows.push(history.into_row());
-
-        let edge = TxEdgeRow::new(
-            full_hash(&txi.previous_output.txid[..]),
-            txi.previous_output.vout as u16,
-            txid,
-            txi_index as u16,
-        );
-        rows.push(edge.into_row());
+#[cfg(not(feature = "liquid"))]
+fn p2tr_output_key(spk: &Script) -> Option<FullHash> {
+    if spk.is_p2tr() {
+        spk.as_bytes()[2..34].try_into().ok()
+    } else {
+        None
     }
+}
 
-    // Index issued assets & native asset pegins/pegouts/burns
-    #[cfg(feature = "liquid")]
-    asset::index_confirmed_tx_assets(
-        tx,
-        confirmed_height,
-        iconfig.network,
-        iconfig.parent_network,
-        rows,
-    );
+// The x-only form of the compressed key pushed by a P2WPKH witness
+#[cfg(not(feature = "liquid"))]
+fn p2wpkh_witness_key(witness: &bitcoin::Witness) -> Option<FullHash> {
+    match witness.nth(1) {
+        Some(pubkey) if witness.len() == 2 && pubkey.len() == 33 => pubkey[1..].try_into().ok(),
+        _ => None,
+    }
 }
 
-fn addr_search_row(spk: &Script, network: Network) -> Option<DBRow> {
-    spk.to_address_str(network).map(|address| DBRow {
+// Witness program addresses are also indexed by their bech32 data part (following the HRP and
+// separator), to be found when searched for without the HRP
+fn addr_search_rows(spk: &Script, network: Network) -> Vec<DBRow> {
+    let address = match spk.to_address_str(network) {
+        Some(address) => address,
+        None => return vec![],
+    };
+    let mut rows = vec![];
+    if spk.is_witness_program() {
+        if let Some((hrp, data)) = address.rsplit_once('1') {
+            rows.push(DBRow {
+                key: [b"b", data.as_bytes()].concat(),
+                value: hrp.as_bytes().to_vec(),
+            });
+        }
+    }
+    rows.push(DBRow {
         key: [b"a", address.as_bytes()].concat(),
         value: vec![],
-    })
+    });
+    rows
 }
 
 fn addr_search_filter(prefix: &str) -> Bytes {
     [b"a", prefix.as_bytes()].concat()
 }
 
+fn bech32_search_filter(data_prefix: &str) -> Bytes {
+    [b"b", data_prefix.as_bytes()].concat()
+}
+
 // TODO: replace by a separate opaque type (similar to Sha256dHash, but without the "double")
 pub type FullHash = [u8; 32]; // serialized SHA256 result
 
+// The history db is flagged when created with --history-bloom. Its markers only cover every
+// funded scripthash if the option stays enabled from the initial sync on, so a mismatch between
+// the flag and the option would have unused-looking scripthashes return no history.
+fn verify_history_bloom(history_db: &DB, enabled: bool) {
+    let flagged = history_db.get(HISTORY_BLOOM_KEY).is_some();
+    if enabled && !flagged {
+        // nothing but the compatibility version, i.e. before the initial sync
+        let is_new = history_db.iter_scan(b"").all(|row| row.key == b"V");
+        if !is_new {
+            panic!("--history-bloom must be enabled from the initial sync, reindex to enable it");
+        }
+        history_db.put(HISTORY_BLOOM_KEY, b"");
+    } else if !enabled && flagged {
+        panic!("the index was built with --history-bloom, which must remain enabled (or reindex without it)");
+    }
+}
+
 pub fn compute_script_hash(script: &Script) -> FullHash {
-    let mut hash = FullHash::default();
-    let mut sha2 = Sha256::new();
-    sha2.input(script.as_bytes());
-    sha2.result(&mut hash);
-    hash
+    sha256::Hash::hash(script.as_bytes()).to_byte_array()
+}
+
+// Hashes the scripts in order, splitting large batches (like all the scripts of a block) across
+// the rayon thread pool
+pub fn compute_script_hashes(scripts: &[&Script]) -> Vec<FullHash> {
+    if scripts.len() < SCRIPT_HASH_BATCH_SIZE {
+        return scripts
+            .iter()
+            .map(|script| compute_script_hash(script))
+            .collect();
+    }
+    scripts
+        .par_chunks(SCRIPT_HASH_BATCH_SIZE)
+        .flat_map_iter(|chunk| chunk.iter().map(|script| compute_script_hash(script)))
+        .collect()
 }
 
 pub fn parse_hash(hash: &FullHash) -> Sha256dHash {
@@ -1261,6 +3220,175 @@ impl TxConfRow {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct TxWtxidKey {
+    code: u8,
+    wtxid: FullHash,
+    txid: FullHash,
+}
+
+struct TxWtxidRow {
+    key: TxWtxidKey,
+}
+
+impl TxWtxidRow {
+    fn new(wtxid: FullHash, txid: FullHash) -> TxWtxidRow {
+        TxWtxidRow {
+            key: TxWtxidKey {
+                code: b'W',
+                wtxid,
+                txid,
+            },
+        }
+    }
+
+    fn filter(wtxid: &[u8]) -> Bytes {
+        [b"W", wtxid].concat()
+    }
+
+    fn into_row(self) -> DBRow {
+        DBRow {
+            key: bincode::serialize_little(&self.key).unwrap(),
+            value: vec![],
+        }
+    }
+
+    fn from_row(row: DBRow) -> Self {
+        TxWtxidRow {
+            key: bincode::deserialize_little(&row.key).expect("failed to parse TxWtxidKey"),
+        }
+    }
+}
+
+struct BlockCountersRow {
+    height: u32,
+    blockhash: BlockHash,
+    counters: BlockCounters,
+}
+
+impl BlockCountersRow {
+    fn new(height: usize, blockhash: BlockHash, counters: BlockCounters) -> Self {
+        BlockCountersRow {
+            height: height as u32,
+            blockhash,
+            counters,
+        }
+    }
+
+    fn filter() -> Bytes {
+        b"G".to_vec()
+    }
+
+    fn into_row(self) -> DBRow {
+        DBRow {
+            key: [b"G", &self.height.to_be_bytes()[..]].concat(),
+            value: bincode::serialize_little(&(self.blockhash, self.counters)).unwrap(),
+        }
+    }
+
+    fn from_row(row: DBRow) -> Self {
+        let height = u32::from_be_bytes(row.key[1..].try_into().unwrap());
+        let (blockhash, counters) =
+            bincode::deserialize_little(&row.value).expect("failed to parse BlockCounters");
+        BlockCountersRow {
+            height,
+            blockhash,
+            counters,
+        }
+    }
+}
+
+struct FundingTxRow {
+    scripthash: FullHash,
+    height: u32,
+    txid: FullHash,
+}
+
+impl FundingTxRow {
+    fn new(scripthash: FullHash, height: u32, txid: FullHash) -> Self {
+        FundingTxRow {
+            scripthash,
+            height,
+            txid,
+        }
+    }
+
+    fn filter(scripthash: &[u8]) -> Bytes {
+        [b"Q", scripthash].concat()
+    }
+
+    fn filter_from(scripthash: &[u8], height: u32) -> Bytes {
+        [b"Q", scripthash, &height.to_be_bytes()].concat()
+    }
+
+    fn into_row(self) -> DBRow {
+        DBRow {
+            key: [
+                b"Q",
+                &self.scripthash[..],
+                &self.height.to_be_bytes(),
+                &self.txid[..],
+            ]
+            .concat(),
+            value: vec![],
+        }
+    }
+
+    fn from_row(row: DBRow) -> Self {
+        FundingTxRow {
+            scripthash: row.key[1..33].try_into().unwrap(),
+            height: u32::from_be_bytes(row.key[33..37].try_into().unwrap()),
+            txid: row.key[37..].try_into().unwrap(),
+        }
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+struct TxFeeRow {
+    txid: FullHash,
+    fee: u64,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl TxFeeRow {
+    fn new(txid: FullHash, fee: u64) -> TxFeeRow {
+        TxFeeRow { txid, fee }
+    }
+
+    fn key(txid: &[u8]) -> Bytes {
+        [b"f", txid].concat()
+    }
+
+    fn into_row(self) -> DBRow {
+        DBRow {
+            key: TxFeeRow::key(&self.txid),
+            value: bincode::serialize_little(&self.fee).unwrap(),
+        }
+    }
+}
+
+struct TxStatsRow {
+    txid: FullHash,
+    stats: TxStats,
+}
+
+impl TxStatsRow {
+    fn new(txid: FullHash, stats: TxStats) -> TxStatsRow {
+        TxStatsRow { txid, stats }
+    }
+
+    fn key(txid: &[u8]) -> Bytes {
+        [b"w", txid].concat()
+    }
+
+    fn into_row(self) -> DBRow {
+        DBRow {
+            key: TxStatsRow::key(&self.txid),
+            value: bincode::serialize_little(&self.stats).unwrap(),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 struct TxOutKey {
     code: u8,
@@ -1274,14 +3402,18 @@ struct TxOutRow {
 }
 
 impl TxOutRow {
-    fn new(txid: &FullHash, vout: usize, txout: &TxOut) -> TxOutRow {
+    // With the script replaced by its id, when in the script dictionary
+    fn new(txid: &FullHash, vout: usize, txout: &TxOut, script_id: Option<u64>) -> TxOutRow {
         TxOutRow {
             key: TxOutKey {
                 code: b'O',
                 txid: *txid,
                 vout: vout as u16,
             },
-            value: serialize(txout),
+            value: match script_id {
+                Some(id) => script_dict::encode_txo(txout, id),
+                None => serialize(txout),
+            },
         }
     }
     fn key(outpoint: &OutPoint) -> Bytes {
@@ -1337,6 +3469,14 @@ impl BlockRow {
         }
     }
 
+    #[cfg(not(feature = "liquid"))]
+    fn new_reward(hash: FullHash, reward: &BlockReward) -> BlockRow {
+        BlockRow {
+            key: BlockKey { code: b'R', hash },
+            value: bincode::serialize_little(reward).unwrap(),
+        }
+    }
+
     fn new_done(hash: FullHash) -> BlockRow {
         BlockRow {
             key: BlockKey { code: b'D', hash },
@@ -1348,6 +3488,10 @@ impl BlockRow {
         b"B".to_vec()
     }
 
+    fn header_key(hash: FullHash) -> Bytes {
+        [b"B", &hash[..]].concat()
+    }
+
     fn txids_key(hash: FullHash) -> Bytes {
         [b"X", &hash[..]].concat()
     }
@@ -1356,10 +3500,19 @@ impl BlockRow {
         [b"M", &hash[..]].concat()
     }
 
+    #[cfg(not(feature = "liquid"))]
+    fn reward_key(hash: FullHash) -> Bytes {
+        [b"R", &hash[..]].concat()
+    }
+
     fn done_filter() -> Bytes {
         b"D".to_vec()
     }
 
+    fn done_key(hash: FullHash) -> Bytes {
+        [b"D", &hash[..]].concat()
+    }
+
     fn into_row(self) -> DBRow {
         DBRow {
             key: bincode::serialize_little(&self.key).unwrap(),
@@ -1375,6 +3528,92 @@ impl BlockRow {
     }
 }
 
+// The progress of the add or index phase, kept in the txstore or history db respectively. All the
+// blocks of the chain up to the cursor are done. The blocks above it may be done too (e.g. the
+// blk*.dat files are not ordered by height), which is checked with their done markers.
+//      c → {height}{blockhash}
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct PhaseCursor {
+    height: u32,
+    blockhash: BlockHash,
+}
+
+impl PhaseCursor {
+    // The cursor right below the lowest pending height (if any) among the new headers, or None if
+    // the new headers didn't get done up to a new cursor
+    fn below(new_headers: &[HeaderEntry], pending: Option<usize>) -> Option<PhaseCursor> {
+        let first = new_headers.first()?.height();
+        let done_below = pending.unwrap_or(usize::MAX).min(first + new_headers.len());
+        let entry = new_headers.get(done_below.checked_sub(first + 1)?)?;
+        Some(PhaseCursor {
+            height: entry.height() as u32,
+            blockhash: *entry.hash(),
+        })
+    }
+
+    fn key() -> Bytes {
+        b"c".to_vec()
+    }
+
+    fn load(db: &DB) -> Option<PhaseCursor> {
+        db.get(&Self::key())
+            .map(|value| bincode::deserialize_little(&value).expect("failed to parse PhaseCursor"))
+    }
+
+    fn save(&self, db: &DB, flush: DBFlush) {
+        db.write(
+            vec![DBRow {
+                key: Self::key(),
+                value: bincode::serialize_little(self).unwrap(),
+            }],
+            flush,
+        );
+    }
+}
+
+// The fetched blocks waiting for the blocks below them to be added before they can be indexed.
+// Once they take more than max_bytes, the highest ones are deferred to a second pass.
+struct WaitingBlocks {
+    blocks: BTreeMap<usize, BlockEntry>,
+    bytes: usize,
+    max_bytes: usize,
+    deferred: Vec<HeaderEntry>,
+}
+
+impl WaitingBlocks {
+    fn new(max_bytes: usize) -> Self {
+        WaitingBlocks {
+            blocks: BTreeMap::new(),
+            bytes: 0,
+            max_bytes,
+            deferred: vec![],
+        }
+    }
+
+    // Buffers the fetched blocks, and returns the ones below added_below ordered by height
+    fn push(
+        &mut self,
+        blocks: impl IntoIterator<Item = BlockEntry>,
+        added_below: usize,
+    ) -> Vec<BlockEntry> {
+        for b in blocks {
+            self.bytes += b.size as usize;
+            self.blocks.insert(b.entry.height(), b);
+        }
+        let still_waiting = self.blocks.split_off(&added_below);
+        let ready: Vec<BlockEntry> = std::mem::replace(&mut self.blocks, still_waiting)
+            .into_values()
+            .collect();
+        self.bytes -= ready.iter().map(|b| b.size as usize).sum::<usize>();
+        while self.bytes > self.max_bytes {
+            let (_, b) = self.blocks.pop_last().unwrap();
+            self.bytes -= b.size as usize;
+            self.deferred.push(b.entry);
+        }
+        ready
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct FundingInfo {
     pub txid: FullHash,
@@ -1427,6 +3666,7 @@ pub struct TxHistoryKey {
     pub code: u8,              // H for script history or I for asset history (elements only)
     pub hash: FullHash, // either a scripthash (always on bitcoin) or an asset id (elements only)
     pub confirmed_height: u32, // MUST be serialized as big-endian (for correct scans).
+    pub tx_position: u32, // within the block, so that rows are scanned in the chain order
     pub txinfo: TxHistoryInfo,
 }
 
@@ -1434,12 +3674,43 @@ pub struct TxHistoryRow {
     pub key: TxHistoryKey,
 }
 
+// The (height, tx position) of a confirmed transaction, at which its history rows are keyed. The
+// position is unknown if the block txids couldn't be looked up.
+#[derive(Clone, Copy, Debug)]
+struct HistoryCursor {
+    height: u32,
+    tx_position: Option<u32>,
+}
+
 impl TxHistoryRow {
-    fn new(script: &Script, confirmed_height: u32, txinfo: TxHistoryInfo) -> Self {
+    fn new(
+        scripthash: FullHash,
+        confirmed_height: u32,
+        tx_position: u32,
+        txinfo: TxHistoryInfo,
+    ) -> Self {
         let key = TxHistoryKey {
             code: b'H',
-            hash: compute_script_hash(&script),
+            hash: scripthash,
+            confirmed_height,
+            tx_position,
+            txinfo,
+        };
+        TxHistoryRow { key }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    fn new_pubkey(
+        pubkey: FullHash,
+        confirmed_height: u32,
+        tx_position: u32,
+        txinfo: TxHistoryInfo,
+    ) -> Self {
+        let key = TxHistoryKey {
+            code: b'x',
+            hash: pubkey,
             confirmed_height,
+            tx_position,
             txinfo,
         };
         TxHistoryRow { key }
@@ -1457,6 +3728,21 @@ impl TxHistoryRow {
         bincode::serialize_big(&(code, full_hash(&hash[..]), height)).unwrap()
     }
 
+    fn prefix_position(code: u8, hash: &[u8], height: u32, tx_position: u32) -> Bytes {
+        bincode::serialize_big(&(code, full_hash(&hash[..]), height, tx_position)).unwrap()
+    }
+
+    fn marker_key(scripthash: &[u8]) -> Bytes {
+        [b"E", scripthash].concat()
+    }
+
+    fn marker_row(scripthash: &FullHash) -> DBRow {
+        DBRow {
+            key: Self::marker_key(scripthash),
+            value: vec![],
+        }
+    }
+
     pub fn into_row(self) -> DBRow {
         DBRow {
             key: bincode::serialize_big(&self.key).unwrap(),
@@ -1583,6 +3869,40 @@ impl StatsCacheRow {
     }
 }
 
+struct StatsCheckpointRow {
+    height: usize,
+    stats: ScriptStats,
+    blockhash: BlockHash,
+}
+
+impl StatsCheckpointRow {
+    fn row(scripthash: &[u8], height: usize, stats: &ScriptStats, blockhash: BlockHash) -> DBRow {
+        DBRow {
+            key: [
+                &Self::filter(scripthash)[..],
+                &(height as u32).to_be_bytes(),
+            ]
+            .concat(),
+            value: bincode::serialize_little(&(stats, blockhash)).unwrap(),
+        }
+    }
+
+    fn filter(scripthash: &[u8]) -> Bytes {
+        [b"P", scripthash].concat()
+    }
+
+    // None for the rows saved before the balance was added to the stats
+    fn from_row(row: DBRow) -> Option<Self> {
+        let height = u32::from_be_bytes(row.key[row.key.len() - 4..].try_into().unwrap());
+        let (stats, blockhash) = bincode::deserialize_little(&row.value).ok()?;
+        Some(StatsCheckpointRow {
+            height: height as usize,
+            stats,
+            blockhash,
+        })
+    }
+}
+
 type CachedUtxoMap = HashMap<(Txid, u32), (u32, Value)>; // (txid,vout) => (block_height,output_value)
 
 struct UtxoCacheRow {
@@ -1615,6 +3935,38 @@ impl UtxoCacheRow {
     }
 }
 
+struct FundingTxCountRow;
+
+impl FundingTxCountRow {
+    fn row(scripthash: &[u8], count: usize, blockhash: &BlockHash) -> DBRow {
+        DBRow {
+            key: Self::key(scripthash),
+            value: bincode::serialize_little(&(count as u64, blockhash)).unwrap(),
+        }
+    }
+
+    fn key(scripthash: &[u8]) -> Bytes {
+        [b"q", scripthash].concat()
+    }
+}
+
+struct UtxoCheckpointRow;
+
+impl UtxoCheckpointRow {
+    fn row(scripthash: &[u8], utxos: &UtxoMap, height: usize, blockhash: &BlockHash) -> DBRow {
+        DBRow {
+            key: Self::key(scripthash),
+            value: bincode::serialize_little(&(make_utxo_cache(utxos), height as u32, blockhash))
+                .unwrap(),
+        }
+    }
+
+    // not `V`, which would match the compatibility key of the db in clear_caches()
+    fn key(scripthash: &[u8]) -> Bytes {
+        [b"o", scripthash].concat()
+    }
+}
+
 // keep utxo cache with just the block height (the hash/timestamp are read later from the headers to reconstruct BlockId)
 // and use a (txid,vout) tuple instead of OutPoints (they don't play nicely with bincode serialization)
 fn make_utxo_cache(utxos: &UtxoMap) -> CachedUtxoMap {
@@ -1663,3 +4015,77 @@ impl GetAmountVal for confidential::Value {
         self
     }
 }
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_txo_value() {
+        let txo = TxOut {
+            value: bitcoin::Amount::from_sat(1_234_567_890),
+            script_pubkey: Script::from_bytes(vec![0x51; 40]),
+        };
+        assert_eq!(parse_txo_value(&serialize(&txo)), 1_234_567_890);
+    }
+
+    #[test]
+    fn test_compute_script_hash() {
+        // the example of the Electrum protocol docs, for 1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa
+        let script =
+            Script::from_hex("76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac").unwrap();
+        assert_eq!(
+            parse_hash(&compute_script_hash(&script)).to_string(),
+            "8b01df4e368ea28f8dc0423bcf7a4923e3a12d307c875e47a0cfbf90b5c39161"
+        );
+
+        // batches large enough to be split keep the order of the scripts
+        let scripts: Vec<Script> = (0..SCRIPT_HASH_BATCH_SIZE as u32 * 2 + 1)
+            .map(|i| Script::from_bytes(i.to_le_bytes().to_vec()))
+            .collect();
+        let script_refs: Vec<&Script> = scripts.iter().collect();
+        let expected: Vec<FullHash> = scripts.iter().map(compute_script_hash).collect();
+        assert_eq!(compute_script_hashes(&script_refs), expected);
+    }
+
+    #[test]
+    fn test_waiting_blocks() {
+        let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Regtest);
+        let mut headers = vec![genesis.header];
+        for _ in 1..5 {
+            let mut header = genesis.header;
+            header.prev_blockhash = headers.last().unwrap().block_hash();
+            headers.push(header);
+        }
+        let entries = HeaderList::empty().order(headers);
+        let block = |height: usize| BlockEntry {
+            block: genesis.clone(),
+            entry: entries[height].clone(),
+            size: 100,
+        };
+        let heights = |blocks: &[BlockEntry]| -> Vec<usize> {
+            blocks.iter().map(|b| b.entry.height()).collect()
+        };
+        let mut waiting = WaitingBlocks::new(250);
+
+        // the blocks fetched before the blocks below them were added are buffered
+        assert!(waiting.push(vec![block(3), block(2)], 0).is_empty());
+        assert_eq!(waiting.bytes, 200);
+        assert!(waiting.deferred.is_empty());
+
+        // past the limit, the highest ones are deferred to the second pass
+        assert!(waiting.push(vec![block(4)], 0).is_empty());
+        assert_eq!(waiting.bytes, 200);
+        let deferred: Vec<usize> = waiting.deferred.iter().map(HeaderEntry::height).collect();
+        assert_eq!(deferred, vec![4]);
+
+        // the buffered blocks get indexed by height once the blocks below them are added
+        let ready = waiting.push(vec![block(1), block(0)], 2);
+        assert_eq!(heights(&ready), vec![0, 1]);
+        let ready = waiting.push(vec![], usize::MAX);
+        assert_eq!(heights(&ready), vec![2, 3]);
+        assert!(waiting.blocks.is_empty());
+        assert_eq!(waiting.bytes, 0);
+        assert_eq!(waiting.deferred.len(), 1);
+    }
+}
@@ -0,0 +1,246 @@
+// Index-time de-duplication of the output scripts kept in the txstore, enabled with
+// --script-dictionary. Scripts that are paid to again are given a sequential id, and the txo rows
+// of the outputs paying to them from then on keep the id in place of the script:
+//
+//      txstore: O{txid}{vout} → {txout-with-empty-script}{script-id}
+//      txstore: S{scripthash} → {script-id}
+//      txstore: s{script-id} → {script}
+//      txstore: s → {next-script-id}
+//
+// The script id trails the serialized output, which tells the rows apart from the plain ones, so
+// that both are resolved by lookup_txo() regardless of the option.
+//
+// Each script in the dictionary takes about 80 bytes of rows, which only pays off once it's
+// referenced by several outputs. Most scripts are only paid to once, so scripts are kept inline
+// until seen for a second time. The scripts seen so far are tracked by an in-memory bloom filter,
+// which is reset once full and isn't persisted: scripts seen before a reset (or a restart) go into
+// the dictionary on their next occurrence after it instead.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use rayon::prelude::*;
+
+#[cfg(not(feature = "liquid"))]
+use bitcoin::consensus::encode::{deserialize_partial, serialize};
+#[cfg(feature = "liquid")]
+use elements::encode::{deserialize_partial, serialize};
+
+use crate::chain::{Script, TxOut};
+use crate::new_index::db::{DBRow, DB};
+use crate::new_index::fetch::BlockEntry;
+use crate::new_index::schema::compute_script_hashes;
+use crate::util::{is_spendable, Bytes, FullHash};
+
+// Shorter scripts are kept inline, their id wouldn't take much less space
+const MIN_SCRIPT_SIZE: usize = 16;
+
+// The bloom filter of the seen scripts takes 128 MiB, and gets reset after about 100M scripts,
+// beyond which its false positive rate (scripts added to the dictionary on their first occurrence)
+// would exceed 1%
+const SEEN_FILTER_BITS: u64 = 1 << 30;
+const SEEN_FILTER_HASHES: u64 = 7;
+const SEEN_FILTER_CAPACITY: usize = (SEEN_FILTER_BITS / 10) as usize;
+
+const NEXT_ID_KEY: &[u8] = b"s";
+
+fn id_key(scripthash: &[u8]) -> Bytes {
+    [b"S", scripthash].concat()
+}
+
+fn script_key(id: u64) -> Bytes {
+    [b"s", &id.to_be_bytes()[..]].concat()
+}
+
+fn parse_id(value: &[u8]) -> u64 {
+    u64::from_le_bytes(value.try_into().expect("invalid script id"))
+}
+
+// The little-endian id in the txo rows, without its trailing zero bytes
+fn encode_id(id: u64) -> Bytes {
+    let len = 8 - (id.leading_zeros() / 8) as usize;
+    id.to_le_bytes()[..len.max(1)].to_vec()
+}
+
+fn decode_id(bytes: &[u8]) -> u64 {
+    assert!(bytes.len() <= 8, "invalid script id");
+    let mut id = [0u8; 8];
+    id[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(id)
+}
+
+// The scripts seen while indexing, as a bloom filter keyed by their scripthash
+pub struct SeenScripts {
+    bits: Vec<u64>,
+    count: usize,
+}
+
+impl SeenScripts {
+    pub fn new() -> Self {
+        SeenScripts {
+            bits: vec![0; (SEEN_FILTER_BITS / 64) as usize],
+            count: 0,
+        }
+    }
+
+    // Mark the script as seen, returning whether it (probably) already was
+    fn insert(&mut self, scripthash: &FullHash) -> bool {
+        if self.count >= SEEN_FILTER_CAPACITY {
+            debug!("resetting the filter of seen scripts");
+            self.bits.iter_mut().for_each(|word| *word = 0);
+            self.count = 0;
+        }
+        // the scripthash is uniformly distributed, its parts are used as the base hashes
+        let h1 = u64::from_le_bytes(scripthash[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(scripthash[8..16].try_into().unwrap());
+        let mut seen = true;
+        for i in 0..SEEN_FILTER_HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % SEEN_FILTER_BITS;
+            let (word, mask) = ((bit / 64) as usize, 1u64 << (bit % 64));
+            if self.bits[word] & mask == 0 {
+                self.bits[word] |= mask;
+                seen = false;
+            }
+        }
+        if !seen {
+            self.count += 1;
+        }
+        seen
+    }
+}
+
+// The ids of the reused scripts paid to by the blocks, along with the dictionary rows of the ones
+// that are new to it, to be written with the txo rows referencing them. Scripts paid to for the
+// first time are left out, unless paid to several times within the blocks.
+pub fn assign_ids<'a>(
+    txstore_db: &DB,
+    seen: &mut SeenScripts,
+    blocks: &'a [BlockEntry],
+) -> (HashMap<&'a Script, u64>, Vec<DBRow>) {
+    let mut occurrences: HashMap<&Script, usize> = HashMap::new();
+    for txo in blocks
+        .iter()
+        .flat_map(|b| b.block.txdata.iter())
+        .flat_map(|tx| tx.output.iter())
+        .filter(|txo| is_spendable(txo) && txo.script_pubkey.len() >= MIN_SCRIPT_SIZE)
+    {
+        *occurrences.entry(&txo.script_pubkey).or_default() += 1;
+    }
+    let (scripts, counts): (Vec<&Script>, Vec<usize>) = occurrences.into_iter().unzip();
+    let scripthashes = compute_script_hashes(&scripts);
+
+    // every script is marked as seen, but only the reused ones are looked up in the dictionary
+    let reused: Vec<(&Script, FullHash)> = scripts
+        .into_iter()
+        .zip(scripthashes)
+        .zip(counts)
+        .filter_map(|((script, scripthash), count)| {
+            let seen_before = seen.insert(&scripthash);
+            (seen_before || count > 1).then(|| (script, scripthash))
+        })
+        .collect();
+    let known_ids: Vec<Option<u64>> = reused
+        .par_iter()
+        .map(|(_, scripthash)| txstore_db.get(&id_key(scripthash)).map(|v| parse_id(&v)))
+        .collect();
+
+    let mut next_id = txstore_db.get(NEXT_ID_KEY).map_or(0, |v| parse_id(&v));
+    let mut rows = vec![];
+    let ids = reused
+        .into_iter()
+        .zip(known_ids)
+        .map(|((script, scripthash), id)| {
+            let id = id.unwrap_or_else(|| {
+                let id = next_id;
+                next_id += 1;
+                rows.push(DBRow {
+                    key: id_key(&scripthash),
+                    value: id.to_le_bytes().to_vec(),
+                });
+                rows.push(DBRow {
+                    key: script_key(id),
+                    value: script.as_bytes().to_vec(),
+                });
+                id
+            });
+            (script, id)
+        })
+        .collect();
+    if !rows.is_empty() {
+        rows.push(DBRow {
+            key: NEXT_ID_KEY.to_vec(),
+            value: next_id.to_le_bytes().to_vec(),
+        });
+    }
+    (ids, rows)
+}
+
+// The output serialized with an empty script, followed by the id of its script
+pub fn encode_txo(txo: &TxOut, id: u64) -> Bytes {
+    let mut txo = txo.clone();
+    txo.script_pubkey = Script::new();
+    [serialize(&txo), encode_id(id)].concat()
+}
+
+// The output of a txo row, with its script looked up in the dictionary if replaced by an id
+pub fn decode_txo(txstore_db: &DB, value: &[u8]) -> TxOut {
+    let (mut txo, consumed): (TxOut, usize) =
+        deserialize_partial(value).expect("failed to parse TxOut");
+    if consumed < value.len() {
+        let id = decode_id(&value[consumed..]);
+        let script = txstore_db
+            .get(&script_key(id))
+            .unwrap_or_else(|| panic!("missing script {} in the dictionary", id));
+        txo.script_pubkey = Script::from(script);
+    }
+    txo
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::new_index::compute_script_hash;
+
+    #[test]
+    fn test_seen_scripts() {
+        let mut seen = SeenScripts::new();
+        let scripthashes: Vec<FullHash> = (0..1000u32)
+            .map(|i| compute_script_hash(&Script::from(i.to_le_bytes().to_vec())))
+            .collect();
+        let false_positives = scripthashes.iter().filter(|sh| seen.insert(sh)).count();
+        assert!(false_positives <= 1);
+        assert!(scripthashes.iter().all(|sh| seen.insert(sh)));
+        assert_eq!(seen.count, 1000 - false_positives);
+    }
+
+    #[test]
+    fn test_encode_id() {
+        assert_eq!(encode_id(0), vec![0]);
+        assert_eq!(encode_id(255), vec![255]);
+        assert_eq!(encode_id(256), vec![0, 1]);
+        assert_eq!(encode_id(u64::MAX).len(), 8);
+        for id in [0, 1, 255, 256, 70_000, 1 << 40, u64::MAX] {
+            assert_eq!(decode_id(&encode_id(id)), id);
+        }
+    }
+
+    #[cfg(not(feature = "liquid"))]
+    #[test]
+    fn test_encode_txo() {
+        use bitcoin::Amount;
+
+        let txo = TxOut {
+            value: Amount::from_sat(150_000_000),
+            script_pubkey: Script::from(vec![0x51; 22]),
+        };
+        // plain rows are consumed entirely, the encoded ones are followed by the id
+        let plain = serialize(&txo);
+        let (_, consumed): (TxOut, usize) = deserialize_partial(&plain).unwrap();
+        assert_eq!(consumed, plain.len());
+        let encoded = encode_txo(&txo, 300);
+        let (stripped, consumed): (TxOut, usize) = deserialize_partial(&encoded).unwrap();
+        assert_eq!(stripped.value, txo.value);
+        assert!(stripped.script_pubkey.is_empty());
+        assert_eq!(decode_id(&encoded[consumed..]), 300);
+    }
+}
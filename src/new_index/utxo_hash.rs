@@ -0,0 +1,191 @@
+// A rolling MuHash3072 of the UTXO set as of every block, in the format of Bitcoin Core's
+// `gettxoutsetinfo muhash`, to cross-check the index against bitcoind. The hash of a block is its
+// parent's, with the outputs created by the block added and the outputs it spent removed. The
+// changes of each block are computed in parallel while indexing its history, and applied in order
+// once the indexing of the new blocks is done:
+//
+//      txstore: u{txid} → {coin-code}
+//      history: v{blockhash} → {muhash}    (the changes made by the block, until they are applied)
+//      history: m{blockhash} → {muhash}    (the UTXO set as of the block)
+//
+// Outputs are hashed with the height and coinbase flag of their transaction, encoded as Bitcoin
+// Core's coin code (height * 2 + is-coinbase), which is kept in the txstore to hash them once spent.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+use bitcoin::hashes::sha256;
+
+use crate::chain::{BlockHash, OutPoint, Script, TxOut};
+use crate::new_index::db::{DBFlush, DBRow, DB};
+use crate::new_index::fetch::BlockEntry;
+use crate::util::muhash::MuHash3072;
+use crate::util::{full_hash, has_prevout, Bytes, FullHash, HeaderEntry};
+
+// The number of blocks whose hash is written at once while catching up
+const APPLY_BATCH_SIZE: usize = 1000;
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+// The coinbase transactions of these blocks were later duplicated (before BIP30) and overwritten
+// in the UTXO set, so Bitcoin Core doesn't account for them
+const BIP30_UNSPENDABLE: [(usize, &str); 2] = [
+    (
+        91722,
+        "00000000000271a2dc26e7667f8419f2e15416dc6955e5a6c6cdf3f2574dd08e",
+    ),
+    (
+        91812,
+        "00000000000af0aed4792b1acee3d966af36cf5def14935db8de83d6f9306f2f",
+    ),
+];
+
+fn coin_code_key(txid: &[u8]) -> Bytes {
+    [b"u", txid].concat()
+}
+
+fn delta_key(blockhash: &[u8]) -> Bytes {
+    [b"v", blockhash].concat()
+}
+
+fn muhash_key(blockhash: &[u8]) -> Bytes {
+    [b"m", blockhash].concat()
+}
+
+fn is_bip30_unspendable(b: &BlockEntry) -> bool {
+    BIP30_UNSPENDABLE
+        .iter()
+        .any(|(height, hash)| b.entry.height() == *height && b.entry.hash().to_string() == *hash)
+}
+
+// Bitcoin Core's CScript::IsUnspendable(), outputs matching it are never added to the UTXO set
+fn is_unspendable(script: &Script) -> bool {
+    script.is_op_return() || script.len() > MAX_SCRIPT_SIZE
+}
+
+// The serialization of a coin hashed into the set (see TxOutSer() in Bitcoin Core's coinstats.cpp)
+fn coin_data(outpoint: &OutPoint, coin_code: u32, txout: &TxOut) -> Bytes {
+    [
+        bitcoin::consensus::serialize(outpoint),
+        coin_code.to_le_bytes().to_vec(),
+        bitcoin::consensus::serialize(txout),
+    ]
+    .concat()
+}
+
+pub fn add_coin_codes(b: &BlockEntry, rows: &mut Vec<DBRow>) {
+    // the duplicated coinbase transactions keep the coin code of their latest occurrence
+    let skip_coinbase = is_bip30_unspendable(b);
+    for tx in &b.block.txdata {
+        if tx.is_coinbase() && skip_coinbase {
+            continue;
+        }
+        let coin_code = b.entry.height() as u32 * 2 + tx.is_coinbase() as u32;
+        rows.push(DBRow {
+            key: coin_code_key(&tx.txid()[..]),
+            value: coin_code.to_le_bytes().to_vec(),
+        });
+    }
+}
+
+pub fn lookup_coin_code(txstore_db: &DB, outpoint: &OutPoint) -> Option<u32> {
+    txstore_db
+        .get(&coin_code_key(&outpoint.txid[..]))
+        .map(|value| u32::from_le_bytes(value[..].try_into().expect("invalid coin code")))
+}
+
+// The changes made by the block to the UTXO set. Unavailable if some of the coins it spends were
+// added before --utxo-muhash was enabled.
+pub fn block_delta(
+    b: &BlockEntry,
+    previous_txos_map: &HashMap<OutPoint, TxOut>,
+    coin_codes: &HashMap<OutPoint, u32>,
+) -> Option<DBRow> {
+    let mut delta = MuHash3072::default();
+    // the outputs of the genesis block are not spendable
+    let skip_coinbase = b.entry.height() == 0 || is_bip30_unspendable(b);
+    for tx in &b.block.txdata {
+        if tx.is_coinbase() && skip_coinbase {
+            continue;
+        }
+        let txid = tx.txid();
+        let coin_code = b.entry.height() as u32 * 2 + tx.is_coinbase() as u32;
+        for (vout, txout) in tx.output.iter().enumerate() {
+            if !is_unspendable(&txout.script_pubkey) {
+                let outpoint = OutPoint::new(txid, vout as u32);
+                delta.insert(&coin_data(&outpoint, coin_code, txout));
+            }
+        }
+        for txin in tx.input.iter().filter(|txin| has_prevout(txin)) {
+            let outpoint = &txin.previous_output;
+            let coin_code = *coin_codes.get(outpoint)?;
+            let txout = previous_txos_map
+                .get(outpoint)
+                .unwrap_or_else(|| panic!("missing previous txo {}", outpoint));
+            delta.remove(&coin_data(outpoint, coin_code, txout));
+        }
+    }
+    Some(DBRow {
+        key: delta_key(&b.entry.hash()[..]),
+        value: delta.to_bytes(),
+    })
+}
+
+// Applies the changes of the new blocks, in order, on top of the hash of the block below them
+pub fn apply_deltas(history_db: &DB, new_headers: &[HeaderEntry], flush: DBFlush) {
+    let first = match new_headers.first() {
+        Some(first) => first,
+        None => return,
+    };
+    let mut muhash = if first.height() == 0 {
+        MuHash3072::default()
+    } else {
+        match get_muhash(history_db, &first.header().prev_blockhash) {
+            Some(muhash) => muhash,
+            None => {
+                warn!(
+                    "UTXO set hash unavailable below block {}, --utxo-muhash must be enabled from the initial sync",
+                    first.hash()
+                );
+                return;
+            }
+        }
+    };
+
+    for chunk in new_headers.chunks(APPLY_BATCH_SIZE) {
+        let (mut rows, mut deletes) = (vec![], vec![]);
+        for entry in chunk {
+            let blockhash: FullHash = full_hash(&entry.hash()[..]);
+            // applied before a restart
+            if let Some(value) = history_db.get(&muhash_key(&blockhash)) {
+                muhash = MuHash3072::from_bytes(&value);
+                continue;
+            }
+            let delta = match history_db.get(&delta_key(&blockhash)) {
+                Some(delta) => delta,
+                None => {
+                    warn!("UTXO set changes unavailable for block {}", entry.hash());
+                    history_db.write_and_delete(rows, deletes, flush);
+                    return;
+                }
+            };
+            muhash.combine(&MuHash3072::from_bytes(&delta));
+            rows.push(DBRow {
+                key: muhash_key(&blockhash),
+                value: muhash.to_bytes(),
+            });
+            deletes.push(delta_key(&blockhash));
+        }
+        history_db.write_and_delete(rows, deletes, flush);
+    }
+}
+
+fn get_muhash(history_db: &DB, blockhash: &BlockHash) -> Option<MuHash3072> {
+    history_db
+        .get(&muhash_key(&blockhash[..]))
+        .map(|value| MuHash3072::from_bytes(&value))
+}
+
+// The hash of the UTXO set as of the block, None if it's not indexed (yet)
+pub fn utxo_muhash(history_db: &DB, blockhash: &BlockHash) -> Option<sha256::Hash> {
+    get_muhash(history_db, blockhash).map(|muhash| muhash.finalize())
+}
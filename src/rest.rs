@@ -1,48 +1,82 @@
+use crate::auth::AuthError;
+#[cfg(not(feature = "liquid"))]
+use crate::chain::custom_address_to_script;
 use crate::chain::{
     address, BlockHash, Network, OutPoint, Script, Sequence, Transaction, TxIn, TxMerkleNode,
-    TxOut, Txid,
+    TxOut, Txid, Wtxid,
 };
-use crate::config::Config;
+use crate::config::{ApiCompat, Config, DisabledRoute, RouteTimeout};
 use crate::errors;
-use crate::new_index::{compute_script_hash, Query, SpendingInput, Utxo};
+use crate::events::{ConfirmationWatch, Webhook, DEFAULT_CONFIRMATION_TARGETS};
+use crate::new_index::activity::{Activity, Granularity};
+use crate::new_index::cluster::ClusterMember;
+#[cfg(not(feature = "liquid"))]
+use crate::new_index::coin_days;
+use crate::new_index::labels::{Label, MAX_LABEL_SIZE};
+#[cfg(feature = "runes")]
+use crate::new_index::runes::{self, RuneId, Runestone};
+use crate::new_index::{compute_script_hash, meta, HistoryOrder, Query, SpendingInput, Utxo};
+use crate::util::cost::{self, QueryCost};
 use crate::util::{
     create_socket, electrum_merkle, extract_tx_prevouts, get_innerscripts, get_tx_fee, has_prevout,
-    is_coinbase, BlockHeaderMeta, BlockId, FullHash, ScriptToAddr, ScriptToAsm, TransactionStatus,
-    DEFAULT_BLOCKHASH,
+    is_coinbase, BlockHeaderMeta, BlockId, FullHash, ScriptToAddr, ScriptToAsm, ScriptTypeCounts,
+    TransactionStatus, TxStats, DEFAULT_BLOCKHASH,
+};
+
+#[cfg(not(feature = "liquid"))]
+use crate::util::{
+    coinbase, get_dust_threshold, policy::standardness_errors, psbt::InputSignatures,
 };
 
 #[cfg(not(feature = "liquid"))]
 use bitcoin::consensus::encode;
 
-use bitcoin::hashes::FromSliceError as HashError;
+use bitcoin::hashes::{sha256, FromSliceError as HashError, Hash};
 use hex::{DisplayHex, FromHex};
+use hyper::body::HttpBody;
+use hyper::server::conn::AddrStream;
+use hyper::server::Builder;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Response, Server, StatusCode};
 use hyperlocal::UnixServerExt;
-use tokio::sync::oneshot;
+use tokio::runtime;
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+use tokio::task;
+use tracing::Instrument;
 
 use std::fs;
+use std::io::Write;
+use std::net::SocketAddr;
 use std::str::FromStr;
 
 #[cfg(feature = "liquid")]
 use {
-    crate::elements::{ebcompact::*, peg::PegoutValue, AssetSorting, IssuanceValue},
+    crate::elements::{ebcompact::*, peg::PegoutValue, unblind, AssetSorting, IssuanceValue},
     elements::{encode, secp256k1_zkp as zkp, AssetId},
 };
 
 use serde::Serialize;
 use serde_json;
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::num::ParseIntError;
 use std::os::unix::fs::FileTypeExt;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 use std::thread;
+use std::time::{Duration, Instant};
 use url::form_urlencoded;
 
 const CHAIN_TXS_PER_PAGE: usize = 25;
 const MAX_MEMPOOL_TXS: usize = 50;
 const BLOCK_LIMIT: usize = 10;
-const ADDRESS_SEARCH_LIMIT: usize = 10;
+const ADDRESS_SEARCH_RESULTS: usize = 10;
+const HEADERS_LIMIT: usize = 2016;
+const CLUSTER_MEMBERS_PER_PAGE: usize = 50;
+const LABELS_LIMIT: usize = 1000;
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+const RAW_BLOCKS_LIMIT: usize = 100;
+const TX_STATUS_LIMIT: usize = 50;
 
 #[cfg(feature = "liquid")]
 const ASSETS_PER_PAGE: usize = 25;
@@ -53,6 +87,84 @@ const TTL_LONG: u32 = 157_784_630; // ttl for static resources (5 years)
 const TTL_SHORT: u32 = 10; // ttl for volatie resources
 const TTL_MEMPOOL_RECENT: u32 = 5; // ttl for GET /mempool/recent
 const CONF_FINAL: usize = 10; // reorgs deeper than this are considered unlikely
+const COINBASE_MATURITY: usize = 100;
+#[cfg(not(feature = "liquid"))]
+const MIN_RELAY_FEERATE: f64 = 1.0; // in sat/vB
+
+#[cfg(not(feature = "liquid"))]
+const BLOCK_INTERVAL_SECS: u64 = 600;
+#[cfg(feature = "liquid")]
+const BLOCK_INTERVAL_SECS: u64 = 60;
+
+// sequential id attached to the log records of each REST request
+static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    // whether the request being handled on this thread is restricted to the Esplora API
+    static ESPLORA_COMPAT: Cell<bool> = Cell::new(false);
+}
+
+fn restricted_to_esplora(config: &Config, path: &str) -> bool {
+    config.api_compat == ApiCompat::Esplora && !is_versioned(path)
+}
+
+fn is_esplora_compat() -> bool {
+    ESPLORA_COMPAT.with(Cell::get)
+}
+
+// For use with skip_serializing_if, on the fields that are not part of Esplora's JSON
+fn skip_in_esplora_compat<T>(_: &T) -> bool {
+    is_esplora_compat()
+}
+
+fn skip_none_or_esplora_compat<T>(value: &Option<T>) -> bool {
+    value.is_none() || is_esplora_compat()
+}
+
+#[derive(Serialize)]
+struct ActivityValue {
+    period_start: u32,
+    #[serde(flatten)]
+    activity: Activity,
+}
+
+#[derive(Serialize)]
+struct LabelValue {
+    scripthash: String,
+    #[serde(flatten)]
+    label: Label,
+}
+
+#[derive(Serialize)]
+struct TxStatusValue {
+    txid: Txid,
+    confirmed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    height: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    blockhash: Option<BlockHash>,
+}
+
+#[derive(Deserialize)]
+struct WebhookRequest {
+    url: String,
+    scripthashes: Vec<String>,
+    secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ConfirmationWatchRequest {
+    url: String,
+    txid: String,
+    confirmations: Option<Vec<u32>>,
+    secret: Option<String>,
+}
+
+#[cfg(feature = "liquid")]
+#[derive(Deserialize)]
+struct UnblindRequest {
+    blinding_keys: Vec<String>,
+}
 
 #[derive(Serialize, Deserialize)]
 struct BlockValue {
@@ -73,6 +185,35 @@ struct BlockValue {
     bits: bitcoin::pow::CompactTarget,
     #[cfg(not(feature = "liquid"))]
     difficulty: f64,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    chainwork: String,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    epoch_position: usize,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    expected_difficulty_change: f64,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    fees: Option<u64>,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    subsidy: Option<u64>,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    output_value: Option<u64>,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    sigop_cost: Option<u64>,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    coin_days_destroyed: Option<f64>,
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    seen_at: Option<u32>,
+
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    script_types: Option<ScriptTypeCounts>,
 
     #[cfg(feature = "liquid")]
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -108,6 +249,29 @@ impl BlockValue {
             nonce: header.nonce,
             #[cfg(not(feature = "liquid"))]
             difficulty: header.difficulty_float(),
+            #[cfg(not(feature = "liquid"))]
+            chainwork: blockhm
+                .header_entry
+                .chainwork()
+                .to_be_bytes()
+                .to_lower_hex_string(),
+            #[cfg(not(feature = "liquid"))]
+            epoch_position: blockhm.epoch.position,
+            #[cfg(not(feature = "liquid"))]
+            expected_difficulty_change: blockhm.epoch.difficulty_change,
+            #[cfg(not(feature = "liquid"))]
+            fees: blockhm.reward.as_ref().map(|r| r.fees),
+            #[cfg(not(feature = "liquid"))]
+            subsidy: blockhm.reward.as_ref().map(|r| r.subsidy),
+            #[cfg(not(feature = "liquid"))]
+            output_value: blockhm.reward.as_ref().map(|r| r.output_value),
+            #[cfg(not(feature = "liquid"))]
+            sigop_cost: blockhm.reward.as_ref().and_then(|r| r.sigop_cost),
+            #[cfg(not(feature = "liquid"))]
+            coin_days_destroyed: blockhm.coin_days_destroyed.map(coin_days::to_coin_days),
+            seen_at: blockhm.seen_at,
+
+            script_types: blockhm.meta.script_types,
 
             #[cfg(feature = "liquid")]
             ext: Some(header.ext.clone()),
@@ -118,31 +282,57 @@ impl BlockValue {
 #[derive(Serialize)]
 struct TransactionValue {
     txid: Txid,
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    wtxid: Wtxid,
     version: u32,
     locktime: u32,
     vin: Vec<TxInValue>,
     vout: Vec<TxOutValue>,
     size: u32,
     weight: u64,
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    vsize: u64,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    sigop_cost: u32,
     fee: u64,
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    feerate: f64,
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    locktime_info: Option<LocktimeValue>,
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    rbf: bool,
+    #[cfg(not(feature = "liquid"))]
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    coin_days_destroyed: Option<f64>,
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    seen_at: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     status: Option<TransactionStatus>,
+    #[cfg(feature = "runes")]
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    runestone: Option<Runestone>,
 }
 
 impl TransactionValue {
+    #[cfg_attr(feature = "liquid", allow(unused_variables))]
     fn new(
         tx: Transaction,
         blockid: Option<BlockId>,
         txos: &HashMap<OutPoint, TxOut>,
+        stored_fee: Option<u64>,
+        stored_stats: Option<TxStats>,
+        stored_coin_days: Option<u64>,
         config: &Config,
     ) -> Self {
         let prevouts = extract_tx_prevouts(&tx, &txos, true);
+        let bip68 = enforces_bip68(&tx);
         let vins: Vec<TxInValue> = tx
             .input
             .iter()
             .enumerate()
             .map(|(index, txin)| {
-                TxInValue::new(txin, prevouts.get(&(index as u32)).cloned(), config)
+                TxInValue::new(txin, prevouts.get(&(index as u32)).cloned(), bip68, config)
             })
             .collect();
         let vouts: Vec<TxOutValue> = tx
@@ -151,14 +341,12 @@ impl TransactionValue {
             .map(|txout| TxOutValue::new(txout, config))
             .collect();
 
-        let fee = get_tx_fee(&tx, &prevouts, config.network_type);
-
-        let weight = tx.weight();
-        #[cfg(not(feature = "liquid"))] // rust-bitcoin has a wrapper Weight type
-        let weight = weight.to_wu();
+        let fee = stored_fee.unwrap_or_else(|| get_tx_fee(&tx, &prevouts, config.network_type));
+        let stats = stored_stats.unwrap_or_else(|| TxStats::new(&tx, txos));
 
         TransactionValue {
             txid: tx.txid(),
+            wtxid: tx.wtxid(),
             #[cfg(not(feature = "liquid"))]
             version: tx.version.0 as u32,
             #[cfg(feature = "liquid")]
@@ -167,9 +355,49 @@ impl TransactionValue {
             vin: vins,
             vout: vouts,
             size: tx.total_size() as u32,
-            weight: weight as u64,
+            weight: stats.weight as u64,
+            vsize: stats.vsize as u64,
+            #[cfg(not(feature = "liquid"))]
+            sigop_cost: stats.sigop_cost,
             fee,
+            feerate: fee as f64 / stats.vsize as f64,
+            locktime_info: LocktimeValue::new(
+                tx.lock_time.to_consensus_u32(),
+                tx.input.iter().map(|txin| txin.sequence.0),
+            ),
+            rbf: tx.input.iter().any(|txin| signals_rbf(txin.sequence.0)),
+            #[cfg(not(feature = "liquid"))]
+            coin_days_destroyed: stored_coin_days.map(coin_days::to_coin_days),
+            // set by prepare_txs()
+            seen_at: None,
             status: Some(TransactionStatus::from(blockid)),
+            #[cfg(feature = "runes")]
+            runestone: runes::decipher(&tx),
+        }
+    }
+}
+
+// An unconfirmed transaction along with its net effect on the balance of the queried script
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct MempoolTxValue {
+    #[serde(flatten)]
+    tx: TransactionValue,
+    balance_delta: i64,
+}
+
+#[derive(Serialize)]
+struct ClusterMemberValue {
+    scripthash: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<String>,
+}
+
+impl ClusterMemberValue {
+    fn new(member: ClusterMember, network: Network) -> Self {
+        ClusterMemberValue {
+            scripthash: member.scripthash.to_lower_hex_string(),
+            address: member.script.to_address_str(network),
         }
     }
 }
@@ -185,6 +413,8 @@ struct TxInValue {
     witness: Option<Vec<String>>,
     is_coinbase: bool,
     sequence: Sequence,
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    relative_timelock: Option<RelativeTimelockValue>,
 
     #[serde(skip_serializing_if = "Option::is_none")]
     inner_redeemscript_asm: Option<String>,
@@ -199,7 +429,7 @@ struct TxInValue {
 }
 
 impl TxInValue {
-    fn new(txin: &TxIn, prevout: Option<&TxOut>, config: &Config) -> Self {
+    fn new(txin: &TxIn, prevout: Option<&TxOut>, bip68: bool, config: &Config) -> Self {
         let witness = &txin.witness;
         #[cfg(feature = "liquid")]
         let witness = &witness.script_witness;
@@ -235,6 +465,11 @@ impl TxInValue {
                 .and_then(|i| i.witness_script.as_ref())
                 .map(ScriptToAsm::to_asm),
 
+            relative_timelock: if bip68 && !is_coinbase {
+                RelativeTimelockValue::new(txin.sequence.0)
+            } else {
+                None
+            },
             is_coinbase,
             sequence: txin.sequence,
             #[cfg(feature = "liquid")]
@@ -251,6 +486,97 @@ impl TxInValue {
     }
 }
 
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+const SEQUENCE_FINAL: u32 = 0xffffffff;
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 512; // in seconds
+
+// The absolute lock time, as a block height or a unix timestamp. Not set if it is zero or
+// disabled by all the inputs having a final sequence.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum LocktimeValue {
+    Height(u32),
+    Timestamp(u32),
+}
+
+impl LocktimeValue {
+    fn new(locktime: u32, mut sequences: impl Iterator<Item = u32>) -> Option<Self> {
+        if locktime == 0 || sequences.all(|sequence| sequence == SEQUENCE_FINAL) {
+            None
+        } else if locktime < LOCKTIME_THRESHOLD {
+            Some(LocktimeValue::Height(locktime))
+        } else {
+            Some(LocktimeValue::Timestamp(locktime))
+        }
+    }
+}
+
+// The BIP 68 relative lock time of an input, in blocks or in seconds
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum RelativeTimelockValue {
+    Blocks(u32),
+    Seconds(u32),
+}
+
+impl RelativeTimelockValue {
+    fn new(sequence: u32) -> Option<Self> {
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+        let value = sequence & SEQUENCE_LOCKTIME_MASK;
+        Some(if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            RelativeTimelockValue::Seconds(value * SEQUENCE_LOCKTIME_GRANULARITY)
+        } else {
+            RelativeTimelockValue::Blocks(value)
+        })
+    }
+}
+
+// BIP 125 opt-in replaceability
+fn signals_rbf(sequence: u32) -> bool {
+    sequence < SEQUENCE_FINAL - 1
+}
+
+// Relative lock times are only enforced for version 2 transactions and above
+fn enforces_bip68(tx: &Transaction) -> bool {
+    #[cfg(not(feature = "liquid"))]
+    let version = tx.version.0;
+    #[cfg(feature = "liquid")]
+    let version = tx.version as i32;
+    version >= 2
+}
+
+// TODO should the following something to put inside rust-elements lib?
+fn script_type(script: &Script, is_fee: bool) -> &'static str {
+    if is_fee {
+        "fee"
+    } else if script.is_empty() {
+        "empty"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_p2tr() {
+        "v1_p2tr"
+    } else if script.is_provably_unspendable() {
+        "provably_unspendable"
+    } else {
+        "unknown"
+    }
+}
+
 #[derive(Serialize, Clone)]
 struct TxOutValue {
     scriptpubkey: Script,
@@ -300,30 +626,7 @@ impl TxOutValue {
         let script_asm = script.to_asm();
         let script_addr = script.to_address_str(config.network_type);
 
-        // TODO should the following something to put inside rust-elements lib?
-        let script_type = if is_fee {
-            "fee"
-        } else if script.is_empty() {
-            "empty"
-        } else if script.is_op_return() {
-            "op_return"
-        } else if script.is_p2pk() {
-            "p2pk"
-        } else if script.is_p2pkh() {
-            "p2pkh"
-        } else if script.is_p2sh() {
-            "p2sh"
-        } else if script.is_p2wpkh() {
-            "v0_p2wpkh"
-        } else if script.is_p2wsh() {
-            "v0_p2wsh"
-        } else if script.is_p2tr() {
-            "v1_p2tr"
-        } else if script.is_provably_unspendable() {
-            "provably_unspendable"
-        } else {
-            "unknown"
-        };
+        let script_type = script_type(script, is_fee);
 
         #[cfg(feature = "liquid")]
         let pegout = PegoutValue::from_txout(txout, config.network_type, config.parent_network);
@@ -346,11 +649,342 @@ impl TxOutValue {
     }
 }
 
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct CoinbaseValue {
+    txid: Txid,
+    scriptsig: Script,
+    scriptsig_asm: String,
+    #[serde(flatten)]
+    decoded: coinbase::CoinbaseScript,
+    total_value: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    witness_commitment: Option<String>,
+    vout: Vec<TxOutValue>,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct PsbtValue {
+    txid: Txid,
+    version: u32,
+    locktime: u32,
+    vin: Vec<PsbtInputValue>,
+    vout: Vec<TxOutValue>,
+    // unknown if some prevouts could not be resolved
+    fee: Option<u64>,
+    // only known once all inputs are finalized
+    vsize: Option<u64>,
+    feerate: Option<f64>,
+    finalized: bool,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct PsbtInputValue {
+    txid: Txid,
+    vout: u32,
+    sequence: Sequence,
+    prevout: Option<TxOutValue>,
+    // "index" if the prevout was found in the index, "psbt" if it was only provided by the PSBT
+    prevout_source: Option<&'static str>,
+    finalized: bool,
+    signatures: usize,
+    signatures_required: Option<usize>,
+    missing_signatures: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    missing_pubkeys: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sighash_type: Option<String>,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl PsbtValue {
+    fn new(psbt: bitcoin::Psbt, query: &Query, config: &Config) -> Self {
+        let tx = &psbt.unsigned_tx;
+        let outpoints = tx.input.iter().map(|txin| txin.previous_output).collect();
+        let indexed_txos = query.chain().lookup_avail_txos(&outpoints);
+
+        let vin: Vec<PsbtInputValue> = tx
+            .input
+            .iter()
+            .zip(psbt.inputs.iter())
+            .map(|(txin, input)| {
+                let outpoint = txin.previous_output;
+                let (prevout, prevout_source) =
+                    match lookup_prevout(&outpoint, &indexed_txos, query) {
+                        Some(txo) => (Some(txo), Some("index")),
+                        None => {
+                            let txo = input.witness_utxo.clone().or_else(|| {
+                                input.non_witness_utxo.as_ref().and_then(|prev_tx| {
+                                    prev_tx.output.get(outpoint.vout as usize).cloned()
+                                })
+                            });
+                            let source = txo.as_ref().map(|_| "psbt");
+                            (txo, source)
+                        }
+                    };
+                let signatures = InputSignatures::new(input, prevout.as_ref());
+                PsbtInputValue {
+                    txid: outpoint.txid,
+                    vout: outpoint.vout,
+                    sequence: txin.sequence,
+                    prevout: prevout.as_ref().map(|txo| TxOutValue::new(txo, config)),
+                    prevout_source,
+                    finalized: signatures.finalized,
+                    signatures: signatures.count,
+                    signatures_required: signatures.required,
+                    missing_signatures: signatures.missing(),
+                    missing_pubkeys: signatures
+                        .missing_pubkeys
+                        .iter()
+                        .map(|pubkey| pubkey.to_string())
+                        .collect(),
+                    sighash_type: input.sighash_type.map(|sighash| sighash.to_string()),
+                }
+            })
+            .collect();
+
+        let total_in = vin
+            .iter()
+            .map(|input| input.prevout.as_ref().map(|prevout| prevout.value))
+            .sum::<Option<u64>>();
+        let total_out: u64 = tx.output.iter().map(|txout| txout.value.to_sat()).sum();
+        let fee = total_in.and_then(|total_in| total_in.checked_sub(total_out));
+
+        let finalized = vin.iter().all(|input| input.finalized);
+        let vsize = if finalized {
+            Some(psbt.clone().extract_tx_unchecked_fee_rate().vsize() as u64)
+        } else {
+            None
+        };
+        let feerate = fee.zip(vsize).map(|(fee, vsize)| fee as f64 / vsize as f64);
+
+        PsbtValue {
+            txid: tx.txid(),
+            version: tx.version.0 as u32,
+            locktime: tx.lock_time.to_consensus_u32(),
+            vout: tx
+                .output
+                .iter()
+                .map(|txout| TxOutValue::new(txout, config))
+                .collect(),
+            vin,
+            fee,
+            vsize,
+            feerate,
+            finalized,
+        }
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct TxPreviewValue {
+    txid: Txid,
+    vin: Vec<TxInValue>,
+    vout: Vec<TxOutValue>,
+    size: u32,
+    weight: u64,
+    vsize: u64,
+    // unknown if some prevouts could not be resolved
+    fee: Option<u64>,
+    feerate: Option<f64>,
+    sigops: usize,
+    // whether the transaction is already known to the index (confirmed or in the mempool)
+    known: bool,
+    standard: bool,
+    // the reasons the transaction would be rejected, using Bitcoin Core's reject reasons
+    errors: Vec<&'static str>,
+    conflicts: Vec<TxConflictValue>,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct TxConflictValue {
+    vin: u32,
+    txid: Txid,
+    status: TransactionStatus,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl TxPreviewValue {
+    fn new(tx: Transaction, query: &Query, config: &Config) -> Self {
+        let txid = tx.txid();
+        let outpoints = tx.input.iter().map(|txin| txin.previous_output).collect();
+        let mut txos = query.chain().lookup_avail_txos(&outpoints);
+        for outpoint in &outpoints {
+            if let Some(txo) = lookup_prevout(outpoint, &txos, query) {
+                txos.insert(*outpoint, txo);
+            }
+        }
+        let prevouts = extract_tx_prevouts(&tx, &txos, true);
+        let mut errors = standardness_errors(&tx, &txos, config.utxo_dust_feerate);
+
+        let fee = if prevouts.len() == tx.input.len() {
+            let total_in: u64 = prevouts.values().map(|txo| txo.value.to_sat()).sum();
+            let total_out: u64 = tx.output.iter().map(|txout| txout.value.to_sat()).sum();
+            if total_in < total_out {
+                errors.push("bad-txns-in-belowout");
+            }
+            total_in.checked_sub(total_out)
+        } else {
+            errors.push("missing-inputs");
+            None
+        };
+        let vsize = tx.vsize() as u64;
+        let feerate = fee.map(|fee| fee as f64 / vsize as f64);
+        if feerate.map_or(false, |feerate| feerate < MIN_RELAY_FEERATE) {
+            errors.push("min relay fee not met");
+        }
+
+        let mut known = false;
+        let mut conflicts = vec![];
+        for (vin, txin) in tx.input.iter().enumerate() {
+            match query.lookup_spend(&txin.previous_output) {
+                Some(spend) if spend.txid == txid => known = true,
+                Some(spend) => conflicts.push(TxConflictValue {
+                    vin: vin as u32,
+                    txid: spend.txid,
+                    status: TransactionStatus::from(spend.confirmed),
+                }),
+                None => (),
+            }
+        }
+        if conflicts.iter().any(|conflict| conflict.status.confirmed) {
+            errors.push("bad-txns-inputs-missingorspent");
+        } else if !conflicts.is_empty() {
+            errors.push("txn-mempool-conflict");
+        }
+
+        TxPreviewValue {
+            txid,
+            vin: tx
+                .input
+                .iter()
+                .enumerate()
+                .map(|(index, txin)| {
+                    TxInValue::new(
+                        txin,
+                        prevouts.get(&(index as u32)).cloned(),
+                        enforces_bip68(&tx),
+                        config,
+                    )
+                })
+                .collect(),
+            vout: tx
+                .output
+                .iter()
+                .map(|txout| TxOutValue::new(txout, config))
+                .collect(),
+            size: tx.total_size() as u32,
+            weight: tx.weight().to_wu(),
+            vsize,
+            fee,
+            feerate,
+            sigops: tx.total_sigop_cost(|outpoint| txos.get(outpoint).cloned()),
+            known,
+            standard: errors.is_empty(),
+            errors,
+            conflicts,
+        }
+    }
+}
+
+// Everything an SPV verifier needs to check the inclusion of a transaction against a trusted
+// checkpoint, in one blob. Numbers are little-endian and the varints are Bitcoin's CompactSize:
+//
+//      varint      header count (n)
+//      n × 80      block headers, from the block after the checkpoint up to the confirming block
+//      u32         position of the transaction in the block
+//      varint      merkle branch length (m)
+//      m × 32      merkle branch, from the transaction up to the merkle root
+//      varint      transaction length
+//      ...         raw transaction
+#[cfg(not(feature = "liquid"))]
+fn proof_bundle(
+    headers: &[bitcoin::block::Header],
+    merkle: &[bitcoin::hashes::sha256d::Hash],
+    pos: usize,
+    rawtx: &[u8],
+) -> Vec<u8> {
+    let merkle: Vec<TxMerkleNode> = merkle
+        .iter()
+        .map(|hash| TxMerkleNode::from_raw_hash(*hash))
+        .collect();
+    [
+        encode::serialize(&headers.to_vec()),
+        (pos as u32).to_le_bytes().to_vec(),
+        encode::serialize(&merkle),
+        encode::serialize(&rawtx.to_vec()),
+    ]
+    .concat()
+}
+
+// Re-serialize the transaction without its witness data, as committed to by its txid
+fn strip_witness(rawtx: &[u8]) -> Result<Vec<u8>, HttpError> {
+    let mut tx: Transaction = encode::deserialize(rawtx)?;
+    for txin in &mut tx.input {
+        txin.witness = Default::default();
+    }
+    #[cfg(feature = "liquid")]
+    for txout in &mut tx.output {
+        txout.witness = Default::default();
+    }
+    Ok(encode::serialize(&tx))
+}
+
+// Look up a prevout in the index, including the mempool
+#[cfg(not(feature = "liquid"))]
+fn lookup_prevout(
+    outpoint: &OutPoint,
+    indexed_txos: &HashMap<OutPoint, TxOut>,
+    query: &Query,
+) -> Option<TxOut> {
+    indexed_txos.get(outpoint).cloned().or_else(|| {
+        query
+            .mempool()
+            .lookup_txn(&outpoint.txid)
+            .and_then(|tx| tx.output.get(outpoint.vout as usize).cloned())
+    })
+}
+
+// Accepts PSBTs encoded as base64 (like Bitcoin Core) or hex
+#[cfg(not(feature = "liquid"))]
+fn parse_psbt(body: &[u8]) -> Result<bitcoin::Psbt, HttpError> {
+    use base64::prelude::{Engine, BASE64_STANDARD};
+
+    let encoded = String::from_utf8(body.to_vec())?;
+    let encoded = encoded.trim();
+    let raw = Vec::<u8>::from_hex(encoded)
+        .or_else(|_| BASE64_STANDARD.decode(encoded))
+        .map_err(|_| {
+            HttpError::from("Invalid PSBT encoding, expected base64 or hex".to_string())
+        })?;
+    bitcoin::Psbt::deserialize(&raw).map_err(|e| HttpError::from(format!("Invalid PSBT: {}", e)))
+}
+
 #[derive(Serialize)]
 struct UtxoValue {
     txid: Txid,
     vout: u32,
     status: TransactionStatus,
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    confirmations: u32,
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    age_blocks: u32,
+    #[serde(skip_serializing_if = "skip_in_esplora_compat")]
+    is_coinbase: bool,
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    spendable_at_height: Option<u32>,
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    is_dust: Option<bool>,
+    // whether the script the utxo pays to was funded by more than one transaction
+    #[serde(skip_serializing_if = "skip_none_or_esplora_compat")]
+    reused: Option<bool>,
+    // with ?include_script=true
+    #[serde(flatten)]
+    script: Option<UtxoScriptValue>,
 
     #[cfg(not(feature = "liquid"))]
     value: u64,
@@ -384,12 +1018,43 @@ struct UtxoValue {
     #[serde(skip_serializing_if = "Option::is_none")]
     range_proof: Option<zkp::RangeProof>,
 }
-impl From<Utxo> for UtxoValue {
-    fn from(utxo: Utxo) -> Self {
+impl UtxoValue {
+    // `dust_threshold` is only known for explicit values
+    fn new(
+        utxo: Utxo,
+        tip_height: usize,
+        dust_threshold: Option<u64>,
+        reused: Option<bool>,
+        is_coinbase: bool,
+        script: Option<UtxoScriptValue>,
+    ) -> Self {
+        let confirmations = utxo.confirmed.as_ref().map_or(0, |blockid| {
+            tip_height.saturating_sub(blockid.height) as u32 + 1
+        });
+        let spendable_at_height = utxo
+            .confirmed
+            .as_ref()
+            .filter(|_| is_coinbase)
+            .map(|blockid| (blockid.height + COINBASE_MATURITY) as u32);
+
+        #[cfg(not(feature = "liquid"))]
+        let value = Some(utxo.value);
+        #[cfg(feature = "liquid")]
+        let value = utxo.value.explicit();
+
         UtxoValue {
             txid: utxo.txid,
             vout: utxo.vout,
             status: TransactionStatus::from(utxo.confirmed),
+            confirmations,
+            age_blocks: confirmations.saturating_sub(1),
+            is_coinbase,
+            spendable_at_height,
+            is_dust: dust_threshold
+                .zip(value)
+                .map(|(threshold, value)| value < threshold),
+            reused,
+            script,
 
             #[cfg(not(feature = "liquid"))]
             value: utxo.value,
@@ -412,40 +1077,172 @@ impl From<Utxo> for UtxoValue {
     }
 }
 
-#[derive(Serialize)]
-struct SpendingValue {
-    spent: bool,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    txid: Option<Txid>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    vin: Option<u32>,
+#[derive(Serialize, Clone)]
+struct UtxoScriptValue {
+    scriptpubkey: Script,
+    scriptpubkey_asm: String,
+    scriptpubkey_type: &'static str,
     #[serde(skip_serializing_if = "Option::is_none")]
-    status: Option<TransactionStatus>,
-}
-impl From<SpendingInput> for SpendingValue {
-    fn from(spend: SpendingInput) -> Self {
-        SpendingValue {
-            spent: true,
-            txid: Some(spend.txid),
-            vin: Some(spend.vin),
-            status: Some(TransactionStatus::from(spend.confirmed)),
-        }
-    }
+    scriptpubkey_address: Option<String>,
 }
-impl Default for SpendingValue {
-    fn default() -> Self {
-        SpendingValue {
-            spent: false,
-            txid: None,
-            vin: None,
-            status: None,
-        }
+
+impl UtxoScriptValue {
+    // All the utxos pay to the same script, which is looked up from the transaction of the first
+    fn lookup(utxos: &[Utxo], query: &Query, config: &Config) -> Option<Self> {
+        let utxo = utxos.first()?;
+        let tx = query.lookup_txn(&utxo.txid)?;
+        let script = &tx.output.get(utxo.vout as usize)?.script_pubkey;
+        Some(UtxoScriptValue {
+            scriptpubkey: script.clone(),
+            scriptpubkey_asm: script.to_asm(),
+            scriptpubkey_type: script_type(script, false),
+            scriptpubkey_address: script.to_address_str(config.network_type),
+        })
     }
 }
 
-fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
-    height.map_or(TTL_SHORT, |height| {
-        if query.chain().best_height() - height >= CONF_FINAL {
+// The coinbase txids of the blocks confirming the utxos, to tell the coinbase outputs apart
+fn utxo_coinbase_txids(utxos: &[Utxo], query: &Query) -> HashSet<Txid> {
+    let blockhashes: HashSet<BlockHash> = utxos
+        .iter()
+        .filter_map(|utxo| utxo.confirmed.as_ref().map(|blockid| blockid.hash))
+        .collect();
+    blockhashes
+        .iter()
+        .filter_map(|hash| query.chain().get_block_coinbase_txid(hash))
+        .collect()
+}
+
+// The dust threshold of the script the utxos pay to. Always None on Liquid, where the value
+// of outputs may be confidential.
+#[cfg_attr(feature = "liquid", allow(unused_variables))]
+fn utxo_dust_threshold(utxos: &[Utxo], query: &Query, config: &Config) -> Option<u64> {
+    #[cfg(not(feature = "liquid"))]
+    {
+        // all the utxos pay to the same script
+        let utxo = utxos.first()?;
+        let tx = query.lookup_txn(&utxo.txid)?;
+        let txout = tx.output.get(utxo.vout as usize)?;
+        Some(get_dust_threshold(
+            &txout.script_pubkey,
+            config.utxo_dust_feerate,
+        ))
+    }
+    #[cfg(feature = "liquid")]
+    None
+}
+
+// Upper bounds (exclusive, in sats) of the value bands used by the utxo summary
+#[cfg(not(feature = "liquid"))]
+const UTXO_VALUE_BANDS: [u64; 6] = [1_000, 10_000, 100_000, 1_000_000, 10_000_000, 100_000_000];
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Default)]
+struct UtxoBandValue {
+    min_value: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_value: Option<u64>,
+    count: usize,
+    value: u64,
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize)]
+struct UtxoSummaryValue {
+    count: usize,
+    value: u64,
+    confirmed_count: usize,
+    confirmed_value: u64,
+    dust_count: usize,
+    dust_value: u64,
+    bands: Vec<UtxoBandValue>,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl Default for UtxoSummaryValue {
+    fn default() -> Self {
+        let mut min_value = 0;
+        let mut bands = vec![];
+        for max_value in UTXO_VALUE_BANDS
+            .iter()
+            .map(|max| Some(*max))
+            .chain(Some(None))
+        {
+            bands.push(UtxoBandValue {
+                min_value,
+                max_value,
+                ..Default::default()
+            });
+            min_value = max_value.unwrap_or(0);
+        }
+        UtxoSummaryValue {
+            count: 0,
+            value: 0,
+            confirmed_count: 0,
+            confirmed_value: 0,
+            dust_count: 0,
+            dust_value: 0,
+            bands,
+        }
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+impl UtxoSummaryValue {
+    fn add(&mut self, utxo: &Utxo, dust_threshold: Option<u64>) {
+        self.count += 1;
+        self.value += utxo.value;
+        if utxo.confirmed.is_some() {
+            self.confirmed_count += 1;
+            self.confirmed_value += utxo.value;
+        }
+        if dust_threshold.map_or(false, |threshold| utxo.value < threshold) {
+            self.dust_count += 1;
+            self.dust_value += utxo.value;
+        }
+        let band = UTXO_VALUE_BANDS
+            .iter()
+            .position(|max_value| utxo.value < *max_value)
+            .unwrap_or(UTXO_VALUE_BANDS.len());
+        self.bands[band].count += 1;
+        self.bands[band].value += utxo.value;
+    }
+}
+
+#[derive(Serialize)]
+struct SpendingValue {
+    spent: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    txid: Option<Txid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vin: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<TransactionStatus>,
+}
+impl From<SpendingInput> for SpendingValue {
+    fn from(spend: SpendingInput) -> Self {
+        SpendingValue {
+            spent: true,
+            txid: Some(spend.txid),
+            vin: Some(spend.vin),
+            status: Some(TransactionStatus::from(spend.confirmed)),
+        }
+    }
+}
+impl Default for SpendingValue {
+    fn default() -> Self {
+        SpendingValue {
+            spent: false,
+            txid: None,
+            vin: None,
+            status: None,
+        }
+    }
+}
+
+fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
+    height.map_or(TTL_SHORT, |height| {
+        if query.chain().best_height() - height >= CONF_FINAL {
             TTL_LONG
         } else {
             TTL_SHORT
@@ -453,14 +1250,33 @@ fn ttl_by_depth(height: Option<usize>, query: &Query) -> u32 {
     })
 }
 
+// Without `with_prevouts` the previous outputs are left out of the inputs, and are only looked up
+// for the transactions that don't have their fee and stats stored by the indexer
 fn prepare_txs(
     txs: Vec<(Transaction, Option<BlockId>)>,
     query: &Query,
     config: &Config,
+    with_prevouts: bool,
 ) -> Vec<TransactionValue> {
+    // the fees and weights of confirmed transactions are stored by the indexer
+    let stored: Vec<(Option<u64>, Option<TxStats>)> = txs
+        .iter()
+        .map(|(tx, blockid)| {
+            let txid = blockid.as_ref().map(|_| tx.txid());
+            #[cfg(not(feature = "liquid"))]
+            let fee = txid.and_then(|txid| query.chain().get_tx_fee(&txid));
+            #[cfg(feature = "liquid")]
+            let fee = None;
+            let stats = txid.and_then(|txid| query.chain().get_tx_stats(&txid));
+            (fee, stats)
+        })
+        .collect();
+
     let outpoints = txs
         .iter()
-        .flat_map(|(tx, _)| {
+        .zip(&stored)
+        .filter(|(_, (fee, stats))| with_prevouts || fee.is_none() || stats.is_none())
+        .flat_map(|((tx, _), _)| {
             tx.input
                 .iter()
                 .filter(|txin| has_prevout(txin))
@@ -471,44 +1287,159 @@ fn prepare_txs(
     let prevouts = query.lookup_txos(&outpoints);
 
     txs.into_iter()
-        .map(|(tx, blockid)| TransactionValue::new(tx, blockid, &prevouts, config))
+        .zip(stored)
+        .map(|((tx, blockid), (fee, stats))| {
+            #[cfg(not(feature = "liquid"))]
+            let coin_days = blockid
+                .as_ref()
+                .and_then(|_| query.chain().tx_coin_days_destroyed(&tx.txid()));
+            #[cfg(feature = "liquid")]
+            let coin_days = None;
+            let seen_at = query.chain().tx_seen_at(&tx.txid());
+            let mut value =
+                TransactionValue::new(tx, blockid, &prevouts, fee, stats, coin_days, config);
+            value.seen_at = seen_at;
+            if !with_prevouts {
+                value.vin.iter_mut().for_each(|vin| vin.prevout = None);
+            }
+            value
+        })
         .collect()
 }
 
-#[tokio::main]
-async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receiver<()>) {
+// Whether to include the previous outputs of the transaction inputs, unless `?prevouts=false`
+fn prevouts_param(query_params: &HashMap<String, String>) -> bool {
+    query_params.get("prevouts").map(String::as_str) != Some("false")
+}
+
+// An additional network served by the same process, with its API available under `/{prefix}`.
+// Its query is set once its initial sync is done, until then its requests are answered with 503.
+pub struct NetworkRoute {
+    pub prefix: String,
+    pub config: Arc<Config>,
+    pub query: Arc<OnceLock<Arc<Query>>>,
+}
+
+// Finds the additional network the request is addressed to by its path prefix, and strips the
+// prefix off the request uri
+fn route_network<'a>(
+    req: &mut hyper::Request<Body>,
+    networks: &'a [NetworkRoute],
+) -> Option<&'a NetworkRoute> {
+    let path = req.uri().path();
+    let (network, path) = networks
+        .iter()
+        .find_map(|network| strip_path_prefix(path, &network.prefix).map(|path| (network, path)))?;
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    let mut parts = req.uri().clone().into_parts();
+    parts.path_and_query = Some(path_and_query.parse().ok()?);
+    *req.uri_mut() = hyper::Uri::from_parts(parts).ok()?;
+    Some(network)
+}
+
+// Strips the `/{prefix}` segment off the start of the path, if it has it
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+    match path.strip_prefix('/')?.strip_prefix(prefix)? {
+        "" => Some("/"),
+        rest if rest.starts_with('/') => Some(rest),
+        _ => None,
+    }
+}
+
+async fn run_server(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    networks: Vec<NetworkRoute>,
+    rx: oneshot::Receiver<()>,
+) {
     let addr = &config.http_addr;
     let socket_file = &config.http_socket_file;
 
     let config = Arc::clone(&config);
     let query = Arc::clone(&query);
+    let networks = Arc::new(networks);
+    let permits = Arc::new(Semaphore::new(config.http_max_concurrency));
 
-    let make_service_fn_inn = || {
+    let make_service_fn_inn = |client: Option<SocketAddr>| {
         let query = Arc::clone(&query);
         let config = Arc::clone(&config);
+        let networks = Arc::clone(&networks);
+        let permits = Arc::clone(&permits);
 
         async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| {
-                let query = Arc::clone(&query);
-                let config = Arc::clone(&config);
+            Ok::<_, hyper::Error>(service_fn(move |mut req| {
+                let mut query = Arc::clone(&query);
+                let mut config = Arc::clone(&config);
+                let permits = Arc::clone(&permits);
+
+                let route = req.uri().path().to_string();
+                let mut syncing = None;
+                if let Some(network) = route_network(&mut req, &networks) {
+                    match network.query.get() {
+                        Some(network_query) => {
+                            query = Arc::clone(network_query);
+                            config = Arc::clone(&network.config);
+                        }
+                        None => syncing = Some(network.prefix.clone()),
+                    }
+                }
 
                 async move {
+                    if let Some(prefix) = syncing {
+                        return Ok(error_response(HttpError(
+                            ErrorCode::IndexLagging,
+                            format!("The {} network is still syncing", prefix),
+                        )));
+                    }
+
+                    let request_id = REQUEST_ID.fetch_add(1, Ordering::Relaxed);
                     let method = req.method().clone();
-                    let uri = req.uri().clone();
-                    let body = hyper::body::to_bytes(req.into_body()).await?;
-
-                    let mut resp = handle_request(method, uri, body, &query, &config)
-                        .unwrap_or_else(|err| {
-                            warn!("{:?}", err);
-                            Response::builder()
-                                .status(err.0)
-                                .header("Content-Type", "text/plain")
-                                .body(Body::from(err.1))
-                                .unwrap()
-                        });
-                    if let Some(ref origins) = config.cors {
-                        resp.headers_mut()
-                            .insert("Access-Control-Allow-Origin", origins.parse().unwrap());
+                    let started = Instant::now();
+                    // the records logged while serving the request are attached to this span
+                    let span = tracing::debug_span!(
+                        target: "electrs::rest",
+                        "request",
+                        request_id,
+                        %method,
+                        %route,
+                        client = ?client
+                    );
+
+                    let resp = serve_request(req, query, Arc::clone(&config), permits)
+                        .instrument(span)
+                        .await?;
+                    let elapsed = started.elapsed();
+                    let query_cost = resp.extensions().get::<QueryCost>().copied();
+
+                    tracing::debug!(
+                        target: "electrs::rest",
+                        request_id,
+                        %method,
+                        %route,
+                        status = resp.status().as_u16(),
+                        duration_ms = elapsed.as_millis() as u64,
+                        client = ?client,
+                        "request served"
+                    );
+                    if let Some(query_cost) =
+                        query_cost.filter(|c| cost::is_slow_query(elapsed, c, &config))
+                    {
+                        tracing::warn!(
+                            target: "electrs::rest",
+                            request_id,
+                            %method,
+                            %route,
+                            status = resp.status().as_u16(),
+                            duration_ms = elapsed.as_millis() as u64,
+                            rows_scanned = query_cost.rows_scanned,
+                            txs_deserialized = query_cost.txs_deserialized,
+                            daemon_rpcs = query_cost.daemon_rpcs,
+                            client = ?client,
+                            "slow query"
+                        );
                     }
                     Ok::<_, hyper::Error>(resp)
                 }
@@ -523,9 +1454,13 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
             let socket = create_socket(&addr);
             socket.listen(511).expect("setting backlog failed");
 
-            Server::from_tcp(socket.into())
+            let builder = Server::from_tcp(socket.into())
                 .expect("Server::from_tcp failed")
-                .serve(make_service_fn(move |_| make_service_fn_inn()))
+                .tcp_keepalive(secs_or_none(config.http_keep_alive_idle));
+            configure_protocol(builder, &config)
+                .serve(make_service_fn(move |conn: &AddrStream| {
+                    make_service_fn_inn(Some(conn.remote_addr()))
+                }))
                 .with_graceful_shutdown(async {
                     rx.await.ok();
                 })
@@ -541,9 +1476,9 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
 
             info!("REST server running on unix socket {}", path.display());
 
-            Server::bind_unix(path)
-                .expect("Server::bind_unix failed")
-                .serve(make_service_fn(move |_| make_service_fn_inn()))
+            let builder = Server::bind_unix(path).expect("Server::bind_unix failed");
+            configure_protocol(builder, &config)
+                .serve(make_service_fn(move |_| make_service_fn_inn(None)))
                 .with_graceful_shutdown(async {
                     rx.await.ok();
                 })
@@ -556,13 +1491,518 @@ async fn run_server(config: Arc<Config>, query: Arc<Query>, rx: oneshot::Receive
     }
 }
 
+// Durations in seconds are disabled with 0
+fn secs_or_none(secs: u64) -> Option<Duration> {
+    Some(Duration::from_secs(secs)).filter(|_| secs > 0)
+}
+
+fn configure_protocol<I>(builder: Builder<I>, config: &Config) -> Builder<I> {
+    let builder = match secs_or_none(config.http_header_read_timeout) {
+        Some(timeout) => builder.http1_header_read_timeout(timeout),
+        None => builder,
+    };
+    if !config.http2 {
+        return builder.http1_only(true);
+    }
+    builder
+        .http2_keep_alive_interval(secs_or_none(config.http2_keep_alive_interval))
+        .http2_max_concurrent_streams(config.http2_max_concurrent_streams)
+}
+
+async fn serve_request(
+    req: hyper::Request<Body>,
+    query: Arc<Query>,
+    config: Arc<Config>,
+    permits: Arc<Semaphore>,
+) -> Result<Response<Body>, hyper::Error> {
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+    let accept_encoding = req
+        .headers()
+        .get(hyper::header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .and_then(negotiate_encoding);
+    let if_none_match = req
+        .headers()
+        .get(hyper::header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let origin = req
+        .headers()
+        .get(hyper::header::ORIGIN)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from);
+    let tip = query.chain().best_header();
+    let esplora_compat = restricted_to_esplora(&config, uri.path());
+
+    // CORS preflights carry no credentials and are answered before authentication
+    if method == Method::OPTIONS && config.runtime().cors.is_some() {
+        return Ok(cors_preflight(&config, origin.as_deref()));
+    }
+
+    // health checks are used by orchestrators that don't carry API keys
+    let is_health_check = is_health_check(uri.path());
+    let auth = if is_health_check {
+        Ok(())
+    } else {
+        authenticate(req.headers(), &query)
+    };
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+
+    let result = match auth {
+        Err(err) => Err(err),
+        // health checks are cheap and must keep responding while the server is saturated
+        Ok(()) if is_health_check => handle_request(method.clone(), uri, body, &query, &config),
+        Ok(()) => {
+            let config = Arc::clone(&config);
+            handle_request_blocking(method.clone(), uri, body, query, config, permits).await
+        }
+    };
+    ESPLORA_COMPAT.with(|cell| cell.set(esplora_compat));
+    let mut resp = result.unwrap_or_else(error_response);
+    if config.runtime().cors.is_some() {
+        add_cors_headers(&mut resp, &config, origin.as_deref());
+    }
+    if method == Method::GET {
+        resp = apply_etag(resp, if_none_match).await?;
+    }
+    if let Some(encoding) = accept_encoding {
+        resp = compress_response(resp, encoding, &config).await?;
+    }
+    // lets clients paginating across several requests detect that the tip moved in between
+    let headers = resp.headers_mut();
+    headers.insert("X-Tip-Height", tip.height().into());
+    headers.insert("X-Tip-Hash", tip.hash().to_string().parse().unwrap());
+    Ok(resp)
+}
+
+// Wait for a free handler slot, giving up once the deadline is reached
+async fn acquire_permit(
+    permits: Arc<Semaphore>,
+    deadline: Option<tokio::time::Instant>,
+) -> Result<OwnedSemaphorePermit, HttpError> {
+    let permit = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, permits.acquire_owned())
+            .await
+            .map_err(|_| {
+                HttpError(
+                    ErrorCode::ServerBusy,
+                    "Server is busy, try again later".to_string(),
+                )
+            })?,
+        None => permits.acquire_owned().await,
+    };
+    Ok(permit.expect("request semaphore closed"))
+}
+
+// Run the request handler on the blocking thread pool, so that slow DB scans don't stall the
+// async workers. At most `http_max_concurrency` handlers run at once; requests that can't get
+// a slot before their timeout are rejected, and those that don't complete in time are answered
+// with an error (the handler itself keeps running until it's done).
+async fn handle_request_blocking(
+    method: Method,
+    uri: hyper::Uri,
+    body: hyper::body::Bytes,
+    query: Arc<Query>,
+    config: Arc<Config>,
+    permits: Arc<Semaphore>,
+) -> Result<Response<Body>, HttpError> {
+    let deadline = request_timeout(&method, uri.path(), &config)
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+
+    let permit = acquire_permit(permits, deadline).await?;
+    let span = tracing::Span::current();
+    let handler = task::spawn_blocking(move || {
+        let _permit = permit;
+        let _span = span.entered();
+        cost::reset();
+        let mut resp =
+            handle_request(method, uri, body, &query, &config).unwrap_or_else(error_response);
+        let query_cost = cost::take();
+        if config.query_cost_headers {
+            let headers = resp.headers_mut();
+            headers.insert("X-Query-Cost-Rows-Scanned", query_cost.rows_scanned.into());
+            headers.insert(
+                "X-Query-Cost-Txs-Deserialized",
+                query_cost.txs_deserialized.into(),
+            );
+            headers.insert("X-Query-Cost-Daemon-Rpcs", query_cost.daemon_rpcs.into());
+        }
+        // picked up by the request logging
+        resp.extensions_mut().insert(query_cost);
+        Ok(resp)
+    });
+    let joined = match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, handler)
+            .await
+            .map_err(|_| HttpError(ErrorCode::Timeout, "Request timed out".to_string()))?,
+        None => handler.await,
+    };
+    joined.unwrap_or_else(|e| {
+        Err(HttpError(
+            ErrorCode::Internal,
+            format!("Request handler failed: {}", e),
+        ))
+    })
+}
+
+// Marks responses whose body is produced while it is being sent, which must not be buffered.
+// They are sent without an ETag, and compressed chunk by chunk.
+struct StreamedBody;
+
+// Runs the producer on the blocking thread pool, sending each chunk it passes to the callback as
+// soon as the client is ready for it. The callback returns false once the client went away.
+fn stream_response(
+    content_type: &str,
+    produce: impl FnOnce(&mut dyn FnMut(hyper::body::Bytes) -> bool) + Send + 'static,
+) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let handle = runtime::Handle::current();
+    let span = tracing::Span::current();
+    task::spawn_blocking(move || {
+        let _span = span.entered();
+        produce(&mut |chunk| handle.block_on(sender.send_data(chunk)).is_ok())
+    });
+    let mut resp = Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", content_type)
+        .body(body)
+        .unwrap();
+    resp.extensions_mut().insert(StreamedBody);
+    resp
+}
+
+// The confirmed history of the scripthash as CSV, in ascending order and with the amounts in sats.
+// The fee is only filled in for the transactions spending from the scripthash.
+#[cfg(not(feature = "liquid"))]
+fn history_csv(query: &Query, scripthash: &[u8], send: &mut dyn FnMut(hyper::body::Bytes) -> bool) {
+    use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+    let mut csv = String::from("timestamp,txid,direction,amount,fee,balance\n");
+    let mut balance: i64 = 0;
+    for delta in query.chain().history_deltas(scripthash) {
+        let amount = delta.funded as i64 - delta.spent as i64;
+        balance += amount;
+        let fee = if delta.spent > 0 {
+            query
+                .chain()
+                .get_tx_fee(&delta.txid)
+                .map(|fee| fee.to_string())
+        } else {
+            None
+        };
+        let timestamp = OffsetDateTime::from_unix_timestamp(delta.blockid.time as i64)
+            .ok()
+            .and_then(|time| time.format(&Rfc3339).ok())
+            .unwrap_or_default();
+        csv.push_str(&format!(
+            "{},{},{},{},{},{}\n",
+            timestamp,
+            delta.txid,
+            if amount >= 0 { "incoming" } else { "outgoing" },
+            amount.abs(),
+            fee.unwrap_or_default(),
+            balance
+        ));
+        if csv.len() >= STREAM_CHUNK_SIZE && !send(std::mem::take(&mut csv).into()) {
+            return;
+        }
+    }
+    send(csv.into());
+}
+
+// Consecutive raw blocks, each prefixed with its size as a 4 bytes little-endian integer
+fn raw_blocks(
+    query: &Query,
+    hashes: &[BlockHash],
+    send: &mut dyn FnMut(hyper::body::Bytes) -> bool,
+) {
+    for hash in hashes {
+        let raw = match query.chain().get_block_raw(hash) {
+            Some(raw) => raw,
+            // orphaned while streaming, end the stream early
+            None => return,
+        };
+        let mut chunk = Vec::with_capacity(4 + raw.len());
+        chunk.extend_from_slice(&(raw.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(&raw);
+        if !send(chunk.into()) {
+            return;
+        }
+    }
+}
+
+fn error_response(err: HttpError) -> Response<Body> {
+    tracing::warn!(target: "electrs::rest", "{:?}", err);
+    // Esplora responds with the bare message
+    if is_esplora_compat() {
+        return Response::builder()
+            .status(err.0.status())
+            .header("Content-Type", "text/plain")
+            .body(Body::from(err.1))
+            .unwrap();
+    }
+    let body = json!({ "code": err.0, "message": err.1 });
+    Response::builder()
+        .status(err.0.status())
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+// Broadcasts are not timed out, since the client would otherwise not know whether the
+// transaction made it to the daemon
+fn request_timeout(method: &Method, path: &str, config: &Config) -> Option<Duration> {
+    if let Some(route) = RouteTimeout::lookup(&config.http_route_timeouts, path) {
+        Some(route.timeout).filter(|timeout| !timeout.is_zero())
+    } else if config.http_request_timeout == 0 || (method == Method::POST && path == "/tx") {
+        None
+    } else {
+        Some(Duration::from_secs(config.http_request_timeout))
+    }
+}
+
+// Tag successful GET responses with a strong ETag derived from their content, and reply with
+// 304 Not Modified if it matches the tag provided by the client in If-None-Match
+async fn apply_etag(
+    resp: Response<Body>,
+    if_none_match: Option<String>,
+) -> Result<Response<Body>, hyper::Error> {
+    if resp.status() != StatusCode::OK || resp.extensions().get::<StreamedBody>().is_some() {
+        return Ok(resp);
+    }
+
+    let (mut parts, body) = resp.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let etag = format!("\"{}\"", &sha256::Hash::hash(&body).to_string()[..32]);
+
+    let not_modified = if_none_match.map_or(false, |tags| {
+        tags.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == etag || tag == "*")
+    });
+
+    parts
+        .headers
+        .insert(hyper::header::ETAG, etag.parse().unwrap());
+
+    if not_modified {
+        parts.status = StatusCode::NOT_MODIFIED;
+        parts.headers.remove(hyper::header::CONTENT_TYPE);
+        Ok(Response::from_parts(parts, Body::empty()))
+    } else {
+        Ok(Response::from_parts(parts, Body::from(body)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ContentEncoding {
+    Brotli,
+    Gzip,
+}
+
+impl ContentEncoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            ContentEncoding::Brotli => "br",
+            ContentEncoding::Gzip => "gzip",
+        }
+    }
+}
+
+// Pick the preferred encoding supported by the client, favoring brotli over gzip
+fn negotiate_encoding(accept_encoding: &str) -> Option<ContentEncoding> {
+    let accepted: Vec<&str> = accept_encoding
+        .split(',')
+        .filter_map(|part| {
+            let mut params = part.split(';').map(str::trim);
+            let name = params.next()?;
+            // skip encodings explicitly marked as not acceptable
+            let disallowed = params.any(|p| p == "q=0" || p == "q=0.0" || p == "q=0.00");
+            (!disallowed).then(|| name)
+        })
+        .collect();
+
+    if accepted.contains(&"br") {
+        Some(ContentEncoding::Brotli)
+    } else if accepted.contains(&"gzip") {
+        Some(ContentEncoding::Gzip)
+    } else {
+        None
+    }
+}
+
+// Incremental encoder for a response body, shared by the buffered and the streamed responses
+enum BodyEncoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl BodyEncoder {
+    fn new(encoding: ContentEncoding, level: u32) -> Self {
+        match encoding {
+            ContentEncoding::Gzip => BodyEncoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(level),
+            )),
+            ContentEncoding::Brotli => {
+                // brotli quality ranges from 0 to 11, scale it from the 1-9 level range
+                let quality = (level * 11 + 8) / 9;
+                BodyEncoder::Brotli(Box::new(brotli::CompressorWriter::new(
+                    Vec::new(),
+                    4096,
+                    quality,
+                    22,
+                )))
+            }
+        }
+    }
+
+    // Feed a chunk of the body, returning the compressed output produced so far
+    fn push(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            BodyEncoder::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+            BodyEncoder::Brotli(encoder) => {
+                encoder.write_all(chunk)?;
+                Ok(std::mem::take(encoder.get_mut()))
+            }
+        }
+    }
+
+    // Terminate the stream, returning the remaining compressed output
+    fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            BodyEncoder::Gzip(encoder) => encoder.finish(),
+            BodyEncoder::Brotli(mut encoder) => {
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+        }
+    }
+}
+
+// Compress a streamed body chunk by chunk as it is being produced, without buffering it
+fn compress_stream(mut body: Body, encoding: ContentEncoding, level: u32) -> Body {
+    let (mut sender, compressed) = Body::channel();
+    tokio::spawn(async move {
+        let mut encoder = BodyEncoder::new(encoding, level);
+        while let Some(chunk) = body.data().await {
+            let output = match chunk {
+                Ok(chunk) => encoder.push(&chunk),
+                Err(e) => {
+                    tracing::warn!(target: "electrs::rest", "failed reading streamed response: {}", e);
+                    return sender.abort();
+                }
+            };
+            match output {
+                Ok(output) if output.is_empty() => (),
+                Ok(output) => {
+                    if sender.send_data(output.into()).await.is_err() {
+                        // the client went away
+                        return;
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(target: "electrs::rest", "failed compressing streamed response: {}", e);
+                    return sender.abort();
+                }
+            }
+        }
+        match encoder.finish() {
+            Ok(output) => {
+                sender.send_data(output.into()).await.ok();
+            }
+            Err(e) => {
+                tracing::warn!(target: "electrs::rest", "failed compressing streamed response: {}", e);
+                sender.abort();
+            }
+        }
+    }.in_current_span());
+    compressed
+}
+
+async fn compress_response(
+    resp: Response<Body>,
+    encoding: ContentEncoding,
+    config: &Config,
+) -> Result<Response<Body>, hyper::Error> {
+    if config.http_compression_level == 0
+        || resp.status() != StatusCode::OK
+        || resp.headers().contains_key(hyper::header::CONTENT_ENCODING)
+    {
+        return Ok(resp);
+    }
+
+    let level = config.http_compression_level;
+    let (mut parts, body) = resp.into_parts();
+    let body = if parts.extensions.get::<StreamedBody>().is_some() {
+        // the size of streamed bodies is not known upfront, always compress them
+        compress_stream(body, encoding, level)
+    } else {
+        let body = hyper::body::to_bytes(body).await?;
+        if body.len() < config.http_compression_min_size {
+            return Ok(Response::from_parts(parts, Body::from(body)));
+        }
+
+        let mut encoder = BodyEncoder::new(encoding, level);
+        let compressed = encoder
+            .push(&body)
+            .and_then(|head| Ok([head, encoder.finish()?].concat()));
+        match compressed {
+            Ok(compressed) => Body::from(compressed),
+            Err(e) => {
+                tracing::warn!(target: "electrs::rest", "failed compressing response: {}", e);
+                return Ok(Response::from_parts(parts, Body::from(body)));
+            }
+        }
+    };
+
+    parts.headers.insert(
+        hyper::header::CONTENT_ENCODING,
+        encoding.as_str().parse().unwrap(),
+    );
+    parts
+        .headers
+        .append(hyper::header::VARY, "Accept-Encoding".parse().unwrap());
+    parts.headers.remove(hyper::header::CONTENT_LENGTH);
+    // the compressed representation is not byte-identical, downgrade the ETag to a weak one
+    if let Some(etag) = parts.headers.get(hyper::header::ETAG).cloned() {
+        if let Ok(etag) = etag.to_str() {
+            if !etag.starts_with("W/") {
+                let weak_etag = format!("W/{}", etag);
+                parts
+                    .headers
+                    .insert(hyper::header::ETAG, weak_etag.parse().unwrap());
+            }
+        }
+    }
+    Ok(Response::from_parts(parts, body))
+}
+
 pub fn start(config: Arc<Config>, query: Arc<Query>) -> Handle {
+    start_with_networks(config, query, vec![])
+}
+
+// Serve the main network along with the given additional networks, on the main network's address
+pub fn start_with_networks(
+    config: Arc<Config>,
+    query: Arc<Query>,
+    networks: Vec<NetworkRoute>,
+) -> Handle {
     let (tx, rx) = oneshot::channel::<()>();
 
     Handle {
         tx,
         thread: thread::spawn(move || {
-            run_server(config, query, rx);
+            let runtime = runtime::Builder::new_multi_thread()
+                .enable_all()
+                .max_blocking_threads(config.http_max_concurrency)
+                .build()
+                .expect("failed building the REST server runtime");
+            runtime.block_on(run_server(config, query, networks, rx));
         }),
     }
 }
@@ -579,23 +2019,115 @@ impl Handle {
     }
 }
 
+// Returns the value to use for Access-Control-Allow-Origin, if the request origin is allowed
+fn cors_allowed_origin(config: &Config, origin: Option<&str>) -> Option<String> {
+    let allowed = config.runtime().cors?;
+    if allowed.trim() == "*" {
+        return Some("*".to_string());
+    }
+    let origin = origin?;
+    allowed
+        .split(',')
+        .map(str::trim)
+        .find(|allowed| *allowed == origin)
+        .map(String::from)
+}
+
+fn add_cors_headers(resp: &mut Response<Body>, config: &Config, origin: Option<&str>) {
+    let headers = resp.headers_mut();
+    if let Some(allowed_origin) = cors_allowed_origin(config, origin) {
+        if allowed_origin != "*" {
+            headers.append(hyper::header::VARY, "Origin".parse().unwrap());
+        }
+        headers.insert(
+            hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            allowed_origin.parse().unwrap(),
+        );
+        headers.insert(
+            hyper::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+            "ETag, X-Tip-Height, X-Tip-Hash".parse().unwrap(),
+        );
+    }
+}
+
+fn cors_preflight(config: &Config, origin: Option<&str>) -> Response<Body> {
+    let mut resp = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap();
+    if cors_allowed_origin(config, origin).is_some() {
+        let headers = resp.headers_mut();
+        headers.insert(
+            hyper::header::ACCESS_CONTROL_ALLOW_METHODS,
+            "GET, POST, PUT, DELETE, OPTIONS".parse().unwrap(),
+        );
+        headers.insert(
+            hyper::header::ACCESS_CONTROL_ALLOW_HEADERS,
+            config.cors_allow_headers.clone(),
+        );
+        headers.insert(
+            hyper::header::ACCESS_CONTROL_MAX_AGE,
+            config.cors_max_age.into(),
+        );
+    }
+    add_cors_headers(&mut resp, config, origin);
+    resp
+}
+
+// Check the API key provided via the `Authorization: Bearer <key>` or `X-API-Key` headers,
+// if authentication is enabled
+fn authenticate(headers: &hyper::HeaderMap, query: &Query) -> Result<(), HttpError> {
+    let api_keys = match query.api_keys() {
+        Some(api_keys) => api_keys,
+        None => return Ok(()),
+    };
+    let key = headers
+        .get(hyper::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            headers
+                .get("X-API-Key")
+                .and_then(|value| value.to_str().ok())
+        });
+    api_keys.check(key).map_err(HttpError::from)
+}
+
 fn handle_request(
     method: Method,
     uri: hyper::Uri,
     body: hyper::body::Bytes,
-    query: &Query,
+    query: &Arc<Query>,
     config: &Config,
 ) -> Result<Response<Body>, HttpError> {
     // TODO it looks hyper does not have routing and query parsing :(
-    let path: Vec<&str> = uri.path().split('/').skip(1).collect();
+    let mut path: Vec<&str> = uri.path().split('/').skip(1).collect();
+    if is_versioned(uri.path()) {
+        path.remove(0);
+    }
+    let esplora_compat = restricted_to_esplora(config, uri.path());
+    ESPLORA_COMPAT.with(|cell| cell.set(esplora_compat));
+    if esplora_compat && !is_esplora_route(&method, &path) && !is_health_check(uri.path()) {
+        return Err(HttpError::not_found(format!(
+            "endpoint does not exist {:?}",
+            uri.path()
+        )));
+    }
+    if let Some(route) = disabled_route(&method, &path, config) {
+        return Err(HttpError(
+            ErrorCode::RouteDisabled,
+            format!("{} is disabled on this server", route.name()),
+        ));
+    }
     let query_params = match uri.query() {
         Some(value) => form_urlencoded::parse(&value.as_bytes())
             .into_owned()
             .collect::<HashMap<String, String>>(),
         None => HashMap::new(),
     };
+    let with_prevouts = prevouts_param(&query_params);
 
-    info!("handle {:?} {:?}", method, uri);
+    tracing::info!(target: "electrs::rest", "handle {:?} {:?}", method, uri);
     match (
         &method,
         path.get(0),
@@ -604,6 +2136,62 @@ fn handle_request(
         path.get(3),
         path.get(4),
     ) {
+        (&Method::GET, Some(&"healthz"), None, None, None, None) => {
+            let indexed_height = query.chain().best_height();
+            match query.check_db() {
+                Ok(()) => health_response(HealthValue {
+                    status: "ok",
+                    indexed_height,
+                    daemon_height: None,
+                    error: None,
+                }),
+                Err(e) => health_response(HealthValue {
+                    status: "unavailable",
+                    indexed_height,
+                    daemon_height: None,
+                    error: Some(e.to_string()),
+                }),
+            }
+        }
+        (&Method::GET, Some(&"readyz"), None, None, None, None) => {
+            let indexed_height = query.chain().best_height();
+            match query.daemon_height() {
+                Ok(daemon_height) => {
+                    let lag = daemon_height.saturating_sub(indexed_height);
+                    health_response(HealthValue {
+                        status: if lag <= config.readiness_max_lag {
+                            "ready"
+                        } else {
+                            "syncing"
+                        },
+                        indexed_height,
+                        daemon_height: Some(daemon_height),
+                        error: None,
+                    })
+                }
+                Err(e) => health_response(HealthValue {
+                    status: "daemon unreachable",
+                    indexed_height,
+                    daemon_height: None,
+                    error: Some(e.to_string()),
+                }),
+            }
+        }
+
+        (&Method::GET, Some(&"stats"), Some(&"db"), None, None, None) => {
+            let stats: HashMap<_, _> = query.chain().store().db_stats().into_iter().collect();
+            json_response(stats, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"stats"), Some(&"chain"), None, None, None) => {
+            let from = query_params
+                .get("from")
+                .map_or(Ok(0), |from| from.parse::<u32>())?;
+            let to = query_params
+                .get("to")
+                .map_or(Ok(u32::MAX), |to| to.parse::<u32>())?;
+            json_response(query.chain().chain_stats_per_day(from, to), TTL_SHORT)
+        }
+
         (&Method::GET, Some(&"blocks"), Some(&"tip"), Some(&"hash"), None, None) => http_message(
             StatusCode::OK,
             query.chain().best_hash().to_string(),
@@ -616,16 +2204,81 @@ fn handle_request(
             TTL_SHORT,
         ),
 
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"difficulty-adjustment"), None, None, None, None) => {
+            let epoch = query
+                .chain()
+                .get_difficulty_epoch(query.chain().best_height())
+                .ok_or_else(|| HttpError::block_not_found("No blocks were indexed".to_string()))?;
+            json_response(epoch, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"blocks"), Some(&"raw"), Some(start_height), Some(count), None) => {
+            let start_height = start_height.parse::<usize>()?;
+            let count = count.parse::<usize>()?;
+            if count == 0 || count > RAW_BLOCKS_LIMIT {
+                return Err(HttpError::from(format!(
+                    "count must be between 1 and {}",
+                    RAW_BLOCKS_LIMIT
+                )));
+            }
+            let hashes: Vec<BlockHash> = query
+                .chain()
+                .headers_range(start_height, count)
+                .iter()
+                .map(|header| header.block_hash())
+                .collect();
+            if hashes.is_empty() {
+                return Err(HttpError::block_not_found("Block not found".to_string()));
+            }
+            let ttl = ttl_by_depth(Some(start_height + hashes.len() - 1), query);
+            let query = Arc::clone(query);
+            let mut resp = stream_response("application/octet-stream", move |send| {
+                raw_blocks(&query, &hashes, send)
+            });
+            resp.headers_mut()
+                .insert("Cache-Control", cache_control(ttl).parse().unwrap());
+            Ok(resp)
+        }
         (&Method::GET, Some(&"blocks"), start_height, None, None, None) => {
             let start_height = start_height.and_then(|height| height.parse::<usize>().ok());
             blocks(&query, start_height)
         }
+        (&Method::GET, Some(&"headers"), Some(start_height), Some(count), None, None) => {
+            let start_height = start_height.parse::<usize>()?;
+            let count = count.parse::<usize>()?;
+            if count == 0 || count > HEADERS_LIMIT {
+                return Err(HttpError::from(format!(
+                    "count must be between 1 and {}",
+                    HEADERS_LIMIT
+                )));
+            }
+            let headers = query.chain().headers_range(start_height, count);
+            if headers.is_empty() {
+                return Err(HttpError::block_not_found("Block not found".to_string()));
+            }
+            let raw: Vec<u8> = headers.iter().flat_map(encode::serialize).collect();
+            // the headers of the last blocks may still be reorged
+            let ttl = ttl_by_depth(Some(start_height + headers.len() - 1), query);
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/octet-stream")
+                .header("Cache-Control", cache_control(ttl))
+                .body(Body::from(raw))
+                .unwrap())
+        }
         (&Method::GET, Some(&"block-height"), Some(height), None, None, None) => {
             let height = height.parse::<usize>()?;
-            let header = query
-                .chain()
-                .header_by_height(height)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            let header = match query.chain().header_by_height(height) {
+                Some(header) => header,
+                // known to the daemon, but not indexed yet
+                None if query.daemon_height().map_or(false, |tip| height <= tip) => {
+                    return Err(HttpError(
+                        ErrorCode::IndexLagging,
+                        "Block not indexed yet".to_string(),
+                    ));
+                }
+                None => return Err(HttpError::block_not_found("Block not found".to_string())),
+            };
             let ttl = ttl_by_depth(Some(height), query);
             http_message(StatusCode::OK, header.hash().to_string(), ttl)
         }
@@ -634,7 +2287,7 @@ fn handle_request(
             let blockhm = query
                 .chain()
                 .get_block_with_meta(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| block_unavailable(query, config, &hash))?;
             let block_value = BlockValue::new(blockhm);
             json_response(block_value, TTL_LONG)
         }
@@ -649,7 +2302,7 @@ fn handle_request(
             let txids = query
                 .chain()
                 .get_block_txids(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| block_unavailable(query, config, &hash))?;
             json_response(txids, TTL_LONG)
         }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"header"), None, None) => {
@@ -657,7 +2310,7 @@ fn handle_request(
             let header = query
                 .chain()
                 .get_block_header(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| HttpError::block_not_found("Block not found".to_string()))?;
 
             let header_hex = encode::serialize_hex(&header);
             http_message(StatusCode::OK, header_hex, TTL_LONG)
@@ -667,22 +2320,73 @@ fn handle_request(
             let raw = query
                 .chain()
                 .get_block_raw(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| block_unavailable(query, config, &hash))?;
 
             Ok(Response::builder()
                 .status(StatusCode::OK)
                 .header("Content-Type", "application/octet-stream")
-                .header("Cache-Control", format!("public, max-age={:}", TTL_LONG))
+                .header("Cache-Control", cache_control(TTL_LONG))
                 .body(Body::from(raw))
                 .unwrap())
         }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"utxo-muhash"), None, None) => {
+            if !config.utxo_muhash {
+                return Err(HttpError::from("UTXO set hashes disabled".to_string()));
+            }
+            let hash = BlockHash::from_str(hash)?;
+            let height = query
+                .chain()
+                .height_by_hash(&hash)
+                .ok_or_else(|| HttpError::block_not_found("Block not found".to_string()))?;
+            let muhash = query.chain().utxo_muhash(&hash).ok_or_else(|| {
+                HttpError::not_found("UTXO set hash not available for this block".to_string())
+            })?;
+            // in the byte order displayed by bitcoind
+            let mut muhash = muhash.to_byte_array();
+            muhash.reverse();
+            http_message(
+                StatusCode::OK,
+                muhash.to_lower_hex_string(),
+                ttl_by_depth(Some(height), query),
+            )
+        }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"block"), Some(hash), Some(&"coinbase"), None, None) => {
+            let hash = BlockHash::from_str(hash)?;
+            let txid = query
+                .chain()
+                .get_block_txids(&hash)
+                .and_then(|txids| txids.first().copied())
+                .ok_or_else(|| block_unavailable(query, config, &hash))?;
+            let tx = query
+                .lookup_txn(&txid)
+                .ok_or_else(|| "missing tx".to_string())?;
+
+            let scriptsig = &tx.input[0].script_sig;
+            let coinbase = CoinbaseValue {
+                txid,
+                scriptsig: scriptsig.clone(),
+                scriptsig_asm: scriptsig.to_asm(),
+                decoded: coinbase::parse_coinbase_script(scriptsig),
+                total_value: tx.output.iter().map(|txout| txout.value.to_sat()).sum(),
+                witness_commitment: coinbase::witness_commitment(&tx),
+                vout: tx
+                    .output
+                    .iter()
+                    .map(|txout| TxOutValue::new(txout, config))
+                    .collect(),
+            };
+            let height = query.chain().blockid_by_hash(&hash).map(|b| b.height);
+            json_response(coinbase, ttl_by_depth(height, query))
+        }
         (&Method::GET, Some(&"block"), Some(hash), Some(&"txid"), Some(index), None) => {
             let hash = BlockHash::from_str(hash)?;
             let index: usize = index.parse()?;
             let txids = query
                 .chain()
                 .get_block_txids(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| block_unavailable(query, config, &hash))?;
             if index >= txids.len() {
                 bail!(HttpError::not_found("tx index out of range".to_string()));
             }
@@ -693,17 +2397,22 @@ fn handle_request(
             let txids = query
                 .chain()
                 .get_block_txids(&hash)
-                .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+                .ok_or_else(|| block_unavailable(query, config, &hash))?;
 
             let start_index = start_index
                 .map_or(0u32, |el| el.parse().unwrap_or(0))
                 .max(0u32) as usize;
+            let limit = limit_param(
+                &query_params,
+                CHAIN_TXS_PER_PAGE,
+                config.rest_block_txs_limit,
+            )?;
             if start_index >= txids.len() {
                 bail!(HttpError::not_found("start index out of range".to_string()));
-            } else if start_index % CHAIN_TXS_PER_PAGE != 0 {
+            } else if start_index % limit != 0 {
                 bail!(HttpError::from(format!(
                     "start index must be a multipication of {}",
-                    CHAIN_TXS_PER_PAGE
+                    limit
                 )));
             }
 
@@ -714,7 +2423,7 @@ fn handle_request(
             let txs = txids
                 .iter()
                 .skip(start_index)
-                .take(CHAIN_TXS_PER_PAGE)
+                .take(limit)
                 .map(|txid| {
                     query
                         .lookup_txn(&txid)
@@ -726,17 +2435,229 @@ fn handle_request(
             // XXX orphraned blocks alway get TTL_SHORT
             let ttl = ttl_by_depth(confirmed_blockid.map(|b| b.height), query);
 
-            json_response(prepare_txs(txs, query, config), ttl)
+            json_response(prepare_txs(txs, query, config, with_prevouts), ttl)
         }
         (&Method::GET, Some(script_type @ &"address"), Some(script_str), None, None, None)
         | (&Method::GET, Some(script_type @ &"scripthash"), Some(script_str), None, None, None) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
-            let stats = query.stats(&script_hash[..]);
+            // the chain stats as last cached, without replaying the blocks added since
+            let cached = match query_params.get("cached").map(String::as_str) {
+                Some("true") if !is_esplora_compat() => {
+                    query.chain().cached_stats(&script_hash[..])
+                }
+                _ => None,
+            };
+            let chain_stats_height = cached.as_ref().map(|(_, height)| *height);
+            let (chain_stats, mempool_stats) = match cached {
+                Some((chain_stats, _)) => (chain_stats, query.mempool().stats(&script_hash[..])),
+                None => query.stats(&script_hash[..]),
+            };
+            let mut value = json!({
+                *script_type: script_str,
+                "chain_stats": chain_stats,
+                "mempool_stats": mempool_stats,
+            });
+            if is_esplora_compat() {
+                for stats in ["chain_stats", "mempool_stats"] {
+                    value[stats].as_object_mut().unwrap().remove("balance");
+                }
+                return json_response(value, TTL_SHORT);
+            }
+            if let Some(height) = chain_stats_height {
+                value["chain_stats_height"] = json!(height);
+            }
+            if let Some(count) = query.funding_tx_count(&script_hash[..]) {
+                value["funding_tx_count"] = json!(count);
+                value["reused"] = json!(count > 1);
+            }
+            // the history rows don't record assets, so only the unspent outputs are accounted for
+            #[cfg(feature = "liquid")]
+            if let Some(asset) = query_params.get("asset") {
+                let asset = AssetId::from_str(asset)?;
+                let utxos: Vec<Utxo> = query
+                    .utxo(&script_hash[..])?
+                    .into_iter()
+                    .filter(|utxo| utxo.asset.explicit() == Some(asset))
+                    .collect();
+                value["asset_stats"] = json!({
+                    "asset": asset,
+                    "utxo_count": utxos.len(),
+                    "utxo_sum": utxos.iter().filter_map(|utxo| utxo.value.explicit()).sum::<u64>(),
+                });
+            }
+            if config.labels {
+                if let Some(label) = Label::get(query.chain().store().cache_db(), &script_hash) {
+                    value["label"] = json!(label);
+                }
+            }
+            if config.metadata {
+                let meta_db = query.chain().store().meta_db();
+                let metadata = meta::get_all(meta_db, &script_hash.to_lower_hex_string());
+                if !metadata.is_empty() {
+                    value["meta"] = json!(metadata);
+                }
+            }
+            json_response(value, TTL_SHORT)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs.csv"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs.csv"),
+            None,
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let query = Arc::clone(query);
+            let mut resp = stream_response("text/csv", move |send| {
+                history_csv(&query, &script_hash[..], send)
+            });
+            resp.headers_mut().insert(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}.csv\"", script_str)
+                    .parse()
+                    .map_err(|_| HttpError::from("Invalid address".to_string()))?,
+            );
+            Ok(resp)
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"activity"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"activity"),
+            None,
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let granularity = query_params
+                .get("granularity")
+                .map_or(Ok(Granularity::Day), |granularity| granularity.parse())?;
+            let periods: Vec<ActivityValue> = query
+                .chain()
+                .activity(&script_hash[..], granularity)
+                .into_iter()
+                .map(|(period_start, activity)| ActivityValue {
+                    period_start,
+                    activity,
+                })
+                .collect();
+            json_response(periods, TTL_SHORT)
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"balance-at"),
+            Some(height),
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"balance-at"),
+            Some(height),
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let height = height.parse::<usize>()?;
+            let blockhash = query
+                .chain()
+                .hash_by_height(height)
+                .ok_or_else(|| HttpError::block_not_found("Block not found".to_string()))?;
+            let stats = query.chain().stats_at_height(&script_hash[..], height);
+
+            #[allow(unused_mut)]
+            let mut res = json!({
+                *script_type: script_str,
+                "height": height,
+                "block_hash": blockhash,
+                "chain_stats": stats,
+            });
+            #[cfg(not(feature = "liquid"))]
+            {
+                res["balance"] = json!(stats.funded_txo_sum - stats.spent_txo_sum);
+            }
+            json_response(res, ttl_by_depth(Some(height), query))
+        }
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"cluster"),
+            last_seen,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"cluster"),
+            last_seen,
+            None,
+        ) => {
+            if !config.address_clustering {
+                return Err(HttpError::not_found(
+                    "Address clustering is disabled".to_string(),
+                ));
+            }
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let last_seen = last_seen.map(|s| parse_scripthash(s)).transpose()?;
+
+            let (cluster_id, size, members) = match query.chain().address_cluster(&script_hash) {
+                Some(cluster) => {
+                    let members = query
+                        .chain()
+                        .address_cluster_members(
+                            &cluster.root,
+                            last_seen.as_ref(),
+                            CLUSTER_MEMBERS_PER_PAGE,
+                        )
+                        .into_iter()
+                        .map(|member| ClusterMemberValue::new(member, config.network_type))
+                        .collect();
+                    (cluster.root, cluster.size, members)
+                }
+                // the script was never spent together with another one
+                None => {
+                    let members = if last_seen.is_none() {
+                        vec![ClusterMemberValue {
+                            scripthash: script_hash.to_lower_hex_string(),
+                            address: if *script_type == "address" {
+                                Some(script_str.to_string())
+                            } else {
+                                None
+                            },
+                        }]
+                    } else {
+                        vec![]
+                    };
+                    (script_hash, 1, members)
+                }
+            };
+
             json_response(
                 json!({
-                    *script_type: script_str,
-                    "chain_stats": stats.0,
-                    "mempool_stats": stats.1,
+                    "cluster_id": cluster_id.to_lower_hex_string(),
+                    "size": size,
+                    "members": members,
                 }),
                 TTL_SHORT,
             )
@@ -758,26 +2679,34 @@ fn handle_request(
             None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            let order = history_order(&query_params)?;
+            let limit = limit_param(&query_params, CHAIN_TXS_PER_PAGE, config.rest_history_limit)?;
 
-            let mut txs = vec![];
-
-            txs.extend(
-                query
-                    .mempool()
-                    .history(&script_hash[..], MAX_MEMPOOL_TXS)
-                    .into_iter()
-                    .map(|tx| (tx, None)),
-            );
-
-            txs.extend(
-                query
-                    .chain()
-                    .history(&script_hash[..], None, CHAIN_TXS_PER_PAGE)
-                    .into_iter()
-                    .map(|(tx, blockid)| (tx, Some(blockid))),
-            );
+            let mempool_txs = query
+                .mempool()
+                .history(&script_hash[..], MAX_MEMPOOL_TXS)
+                .into_iter()
+                .map(|tx| (tx, None));
+            let chain_txs = query
+                .chain()
+                .history(&script_hash[..], None, limit, order)
+                .into_iter()
+                .map(|(tx, blockid)| (tx, Some(blockid)));
+
+            let txs = match order {
+                HistoryOrder::Descending => mempool_txs.chain(chain_txs).collect(),
+                // unconfirmed transactions come last, so they're only included once the full
+                // confirmed history fits within the first page
+                HistoryOrder::Ascending => {
+                    let mut txs: Vec<_> = chain_txs.collect();
+                    if txs.len() < limit {
+                        txs.extend(mempool_txs);
+                    }
+                    txs
+                }
+            };
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            json_response(prepare_txs(txs, query, config, with_prevouts), TTL_SHORT)
         }
 
         (
@@ -798,19 +2727,17 @@ fn handle_request(
         ) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
             let last_seen_txid = last_seen_txid.and_then(|txid| Txid::from_str(txid).ok());
+            let order = history_order(&query_params)?;
+            let limit = limit_param(&query_params, CHAIN_TXS_PER_PAGE, config.rest_history_limit)?;
 
             let txs = query
                 .chain()
-                .history(
-                    &script_hash[..],
-                    last_seen_txid.as_ref(),
-                    CHAIN_TXS_PER_PAGE,
-                )
+                .history(&script_hash[..], last_seen_txid.as_ref(), limit, order)
                 .into_iter()
                 .map(|(tx, blockid)| (tx, Some(blockid)))
                 .collect();
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            json_response(prepare_txs(txs, query, config, with_prevouts), TTL_SHORT)
         }
         (
             &Method::GET,
@@ -834,18 +2761,116 @@ fn handle_request(
                 .mempool()
                 .history(&script_hash[..], MAX_MEMPOOL_TXS)
                 .into_iter()
-                .map(|tx| (tx, None))
+                .map(|tx| (tx, None))
+                .collect();
+
+            json_response(prepare_txs(txs, query, config, with_prevouts), TTL_SHORT)
+        }
+        #[cfg(not(feature = "liquid"))]
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"mempool"),
+            Some(&"full"),
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"txs"),
+            Some(&"mempool"),
+            Some(&"full"),
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+
+            // the net delta covers all the unconfirmed transactions, not only the listed ones
+            let ((txs, deltas), net_delta): ((Vec<_>, Vec<_>), i64) = {
+                let mempool = query.mempool();
+                let txs = mempool
+                    .history_with_delta(&script_hash[..], MAX_MEMPOOL_TXS)
+                    .into_iter()
+                    .map(|(tx, delta)| ((tx, None), delta))
+                    .unzip();
+                (txs, mempool.stats(&script_hash[..]).balance)
+            };
+
+            let txs: Vec<MempoolTxValue> = prepare_txs(txs, query, config, with_prevouts)
+                .into_iter()
+                .zip(deltas)
+                .map(|(tx, balance_delta)| MempoolTxValue { tx, balance_delta })
+                .collect();
+
+            json_response(
+                json!({
+                    *script_type: script_str,
+                    "balance_delta": net_delta,
+                    "txs": txs,
+                }),
+                TTL_SHORT,
+            )
+        }
+
+        (
+            &Method::GET,
+            Some(script_type @ &"address"),
+            Some(script_str),
+            Some(&"utxo"),
+            None,
+            None,
+        )
+        | (
+            &Method::GET,
+            Some(script_type @ &"scripthash"),
+            Some(script_str),
+            Some(&"utxo"),
+            None,
+            None,
+        ) => {
+            let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
+            #[allow(unused_mut)]
+            let mut utxos = query.utxo(&script_hash[..])?;
+            // blinded outputs are never matched, as their asset is unknown
+            #[cfg(feature = "liquid")]
+            if let Some(asset) = query_params.get("asset") {
+                let asset = AssetId::from_str(asset)?;
+                utxos.retain(|utxo| utxo.asset.explicit() == Some(asset));
+            }
+            let tip_height = query.chain().best_height();
+            let dust_threshold = utxo_dust_threshold(&utxos, query, config);
+            let reused = query
+                .funding_tx_count(&script_hash[..])
+                .map(|count| count > 1);
+            let coinbase_txids = utxo_coinbase_txids(&utxos, query);
+            let script = match query_params.get("include_script").map(String::as_str) {
+                Some("true") => UtxoScriptValue::lookup(&utxos, query, config),
+                _ => None,
+            };
+            let utxos: Vec<UtxoValue> = utxos
+                .into_iter()
+                .map(|utxo| {
+                    let is_coinbase = coinbase_txids.contains(&utxo.txid);
+                    UtxoValue::new(
+                        utxo,
+                        tip_height,
+                        dust_threshold,
+                        reused,
+                        is_coinbase,
+                        script.clone(),
+                    )
+                })
                 .collect();
-
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            // XXX paging?
+            json_response(utxos, TTL_SHORT)
         }
-
+        #[cfg(not(feature = "liquid"))]
         (
             &Method::GET,
             Some(script_type @ &"address"),
             Some(script_str),
             Some(&"utxo"),
-            None,
+            Some(&"summary"),
             None,
         )
         | (
@@ -853,35 +2878,84 @@ fn handle_request(
             Some(script_type @ &"scripthash"),
             Some(script_str),
             Some(&"utxo"),
-            None,
+            Some(&"summary"),
             None,
         ) => {
             let script_hash = to_scripthash(script_type, script_str, config.network_type)?;
-            let utxos: Vec<UtxoValue> = query
-                .utxo(&script_hash[..])?
-                .into_iter()
-                .map(UtxoValue::from)
-                .collect();
-            // XXX paging?
-            json_response(utxos, TTL_SHORT)
+            let utxos = query.utxo(&script_hash[..])?;
+            let dust_threshold = utxo_dust_threshold(&utxos, query, config);
+
+            let mut summary = UtxoSummaryValue::default();
+            for utxo in utxos {
+                summary.add(&utxo, dust_threshold);
+            }
+            json_response(summary, TTL_SHORT)
         }
         (&Method::GET, Some(&"address-prefix"), Some(prefix), None, None, None) => {
             if !config.address_search {
                 return Err(HttpError::from("address search disabled".to_string()));
             }
-            let results = query.chain().address_search(prefix, ADDRESS_SEARCH_LIMIT);
+            let limit = limit_param(
+                &query_params,
+                ADDRESS_SEARCH_RESULTS,
+                config.rest_address_search_limit,
+            )?;
+            let results = query.chain().address_search(prefix, limit);
             json_response(results, TTL_SHORT)
         }
+        #[cfg(not(feature = "liquid"))]
+        (
+            &Method::GET,
+            Some(&"pubkey"),
+            Some(pubkey),
+            Some(&"txs"),
+            None,
+            last_seen_txid @ None,
+        )
+        | (
+            &Method::GET,
+            Some(&"pubkey"),
+            Some(pubkey),
+            Some(&"txs"),
+            Some(&"chain"),
+            last_seen_txid,
+        ) => {
+            if !config.index_pubkeys {
+                return Err(HttpError::from("pubkey index disabled".to_string()));
+            }
+            let pubkey = bitcoin::key::XOnlyPublicKey::from_str(pubkey)
+                .map_err(|_| HttpError::from("Invalid x-only public key".to_string()))?;
+            let last_seen_txid = last_seen_txid.and_then(|txid| Txid::from_str(txid).ok());
+            let order = history_order(&query_params)?;
+            let limit = limit_param(&query_params, CHAIN_TXS_PER_PAGE, config.rest_history_limit)?;
+
+            let txs = query
+                .chain()
+                .pubkey_history(&pubkey.serialize(), last_seen_txid.as_ref(), limit, order)
+                .into_iter()
+                .map(|(tx, blockid)| (tx, Some(blockid)))
+                .collect();
+
+            json_response(prepare_txs(txs, query, config, with_prevouts), TTL_SHORT)
+        }
         (&Method::GET, Some(&"tx"), Some(hash), None, None, None) => {
             let hash = Txid::from_str(hash)?;
             let tx = query
                 .lookup_txn(&hash)
-                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+                .ok_or_else(|| HttpError::tx_not_found("Transaction not found".to_string()))?;
             let blockid = query.chain().tx_confirming_block(&hash);
             let ttl = ttl_by_depth(blockid.as_ref().map(|b| b.height), query);
 
-            let tx = prepare_txs(vec![(tx, blockid)], query, config).remove(0);
+            let tx = prepare_txs(vec![(tx, blockid)], query, config, with_prevouts).remove(0);
 
+            if config.metadata && !is_esplora_compat() {
+                let metadata = meta::get_all(query.chain().store().meta_db(), &hash.to_string());
+                if !metadata.is_empty() {
+                    let mut value = serde_json::to_value(tx)?;
+                    value["meta"] = json!(metadata);
+                    return json_response(value, ttl);
+                }
+            }
             json_response(tx, ttl)
         }
         (&Method::GET, Some(&"tx"), Some(hash), Some(out_type @ &"hex"), None, None)
@@ -889,7 +2963,11 @@ fn handle_request(
             let hash = Txid::from_str(hash)?;
             let rawtx = query
                 .lookup_raw_txn(&hash)
-                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+                .ok_or_else(|| HttpError::tx_not_found("Transaction not found".to_string()))?;
+            let rawtx = match query_params.get("stripped").map(String::as_str) {
+                Some("true") => strip_witness(&rawtx)?,
+                _ => rawtx,
+            };
 
             let (content_type, body) = match *out_type {
                 "raw" => ("application/octet-stream", Body::from(rawtx)),
@@ -901,21 +2979,74 @@ fn handle_request(
             Ok(Response::builder()
                 .status(StatusCode::OK)
                 .header("Content-Type", content_type)
-                .header("Cache-Control", format!("public, max-age={:}", ttl))
+                .header("Cache-Control", cache_control(ttl))
                 .body(body)
                 .unwrap())
         }
+        (&Method::GET, Some(&"wtxid"), Some(wtxid), None, None, None) => {
+            let wtxid = Wtxid::from_str(wtxid)?;
+            let txid = query
+                .lookup_txid_by_wtxid(&wtxid)
+                .ok_or_else(|| HttpError::tx_not_found("Transaction not found".to_string()))?;
+            let ttl = ttl_by_depth(query.get_tx_status(&txid).block_height, query);
+            http_message(StatusCode::OK, txid.to_string(), ttl)
+        }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"status"), None, None) => {
             let hash = Txid::from_str(hash)?;
             let status = query.get_tx_status(&hash);
             let ttl = ttl_by_depth(status.block_height, query);
             json_response(status, ttl)
         }
+        (&Method::GET, Some(&"txs"), Some(&"status"), None, None, None)
+        | (&Method::POST, Some(&"txs"), Some(&"status"), None, None, None) => {
+            // a json array of txids as the body, or comma-separated in ?txids=
+            let txids: Vec<Txid> = match method {
+                Method::POST => serde_json::from_slice(&body)?,
+                _ => query_params
+                    .get("txids")
+                    .map_or("", String::as_str)
+                    .split(',')
+                    .filter(|txid| !txid.is_empty())
+                    .map(Txid::from_str)
+                    .collect::<Result<_, _>>()?,
+            };
+            if txids.is_empty() || txids.len() > TX_STATUS_LIMIT {
+                return Err(HttpError::from(format!(
+                    "between 1 and {} txids are expected",
+                    TX_STATUS_LIMIT
+                )));
+            }
+            let statuses: Vec<TxStatusValue> = txids
+                .iter()
+                .zip(query.chain().txs_confirming_blocks(&txids))
+                .map(|(txid, blockid)| TxStatusValue {
+                    txid: *txid,
+                    confirmed: blockid.is_some(),
+                    height: blockid.as_ref().map(|b| b.height),
+                    blockhash: blockid.map(|b| b.hash),
+                })
+                .collect();
+            json_response(statuses, TTL_SHORT)
+        }
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"mempool-position"), None, None) => {
+            let hash = Txid::from_str(hash)?;
+            let position = query.mempool().position(&hash).ok_or_else(|| {
+                HttpError::tx_not_found("Transaction not found in mempool".to_string())
+            })?;
+            let eta = (position.block + 1) * BLOCK_INTERVAL_SECS;
+            json_response(
+                json!({
+                    "position": position,
+                    "eta_seconds": eta,
+                }),
+                TTL_MEMPOOL_RECENT,
+            )
+        }
 
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"merkle-proof"), None, None) => {
             let hash = Txid::from_str(hash)?;
             let blockid = query.chain().tx_confirming_block(&hash).ok_or_else(|| {
-                HttpError::not_found("Transaction not found or is unconfirmed".to_string())
+                HttpError::tx_not_found("Transaction not found or is unconfirmed".to_string())
             })?;
             let (merkle, pos) =
                 electrum_merkle::get_tx_merkle_proof(query.chain(), &hash, &blockid.hash)?;
@@ -931,7 +3062,7 @@ fn handle_request(
             let hash = Txid::from_str(hash)?;
 
             let merkleblock = query.chain().get_merkleblock_proof(&hash).ok_or_else(|| {
-                HttpError::not_found("Transaction not found or is unconfirmed".to_string())
+                HttpError::tx_not_found("Transaction not found or is unconfirmed".to_string())
             })?;
 
             let height = query
@@ -944,6 +3075,42 @@ fn handle_request(
                 ttl_by_depth(height, query),
             )
         }
+        #[cfg(not(feature = "liquid"))]
+        (&Method::GET, Some(&"tx"), Some(hash), Some(&"proof-bundle"), None, None) => {
+            let hash = Txid::from_str(hash)?;
+            let blockid = query.chain().tx_confirming_block(&hash).ok_or_else(|| {
+                HttpError::tx_not_found("Transaction not found or is unconfirmed".to_string())
+            })?;
+            // the headers start right after the checkpoint, which defaults to the parent block
+            let checkpoint = match query_params.get("checkpoint") {
+                Some(height) => height.parse::<usize>()?,
+                None => blockid.height.saturating_sub(1),
+            };
+            if checkpoint >= blockid.height || blockid.height - checkpoint > HEADERS_LIMIT {
+                return Err(HttpError::from(format!(
+                    "checkpoint must be below the confirming block and at most {} blocks before it",
+                    HEADERS_LIMIT
+                )));
+            }
+            let headers = query
+                .chain()
+                .headers_range(checkpoint + 1, blockid.height - checkpoint);
+            let (merkle, pos) =
+                electrum_merkle::get_tx_merkle_proof(query.chain(), &hash, &blockid.hash)?;
+            let rawtx = query
+                .lookup_raw_txn(&hash)
+                .ok_or_else(|| HttpError::tx_not_found("Transaction not found".to_string()))?;
+
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/octet-stream")
+                .header(
+                    "Cache-Control",
+                    cache_control(ttl_by_depth(Some(blockid.height), query)),
+                )
+                .body(Body::from(proof_bundle(&headers, &merkle, pos, &rawtx)))
+                .unwrap())
+        }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"outspend"), Some(index), None) => {
             let hash = Txid::from_str(hash)?;
             let outpoint = OutPoint {
@@ -962,11 +3129,28 @@ fn handle_request(
             );
             json_response(spend, ttl)
         }
+        (&Method::GET, Some(&"outpoint"), Some(outpoint), Some(&"conflicts"), None, None) => {
+            let (hash, index) = outpoint
+                .split_once(':')
+                .ok_or_else(|| HttpError::from("Invalid outpoint".to_string()))?;
+            let outpoint = OutPoint {
+                txid: Txid::from_str(hash)?,
+                vout: index.parse::<u32>()?,
+            };
+            let spend = query.lookup_spend(&outpoint);
+            let conflicts: Vec<serde_json::Value> = query
+                .lookup_conflicting_spends(&outpoint, spend.as_ref())
+                .into_iter()
+                .map(|(txid, vin)| json!({ "txid": txid, "vin": vin }))
+                .collect();
+            let spend = spend.map_or_else(SpendingValue::default, SpendingValue::from);
+            json_response(json!({ "spend": spend, "conflicts": conflicts }), TTL_SHORT)
+        }
         (&Method::GET, Some(&"tx"), Some(hash), Some(&"outspends"), None, None) => {
             let hash = Txid::from_str(hash)?;
             let tx = query
                 .lookup_txn(&hash)
-                .ok_or_else(|| HttpError::not_found("Transaction not found".to_string()))?;
+                .ok_or_else(|| HttpError::tx_not_found("Transaction not found".to_string()))?;
             let spends: Vec<SpendingValue> = query
                 .lookup_tx_spends(tx)
                 .into_iter()
@@ -993,6 +3177,31 @@ fn handle_request(
             http_message(StatusCode::OK, txid.to_string(), 0)
         }
 
+        #[cfg(not(feature = "liquid"))]
+        (&Method::POST, Some(&"tx"), Some(&"preview"), None, None, None) => {
+            let txhex = String::from_utf8(body.to_vec())?;
+            let tx: Transaction = encode::deserialize(&Vec::from_hex(txhex.trim())?)
+                .map_err(|_| HttpError::from("Invalid transaction".to_string()))?;
+            json_response(TxPreviewValue::new(tx, query, config), 0)
+        }
+
+        #[cfg(not(feature = "liquid"))]
+        (&Method::POST, Some(&"psbt"), Some(&"decode"), None, None, None) => {
+            let psbt = parse_psbt(&body)?;
+            json_response(PsbtValue::new(psbt, query, config), 0)
+        }
+
+        #[cfg(feature = "liquid")]
+        (&Method::POST, Some(&"tx"), Some(hash), Some(&"unblind"), None, None) => {
+            let hash = Txid::from_str(hash)?;
+            let request: UnblindRequest = serde_json::from_slice(&body)?;
+            let keys = unblind::parse_blinding_keys(&request.blinding_keys)?;
+            let tx = query
+                .lookup_txn(&hash)
+                .ok_or_else(|| HttpError::tx_not_found("Transaction not found".to_string()))?;
+            json_response(unblind::unblind_outputs(&tx, &keys), 0)
+        }
+
         (&Method::GET, Some(&"mempool"), None, None, None, None) => {
             json_response(query.mempool().backlog_stats(), TTL_SHORT)
         }
@@ -1004,6 +3213,190 @@ fn handle_request(
             let recent = mempool.recent_txs_overview();
             json_response(recent, TTL_MEMPOOL_RECENT)
         }
+        (&Method::GET, Some(&"mempool"), Some(&"template"), None, None, None) => {
+            let mempool = query.mempool();
+            let template = mempool.block_template();
+            json_response(
+                json!({
+                    "id": template.id,
+                    "txids": template.txids,
+                }),
+                TTL_MEMPOOL_RECENT,
+            )
+        }
+        (&Method::GET, Some(&"mempool"), Some(&"template"), Some(&"diff"), base_id, None) => {
+            let mempool = query.mempool();
+            let template = mempool.block_template();
+            // defaults to the changes from the previous template
+            let base_id = match base_id {
+                Some(base_id) => base_id.parse::<u64>()?,
+                None => template.id.saturating_sub(1),
+            };
+            // the full template is returned instead if the base is too old
+            let body = match mempool.block_template_diff(base_id) {
+                Some(diff) => json!({
+                    "id": template.id,
+                    "base_id": base_id,
+                    "added": diff.added,
+                    "removed": diff.removed,
+                }),
+                None => json!({
+                    "id": template.id,
+                    "txids": template.txids,
+                }),
+            };
+            json_response(body, TTL_MEMPOOL_RECENT)
+        }
+
+        (&Method::POST, Some(&"hooks"), None, None, None, None) => {
+            let webhooks = query
+                .webhooks()
+                .ok_or_else(|| HttpError::not_found("Webhooks are disabled".to_string()))?;
+            require_api_keys(query, "Registering webhooks")?;
+            let request: WebhookRequest = serde_json::from_slice(&body)?;
+            let webhook = Webhook {
+                url: request.url,
+                scripthashes: request
+                    .scripthashes
+                    .iter()
+                    .map(|scripthash| parse_scripthash(scripthash))
+                    .collect::<Result<_, _>>()?,
+                secret: request.secret,
+            };
+            let id = webhooks.register_hook(query.chain().store().cache_db(), webhook)?;
+            json_response(json!({ "id": id.to_lower_hex_string() }), 0)
+        }
+        (&Method::POST, Some(&"hooks"), Some(&"confirmations"), None, None, None) => {
+            let webhooks = query
+                .webhooks()
+                .ok_or_else(|| HttpError::not_found("Webhooks are disabled".to_string()))?;
+            require_api_keys(query, "Registering webhooks")?;
+            let request: ConfirmationWatchRequest = serde_json::from_slice(&body)?;
+            let watch = ConfirmationWatch::new(
+                request.url,
+                Txid::from_str(&request.txid)?,
+                request
+                    .confirmations
+                    .unwrap_or_else(|| DEFAULT_CONFIRMATION_TARGETS.to_vec()),
+                request.secret,
+            );
+            let id = webhooks.register_watch(query.chain().store().cache_db(), watch)?;
+            json_response(json!({ "id": id.to_lower_hex_string() }), 0)
+        }
+        (&Method::GET, Some(&"hooks"), Some(&"confirmations"), Some(id), None, None) => {
+            let webhooks = query
+                .webhooks()
+                .ok_or_else(|| HttpError::not_found("Webhooks are disabled".to_string()))?;
+            let watch_id = FullHash::from_hex(id)?;
+            let watch = webhooks
+                .watch(&watch_id)
+                .ok_or_else(|| HttpError::not_found("Confirmation watch not found".to_string()))?;
+            // the secret is never returned
+            json_response(
+                json!({
+                    "id": id,
+                    "url": watch.url,
+                    "txid": watch.txid,
+                    "confirmations": watch.targets,
+                    "notified": watch.notified,
+                }),
+                0,
+            )
+        }
+        (&Method::DELETE, Some(&"hooks"), Some(&"confirmations"), Some(id), None, None) => {
+            let webhooks = query
+                .webhooks()
+                .ok_or_else(|| HttpError::not_found("Webhooks are disabled".to_string()))?;
+            let watch_id = FullHash::from_hex(id)?;
+            if !webhooks.delete_watch(query.chain().store().cache_db(), &watch_id) {
+                return Err(HttpError::not_found(
+                    "Confirmation watch not found".to_string(),
+                ));
+            }
+            http_message(StatusCode::OK, "", 0)
+        }
+        (&Method::GET, Some(&"hooks"), Some(id), None, None, None) => {
+            let webhooks = query
+                .webhooks()
+                .ok_or_else(|| HttpError::not_found("Webhooks are disabled".to_string()))?;
+            let hook_id = FullHash::from_hex(id)?;
+            let webhook = webhooks
+                .hook(&hook_id)
+                .ok_or_else(|| HttpError::not_found("Webhook not found".to_string()))?;
+            // the secret is never returned
+            json_response(
+                json!({
+                    "id": id,
+                    "url": webhook.url,
+                    "scripthashes": webhook.scripthashes.iter().map(|sh| sh.to_lower_hex_string()).collect::<Vec<_>>(),
+                }),
+                0,
+            )
+        }
+        (&Method::DELETE, Some(&"hooks"), Some(id), None, None, None) => {
+            let webhooks = query
+                .webhooks()
+                .ok_or_else(|| HttpError::not_found("Webhooks are disabled".to_string()))?;
+            let hook_id = FullHash::from_hex(id)?;
+            if !webhooks.delete_hook(query.chain().store().cache_db(), &hook_id) {
+                return Err(HttpError::not_found("Webhook not found".to_string()));
+            }
+            http_message(StatusCode::OK, "", 0)
+        }
+
+        (&Method::GET, Some(&"labels"), None, None, None, None) => {
+            if !config.labels {
+                return Err(HttpError::not_found("Labels are disabled".to_string()));
+            }
+            let labels: Vec<LabelValue> = Label::search(
+                query.chain().store().cache_db(),
+                query_params.get("q").map(String::as_str),
+            )
+            .into_iter()
+            .take(LABELS_LIMIT)
+            .map(|(scripthash, label)| LabelValue {
+                scripthash: scripthash.to_lower_hex_string(),
+                label,
+            })
+            .collect();
+            json_response(labels, 0)
+        }
+        (&Method::GET, Some(&"labels"), Some(scripthash), None, None, None) => {
+            if !config.labels {
+                return Err(HttpError::not_found("Labels are disabled".to_string()));
+            }
+            let script_hash = parse_scripthash(scripthash)?;
+            let label = Label::get(query.chain().store().cache_db(), &script_hash)
+                .ok_or_else(|| HttpError::not_found("Label not found".to_string()))?;
+            json_response(label, 0)
+        }
+        (&Method::PUT, Some(&"labels"), Some(scripthash), None, None, None) => {
+            if !config.labels {
+                return Err(HttpError::not_found("Labels are disabled".to_string()));
+            }
+            require_api_keys(query, "Changing labels")?;
+            let script_hash = parse_scripthash(scripthash)?;
+            if body.len() > MAX_LABEL_SIZE {
+                return Err(HttpError::from(format!(
+                    "label must be at most {} bytes",
+                    MAX_LABEL_SIZE
+                )));
+            }
+            let label: Label = serde_json::from_slice(&body)?;
+            label.set(query.chain().store().cache_db(), &script_hash)?;
+            json_response(label, 0)
+        }
+        (&Method::DELETE, Some(&"labels"), Some(scripthash), None, None, None) => {
+            if !config.labels {
+                return Err(HttpError::not_found("Labels are disabled".to_string()));
+            }
+            require_api_keys(query, "Changing labels")?;
+            let script_hash = parse_scripthash(scripthash)?;
+            if !Label::delete(query.chain().store().cache_db(), &script_hash) {
+                return Err(HttpError::not_found("Label not found".to_string()));
+            }
+            http_message(StatusCode::OK, "", 0)
+        }
 
         (&Method::GET, Some(&"fee-estimates"), None, None, None, None) => {
             json_response(query.estimate_fee_map(), TTL_SHORT)
@@ -1067,7 +3460,7 @@ fn handle_request(
                     .map(|(tx, blockid)| (tx, Some(blockid))),
             );
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            json_response(prepare_txs(txs, query, config, with_prevouts), TTL_SHORT)
         }
 
         #[cfg(feature = "liquid")]
@@ -1089,7 +3482,7 @@ fn handle_request(
                 .map(|(tx, blockid)| (tx, Some(blockid)))
                 .collect();
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            json_response(prepare_txs(txs, query, config, with_prevouts), TTL_SHORT)
         }
 
         #[cfg(feature = "liquid")]
@@ -1103,7 +3496,38 @@ fn handle_request(
                 .map(|tx| (tx, None))
                 .collect();
 
-            json_response(prepare_txs(txs, query, config), TTL_SHORT)
+            json_response(prepare_txs(txs, query, config, with_prevouts), TTL_SHORT)
+        }
+
+        // Only confirmed transactions are indexed by rune
+        #[cfg(feature = "runes")]
+        (
+            &Method::GET,
+            Some(&"rune"),
+            Some(rune_str),
+            Some(&"txs"),
+            None,
+            last_seen_txid @ None,
+        )
+        | (
+            &Method::GET,
+            Some(&"rune"),
+            Some(rune_str),
+            Some(&"txs"),
+            Some(&"chain"),
+            last_seen_txid,
+        ) => {
+            let rune_id = RuneId::from_str(rune_str)?;
+            let last_seen_txid = last_seen_txid.and_then(|txid| Txid::from_str(txid).ok());
+
+            let txs = query
+                .chain()
+                .rune_history(&rune_id, last_seen_txid.as_ref(), CHAIN_TXS_PER_PAGE)
+                .into_iter()
+                .map(|(tx, blockid)| (tx, Some(blockid)))
+                .collect();
+
+            json_response(prepare_txs(txs, query, config, with_prevouts), TTL_SHORT)
         }
 
         #[cfg(feature = "liquid")]
@@ -1140,16 +3564,104 @@ where
     Ok(Response::builder()
         .status(status)
         .header("Content-Type", "text/plain")
-        .header("Cache-Control", format!("public, max-age={:}", ttl))
+        .header("Cache-Control", cache_control(ttl))
         .body(message.into())
         .unwrap())
 }
 
+#[derive(Serialize)]
+struct HealthValue {
+    status: &'static str,
+    indexed_height: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    daemon_height: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn is_health_check(path: &str) -> bool {
+    path == "/healthz" || path == "/readyz"
+}
+
+// This fork's extensions are also served under /v1/, which --api-compat esplora doesn't restrict
+fn is_versioned(path: &str) -> bool {
+    path == "/v1" || path.starts_with("/v1/")
+}
+
+// The routes of Blockstream's Esplora API, the only ones served with --api-compat esplora
+fn is_esplora_route(method: &Method, path: &[&str]) -> bool {
+    match (method, path) {
+        (&Method::GET, ["blocks"] | ["blocks", _] | ["blocks", "tip", "hash" | "height"])
+        | (&Method::GET, ["block-height", _])
+        | (&Method::GET, ["block", _] | ["block", _, "txid", _])
+        | (&Method::GET, ["block", _, "status" | "txids" | "txs" | "header" | "raw"])
+        | (&Method::GET, ["block", _, "txs", _])
+        | (&Method::GET, ["address" | "scripthash", _])
+        | (&Method::GET, ["address" | "scripthash", _, "txs" | "utxo"])
+        | (&Method::GET, ["address" | "scripthash", _, "txs", "chain" | "mempool"])
+        | (&Method::GET, ["address" | "scripthash", _, "txs", "chain", _])
+        | (&Method::GET, ["address-prefix", _])
+        | (&Method::GET, ["tx", _] | ["tx", _, "outspend", _])
+        | (&Method::GET, ["tx", _, "hex" | "raw" | "status" | "outspends"])
+        | (&Method::GET, ["tx", _, "merkle-proof" | "merkleblock-proof"])
+        | (&Method::POST, ["tx"])
+        | (&Method::GET, ["broadcast"])
+        | (&Method::GET, ["mempool"] | ["mempool", "txids" | "recent"])
+        | (&Method::GET, ["fee-estimates"]) => true,
+        #[cfg(feature = "liquid")]
+        (&Method::GET, ["assets", "registry"])
+        | (&Method::GET, ["asset", _] | ["asset", _, "txs" | "supply"])
+        | (&Method::GET, ["asset", _, "txs", "chain" | "mempool"])
+        | (&Method::GET, ["asset", _, "txs", "chain", _] | ["asset", _, "supply", _]) => true,
+        _ => false,
+    }
+}
+
+// The route disabled with --disable-routes that the request is for, if any
+fn disabled_route(method: &Method, path: &[&str], config: &Config) -> Option<DisabledRoute> {
+    let route = match (method, path) {
+        (&Method::GET, ["address" | "scripthash", _, "utxo"])
+        | (&Method::GET, ["address" | "scripthash", _, "utxo", "summary"]) => DisabledRoute::Utxo,
+        (&Method::GET, ["address" | "scripthash", _, "txs" | "txs.csv"])
+        | (&Method::GET, ["address" | "scripthash", _, "txs", "chain" | "mempool"])
+        | (&Method::GET, ["address" | "scripthash", _, "txs", "chain", _])
+        | (&Method::GET, ["address" | "scripthash", _, "txs", "mempool", "full"]) => {
+            DisabledRoute::History
+        }
+        (&Method::GET, ["address-prefix", _]) => DisabledRoute::AddressSearch,
+        (&Method::GET, ["block", _, "txs"] | ["block", _, "txs", _] | ["block", _, "raw"])
+        | (&Method::GET, ["blocks", "raw", _, _]) => DisabledRoute::FullBlockTxs,
+        _ => return None,
+    };
+    Some(route).filter(|route| config.disabled_routes.contains(route))
+}
+
+fn health_response(value: HealthValue) -> Result<Response<Body>, HttpError> {
+    let status = match value.status {
+        "ok" | "ready" => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Cache-Control", "no-store")
+        .body(Body::from(serde_json::to_string(&value)?))
+        .unwrap())
+}
+
+fn cache_control(ttl: u32) -> String {
+    if ttl == TTL_LONG {
+        format!("public, max-age={:}, immutable", ttl)
+    } else {
+        format!("public, max-age={:}", ttl)
+    }
+}
+
 fn json_response<T: Serialize>(value: T, ttl: u32) -> Result<Response<Body>, HttpError> {
     let value = serde_json::to_string(&value)?;
     Ok(Response::builder()
         .header("Content-Type", "application/json")
-        .header("Cache-Control", format!("public, max-age={:}", ttl))
+        .header("Cache-Control", cache_control(ttl))
         .body(Body::from(value))
         .unwrap())
 }
@@ -1160,7 +3672,7 @@ fn blocks(query: &Query, start_height: Option<usize>) -> Result<Response<Body>,
         Some(height) => *query
             .chain()
             .header_by_height(height)
-            .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?
+            .ok_or_else(|| HttpError::block_not_found("Block not found".to_string()))?
             .hash(),
         None => query.chain().best_hash(),
     };
@@ -1170,7 +3682,7 @@ fn blocks(query: &Query, start_height: Option<usize>) -> Result<Response<Body>,
         let blockhm = query
             .chain()
             .get_block_with_meta(&current_hash)
-            .ok_or_else(|| HttpError::not_found("Block not found".to_string()))?;
+            .ok_or_else(|| HttpError::block_not_found("Block not found".to_string()))?;
         current_hash = blockhm.header_entry.header().prev_blockhash;
 
         #[allow(unused_mut)]
@@ -1202,7 +3714,42 @@ fn to_scripthash(
     }
 }
 
+fn history_order(query_params: &HashMap<String, String>) -> Result<HistoryOrder, HttpError> {
+    match query_params.get("order").map(String::as_str) {
+        None | Some("desc") => Ok(HistoryOrder::Descending),
+        Some("asc") => Ok(HistoryOrder::Ascending),
+        Some(_) => Err(HttpError::from(
+            "Invalid order, expected asc or desc".to_string(),
+        )),
+    }
+}
+
+// The number of results requested with ?limit=, which is enforced against the maximum configured
+// for the route. Without it, the route's default is used (capped by the maximum too).
+fn limit_param(
+    query_params: &HashMap<String, String>,
+    default: usize,
+    max: usize,
+) -> Result<usize, HttpError> {
+    match query_params.get("limit") {
+        None => Ok(default.min(max).max(1)),
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if limit >= 1 && limit <= max => Ok(limit),
+            _ => Err(HttpError::from(format!(
+                "limit must be between 1 and {}",
+                max
+            ))),
+        },
+    }
+}
+
 fn address_to_scripthash(addr: &str, network: Network) -> Result<FullHash, HttpError> {
+    #[cfg(not(feature = "liquid"))]
+    if network == Network::Custom {
+        let script = custom_address_to_script(addr).map_err(|e| HttpError::from(e.to_string()))?;
+        return Ok(compute_script_hash(&script));
+    }
+
     #[cfg(not(feature = "liquid"))]
     let addr = address::Address::from_str(addr)?;
     #[cfg(feature = "liquid")]
@@ -1224,22 +3771,108 @@ fn address_to_scripthash(addr: &str, network: Network) -> Result<FullHash, HttpE
     Ok(compute_script_hash(&addr.script_pubkey()))
 }
 
+// The error for a block that's unavailable, either unknown or (in light mode) no longer available
+// from a pruned node
+fn block_unavailable(query: &Query, config: &Config, hash: &BlockHash) -> HttpError {
+    let height = query.chain().height_by_hash(hash);
+    match (query.daemon_prune_height(), height) {
+        (Some(prune_height), Some(height)) if config.light_mode && height < prune_height => {
+            HttpError(
+                ErrorCode::SourcePruned,
+                format!(
+                    "Block pruned by the node, which has the blocks from height {}",
+                    prune_height
+                ),
+            )
+        }
+        _ => HttpError::block_not_found("Block not found".to_string()),
+    }
+}
+
 fn parse_scripthash(scripthash: &str) -> Result<FullHash, HttpError> {
     FullHash::from_hex(scripthash).map_err(|_| HttpError::from("Invalid scripthash".to_string()))
 }
 
+// Webhook notifications are sent on the server's behalf, so only authenticated clients may
+// register them. Every request is authenticated when API keys are configured.
+fn require_api_keys(query: &Query, action: &str) -> Result<(), HttpError> {
+    if query.api_keys().is_none() {
+        return Err(HttpError(
+            ErrorCode::RouteDisabled,
+            format!("{} requires API key authentication", action),
+        ));
+    }
+    Ok(())
+}
+
+// Machine-readable error codes, returned along with a human-readable message as
+// {"code": "tx-not-found", "message": "Transaction not found"}
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorCode {
+    InvalidRequest,
+    NotFound,
+    TxNotFound,
+    BlockNotFound,
+    TooPopular,
+    Unauthorized,
+    RateLimited,
+    ServerBusy,
+    Timeout,
+    DaemonUnreachable,
+    IndexLagging,
+    SourcePruned,
+    RouteDisabled,
+    Internal,
+}
+
+impl ErrorCode {
+    fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::NotFound | ErrorCode::TxNotFound | ErrorCode::BlockNotFound => {
+                StatusCode::NOT_FOUND
+            }
+            ErrorCode::TooPopular => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::RouteDisabled => StatusCode::FORBIDDEN,
+            ErrorCode::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ErrorCode::ServerBusy | ErrorCode::IndexLagging => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::DaemonUnreachable => StatusCode::BAD_GATEWAY,
+            ErrorCode::SourcePruned => StatusCode::GONE,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
 #[derive(Debug)]
-struct HttpError(StatusCode, String);
+struct HttpError(ErrorCode, String);
 
 impl HttpError {
     fn not_found(msg: String) -> Self {
-        HttpError(StatusCode::NOT_FOUND, msg)
+        HttpError(ErrorCode::NotFound, msg)
+    }
+    fn tx_not_found(msg: String) -> Self {
+        HttpError(ErrorCode::TxNotFound, msg)
+    }
+    fn block_not_found(msg: String) -> Self {
+        HttpError(ErrorCode::BlockNotFound, msg)
     }
 }
 
 impl From<String> for HttpError {
     fn from(msg: String) -> Self {
-        HttpError(StatusCode::BAD_REQUEST, msg)
+        HttpError(ErrorCode::InvalidRequest, msg)
+    }
+}
+impl From<AuthError> for HttpError {
+    fn from(e: AuthError) -> Self {
+        let code = match e {
+            AuthError::Missing | AuthError::Invalid => ErrorCode::Unauthorized,
+            AuthError::RateLimited => ErrorCode::RateLimited,
+        };
+        HttpError(code, e.to_string())
     }
 }
 impl From<ParseIntError> for HttpError {
@@ -1274,10 +3907,19 @@ impl From<bitcoin::address::Error> for HttpError {
 }
 impl From<errors::Error> for HttpError {
     fn from(e: errors::Error) -> Self {
-        warn!("errors::Error: {:?}", e);
+        tracing::warn!(target: "electrs::rest", "errors::Error: {:?}", e);
+        match e.kind() {
+            errors::ErrorKind::TooPopular => {
+                return HttpError(ErrorCode::TooPopular, e.to_string())
+            }
+            errors::ErrorKind::Connection(_) => {
+                return HttpError(ErrorCode::DaemonUnreachable, e.to_string())
+            }
+            _ => (),
+        }
         match e.description().to_string().as_ref() {
             "getblock RPC error: {\"code\":-5,\"message\":\"Block not found\"}" => {
-                HttpError::not_found("Block not found".to_string())
+                HttpError::block_not_found("Block not found".to_string())
             }
             _ => HttpError::from(e.to_string()),
         }
@@ -1315,9 +3957,16 @@ impl From<address::AddressError> for HttpError {
 
 #[cfg(test)]
 mod tests {
-    use crate::rest::HttpError;
+    use crate::rest::{
+        acquire_permit, error_response, is_esplora_route, limit_param, negotiate_encoding,
+        strip_path_prefix, ContentEncoding, ErrorCode, HttpError,
+    };
+    use hyper::Method;
     use serde_json::Value;
     use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
 
     #[test]
     fn test_parse_query_param() {
@@ -1359,6 +4008,41 @@ mod tests {
         assert_eq!(10, limit);
     }
 
+    #[test]
+    fn test_limit_param() {
+        let mut query_params = HashMap::new();
+        assert_eq!(limit_param(&query_params, 25, 100).unwrap(), 25);
+        assert_eq!(limit_param(&query_params, 25, 10).unwrap(), 10);
+
+        query_params.insert("limit".to_string(), "100".to_string());
+        assert_eq!(limit_param(&query_params, 25, 100).unwrap(), 100);
+
+        for limit in ["0", "101", "aaa"] {
+            query_params.insert("limit".to_string(), limit.to_string());
+            let err = limit_param(&query_params, 25, 100).unwrap_err();
+            assert_eq!(err.0, ErrorCode::InvalidRequest);
+            assert_eq!(err.1, "limit must be between 1 and 100");
+        }
+    }
+
+    #[test]
+    fn test_is_esplora_route() {
+        let route = |method: &Method, path: &str| {
+            let path: Vec<&str> = path.split('/').skip(1).collect();
+            is_esplora_route(method, &path)
+        };
+        assert!(route(&Method::GET, "/blocks/tip/height"));
+        assert!(route(&Method::GET, "/block/00ff/txs/25"));
+        assert!(route(&Method::GET, "/address/bc1q/txs/chain/00ff"));
+        assert!(route(&Method::GET, "/tx/00ff/merkleblock-proof"));
+        assert!(route(&Method::POST, "/tx"));
+
+        assert!(!route(&Method::GET, "/tx"));
+        assert!(!route(&Method::GET, "/block/00ff/coinbase"));
+        assert!(!route(&Method::GET, "/address/bc1q/txs.csv"));
+        assert!(!route(&Method::GET, "/pubkey/00ff/txs"));
+    }
+
     #[test]
     fn test_parse_value_param() {
         let v: Value = json!({ "confirmations": 10 });
@@ -1380,4 +4064,122 @@ mod tests {
 
         assert!(err.is_err());
     }
+
+    #[test]
+    fn test_error_response() {
+        let resp = error_response(HttpError::tx_not_found("Transaction not found".to_string()));
+        assert_eq!(resp.status(), hyper::StatusCode::NOT_FOUND);
+        assert_eq!(
+            resp.headers()["Content-Type"],
+            hyper::header::HeaderValue::from_static("application/json")
+        );
+
+        let resp = error_response(HttpError(ErrorCode::TooPopular, "Too many".to_string()));
+        assert_eq!(resp.status(), hyper::StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            serde_json::to_value(ErrorCode::DaemonUnreachable).unwrap(),
+            json!("daemon-unreachable")
+        );
+
+        let resp = error_response(HttpError(ErrorCode::SourcePruned, "Pruned".to_string()));
+        assert_eq!(resp.status(), hyper::StatusCode::GONE);
+        assert_eq!(
+            serde_json::to_value(ErrorCode::SourcePruned).unwrap(),
+            json!("source-pruned")
+        );
+
+        let resp = error_response(HttpError(ErrorCode::RouteDisabled, "Disabled".to_string()));
+        assert_eq!(resp.status(), hyper::StatusCode::FORBIDDEN);
+        assert_eq!(
+            serde_json::to_value(ErrorCode::RouteDisabled).unwrap(),
+            json!("route-disabled")
+        );
+    }
+
+    #[test]
+    fn test_acquire_permit() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let permits = Arc::new(Semaphore::new(1));
+            let deadline = || Some(tokio::time::Instant::now() + Duration::from_millis(50));
+
+            let permit = acquire_permit(Arc::clone(&permits), deadline())
+                .await
+                .unwrap();
+            // all the slots are taken until the deadline, the request is rejected as overloaded
+            let err = acquire_permit(Arc::clone(&permits), deadline())
+                .await
+                .unwrap_err();
+            assert_eq!(err.0, ErrorCode::ServerBusy);
+            let resp = error_response(err);
+            assert_eq!(resp.status(), hyper::StatusCode::SERVICE_UNAVAILABLE);
+
+            drop(permit);
+            assert!(acquire_permit(permits, deadline()).await.is_ok());
+        });
+    }
+
+    #[test]
+    fn test_timelocks() {
+        use crate::rest::{LocktimeValue, RelativeTimelockValue};
+
+        let sequences = || vec![0xfffffffd, 0xffffffff].into_iter();
+        assert_eq!(LocktimeValue::new(0, sequences()), None);
+        assert_eq!(
+            LocktimeValue::new(800_000, sequences()),
+            Some(LocktimeValue::Height(800_000))
+        );
+        assert_eq!(
+            LocktimeValue::new(1_700_000_000, sequences()),
+            Some(LocktimeValue::Timestamp(1_700_000_000))
+        );
+        assert_eq!(
+            LocktimeValue::new(800_000, vec![0xffffffff].into_iter()),
+            None
+        );
+
+        assert_eq!(RelativeTimelockValue::new(0xfffffffe), None);
+        assert_eq!(
+            RelativeTimelockValue::new(144),
+            Some(RelativeTimelockValue::Blocks(144))
+        );
+        assert_eq!(
+            RelativeTimelockValue::new((1 << 22) | 10),
+            Some(RelativeTimelockValue::Seconds(5120))
+        );
+        assert_eq!(
+            serde_json::to_value(RelativeTimelockValue::Blocks(144)).unwrap(),
+            json!({ "blocks": 144 })
+        );
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        assert_eq!(
+            negotiate_encoding("gzip, deflate, br"),
+            Some(ContentEncoding::Brotli)
+        );
+        assert_eq!(
+            negotiate_encoding("gzip;q=1.0, br;q=0"),
+            Some(ContentEncoding::Gzip)
+        );
+        assert_eq!(negotiate_encoding("deflate"), None);
+        assert_eq!(negotiate_encoding(""), None);
+    }
+
+    #[test]
+    fn test_strip_path_prefix() {
+        assert_eq!(strip_path_prefix("/testnet", "testnet"), Some("/"));
+        assert_eq!(strip_path_prefix("/testnet/", "testnet"), Some("/"));
+        assert_eq!(
+            strip_path_prefix("/testnet/tx/abcd", "testnet"),
+            Some("/tx/abcd")
+        );
+        assert_eq!(strip_path_prefix("/testnet4/tx/abcd", "testnet"), None);
+        assert_eq!(strip_path_prefix("/tx/testnet", "testnet"), None);
+        assert_eq!(strip_path_prefix("", "testnet"), None);
+    }
 }
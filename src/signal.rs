@@ -1,15 +1,18 @@
 use crossbeam_channel as channel;
 use crossbeam_channel::RecvTimeoutError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM, SIGUSR1};
 
 use crate::errors::*;
 
 #[derive(Clone)] // so multiple threads could wait on signals
 pub struct Waiter {
     receiver: channel::Receiver<i32>,
+    reload: Arc<AtomicBool>,
 }
 
 fn notify(signals: &[i32]) -> channel::Receiver<i32> {
@@ -31,10 +34,17 @@ impl Waiter {
             receiver: notify(&[
                 SIGINT, SIGTERM,
                 SIGUSR1, // allow external triggering (e.g. via bitcoind `blocknotify`)
+                SIGHUP,  // reload the runtime config
             ]),
+            reload: Arc::new(AtomicBool::new(false)),
         }
     }
 
+    // Whether a config reload was requested with SIGHUP since the last call
+    pub fn reload_requested(&self) -> bool {
+        self.reload.swap(false, Ordering::Relaxed)
+    }
+
     pub fn wait(&self, duration: Duration, accept_sigusr: bool) -> Result<()> {
         // Determine the deadline time based on the duration, so that it doesn't
         // get pushed back when wait_deadline() recurses
@@ -43,8 +53,11 @@ impl Waiter {
 
     fn wait_deadline(&self, deadline: Instant, accept_sigusr: bool) -> Result<()> {
         match self.receiver.recv_deadline(deadline) {
-            Ok(sig) if sig == SIGUSR1 => {
-                trace!("notified via SIGUSR1");
+            Ok(sig) if sig == SIGUSR1 || sig == SIGHUP => {
+                trace!("notified via signal {}", sig);
+                if sig == SIGHUP {
+                    self.reload.store(true, Ordering::Relaxed);
+                }
                 if accept_sigusr {
                     Ok(())
                 } else {
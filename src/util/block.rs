@@ -1,6 +1,13 @@
-use crate::chain::{BlockHash, BlockHeader};
+#[cfg(feature = "liquid")]
+use crate::elements::ebcompact::*;
+
+#[cfg(not(feature = "liquid"))]
+use bitcoin::pow::Work;
+
+use crate::chain::{Block, BlockHash, BlockHeader};
 use crate::errors::*;
 use crate::new_index::BlockEntry;
+use crate::util::bincode;
 
 use std::collections::HashMap;
 use std::fmt;
@@ -10,6 +17,10 @@ use time::format_description::well_known::Rfc3339;
 use time::OffsetDateTime as DateTime;
 
 const MTP_SPAN: usize = 11;
+#[cfg(not(feature = "liquid"))]
+pub const DIFFICULTY_ADJUSTMENT_INTERVAL: usize = 2016;
+#[cfg(not(feature = "liquid"))]
+const TARGET_BLOCK_SPACING: f64 = 600.0;
 
 lazy_static! {
     pub static ref DEFAULT_BLOCKHASH: BlockHash =
@@ -40,6 +51,9 @@ pub struct HeaderEntry {
     height: usize,
     hash: BlockHash,
     header: BlockHeader,
+    // the total work of the chain up to and including this block
+    #[cfg(not(feature = "liquid"))]
+    chainwork: Work,
 }
 
 impl HeaderEntry {
@@ -54,6 +68,11 @@ impl HeaderEntry {
     pub fn height(&self) -> usize {
         self.height
     }
+
+    #[cfg(not(feature = "liquid"))]
+    pub fn chainwork(&self) -> Work {
+        self.chainwork
+    }
 }
 
 impl fmt::Debug for HeaderEntry {
@@ -121,6 +140,32 @@ impl HeaderList {
         headers
     }
 
+    // Restores the list from the (blockhash, header) pairs of the chain, ordered by height and
+    // with already computed hashes. Returns None if they don't link up from the genesis block.
+    pub fn from_chain(chain: Vec<(BlockHash, BlockHeader)>) -> Option<HeaderList> {
+        let mut headers = HeaderList::empty();
+        for (height, (hash, header)) in chain.into_iter().enumerate() {
+            if header.prev_blockhash != headers.tip {
+                return None;
+            }
+            #[cfg(not(feature = "liquid"))]
+            let chainwork = match headers.headers.last() {
+                Some(prev) => prev.chainwork + header.work(),
+                None => header.work(),
+            };
+            headers.tip = hash;
+            headers.heights.insert(hash, height);
+            headers.headers.push(HeaderEntry {
+                height,
+                hash,
+                header,
+                #[cfg(not(feature = "liquid"))]
+                chainwork,
+            });
+        }
+        Some(headers)
+    }
+
     pub fn order(&self, new_headers: Vec<BlockHeader>) -> Vec<HeaderEntry> {
         // header[i] -> header[i-1] (i.e. header.last() is the tip)
         struct HashedHeader {
@@ -142,24 +187,71 @@ impl HeaderList {
             Some(h) => h.header.prev_blockhash,
             None => return vec![], // hashed_headers is empty
         };
-        let new_height: usize = if prev_blockhash == *DEFAULT_BLOCKHASH {
-            0
+        let prev_entry = if prev_blockhash == *DEFAULT_BLOCKHASH {
+            None
         } else {
-            self.header_by_blockhash(&prev_blockhash)
-                .unwrap_or_else(|| panic!("{} is not part of the blockchain", prev_blockhash))
-                .height()
-                + 1
+            Some(
+                self.header_by_blockhash(&prev_blockhash)
+                    .unwrap_or_else(|| panic!("{} is not part of the blockchain", prev_blockhash)),
+            )
         };
+        let new_height = prev_entry.map_or(0, |entry| entry.height() + 1);
+        #[cfg(not(feature = "liquid"))]
+        let mut chainwork =
+            prev_entry.map_or(Work::from_be_bytes([0; 32]), |entry| entry.chainwork);
         (new_height..)
             .zip(hashed_headers.into_iter())
-            .map(|(height, hashed_header)| HeaderEntry {
-                height,
-                hash: hashed_header.blockhash,
-                header: hashed_header.header,
+            .map(|(height, hashed_header)| {
+                #[cfg(not(feature = "liquid"))]
+                {
+                    chainwork = chainwork + hashed_header.header.work();
+                }
+                HeaderEntry {
+                    height,
+                    hash: hashed_header.blockhash,
+                    header: hashed_header.header,
+                    #[cfg(not(feature = "liquid"))]
+                    chainwork,
+                }
             })
             .collect()
     }
 
+    // Checks the new headers (as returned by order()) against the checkpoints, and that they don't
+    // replace the current tip with a chain of lower work
+    pub fn validate(
+        &self,
+        new_headers: &[HeaderEntry],
+        checkpoints: &[(usize, &str)],
+    ) -> Result<()> {
+        for entry in new_headers {
+            if let Some((height, hash)) = checkpoints
+                .iter()
+                .find(|(height, _)| *height == entry.height())
+            {
+                ensure!(
+                    entry.hash().to_string() == *hash,
+                    "block {} conflicts with the checkpoint {} at height {}",
+                    entry.hash(),
+                    hash,
+                    height
+                );
+            }
+        }
+        #[cfg(not(feature = "liquid"))]
+        if let (Some(new_tip), Some(tip)) = (new_headers.last(), self.headers.last()) {
+            ensure!(
+                new_tip.chainwork >= tip.chainwork,
+                "block {} has less chainwork than the current tip {} ({:x} < {:x})",
+                new_tip.hash(),
+                tip.hash(),
+                new_tip.chainwork,
+                tip.chainwork
+            );
+        }
+        Ok(())
+    }
+
     pub fn apply(&mut self, new_headers: Vec<HeaderEntry>) {
         // new_headers[i] -> new_headers[i - 1] (i.e. new_headers.last() is the tip)
         for i in 1..new_headers.len() {
@@ -257,6 +349,62 @@ impl HeaderList {
             timestamps[timestamps.len() / 2]
         }
     }
+
+    // The progress of the difficulty epoch as of the given block, with the difficulty change
+    // expected at the next retarget if blocks keep coming at the epoch's average rate so far
+    #[cfg(not(feature = "liquid"))]
+    pub fn difficulty_epoch(&self, height: usize) -> Option<DifficultyEpoch> {
+        let entry = self.header_by_height(height)?;
+        let position = height % DIFFICULTY_ADJUSTMENT_INTERVAL;
+        let start = &self.headers[height - position];
+        let remaining_blocks = DIFFICULTY_ADJUSTMENT_INTERVAL - position;
+
+        let avg_block_time = if position > 0 {
+            (entry.header.time as f64 - start.header.time as f64).max(0.0) / position as f64
+        } else {
+            TARGET_BLOCK_SPACING
+        };
+        let change = (TARGET_BLOCK_SPACING / avg_block_time).clamp(0.25, 4.0);
+        let previous_retarget = start
+            .height
+            .checked_sub(DIFFICULTY_ADJUSTMENT_INTERVAL)
+            .and_then(|prev_height| self.header_by_height(prev_height))
+            .map(|prev_start| {
+                (start.header.difficulty_float() / prev_start.header.difficulty_float() - 1.0)
+                    * 100.0
+            });
+
+        Some(DifficultyEpoch {
+            start_height: start.height,
+            position,
+            remaining_blocks,
+            next_retarget_height: height + remaining_blocks,
+            progress_percent: position as f64 * 100.0 / DIFFICULTY_ADJUSTMENT_INTERVAL as f64,
+            avg_block_time,
+            difficulty_change: (change - 1.0) * 100.0,
+            estimated_retarget_time: entry.header.time as u64
+                + (remaining_blocks as f64 * avg_block_time) as u64,
+            previous_retarget,
+        })
+    }
+}
+
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Debug, Clone)]
+pub struct DifficultyEpoch {
+    pub start_height: usize,
+    // the number of blocks since the start of the epoch
+    pub position: usize,
+    pub remaining_blocks: usize,
+    pub next_retarget_height: usize,
+    pub progress_percent: f64,
+    // in seconds
+    pub avg_block_time: f64,
+    // expected, in percent
+    pub difficulty_change: f64,
+    pub estimated_retarget_time: u64,
+    // the change in percent at the start of this epoch, None for the first epoch
+    pub previous_retarget: Option<f64>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -290,12 +438,121 @@ pub struct BlockMeta {
     pub tx_count: u32,
     pub size: u32,
     pub weight: u32,
+    // only available when --script-type-stats was enabled while adding the block
+    #[serde(default)]
+    pub script_types: Option<ScriptTypeCounts>,
+}
+
+// The number of outputs of each script type
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct ScriptTypeCounts {
+    pub p2pk: u32,
+    pub p2pkh: u32,
+    pub p2sh: u32,
+    pub v0_p2wpkh: u32,
+    pub v0_p2wsh: u32,
+    pub v1_p2tr: u32,
+    pub op_return: u32,
+    pub other: u32,
+}
+
+impl ScriptTypeCounts {
+    pub fn from_block(block: &Block) -> Self {
+        let mut counts = ScriptTypeCounts::default();
+        for txout in block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+            let script = &txout.script_pubkey;
+            let count = if script.is_p2pk() {
+                &mut counts.p2pk
+            } else if script.is_p2pkh() {
+                &mut counts.p2pkh
+            } else if script.is_p2sh() {
+                &mut counts.p2sh
+            } else if script.is_p2wpkh() {
+                &mut counts.v0_p2wpkh
+            } else if script.is_p2wsh() {
+                &mut counts.v0_p2wsh
+            } else if script.is_p2tr() {
+                &mut counts.v1_p2tr
+            } else if script.is_op_return() {
+                &mut counts.op_return
+            } else {
+                &mut counts.other
+            };
+            *count += 1;
+        }
+        counts
+    }
+
+    pub fn add(&mut self, other: &ScriptTypeCounts) {
+        self.p2pk += other.p2pk;
+        self.p2pkh += other.p2pkh;
+        self.p2sh += other.p2sh;
+        self.v0_p2wpkh += other.v0_p2wpkh;
+        self.v0_p2wsh += other.v0_p2wsh;
+        self.v1_p2tr += other.v1_p2tr;
+        self.op_return += other.op_return;
+        self.other += other.other;
+    }
+}
+
+// The fees collected and the value created by a block, computed while indexing its history.
+// Unavailable for blocks that were indexed before it was introduced.
+#[cfg(not(feature = "liquid"))]
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BlockReward {
+    pub fees: u64,
+    // the part of the coinbase value not accounted for by the fees
+    pub subsidy: u64,
+    pub output_value: u64,
+    // the total sigop cost of the block's transactions
+    pub sigop_cost: Option<u64>,
+}
+
+#[cfg(not(feature = "liquid"))]
+impl BlockReward {
+    // Blocks indexed before sigop costs were introduced have their reward rows encoded without
+    // the sigop_cost field
+    pub fn from_db_value(value: &[u8]) -> BlockReward {
+        bincode::deserialize_little(value).unwrap_or_else(|_| {
+            let (fees, subsidy, output_value) =
+                bincode::deserialize_little(value).expect("failed to parse BlockReward");
+            BlockReward {
+                fees,
+                subsidy,
+                output_value,
+                sigop_cost: None,
+            }
+        })
+    }
+}
+
+// Chain-wide counters of a block, computed while indexing its history. Unavailable for blocks that
+// were indexed before they were introduced.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct BlockCounters {
+    pub tx_count: u32,
+    // inputs spending a previous output (i.e. excluding the coinbase)
+    pub input_count: u32,
+    pub output_count: u32,
+    // the change in the size of the UTXO set (spendable outputs created minus outputs spent)
+    pub utxo_delta: i64,
+    #[cfg(not(feature = "liquid"))]
+    pub fees: u64,
 }
 
 pub struct BlockHeaderMeta {
     pub header_entry: HeaderEntry,
     pub meta: BlockMeta,
     pub mtp: u32,
+    #[cfg(not(feature = "liquid"))]
+    pub reward: Option<BlockReward>,
+    // in satoshi-days
+    #[cfg(not(feature = "liquid"))]
+    pub coin_days_destroyed: Option<u64>,
+    // only available with --first-seen
+    pub seen_at: Option<u32>,
+    #[cfg(not(feature = "liquid"))]
+    pub epoch: DifficultyEpoch,
 }
 
 impl From<&BlockEntry> for BlockMeta {
@@ -311,6 +568,7 @@ impl From<&BlockEntry> for BlockMeta {
             // because u32::MAX is far above MAX_BLOCK_WEIGHT.
             weight: weight as u32,
             size: b.size,
+            script_types: None,
         }
     }
 }
@@ -333,6 +591,60 @@ impl BlockMeta {
                 .chain_err(|| "missing weight")?
                 .as_f64()
                 .chain_err(|| "weight not a number")? as u32,
+            script_types: None,
+        })
+    }
+
+    // Blocks added before script type stats were introduced have their meta rows encoded without
+    // the script_types field
+    pub fn from_db_value(value: &[u8]) -> BlockMeta {
+        bincode::deserialize_little(value).unwrap_or_else(|_| {
+            let (tx_count, size, weight) =
+                bincode::deserialize_little(value).expect("failed to parse BlockMeta");
+            BlockMeta {
+                tx_count,
+                size,
+                weight,
+                script_types: None,
+            }
         })
     }
 }
+
+#[cfg(all(test, not(feature = "liquid")))]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{CompactTarget, Network};
+
+    fn child(prev: &BlockHeader, bits: u32, nonce: u32) -> BlockHeader {
+        BlockHeader {
+            prev_blockhash: prev.block_hash(),
+            time: prev.time + 600,
+            bits: CompactTarget::from_consensus(bits),
+            nonce,
+            ..*prev
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let genesis = genesis_block(Network::Regtest).header;
+        let tip = child(&genesis, 0x1d00ffff, 0);
+        let mut headers = HeaderList::empty();
+        headers.apply(headers.order(vec![genesis, tip]));
+
+        // a longer fork with less work
+        let fork = child(&genesis, 0x207fffff, 1);
+        let fork = headers.order(vec![fork, child(&fork, 0x207fffff, 2)]);
+        assert!(headers.validate(&fork, &[]).is_err());
+
+        let next = headers.order(vec![child(&tip, 0x1d00ffff, 3)]);
+        assert!(headers.validate(&next, &[]).is_ok());
+        let checkpoint = next[0].hash().to_string();
+        assert!(headers.validate(&next, &[(2, &checkpoint)]).is_ok());
+        assert!(headers
+            .validate(&next, &[(2, &genesis.block_hash().to_string())])
+            .is_err());
+    }
+}
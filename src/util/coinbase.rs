@@ -0,0 +1,94 @@
+// Heuristic decoding of coinbase scriptSigs, for attributing blocks to mining pools. Only the
+// height is mandated (by BIP34), but most pools follow it with their extra-nonce and tag their
+// blocks with a readable name.
+
+use bitcoin::script::Instruction;
+use bitcoin::{Script, Transaction};
+use hex::DisplayHex;
+
+const MIN_POOL_TAG_LEN: usize = 4;
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct CoinbaseScript {
+    // The number pushed first, which is the block height since BIP34
+    pub height: Option<i64>,
+    // The push following the height, as hex
+    pub extra_nonce: Option<String>,
+    // The longest run of printable characters within the pushes
+    pub pool_tag: Option<String>,
+}
+
+pub fn parse_coinbase_script(script: &Script) -> CoinbaseScript {
+    let mut instructions = script.instructions().map_while(Result::ok);
+    let height = instructions
+        .next()
+        .and_then(|instruction| instruction.script_num());
+    let pushes: Vec<&[u8]> = instructions
+        .filter_map(|instruction| match instruction {
+            Instruction::PushBytes(bytes) => Some(bytes.as_bytes()),
+            Instruction::Op(_) => None,
+        })
+        .collect();
+
+    let extra_nonce = pushes
+        .first()
+        .filter(|bytes| height.is_some() && !bytes.is_empty())
+        .map(|bytes| bytes.to_lower_hex_string());
+    let pool_tag = pushes
+        .iter()
+        .flat_map(|bytes| bytes.split(|byte| !(0x20..0x7f).contains(byte)))
+        .map(|run| String::from_utf8_lossy(run).trim().to_string())
+        .filter(|tag| tag.len() >= MIN_POOL_TAG_LEN)
+        .max_by_key(|tag| tag.len());
+
+    CoinbaseScript {
+        height,
+        extra_nonce,
+        pool_tag,
+    }
+}
+
+// The BIP141 commitment to the witness data of the block, the last matching output wins
+pub fn witness_commitment(coinbase: &Transaction) -> Option<String> {
+    coinbase
+        .output
+        .iter()
+        .rev()
+        .map(|txout| txout.script_pubkey.as_bytes())
+        .find(|spk| spk.len() >= 38 && spk.starts_with(&WITNESS_COMMITMENT_HEADER))
+        .map(|spk| spk[6..38].to_lower_hex_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::script::Builder;
+
+    #[test]
+    fn test_parse_coinbase_script() {
+        let script = Builder::new()
+            .push_int(840000)
+            .push_slice([0xde, 0xad, 0xbe, 0xef, 0x00, 0x00, 0x00, 0x01])
+            .push_slice(b"\x01\x02/ViaBTC/Mined by nobody/\x00")
+            .into_script();
+        assert_eq!(
+            parse_coinbase_script(&script),
+            CoinbaseScript {
+                height: Some(840000),
+                extra_nonce: Some("deadbeef00000001".to_string()),
+                pool_tag: Some("/ViaBTC/Mined by nobody/".to_string()),
+            }
+        );
+
+        let script = Builder::new().push_int(1).into_script();
+        assert_eq!(
+            parse_coinbase_script(&script),
+            CoinbaseScript {
+                height: Some(1),
+                extra_nonce: None,
+                pool_tag: None,
+            }
+        );
+    }
+}
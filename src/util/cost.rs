@@ -0,0 +1,82 @@
+use std::cell::Cell;
+use std::time::Duration;
+
+use crate::config::Config;
+
+// Accounting of the work done to serve a query, tracked per thread. REST handlers and Electrum
+// connections process one query at a time on their own thread, so resetting the counters before
+// a query and taking them afterwards gives the cost of that query.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct QueryCost {
+    pub rows_scanned: u64,
+    pub txs_deserialized: u64,
+    pub daemon_rpcs: u64,
+}
+
+thread_local! {
+    static COST: Cell<QueryCost> = Cell::new(QueryCost::default());
+}
+
+fn update<F: FnOnce(&mut QueryCost)>(f: F) {
+    COST.with(|cost| {
+        let mut current = cost.get();
+        f(&mut current);
+        cost.set(current);
+    })
+}
+
+pub fn add_rows_scanned(count: u64) {
+    update(|cost| cost.rows_scanned += count)
+}
+
+pub fn add_txs_deserialized(count: u64) {
+    update(|cost| cost.txs_deserialized += count)
+}
+
+pub fn add_daemon_rpc() {
+    update(|cost| cost.daemon_rpcs += 1)
+}
+
+pub fn reset() {
+    COST.with(|cost| cost.set(QueryCost::default()))
+}
+
+// Returns the cost accumulated since the last reset, and resets it
+pub fn take() -> QueryCost {
+    COST.with(|cost| cost.replace(QueryCost::default()))
+}
+
+pub fn is_slow_query(elapsed: Duration, cost: &QueryCost, config: &Config) -> bool {
+    (config.slow_query_ms > 0 && elapsed.as_millis() as u64 >= config.slow_query_ms)
+        || (config.slow_query_rows > 0 && cost.rows_scanned >= config.slow_query_rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cost_accounting() {
+        reset();
+        add_rows_scanned(10);
+        add_rows_scanned(5);
+        add_txs_deserialized(2);
+        add_daemon_rpc();
+        assert_eq!(
+            take(),
+            QueryCost {
+                rows_scanned: 15,
+                txs_deserialized: 2,
+                daemon_rpcs: 1,
+            }
+        );
+        assert_eq!(take(), QueryCost::default());
+
+        // counters are not shared between threads
+        add_daemon_rpc();
+        std::thread::spawn(|| assert_eq!(take(), QueryCost::default()))
+            .join()
+            .unwrap();
+        assert_eq!(take().daemon_rpcs, 1);
+    }
+}
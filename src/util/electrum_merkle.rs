@@ -8,17 +8,13 @@ pub fn get_tx_merkle_proof(
     tx_hash: &Txid,
     block_hash: &BlockHash,
 ) -> Result<(Vec<Sha256dHash>, usize)> {
-    let txids = chain
-        .get_block_txids(&block_hash)
+    let tree = chain
+        .get_block_merkle_tree(&block_hash)
         .chain_err(|| format!("missing block txids for #{}", block_hash))?;
-    let pos = txids
-        .iter()
-        .position(|txid| txid == tx_hash)
+    let pos = tree
+        .position(&Sha256dHash::from(*tx_hash))
         .chain_err(|| format!("missing txid {}", tx_hash))?;
-    let txids = txids.into_iter().map(Sha256dHash::from).collect();
-
-    let (branch, _root) = create_merkle_branch_and_root(txids, pos);
-    Ok((branch, pos))
+    Ok((tree.branch(pos), pos))
 }
 
 pub fn get_header_merkle_proof(
@@ -30,21 +26,14 @@ pub fn get_header_merkle_proof(
         bail!("cp_height #{} < height #{}", cp_height, height);
     }
 
-    let best_height = chain.best_height();
-    if best_height < cp_height {
-        bail!(
+    // the checkpoint root commits to the hashes of every header up to cp_height
+    let header_hashes = chain.hashes_up_to(cp_height).chain_err(|| {
+        format!(
             "cp_height #{} above best block height #{}",
             cp_height,
-            best_height
-        );
-    }
-
-    let heights: Vec<usize> = (0..=cp_height).collect();
-    let header_hashes: Vec<BlockHash> = heights
-        .into_iter()
-        .map(|height| chain.hash_by_height(height))
-        .collect::<Option<Vec<BlockHash>>>()
-        .chain_err(|| "missing block headers")?;
+            chain.best_height()
+        )
+    })?;
 
     let header_hashes = header_hashes.into_iter().map(Sha256dHash::from).collect();
     Ok(create_merkle_branch_and_root(header_hashes, height))
@@ -60,22 +49,20 @@ pub fn get_id_from_pos(
         .hash_by_height(height)
         .chain_err(|| format!("missing block #{}", height))?;
 
-    let txids = chain
-        .get_block_txids(&header_hash)
+    let tree = chain
+        .get_block_merkle_tree(&header_hash)
         .chain_err(|| format!("missing block txids #{}", height))?;
 
-    let txid = *txids
-        .get(tx_pos)
+    let txid = tree
+        .leaf(tx_pos)
         .chain_err(|| format!("No tx in position #{} in block #{}", tx_pos, height))?;
 
-    let txids = txids.into_iter().map(Sha256dHash::from).collect();
-
     let branch = if want_merkle {
-        create_merkle_branch_and_root(txids, tx_pos).0
+        tree.branch(tx_pos)
     } else {
         vec![]
     };
-    Ok((txid, branch))
+    Ok((Txid::from(txid), branch))
 }
 
 fn merklize(left: Sha256dHash, right: Sha256dHash) -> Sha256dHash {
@@ -84,22 +71,79 @@ fn merklize(left: Sha256dHash, right: Sha256dHash) -> Sha256dHash {
 }
 
 fn create_merkle_branch_and_root(
-    mut hashes: Vec<Sha256dHash>,
-    mut index: usize,
+    hashes: Vec<Sha256dHash>,
+    index: usize,
 ) -> (Vec<Sha256dHash>, Sha256dHash) {
-    let mut merkle = vec![];
-    while hashes.len() > 1 {
-        if hashes.len() % 2 != 0 {
-            let last = *hashes.last().unwrap();
-            hashes.push(last);
+    let tree = MerkleTree::new(hashes);
+    (tree.branch(index), tree.root())
+}
+
+// All the levels of a merkle tree, from the leaves up to the root, so that the branches of any
+// leaf can be read without hashing again
+pub struct MerkleTree {
+    levels: Vec<Vec<Sha256dHash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Sha256dHash>) -> MerkleTree {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let parents = level
+                .chunks(2)
+                .map(|pair| merklize(pair[0], *pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+            levels.push(parents);
         }
-        index = if index % 2 == 0 { index + 1 } else { index - 1 };
-        merkle.push(hashes[index]);
-        index /= 2;
-        hashes = hashes
-            .chunks(2)
-            .map(|pair| merklize(pair[0], pair[1]))
-            .collect()
+        MerkleTree { levels }
+    }
+
+    pub fn leaf(&self, index: usize) -> Option<Sha256dHash> {
+        self.levels[0].get(index).copied()
+    }
+
+    pub fn position(&self, leaf: &Sha256dHash) -> Option<usize> {
+        self.levels[0].iter().position(|hash| hash == leaf)
+    }
+
+    pub fn root(&self) -> Sha256dHash {
+        self.levels.last().unwrap()[0]
+    }
+
+    // The siblings of the leaf at every level, the last hash of an odd level being its own sibling
+    pub fn branch(&self, mut index: usize) -> Vec<Sha256dHash> {
+        let mut branch = vec![];
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            branch.push(*level.get(sibling).unwrap_or(&level[index]));
+            index /= 2;
+        }
+        branch
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merkle_tree() {
+        let leaves: Vec<Sha256dHash> = (0u8..5).map(|i| Sha256dHash::hash(&[i])).collect();
+        let tree = MerkleTree::new(leaves.clone());
+
+        let ab = merklize(leaves[0], leaves[1]);
+        let cd = merklize(leaves[2], leaves[3]);
+        let ee = merklize(leaves[4], leaves[4]);
+        let eeee = merklize(ee, ee);
+        let root = merklize(merklize(ab, cd), eeee);
+        assert_eq!(tree.root(), root);
+        assert_eq!(tree.branch(2), vec![leaves[3], ab, eeee]);
+        assert_eq!(tree.branch(4), vec![leaves[4], ee, merklize(ab, cd)]);
+        assert_eq!(tree.position(&leaves[3]), Some(3));
+        assert_eq!(tree.leaf(5), None);
+
+        let single = MerkleTree::new(vec![leaves[0]]);
+        assert_eq!(single.root(), leaves[0]);
+        assert!(single.branch(0).is_empty());
     }
-    (merkle, hashes[0])
 }
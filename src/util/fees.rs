@@ -1,3 +1,5 @@
+#[cfg(not(feature = "liquid"))]
+use crate::chain::Script;
 use crate::chain::{Network, Transaction, TxOut};
 use std::collections::HashMap;
 
@@ -46,6 +48,20 @@ pub fn get_tx_fee(tx: &Transaction, _prevouts: &HashMap<u32, &TxOut>, network: N
     tx.fee_in(*network.native_asset())
 }
 
+// The minimum value for an output paying to `script` not to be considered dust at the given
+// feerate (in sat/vB), following Bitcoin Core's GetDustThreshold()
+#[cfg(not(feature = "liquid"))]
+pub fn get_dust_threshold(script: &Script, feerate: f64) -> u64 {
+    let output_size = 8 + bitcoin::VarInt(script.len() as u64).size() + script.len();
+    // the size of the input that would later spend it
+    let spend_size = if script.is_witness_program() {
+        32 + 4 + 1 + (107 / 4) + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    ((output_size + spend_size) as f64 * feerate) as u64
+}
+
 pub fn make_fee_histogram(mut entries: Vec<&TxFeeInfo>) -> Vec<(f64, u64)> {
     entries.sort_unstable_by(|e1, e2| e1.fee_per_vbyte.partial_cmp(&e2.fee_per_vbyte).unwrap());
 
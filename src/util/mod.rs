@@ -3,17 +3,31 @@ mod script;
 mod transaction;
 
 pub mod bincode;
+#[cfg(not(feature = "liquid"))]
+pub mod coinbase;
+pub mod cost;
 pub mod electrum_merkle;
 pub mod fees;
+#[cfg(not(feature = "liquid"))]
+pub mod muhash;
+#[cfg(not(feature = "liquid"))]
+pub mod policy;
+#[cfg(not(feature = "liquid"))]
+pub mod psbt;
 
 pub use self::block::{
-    BlockHeaderMeta, BlockId, BlockMeta, BlockStatus, HeaderEntry, HeaderList, DEFAULT_BLOCKHASH,
+    BlockCounters, BlockHeaderMeta, BlockId, BlockMeta, BlockStatus, HeaderEntry, HeaderList,
+    ScriptTypeCounts, DEFAULT_BLOCKHASH,
 };
+#[cfg(not(feature = "liquid"))]
+pub use self::block::{BlockReward, DifficultyEpoch};
 pub use self::fees::get_tx_fee;
+#[cfg(not(feature = "liquid"))]
+pub use self::fees::get_dust_threshold;
 pub use self::script::{get_innerscripts, ScriptToAddr, ScriptToAsm};
 pub use self::transaction::{
     extract_tx_prevouts, has_prevout, is_coinbase, is_spendable, serialize_outpoint,
-    TransactionStatus, TxInput,
+    TransactionStatus, TxInput, TxStats,
 };
 
 use std::collections::HashMap;
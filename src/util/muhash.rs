@@ -0,0 +1,266 @@
+// MuHash3072, the rolling set hash used by Bitcoin Core for `gettxoutsetinfo muhash` (see
+// crypto/muhash.cpp). Elements are hashed to numbers modulo the prime 2^3072 - 1103717, and the
+// set hash is their product, so that elements can be added and removed in any order. Removals
+// are accumulated in a separate denominator, which is only inverted when finalizing.
+
+use std::convert::TryInto;
+
+use bitcoin::hashes::{sha256, Hash};
+
+const LIMBS: usize = 48;
+const BYTE_SIZE: usize = LIMBS * 8;
+// 2^3072 minus the prime
+const MAX_PRIME_DIFF: u64 = 1103717;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Num3072([u64; LIMBS]);
+
+impl Num3072 {
+    fn one() -> Num3072 {
+        let mut limbs = [0; LIMBS];
+        limbs[0] = 1;
+        Num3072(limbs)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Num3072 {
+        let mut limbs = [0; LIMBS];
+        for (limb, chunk) in limbs.iter_mut().zip(bytes.chunks_exact(8)) {
+            *limb = u64::from_le_bytes(chunk.try_into().unwrap());
+        }
+        Num3072(limbs)
+    }
+
+    fn to_bytes(&self) -> [u8; BYTE_SIZE] {
+        let mut bytes = [0; BYTE_SIZE];
+        for (chunk, limb) in bytes.chunks_exact_mut(8).zip(self.0.iter()) {
+            chunk.copy_from_slice(&limb.to_le_bytes());
+        }
+        bytes
+    }
+
+    // Hashes the data to a number, by expanding its SHA256 with ChaCha20
+    fn hash(data: &[u8]) -> Num3072 {
+        let key = sha256::Hash::hash(data).to_byte_array();
+        Num3072::from_bytes(&chacha20_keystream(&key))
+    }
+
+    // Whether the number is at least the prime, which can happen for 3072-bit numbers above it
+    fn is_overflow(&self) -> bool {
+        self.0[0] > u64::MAX - MAX_PRIME_DIFF && self.0[1..].iter().all(|&limb| limb == u64::MAX)
+    }
+
+    // Adds `value` times 2^3072 (which is congruent to `value` times MAX_PRIME_DIFF)
+    fn fold(&mut self, value: u128) {
+        let mut carry = value;
+        while carry != 0 {
+            carry *= MAX_PRIME_DIFF as u128;
+            for limb in self.0.iter_mut() {
+                let sum = *limb as u128 + carry;
+                *limb = sum as u64;
+                carry = sum >> 64;
+                if carry == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn mul(&self, other: &Num3072) -> Num3072 {
+        let mut wide = [0u64; 2 * LIMBS];
+        for i in 0..LIMBS {
+            let mut carry: u128 = 0;
+            for j in 0..LIMBS {
+                let product = self.0[i] as u128 * other.0[j] as u128 + wide[i + j] as u128 + carry;
+                wide[i + j] = product as u64;
+                carry = product >> 64;
+            }
+            wide[i + LIMBS] = carry as u64;
+        }
+
+        let mut result = Num3072([0; LIMBS]);
+        let mut carry: u128 = 0;
+        for i in 0..LIMBS {
+            let sum = wide[i] as u128 + wide[i + LIMBS] as u128 * MAX_PRIME_DIFF as u128 + carry;
+            result.0[i] = sum as u64;
+            carry = sum >> 64;
+        }
+        result.fold(carry);
+        if result.is_overflow() {
+            // subtracting the prime is the same as adding MAX_PRIME_DIFF modulo 2^3072
+            let mut carry = MAX_PRIME_DIFF;
+            for limb in result.0.iter_mut() {
+                let (sum, overflow) = limb.overflowing_add(carry);
+                *limb = sum;
+                carry = overflow as u64;
+            }
+        }
+        result
+    }
+
+    // The modular inverse, computed as self^(p-2) by Fermat's little theorem
+    fn inverse(&self) -> Num3072 {
+        let mut exponent = [u64::MAX; LIMBS];
+        exponent[0] -= MAX_PRIME_DIFF + 1;
+        let mut result = Num3072::one();
+        for limb in exponent.iter().rev() {
+            for bit in (0..64).rev() {
+                result = result.mul(&result);
+                if (limb >> bit) & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MuHash3072 {
+    numerator: Num3072,
+    denominator: Num3072,
+}
+
+impl Default for MuHash3072 {
+    fn default() -> Self {
+        MuHash3072 {
+            numerator: Num3072::one(),
+            denominator: Num3072::one(),
+        }
+    }
+}
+
+impl MuHash3072 {
+    pub const SERIALIZED_SIZE: usize = 2 * BYTE_SIZE;
+
+    pub fn insert(&mut self, data: &[u8]) {
+        self.numerator = self.numerator.mul(&Num3072::hash(data));
+    }
+
+    pub fn remove(&mut self, data: &[u8]) {
+        self.denominator = self.denominator.mul(&Num3072::hash(data));
+    }
+
+    // Applies the insertions and removals accumulated by `other`
+    pub fn combine(&mut self, other: &MuHash3072) {
+        self.numerator = self.numerator.mul(&other.numerator);
+        self.denominator = self.denominator.mul(&other.denominator);
+    }
+
+    // Bitcoin Core displays the result in reverse byte order, like other uint256s
+    pub fn finalize(&self) -> sha256::Hash {
+        let value = self.numerator.mul(&self.denominator.inverse());
+        sha256::Hash::hash(&value.to_bytes())
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.numerator.to_bytes(), self.denominator.to_bytes()].concat()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> MuHash3072 {
+        assert_eq!(
+            bytes.len(),
+            Self::SERIALIZED_SIZE,
+            "invalid MuHash3072 size"
+        );
+        MuHash3072 {
+            numerator: Num3072::from_bytes(&bytes[..BYTE_SIZE]),
+            denominator: Num3072::from_bytes(&bytes[BYTE_SIZE..]),
+        }
+    }
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+// The ChaCha20 keystream for the key, with an all-zero nonce and the block counter starting at 0
+fn chacha20_keystream(key: &[u8; 32]) -> [u8; BYTE_SIZE] {
+    let mut input = [0u32; 16];
+    input[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for (word, chunk) in input[4..12].iter_mut().zip(key.chunks_exact(4)) {
+        *word = u32::from_le_bytes(chunk.try_into().unwrap());
+    }
+
+    let mut keystream = [0; BYTE_SIZE];
+    for (counter, block) in keystream.chunks_exact_mut(64).enumerate() {
+        input[12] = counter as u32;
+        let mut state = input;
+        for _ in 0..10 {
+            quarter_round(&mut state, 0, 4, 8, 12);
+            quarter_round(&mut state, 1, 5, 9, 13);
+            quarter_round(&mut state, 2, 6, 10, 14);
+            quarter_round(&mut state, 3, 7, 11, 15);
+            quarter_round(&mut state, 0, 5, 10, 15);
+            quarter_round(&mut state, 1, 6, 11, 12);
+            quarter_round(&mut state, 2, 7, 8, 13);
+            quarter_round(&mut state, 3, 4, 9, 14);
+        }
+        for (i, chunk) in block.chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&state[i].wrapping_add(input[i]).to_le_bytes());
+        }
+    }
+    keystream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex::DisplayHex;
+
+    fn from_int(i: u8) -> [u8; 32] {
+        let mut data = [0; 32];
+        data[0] = i;
+        data
+    }
+
+    fn display(hash: sha256::Hash) -> String {
+        let mut bytes = hash.to_byte_array();
+        bytes.reverse();
+        bytes.to_lower_hex_string()
+    }
+
+    #[test]
+    fn test_muhash() {
+        // from Bitcoin Core's crypto_tests
+        let mut muhash = MuHash3072::default();
+        muhash.insert(&from_int(0));
+        muhash.insert(&from_int(1));
+        muhash.remove(&from_int(2));
+        assert_eq!(
+            display(muhash.finalize()),
+            "10d312b100cbd32ada024a6646e40d3482fcff103668d2625f10002a607d5863"
+        );
+
+        // the order of the operations doesn't matter
+        let mut other = MuHash3072::default();
+        other.remove(&from_int(2));
+        other.insert(&from_int(3));
+        other.insert(&from_int(1));
+        let mut removed = MuHash3072::default();
+        removed.remove(&from_int(3));
+        other.combine(&removed);
+        other.insert(&from_int(0));
+        assert_eq!(other.finalize(), muhash.finalize());
+
+        let restored = MuHash3072::from_bytes(&muhash.to_bytes());
+        assert_eq!(restored.finalize(), muhash.finalize());
+    }
+
+    #[test]
+    fn test_chacha20_keystream() {
+        // the all-zero key and nonce test vector from RFC 7539 appendix A.1
+        let keystream = chacha20_keystream(&[0; 32]);
+        assert_eq!(
+            keystream[..64].to_lower_hex_string(),
+            "76b8e0ada0f13d90405d6ae55386bd28bdd219b8a08ded1aa836efcc8b770dc7\
+             da41597c5157488d7724e03fb8d84a376a43b8f41518a11cc387b669b2ee6586"
+        );
+    }
+}
@@ -0,0 +1,256 @@
+// A subset of Bitcoin Core's transaction standardness rules (policy/policy.cpp), that can be checked
+// without access to the mempool. Failures are reported using Core's reject reasons.
+
+use std::collections::HashMap;
+
+use crate::chain::{OutPoint, Transaction, TxOut};
+use crate::util::fees::get_dust_threshold;
+use crate::util::psbt::parse_multisig;
+
+const MAX_STANDARD_VERSION: i32 = 2;
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+const MAX_OP_RETURN_RELAY: usize = 83;
+const MAX_STANDARD_TX_SIGOPS_COST: usize = 16_000;
+const MAX_BARE_MULTISIG_KEYS: usize = 3;
+
+pub fn standardness_errors(
+    tx: &Transaction,
+    prevouts: &HashMap<OutPoint, TxOut>,
+    dust_feerate: f64,
+) -> Vec<&'static str> {
+    let mut errors = vec![];
+
+    if tx.version.0 < 1 || tx.version.0 > MAX_STANDARD_VERSION {
+        errors.push("version");
+    }
+    if tx.weight().to_wu() > MAX_STANDARD_TX_WEIGHT {
+        errors.push("tx-size");
+    }
+
+    for txin in &tx.input {
+        if txin.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            errors.push("scriptsig-size");
+        }
+        if !txin.script_sig.is_push_only() {
+            errors.push("scriptsig-not-pushonly");
+        }
+    }
+
+    let mut op_returns = 0;
+    for txout in &tx.output {
+        let spk = &txout.script_pubkey;
+        if spk.is_op_return() {
+            op_returns += 1;
+            if spk.len() > MAX_OP_RETURN_RELAY {
+                errors.push("scriptpubkey");
+            }
+        } else if spk.is_p2pk()
+            || spk.is_p2pkh()
+            || spk.is_p2sh()
+            || spk.is_witness_program()
+            || parse_multisig(spk).map_or(false, |(_, keys)| keys.len() <= MAX_BARE_MULTISIG_KEYS)
+        {
+            if txout.value.to_sat() < get_dust_threshold(spk, dust_feerate) {
+                errors.push("dust");
+            }
+        } else {
+            errors.push("scriptpubkey");
+        }
+    }
+    if op_returns > 1 {
+        errors.push("multi-op-return");
+    }
+
+    let sigops = tx.total_sigop_cost(|outpoint| prevouts.get(outpoint).cloned());
+    if sigops > MAX_STANDARD_TX_SIGOPS_COST {
+        errors.push("bad-txns-too-many-sigops");
+    }
+
+    errors.sort_unstable();
+    errors.dedup();
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::opcodes::all::{OP_CHECKMULTISIG, OP_DUP, OP_PUSHNUM_1, OP_RETURN};
+    use bitcoin::blockdata::script::{Builder, PushBytesBuf};
+    use bitcoin::hashes::Hash;
+    use bitcoin::{
+        absolute, transaction, Amount, PublicKey, ScriptBuf, Sequence, TxIn, Txid, WPubkeyHash,
+        Witness,
+    };
+    use std::convert::TryFrom;
+    use std::str::FromStr;
+
+    const DUST_FEERATE: f64 = 3.0;
+
+    fn push_bytes(len: usize) -> PushBytesBuf {
+        PushBytesBuf::try_from(vec![0x51; len]).unwrap()
+    }
+
+    fn op_return(len: usize) -> ScriptBuf {
+        Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_slice(push_bytes(len))
+            .into_script()
+    }
+
+    fn p2wpkh_out(value: u64) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20])),
+        }
+    }
+
+    // Bare `1-of-n` multisig over the first `n` multiples of the generator
+    fn bare_multisig(n: usize) -> ScriptBuf {
+        let keys = [
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+            "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+            "02e493dbf1c10d80f3581e4904930b1404cc6c13900ee0758474fa94abe8c4cd13",
+        ];
+        keys[..n]
+            .iter()
+            .fold(Builder::new().push_opcode(OP_PUSHNUM_1), |builder, key| {
+                builder.push_key(&PublicKey::from_str(key).unwrap())
+            })
+            .push_int(n as i64)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script()
+    }
+
+    // A standard transaction spending a single input to a single P2WPKH output
+    fn standard_tx() -> Transaction {
+        Transaction {
+            version: transaction::Version(2),
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![p2wpkh_out(10_000)],
+        }
+    }
+
+    fn errors(tx: &Transaction) -> Vec<&'static str> {
+        standardness_errors(tx, &HashMap::new(), DUST_FEERATE)
+    }
+
+    #[test]
+    fn test_standard() {
+        let mut tx = standard_tx();
+        assert!(errors(&tx).is_empty());
+
+        // A single small OP_RETURN and a bare 1-of-3 multisig are standard too
+        tx.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: op_return(MAX_OP_RETURN_RELAY - 3),
+        });
+        let multisig = bare_multisig(MAX_BARE_MULTISIG_KEYS);
+        tx.output.push(TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: multisig,
+        });
+        assert!(errors(&tx).is_empty());
+    }
+
+    #[test]
+    fn test_version() {
+        for version in [0, 3] {
+            let mut tx = standard_tx();
+            tx.version = transaction::Version(version);
+            assert_eq!(errors(&tx), vec!["version"]);
+        }
+    }
+
+    #[test]
+    fn test_tx_size() {
+        let mut tx = standard_tx();
+        // 31 bytes (124 WU) each
+        tx.output = vec![p2wpkh_out(10_000); 3300];
+        assert!(tx.weight().to_wu() > MAX_STANDARD_TX_WEIGHT);
+        assert_eq!(errors(&tx), vec!["tx-size"]);
+    }
+
+    #[test]
+    fn test_scriptsig() {
+        let mut tx = standard_tx();
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(push_bytes(MAX_STANDARD_SCRIPTSIG_SIZE))
+            .into_script();
+        assert_eq!(errors(&tx), vec!["scriptsig-size"]);
+
+        tx.input[0].script_sig = Builder::new().push_opcode(OP_DUP).into_script();
+        assert_eq!(errors(&tx), vec!["scriptsig-not-pushonly"]);
+    }
+
+    #[test]
+    fn test_scriptpubkey() {
+        // Non-standard output script type
+        let mut tx = standard_tx();
+        tx.output[0].script_pubkey = Builder::new().push_opcode(OP_PUSHNUM_1).into_script();
+        assert_eq!(errors(&tx), vec!["scriptpubkey"]);
+
+        // Bare multisig with too many keys
+        tx.output[0].script_pubkey = bare_multisig(MAX_BARE_MULTISIG_KEYS + 1);
+        assert_eq!(errors(&tx), vec!["scriptpubkey"]);
+
+        // Oversized OP_RETURN
+        let mut tx = standard_tx();
+        tx.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: op_return(MAX_OP_RETURN_RELAY),
+        });
+        assert_eq!(errors(&tx), vec!["scriptpubkey"]);
+    }
+
+    #[test]
+    fn test_dust() {
+        let mut tx = standard_tx();
+        let threshold = get_dust_threshold(&tx.output[0].script_pubkey, DUST_FEERATE);
+        tx.output[0].value = Amount::from_sat(threshold);
+        assert!(errors(&tx).is_empty());
+        tx.output[0].value = Amount::from_sat(threshold - 1);
+        assert_eq!(errors(&tx), vec!["dust"]);
+    }
+
+    #[test]
+    fn test_multi_op_return() {
+        let mut tx = standard_tx();
+        for _ in 0..2 {
+            tx.output.push(TxOut {
+                value: Amount::ZERO,
+                script_pubkey: op_return(10),
+            });
+        }
+        assert_eq!(errors(&tx), vec!["multi-op-return"]);
+    }
+
+    #[test]
+    fn test_sigops() {
+        // A P2SH redeem script with a bare OP_CHECKMULTISIG counts as 20 sigops (80 cost)
+        let redeem_script = ScriptBuf::from(vec![OP_CHECKMULTISIG.to_u8(); 201]);
+        let mut tx = standard_tx();
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(PushBytesBuf::try_from(redeem_script.to_bytes()).unwrap())
+            .into_script();
+        let prevout = TxOut {
+            value: Amount::from_sat(20_000),
+            script_pubkey: ScriptBuf::new_p2sh(&redeem_script.script_hash()),
+        };
+        let prevouts = HashMap::from([(tx.input[0].previous_output, prevout)]);
+        assert_eq!(
+            standardness_errors(&tx, &prevouts, DUST_FEERATE),
+            vec!["bad-txns-too-many-sigops"]
+        );
+
+        // Without the prevout the P2SH sigops can't be counted
+        assert!(errors(&tx).is_empty());
+    }
+}
@@ -0,0 +1,136 @@
+use bitcoin::opcodes::all::OP_CHECKMULTISIG;
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::psbt::Input;
+use bitcoin::script::Instruction;
+use bitcoin::{PublicKey, Script, TxOut};
+
+// The signing status of a PSBT input
+pub struct InputSignatures {
+    pub finalized: bool,
+    pub count: usize,
+    // None if the spent script type is not recognized
+    pub required: Option<usize>,
+    // The keys of a multisig script that did not sign yet
+    pub missing_pubkeys: Vec<PublicKey>,
+}
+
+impl InputSignatures {
+    pub fn new(input: &Input, prevout: Option<&TxOut>) -> Self {
+        let finalized = input.final_script_sig.is_some() || input.final_script_witness.is_some();
+        let count = input.partial_sigs.len()
+            + input.tap_script_sigs.len()
+            + input.tap_key_sig.map_or(0, |_| 1);
+
+        let multisig = input
+            .witness_script
+            .as_deref()
+            .or(input.redeem_script.as_deref())
+            .and_then(parse_multisig);
+
+        let (required, missing_pubkeys) = if let Some((required, pubkeys)) = multisig {
+            let missing_pubkeys = if !finalized && count < required {
+                pubkeys
+                    .into_iter()
+                    .filter(|pubkey| !input.partial_sigs.contains_key(pubkey))
+                    .collect()
+            } else {
+                vec![]
+            };
+            (Some(required), missing_pubkeys)
+        } else {
+            let spk = prevout.map(|txo| txo.script_pubkey.as_script());
+            let single_key = spk.map_or(false, |spk| {
+                spk.is_p2pkh() || spk.is_p2wpkh() || spk.is_p2tr() || spk.is_p2pk()
+            }) || input
+                .redeem_script
+                .as_ref()
+                .map_or(false, |rs| rs.is_p2wpkh());
+            (Some(1).filter(|_| single_key), vec![])
+        };
+
+        InputSignatures {
+            finalized,
+            count,
+            required,
+            missing_pubkeys,
+        }
+    }
+
+    // The number of signatures still needed, or None if unknown
+    pub fn missing(&self) -> Option<usize> {
+        if self.finalized {
+            return Some(0);
+        }
+        self.required
+            .map(|required| required.saturating_sub(self.count))
+    }
+}
+
+// Parses a bare `<m> <pubkey>... <n> OP_CHECKMULTISIG` script into its threshold and keys
+pub fn parse_multisig(script: &Script) -> Option<(usize, Vec<PublicKey>)> {
+    let instructions = script
+        .instructions()
+        .collect::<Result<Vec<Instruction>, _>>()
+        .ok()?;
+    let (last, rest) = instructions.split_last()?;
+    if *last != Instruction::Op(OP_CHECKMULTISIG) {
+        return None;
+    }
+    let (n, rest) = rest.split_last()?;
+    let (m, keys) = rest.split_first()?;
+    let (m, n) = (push_num(m)?, push_num(n)?);
+
+    let pubkeys = keys
+        .iter()
+        .map(|instruction| match instruction {
+            Instruction::PushBytes(bytes) => PublicKey::from_slice(bytes.as_bytes()).ok(),
+            Instruction::Op(_) => None,
+        })
+        .collect::<Option<Vec<PublicKey>>>()?;
+    if m > n || pubkeys.len() != n {
+        return None;
+    }
+    Some((m, pubkeys))
+}
+
+fn push_num(instruction: &Instruction) -> Option<usize> {
+    match instruction {
+        Instruction::Op(op) => match op.classify(ClassifyContext::Legacy) {
+            Class::PushNum(num) if num > 0 => Some(num as usize),
+            _ => None,
+        },
+        Instruction::PushBytes(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::blockdata::script::Builder;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_multisig() {
+        let pubkeys: Vec<PublicKey> = [
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "02c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+            "02f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+        ]
+        .iter()
+        .map(|pubkey| PublicKey::from_str(pubkey).unwrap())
+        .collect();
+        let script = pubkeys
+            .iter()
+            .fold(Builder::new().push_int(2), |builder, pubkey| {
+                builder.push_key(pubkey)
+            })
+            .push_int(3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script();
+        assert_eq!(parse_multisig(&script), Some((2, pubkeys)));
+
+        let wpkh = bitcoin::WPubkeyHash::from_str("751e76e8199196d454941c45d1b3a323f1433bd6");
+        let p2wpkh = bitcoin::ScriptBuf::new_v0_p2wpkh(&wpkh.unwrap());
+        assert_eq!(parse_multisig(&p2wpkh), None);
+    }
+}
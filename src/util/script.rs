@@ -3,6 +3,8 @@ use crate::elements::ebcompact::*;
 #[cfg(feature = "liquid")]
 use elements::address as elements_address;
 
+#[cfg(not(feature = "liquid"))]
+use crate::chain::custom_script_to_address;
 use crate::chain::{script, Network, Script, TxIn, TxOut};
 use script::Instruction::PushBytes;
 
@@ -27,6 +29,9 @@ pub trait ScriptToAddr {
 #[cfg(not(feature = "liquid"))]
 impl ScriptToAddr for bitcoin::Script {
     fn to_address_str(&self, network: Network) -> Option<String> {
+        if network == Network::Custom {
+            return custom_script_to_address(self);
+        }
         bitcoin::Address::from_script(self, network.into())
             .map(|s| s.to_string())
             .ok()
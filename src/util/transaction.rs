@@ -45,6 +45,33 @@ impl From<Option<BlockId>> for TransactionStatus {
     }
 }
 
+// The weight, virtual size and sigop cost of a transaction. Persisted by the indexer for confirmed
+// transactions, so that they don't have to be recomputed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TxStats {
+    pub weight: u32,
+    pub vsize: u32,
+    #[cfg(not(feature = "liquid"))]
+    pub sigop_cost: u32,
+}
+
+impl TxStats {
+    // Inputs whose prevout is missing from `txos` are not accounted for in the sigop cost
+    #[cfg_attr(feature = "liquid", allow(unused_variables))]
+    pub fn new(tx: &Transaction, txos: &HashMap<OutPoint, TxOut>) -> TxStats {
+        let weight = tx.weight();
+        #[cfg(not(feature = "liquid"))] // rust-bitcoin has a wrapper Weight type
+        let weight = weight.to_wu();
+
+        TxStats {
+            weight: weight as u32,
+            vsize: ((weight + 3) / 4) as u32,
+            #[cfg(not(feature = "liquid"))]
+            sigop_cost: tx.total_sigop_cost(|outpoint| txos.get(outpoint).cloned()) as u32,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct TxInput {
     pub txid: Txid,
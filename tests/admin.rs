@@ -0,0 +1,48 @@
+pub mod common;
+use common::Result;
+
+use serde_json::Value;
+
+/// Test the maintenance operations of the admin server
+#[test]
+fn test_admin() -> Result<()> {
+    let (admin, admin_addr, tester) = common::init_admin_tester()?;
+    let post = |path: &str| -> Result<Value> {
+        Ok(ureq::post(&format!("http://{}{}", admin_addr, path))
+            .call()?
+            .into_json()?)
+    };
+
+    // Compact each db, unknown dbs are not found
+    for db in ["txstore", "history", "cache", "meta"] {
+        let res = post(&format!("/compact/{}", db))?;
+        assert!(res["duration_ms"].is_u64());
+    }
+    let err = ureq::post(&format!("http://{}/compact/mempool", admin_addr))
+        .call()
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(404, _)));
+
+    // Clearing the caches drops the cached aggregates, but keeps the other rows of the cache db
+    let store = tester.query().chain().store();
+    let cached_key = [b"A".to_vec(), vec![1; 32]].concat();
+    store.cache_db().put(&cached_key, b"cached");
+    let res = post("/caches/clear")?;
+    assert!(res["deleted_rows"].as_u64().unwrap() >= 1);
+    assert!(store.cache_db().get(&cached_key).is_none());
+    assert!(store.cache_db().get(b"V").is_some());
+    assert_eq!(post("/caches/clear")?["deleted_rows"].as_u64(), Some(0));
+
+    // Pausing and resuming the indexing
+    let get_state = || -> Result<Value> { common::rest_get_json(&admin_addr, "/state") };
+    assert!(!admin.indexing_paused());
+    assert_eq!(post("/indexing/pause")?["indexing_paused"], true);
+    assert!(admin.indexing_paused());
+    assert_eq!(get_state()?["indexing"]["paused"], true);
+    assert_eq!(post("/indexing/resume")?["indexing_paused"], false);
+    assert!(!admin.indexing_paused());
+    assert_eq!(get_state()?["indexing"]["paused"], false);
+    assert_eq!(get_state()?["tip"]["height"].as_u64(), Some(101));
+
+    Ok(())
+}
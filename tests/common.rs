@@ -1,12 +1,13 @@
-use std::sync::{Arc, Once, RwLock};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Write};
+use std::sync::{Arc, Once, RwLock, Weak};
+use std::time::{Duration, Instant};
 use std::{env, net};
 
 use stderrlog::StdErrLog;
 use tempfile::TempDir;
 
-use serde_json::json;
-#[cfg(feature = "liquid")]
-use serde_json::Value;
+use serde_json::{json, Value};
 
 #[cfg(not(feature = "liquid"))]
 use bitcoind::{self as noded, BitcoinD as NodeD};
@@ -16,11 +17,13 @@ use elementsd::{self as noded, ElementsD as NodeD};
 use noded::bitcoincore_rpc::{self, RpcApi};
 
 use electrs::{
+    admin::Admin,
+    auth::ApiKeys,
     chain::{Address, BlockHash, Network, Txid},
-    config::Config,
+    config::{ApiCompat, Config, RuntimeConfig},
     daemon::Daemon,
-    electrum::RPC as ElectrumRPC,
-    metrics::Metrics,
+    electrum::{ClientStats, RPC as ElectrumRPC},
+    metrics::{MetricOpts, Metrics},
     new_index::{ChainQuery, FetchFrom, Indexer, Mempool, Query, Store},
     rest,
     signal::Waiter,
@@ -31,6 +34,7 @@ pub struct TestRunner {
     /// bitcoind::BitcoinD or an elementsd::ElementsD in liquid mode
     node: NodeD,
     _electrsdb: TempDir, // rm'd when dropped
+    store: Weak<Store>,
     indexer: Indexer,
     query: Arc<Query>,
     daemon: Arc<Daemon>,
@@ -40,6 +44,11 @@ pub struct TestRunner {
 
 impl TestRunner {
     pub fn new() -> Result<TestRunner> {
+        Self::new_with(|_| ())
+    }
+
+    /// Like new(), with the default test config adjusted by `configure`
+    pub fn new_with(configure: impl FnOnce(&mut Config)) -> Result<TestRunner> {
         let log = init_log();
 
         // Setup the bitcoind/elementsd config
@@ -84,7 +93,7 @@ impl TestRunner {
 
         let electrsdb = tempfile::tempdir().unwrap();
 
-        let config = Arc::new(Config {
+        let mut config = Config {
             log,
             network_type,
             db_path: electrsdb.path().to_path_buf(),
@@ -96,16 +105,68 @@ impl TestRunner {
             http_addr: rand_available_addr(),
             http_socket_file: None, // XXX test with socket file or tcp?
             monitoring_addr: rand_available_addr(),
+            admin_addr: None,
             jsonrpc_import: false,
+            allow_pruned: false,
             light_mode: false,
             address_search: true,
+            address_clustering: false,
+            script_type_stats: true,
+            address_reuse: true,
+            history_bloom: true,
+            index_pubkeys: true,
+            utxo_muhash: true,
+            coin_days_destroyed: true,
+            script_dictionary: false,
+            checkpoints: true,
             index_unspendables: false,
-            cors: None,
+            cors_allow_headers: "Content-Type".parse().unwrap(),
+            cors_max_age: 86400,
+            http_compression_min_size: 1024,
+            http_compression_level: 6,
+            http_max_concurrency: 16,
+            http_request_timeout: 30,
+            http_route_timeouts: vec![],
+            http_header_read_timeout: 30,
+            http_keep_alive_idle: 60,
+            http2: false,
+            http2_keep_alive_interval: 30,
+            http2_max_concurrent_streams: 100,
+            slow_query_ms: 1000,
+            slow_query_rows: 100_000,
+            query_cost_headers: true,
+            event_bus_url: None,
+            event_bus_prefix: "electrs".to_string(),
+            webhooks: true,
+            labels: true,
+            first_seen: true,
+            metadata: true,
             precache_scripts: None,
-            utxos_limit: 100,
+            networks: vec![],
+            utxo_dust_feerate: 3.0,
+            rest_history_limit: 25,
+            rest_block_txs_limit: 25,
+            rest_address_search_limit: 10,
+            api_compat: ApiCompat::Native,
+            disabled_routes: vec![],
             electrum_txs_limit: 100,
+            electrum_max_connections: 0,
+            electrum_max_subscriptions: 0,
+            electrum_max_pending: 0,
+            electrum_idle_timeout: 0,
             electrum_banner: "".into(),
             electrum_rpc_logging: None,
+            electrum_listeners: vec![],
+            auth_keys_file: None,
+            readiness_max_lag: 2,
+            verify_index: false,
+            repair_index: false,
+            runtime: Arc::new(RwLock::new(RuntimeConfig {
+                verbosity: 0,
+                utxos_limit: 100,
+                cors: None,
+            })),
+            args: None,
 
             #[cfg(feature = "liquid")]
             asset_db_path: None, // XXX
@@ -116,8 +177,14 @@ impl TestRunner {
             //#[cfg(feature = "electrum-discovery")]
             //electrum_announce: bool,
             //#[cfg(feature = "electrum-discovery")]
+            //electrum_peers_allowlist: Option<HashSet<String>>,
+            //#[cfg(feature = "electrum-discovery")]
             //tor_proxy: Option<std::net::SocketAddr>,
-        });
+            #[cfg(feature = "grpc")]
+            grpc_addr: None,
+        };
+        configure(&mut config);
+        let config = Arc::new(config);
 
         let signal = Waiter::start();
         let metrics = Metrics::new(rand_available_addr());
@@ -129,12 +196,11 @@ impl TestRunner {
             config.daemon_rpc_addr,
             config.cookie_getter(),
             config.network_type,
+            config.allow_pruned,
             signal.clone(),
             &metrics,
         )?);
 
-        let store = Arc::new(Store::open(&config.db_path.join("newindex"), &config));
-
         let fetch_from = if !env::var("JSONRPC_IMPORT").is_ok() && !cfg!(feature = "liquid") {
             // run the initial indexing from the blk files then switch to using the jsonrpc,
             // similarly to how electrs is typically used.
@@ -146,37 +212,55 @@ impl TestRunner {
             FetchFrom::Bitcoind
         };
 
-        let mut indexer = Indexer::open(Arc::clone(&store), fetch_from, &config, &metrics);
-        indexer.update(&daemon)?;
-        indexer.fetch_from(FetchFrom::Bitcoind);
+        let (store, indexer, query, mempool) = open_index(&config, &daemon, &metrics, fetch_from)?;
 
-        let chain = Arc::new(ChainQuery::new(
-            Arc::clone(&store),
-            Arc::clone(&daemon),
-            &config,
-            &metrics,
-        ));
+        Ok(TestRunner {
+            config,
+            node,
+            _electrsdb: electrsdb,
+            store,
+            indexer,
+            query,
+            daemon,
+            mempool,
+            metrics,
+        })
+    }
 
-        let mempool = Arc::new(RwLock::new(Mempool::new(
-            Arc::clone(&chain),
-            &metrics,
-            Arc::clone(&config),
-        )));
-        Mempool::update(&mempool, &daemon)?;
-
-        let query = Arc::new(Query::new(
-            Arc::clone(&chain),
-            Arc::clone(&mempool),
-            Arc::clone(&daemon),
-            Arc::clone(&config),
-            #[cfg(feature = "liquid")]
-            None, // TODO
-        ));
+    /// Reopen the index from disk, as a restarted electrs would. The servers started with the
+    /// previous query() must be stopped first, or the db can't be reopened.
+    pub fn restart(self) -> Result<TestRunner> {
+        let TestRunner {
+            config,
+            node,
+            _electrsdb,
+            store,
+            indexer,
+            query,
+            daemon,
+            mempool,
+            metrics: _,
+        } = self;
+        drop((indexer, query, mempool));
+        // the db gets closed once the background threads holding the store notice the drop
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while store.strong_count() > 0 {
+            if Instant::now() > deadline {
+                return Err("the store wasn't closed".into());
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+
+        // registering the metrics of the new index in the same registry would fail
+        let metrics = Metrics::new(rand_available_addr());
+        let (store, indexer, query, mempool) =
+            open_index(&config, &daemon, &metrics, FetchFrom::Bitcoind)?;
 
         Ok(TestRunner {
             config,
             node,
-            _electrsdb: electrsdb,
+            _electrsdb,
+            store,
             indexer,
             query,
             daemon,
@@ -192,6 +276,10 @@ impl TestRunner {
         return &self.node.client();
     }
 
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
     pub fn sync(&mut self) -> Result<()> {
         self.indexer.update(&self.daemon)?;
         Mempool::update(&self.mempool, &self.daemon)?;
@@ -206,6 +294,25 @@ impl TestRunner {
         Ok(generated.remove(0))
     }
 
+    pub fn mine_blocks(&mut self, num_blocks: u32) -> Result<Vec<BlockHash>> {
+        let generated = generate(self.node_client(), num_blocks)?;
+        self.sync()?;
+        Ok(generated)
+    }
+
+    /// Replace the last `depth` blocks with `depth + 1` new ones, then sync the index.
+    /// Transactions confirmed in the invalidated blocks return to the node's mempool and
+    /// get confirmed again in the replacement chain.
+    pub fn reorg(&mut self, depth: u32) -> Result<Vec<BlockHash>> {
+        let client = self.node_client();
+        let tip_height: u32 = client.call("getblockcount", &[])?;
+        let fork_hash: BlockHash =
+            client.call("getblockhash", &[(tip_height - depth + 1).into()])?;
+        // Must use raw call() because invalidate_block() expects a bitcoin::BlockHash
+        client.call::<Value>("invalidateblock", &[fork_hash.to_string().into()])?;
+        self.mine_blocks(depth + 1)
+    }
+
     pub fn send(&mut self, addr: &Address, amount: bitcoin::Amount) -> Result<Txid> {
         // Must use raw call() because send_to_address() expects a bitcoin::Address and not an elements::Address
         let txid = self.node_client().call(
@@ -263,13 +370,23 @@ impl TestRunner {
 }
 
 pub fn init_rest_tester() -> Result<(rest::Handle, net::SocketAddr, TestRunner)> {
-    let tester = TestRunner::new()?;
+    init_rest_tester_with(|_| ())
+}
+pub fn init_rest_tester_with(
+    configure: impl FnOnce(&mut Config),
+) -> Result<(rest::Handle, net::SocketAddr, TestRunner)> {
+    let tester = TestRunner::new_with(configure)?;
     let rest_server = rest::start(Arc::clone(&tester.config), Arc::clone(&tester.query));
     log::info!("REST server running on {}", tester.config.http_addr);
     Ok((rest_server, tester.config.http_addr, tester))
 }
 pub fn init_electrum_tester() -> Result<(ElectrumRPC, net::SocketAddr, TestRunner)> {
-    let tester = TestRunner::new()?;
+    init_electrum_tester_with(|_| ())
+}
+pub fn init_electrum_tester_with(
+    configure: impl FnOnce(&mut Config),
+) -> Result<(ElectrumRPC, net::SocketAddr, TestRunner)> {
+    let tester = TestRunner::new_with(configure)?;
     let electrum_server = ElectrumRPC::start(
         Arc::clone(&tester.config),
         Arc::clone(&tester.query),
@@ -282,6 +399,133 @@ pub fn init_electrum_tester() -> Result<(ElectrumRPC, net::SocketAddr, TestRunne
     Ok((electrum_server, tester.config.electrum_rpc_addr, tester))
 }
 
+#[cfg(feature = "grpc")]
+pub fn init_grpc_tester_with(
+    configure: impl FnOnce(&mut Config),
+) -> Result<(electrs::grpc::Handle, net::SocketAddr, TestRunner)> {
+    let grpc_addr = rand_available_addr();
+    let tester = TestRunner::new_with(|config| {
+        config.grpc_addr = Some(grpc_addr);
+        configure(config)
+    })?;
+    let grpc_server = electrs::grpc::start(Arc::clone(&tester.config), Arc::clone(&tester.query))
+        .expect("gRPC server not enabled");
+    log::info!("gRPC server running on {}", grpc_addr);
+    Ok((grpc_server, grpc_addr, tester))
+}
+
+pub fn init_admin_tester() -> Result<(Admin, net::SocketAddr, TestRunner)> {
+    let admin_addr = rand_available_addr();
+    let tester = TestRunner::new()?;
+    let electrum = ClientStats {
+        clients: tester
+            .metrics
+            .gauge(MetricOpts::new("electrum_clients", "# of Electrum clients")),
+        subscriptions: tester.metrics.gauge(MetricOpts::new(
+            "electrum_subscriptions",
+            "# of Electrum subscriptions",
+        )),
+    };
+    let admin = Admin::start(
+        admin_addr,
+        Arc::clone(&tester.query),
+        tester.indexer.pending_blocks(),
+        electrum,
+    );
+    Ok((admin, admin_addr, tester))
+}
+
+/// GET a REST endpoint and parse its response as JSON
+pub fn rest_get_json(rest_addr: &net::SocketAddr, path: &str) -> Result<Value> {
+    Ok(ureq::get(&format!("http://{}{}", rest_addr, path))
+        .call()?
+        .into_json::<Value>()?)
+}
+
+/// GET a REST endpoint and return its response as plain text
+pub fn rest_get_plain(rest_addr: &net::SocketAddr, path: &str) -> Result<String> {
+    Ok(ureq::get(&format!("http://{}{}", rest_addr, path))
+        .call()?
+        .into_string()?)
+}
+
+/// A line-delimited JSON-RPC connection to the Electrum server. The notifications received while
+/// waiting for a response are queued, to be read with `notification()`.
+pub struct ElectrumConn {
+    reader: BufReader<net::TcpStream>,
+    writer: net::TcpStream,
+    notifications: VecDeque<Value>,
+    next_id: u64,
+}
+
+impl ElectrumConn {
+    pub fn connect(addr: &net::SocketAddr) -> Result<ElectrumConn> {
+        let stream = net::TcpStream::connect(addr)?;
+        Ok(ElectrumConn {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            notifications: VecDeque::new(),
+            next_id: 0,
+        })
+    }
+
+    /// Call the method and return the response, holding either its `result` or its `error`
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.next_id += 1;
+        let req = json!({"jsonrpc": "2.0", "id": self.next_id, "method": method, "params": params});
+        self.writer.write_all(format!("{}\n", req).as_bytes())?;
+        loop {
+            let message = self.read_message()?;
+            if message.get("id").is_some() {
+                assert_eq!(message["id"], self.next_id, "unexpected response id");
+                return Ok(message);
+            }
+            self.notifications.push_back(message);
+        }
+    }
+
+    /// The next notification, or None if none arrives within the timeout
+    pub fn notification(&mut self, timeout: Duration) -> Result<Option<Value>> {
+        if let Some(notification) = self.notifications.pop_front() {
+            return Ok(Some(notification));
+        }
+        self.reader.get_ref().set_read_timeout(Some(timeout))?;
+        let message = match self.read_message() {
+            Ok(message) => Some(message),
+            Err(Error(ErrorKind::Io(e), _))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                None
+            }
+            Err(e) => return Err(e),
+        };
+        self.reader.get_ref().set_read_timeout(None)?;
+        Ok(message)
+    }
+
+    /// Whether the server closed the connection, waiting up to the timeout for it
+    pub fn is_closed(&mut self, timeout: Duration) -> Result<bool> {
+        self.reader.get_ref().set_read_timeout(Some(timeout))?;
+        let mut line = String::new();
+        Ok(match self.reader.read_line(&mut line) {
+            Ok(0) => true,
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => true,
+            _ => false,
+        })
+    }
+
+    fn read_message(&mut self) -> Result<Value> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err("connection closed by the server".into());
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+}
+
 #[cfg(not(feature = "liquid"))]
 fn raw_new_address(
     client: &bitcoincore_rpc::Client,
@@ -296,6 +540,46 @@ fn raw_new_address(client: &bitcoincore_rpc::Client) -> bitcoincore_rpc::Result<
     Ok(client.call::<Address>("getnewaddress", &[])?)
 }
 
+// Opens the index in the db directory, and updates it to the node's tip
+fn open_index(
+    config: &Arc<Config>,
+    daemon: &Arc<Daemon>,
+    metrics: &Metrics,
+    fetch_from: FetchFrom,
+) -> Result<(Weak<Store>, Indexer, Arc<Query>, Arc<RwLock<Mempool>>)> {
+    let store = Arc::new(Store::open(&config.db_path.join("newindex"), config));
+
+    let mut indexer = Indexer::open(Arc::clone(&store), fetch_from, config, metrics);
+    indexer.update(daemon)?;
+    indexer.fetch_from(FetchFrom::Bitcoind);
+
+    let chain = Arc::new(ChainQuery::new(
+        Arc::clone(&store),
+        Arc::clone(daemon),
+        config,
+        metrics,
+    ));
+
+    let mempool = Arc::new(RwLock::new(Mempool::new(
+        Arc::clone(&chain),
+        metrics,
+        Arc::clone(config),
+    )));
+    Mempool::update(&mempool, daemon)?;
+
+    let query = Arc::new(Query::new(
+        Arc::clone(&chain),
+        Arc::clone(&mempool),
+        Arc::clone(daemon),
+        Arc::clone(config),
+        ApiKeys::from_config(config)?,
+        #[cfg(feature = "liquid")]
+        None, // TODO
+    ));
+
+    Ok((Arc::downgrade(&store), indexer, query, mempool))
+}
+
 fn generate(
     client: &bitcoincore_rpc::Client,
     num_blocks: u32,
@@ -1,11 +1,20 @@
 pub mod common;
-use common::Result;
+use common::{ElectrumConn, Result};
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::str::FromStr;
+use std::time::Duration;
+
+use bitcoin::hashes::{sha256, sha256d, Hash, HashEngine};
 
 use bitcoind::bitcoincore_rpc::RpcApi;
 use electrumd::jsonrpc::serde_json::json;
 use electrumd::ElectrumD;
 
 use electrs::chain::Address;
+use electrs::new_index::compute_script_hash;
 
 #[cfg(not(feature = "liquid"))]
 use bitcoin::address;
@@ -134,3 +143,376 @@ fn test_electrum() -> Result<()> {
 
     Ok(())
 }
+
+/// Test the server.auth handshake required when API keys are configured
+#[test]
+fn test_electrum_auth() -> Result<()> {
+    let keys_file = tempfile::NamedTempFile::new()?;
+    std::fs::write(keys_file.path(), "secret\nlimited:1\n")?;
+    let keys_path = keys_file.path().to_path_buf();
+    let (_electrum_server, electrum_addr, _tester) =
+        common::init_electrum_tester_with(|config| config.auth_keys_file = Some(keys_path))?;
+
+    let mut conn = ElectrumConn::connect(&electrum_addr)?;
+    let mut call = |method: &str, params| conn.call(method, params);
+
+    // Only the handshake methods are available before authenticating
+    assert!(call("server.version", json!([]))?["result"].is_array());
+    let res = call("blockchain.headers.subscribe", json!([]))?;
+    assert_eq!(res["error"].as_str(), Some("missing API key"));
+
+    // Unknown keys are refused and leave the connection unauthenticated
+    let res = call("server.auth", json!(["wrong"]))?;
+    assert_eq!(res["error"].as_str(), Some("invalid API key"));
+    let res = call("blockchain.headers.subscribe", json!([]))?;
+    assert_eq!(res["error"].as_str(), Some("missing API key"));
+
+    assert_eq!(call("server.auth", json!(["secret"]))?["result"], true);
+    let res = call("blockchain.headers.subscribe", json!([]))?;
+    assert_eq!(res["result"]["height"].as_u64(), Some(101));
+
+    // Keys with a rate limit are rejected once it's exhausted
+    assert_eq!(call("server.auth", json!(["limited"]))?["result"], true);
+    assert!(call("blockchain.relayfee", json!([]))?["result"].is_number());
+    let res = call("blockchain.relayfee", json!([]))?;
+    assert_eq!(res["error"].as_str(), Some("API key rate limit exceeded"));
+
+    Ok(())
+}
+
+/// Test the proofs of the tip header against a checkpoint
+#[test]
+fn test_electrum_headers_subscribe_checkpoint() -> Result<()> {
+    let (_electrum_server, electrum_addr, tester) = common::init_electrum_tester()?;
+    let mut conn = ElectrumConn::connect(&electrum_addr)?;
+    let tip_hash = tester.node_client().get_best_block_hash()?;
+
+    // A checkpoint at the tip proves it
+    let res = conn.call("blockchain.headers.subscribe", json!([101]))?;
+    let result = &res["result"];
+    assert_eq!(result["height"].as_u64(), Some(101));
+    let root = result["root"].as_str().expect("missing root");
+    let branch: Vec<&str> = result["branch"]
+        .as_array()
+        .expect("missing branch")
+        .iter()
+        .map(|hash| hash.as_str().unwrap())
+        .collect();
+    assert_eq!(merkle_root(tip_hash.to_raw_hash(), 101, &branch), root);
+
+    // Proven against the same root as the other headers up to the checkpoint
+    let res = conn.call("blockchain.block.header", json!([50, 101]))?;
+    assert_eq!(res["result"]["root"].as_str(), Some(root));
+
+    // Older checkpoints can't prove the tip, which is returned without a proof
+    let res = conn.call("blockchain.headers.subscribe", json!([100]))?;
+    assert_eq!(res["result"]["height"].as_u64(), Some(101));
+    assert!(res["result"].get("root").is_none());
+    assert!(res["result"].get("branch").is_none());
+    let res = conn.call("blockchain.headers.subscribe", json!([]))?;
+    assert!(res["result"].get("root").is_none());
+
+    // Checkpoints above the tip mean the server is lagging behind the client
+    let res = conn.call("blockchain.headers.subscribe", json!([102]))?;
+    assert!(res["error"]
+        .as_str()
+        .unwrap()
+        .contains("cp_height #102 above best block height #101"));
+
+    Ok(())
+}
+
+/// Test that connections above --electrum-max-connections are rejected
+#[test]
+fn test_electrum_max_connections() -> Result<()> {
+    let (_electrum_server, electrum_addr, _tester) =
+        common::init_electrum_tester_with(|config| config.electrum_max_connections = 1)?;
+
+    let mut conn = ElectrumConn::connect(&electrum_addr)?;
+    assert!(conn.call("server.version", json!([]))?["result"].is_array());
+
+    let mut rejected = ElectrumConn::connect(&electrum_addr)?;
+    let reply = rejected
+        .notification(Duration::from_secs(5))?
+        .expect("rejection reply");
+    assert_eq!(reply["error"].as_str(), Some("too many connections"));
+    assert!(rejected.is_closed(Duration::from_secs(5))?);
+
+    // the slot is freed once the connected client goes away
+    drop(conn);
+    let accepted = (0..50).any(|_| {
+        std::thread::sleep(Duration::from_millis(100));
+        ElectrumConn::connect(&electrum_addr)
+            .and_then(|mut conn| conn.call("server.version", json!([])))
+            .map_or(false, |res| res["result"].is_array())
+    });
+    assert!(accepted);
+
+    Ok(())
+}
+
+/// Test that subscriptions above --electrum-max-subscriptions are refused
+#[test]
+fn test_electrum_max_subscriptions() -> Result<()> {
+    let (_electrum_server, electrum_addr, _tester) =
+        common::init_electrum_tester_with(|config| config.electrum_max_subscriptions = 2)?;
+
+    let mut conn = ElectrumConn::connect(&electrum_addr)?;
+    let mut subscribe = |index: u8| {
+        let scripthash = format!("{:064x}", index);
+        conn.call("blockchain.scripthash.subscribe", json!([scripthash]))
+    };
+
+    assert!(subscribe(1)?["result"].is_null());
+    assert!(subscribe(2)?["result"].is_null());
+    let res = subscribe(3)?;
+    assert_eq!(
+        res["error"].as_str(),
+        Some("too many subscriptions (limit is 2)")
+    );
+    // subscribing again to a scripthash doesn't count against the limit
+    assert!(subscribe(2)?["result"].is_null());
+
+    Ok(())
+}
+
+/// Test that clients sending requests faster than they are answered are disconnected once
+/// more than --electrum-max-pending requests are queued
+#[test]
+fn test_electrum_max_pending() -> Result<()> {
+    let (_electrum_server, electrum_addr, _tester) =
+        common::init_electrum_tester_with(|config| config.electrum_max_pending = 1)?;
+
+    const REQUESTS: usize = 1000;
+    let stream = TcpStream::connect(electrum_addr)?;
+    let mut writer = stream.try_clone()?;
+    // pipeline all the requests without waiting for the replies, ignoring the failures once
+    // the server closed the connection
+    let pipeline = std::thread::spawn(move || {
+        for id in 0..REQUESTS {
+            let req = json!({"jsonrpc": "2.0", "id": id, "method": "blockchain.block.header", "params": [id % 100]});
+            if writer.write_all(format!("{}\n", req).as_bytes()).is_err() {
+                break;
+            }
+        }
+    });
+
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let replies = BufReader::new(stream)
+        .lines()
+        .take_while(|line| line.is_ok())
+        .count();
+    assert!(replies < REQUESTS, "the client wasn't disconnected");
+    pipeline.join().unwrap();
+
+    Ok(())
+}
+
+/// Test that connections without any request for --electrum-idle-timeout are closed
+#[test]
+fn test_electrum_idle_timeout() -> Result<()> {
+    let (_electrum_server, electrum_addr, _tester) =
+        common::init_electrum_tester_with(|config| config.electrum_idle_timeout = 3)?;
+
+    let mut conn = ElectrumConn::connect(&electrum_addr)?;
+    // requests keep the connection alive
+    for _ in 0..4 {
+        assert!(conn.call("server.ping", json!([]))?["result"].is_null());
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    assert!(!conn.is_closed(Duration::from_millis(500))?);
+    assert!(conn.is_closed(Duration::from_secs(5))?);
+
+    Ok(())
+}
+
+/// Test the balance delta and fee of each transaction returned by blockchain.scripthash.get_history_ex
+#[cfg_attr(not(feature = "liquid"), test)]
+#[cfg_attr(feature = "liquid", allow(dead_code))]
+fn test_electrum_get_history_ex() -> Result<()> {
+    let (_electrum_server, electrum_addr, mut tester) = common::init_electrum_tester()?;
+    let mut conn = ElectrumConn::connect(&electrum_addr)?;
+
+    let addr = tester.newaddress()?;
+    let scripthash = electrum_scripthash(&addr);
+    let wallet_tx =
+        |tester: &common::TestRunner, txid: &bitcoin::Txid| -> Result<serde_json::Value> {
+            Ok(tester
+                .node_client()
+                .call("gettransaction", &[txid.to_string().into()])?)
+        };
+    // the fee paid by the wallet, in sats
+    let fee_sats = |tx: &serde_json::Value| (-tx["fee"].as_f64().unwrap() * 1e8).round() as i64;
+
+    // Receive 1 BTC, confirmed
+    let txid1 = tester.send(&addr, "1 BTC".parse().unwrap())?;
+    let tx1 = wallet_tx(&tester, &txid1)?;
+    tester.mine()?;
+    let vout = tx1["details"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|detail| detail["address"].as_str() == Some(&addr.to_string()))
+        .and_then(|detail| detail["vout"].as_u64())
+        .expect("missing funding output");
+
+    // Send it all elsewhere, unconfirmed
+    let sent: serde_json::Value = tester.node_client().call(
+        "sendall",
+        &[
+            json!([tester.newaddress()?.to_string()]),
+            serde_json::Value::Null,
+            "unset".into(),
+            10.into(),
+            json!({ "inputs": [{ "txid": txid1.to_string(), "vout": vout }] }),
+        ],
+    )?;
+    let txid2: bitcoin::Txid = sent["txid"].as_str().unwrap().parse().unwrap();
+    let tx2 = wallet_tx(&tester, &txid2)?;
+    tester.sync()?;
+
+    let history = conn.call("blockchain.scripthash.get_history_ex", json!([scripthash]))?;
+    assert_eq!(
+        history["result"],
+        json!([
+            {
+                "tx_hash": txid1.to_string(),
+                "height": 102,
+                "delta": 100_000_000,
+                "fee": fee_sats(&tx1),
+            },
+            {
+                "tx_hash": txid2.to_string(),
+                "height": 0,
+                "delta": -100_000_000,
+                "fee": fee_sats(&tx2),
+            },
+        ])
+    );
+
+    // The spend keeps its delta and fee once confirmed
+    tester.mine()?;
+    let history = conn.call("blockchain.scripthash.get_history_ex", json!([scripthash]))?;
+    assert_eq!(history["result"][1]["height"].as_u64(), Some(103));
+    assert_eq!(history["result"][1]["delta"].as_i64(), Some(-100_000_000));
+    assert_eq!(history["result"][1]["fee"].as_i64(), Some(fee_sats(&tx2)));
+
+    Ok(())
+}
+
+/// Test the notifications of a client subscribed to several scripthashes, as transactions get
+/// into the mempool and then confirmed in a new block
+#[cfg_attr(not(feature = "liquid"), test)]
+#[cfg_attr(feature = "liquid", allow(dead_code))]
+fn test_electrum_subscriptions_new_block() -> Result<()> {
+    let (electrum_server, electrum_addr, mut tester) = common::init_electrum_tester()?;
+    let mut conn = ElectrumConn::connect(&electrum_addr)?;
+
+    let addrs = [
+        tester.newaddress()?,
+        tester.newaddress()?,
+        tester.newaddress()?,
+    ];
+    let scripthashes: Vec<String> = addrs.iter().map(electrum_scripthash).collect();
+    let res = conn.call("blockchain.headers.subscribe", json!([]))?;
+    assert_eq!(res["result"]["height"].as_u64(), Some(101));
+    for scripthash in &scripthashes {
+        let res = conn.call("blockchain.scripthash.subscribe", json!([scripthash]))?;
+        assert!(res["result"].is_null());
+    }
+
+    // Collect the notifications sent after the update, keeping the latest status of each
+    // scripthash
+    let wait_notifications =
+        |conn: &mut ElectrumConn| -> Result<(Vec<u64>, HashMap<String, String>)> {
+            electrum_server.notify();
+            let (mut heights, mut statuses) = (vec![], HashMap::new());
+            while let Some(notification) = conn.notification(Duration::from_secs(2))? {
+                let params = &notification["params"];
+                match notification["method"].as_str() {
+                    Some("blockchain.headers.subscribe") => {
+                        heights.push(params[0]["height"].as_u64().unwrap())
+                    }
+                    Some("blockchain.scripthash.subscribe") => {
+                        let scripthash = params[0].as_str().unwrap().to_string();
+                        statuses.insert(scripthash, params[1].as_str().unwrap().to_string());
+                    }
+                    method => panic!("unexpected notification {:?}", method),
+                }
+            }
+            Ok((heights, statuses))
+        };
+
+    // Pay to the first two scripts
+    let txid0 = tester.send(&addrs[0], "1 BTC".parse().unwrap())?;
+    let txid1 = tester.send(&addrs[1], "2 BTC".parse().unwrap())?;
+    let mempool_height = |txid: &bitcoin::Txid| {
+        if tester.query().has_unconfirmed_parents(txid) {
+            -1
+        } else {
+            0
+        }
+    };
+    let expected = HashMap::from([
+        (
+            scripthashes[0].clone(),
+            status_hash(&[(txid0, mempool_height(&txid0))]),
+        ),
+        (
+            scripthashes[1].clone(),
+            status_hash(&[(txid1, mempool_height(&txid1))]),
+        ),
+    ]);
+    let (heights, statuses) = wait_notifications(&mut conn)?;
+    assert!(heights.is_empty());
+    assert_eq!(statuses, expected);
+
+    // Once confirmed, the new header and the new statuses of both are notified, but nothing for
+    // the unused script
+    tester.mine()?;
+    let expected = HashMap::from([
+        (scripthashes[0].clone(), status_hash(&[(txid0, 102)])),
+        (scripthashes[1].clone(), status_hash(&[(txid1, 102)])),
+    ]);
+    let (heights, statuses) = wait_notifications(&mut conn)?;
+    assert_eq!(heights, vec![102]);
+    assert_eq!(statuses, expected);
+
+    // Nothing changed with the next block
+    tester.mine()?;
+    let (heights, statuses) = wait_notifications(&mut conn)?;
+    assert_eq!(heights, vec![103]);
+    assert!(statuses.is_empty());
+
+    Ok(())
+}
+
+// The scripthash of the address, as used by the Electrum protocol
+fn electrum_scripthash(addr: &Address) -> String {
+    sha256d::Hash::from_byte_array(compute_script_hash(&addr.script_pubkey())).to_string()
+}
+
+// The Electrum status of a history, as the sha256 of its concatenated "{txid}:{height}:" entries
+fn status_hash(history: &[(bitcoin::Txid, i64)]) -> String {
+    let mut engine = sha256::Hash::engine();
+    for (txid, height) in history {
+        engine.input(format!("{}:{}:", txid, height).as_bytes());
+    }
+    sha256::Hash::from_engine(engine).to_string()
+}
+
+// Fold the merkle branch of the leaf at `index`, returning the root in display order
+fn merkle_root(leaf: sha256d::Hash, mut index: usize, branch: &[&str]) -> String {
+    let mut hash = leaf;
+    for sibling in branch {
+        let sibling = sha256d::Hash::from_str(sibling).expect("invalid branch hash");
+        let (left, right) = if index % 2 == 0 {
+            (hash, sibling)
+        } else {
+            (sibling, hash)
+        };
+        hash = sha256d::Hash::hash(&[&left[..], &right[..]].concat());
+        index /= 2;
+    }
+    hash.to_string()
+}
@@ -0,0 +1,84 @@
+#![cfg(all(feature = "grpc", not(feature = "liquid")))]
+
+use bitcoin::consensus::encode::serialize;
+use bitcoind::bitcoincore_rpc::RpcApi;
+use tonic::{Code, Request};
+
+use electrs::grpc::proto::{electrs_client::ElectrsClient, TransactionRequest};
+
+pub mod common;
+
+use common::Result;
+
+#[test]
+fn test_grpc() -> Result<()> {
+    let keys_file = tempfile::NamedTempFile::new()?;
+    std::fs::write(keys_file.path(), "secret\nlimited:1\n")?;
+    let keys_path = keys_file.path().to_path_buf();
+    let (grpc_handle, grpc_addr, mut tester) =
+        common::init_grpc_tester_with(|config| config.auth_keys_file = Some(keys_path))?;
+
+    let addr = tester.newaddress()?;
+    let txid = tester.send(&addr, "0.5 BTC".parse().unwrap())?;
+    let blockhash = tester.mine()?;
+    let raw_tx: String = tester
+        .node_client()
+        .call("getrawtransaction", &[txid.to_string().into()])?;
+
+    let request = |metadata: Option<(&'static str, &str)>| {
+        let mut request = Request::new(TransactionRequest {
+            txid: serialize(&txid),
+        });
+        if let Some((name, value)) = metadata {
+            request.metadata_mut().insert(name, value.parse().unwrap());
+        }
+        request
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(async {
+        let mut client = ElectrsClient::connect(format!("http://{}", grpc_addr))
+            .await
+            .expect("failed connecting to the gRPC server");
+
+        // Missing and unknown keys are rejected
+        let status = client.get_transaction(request(None)).await.unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+        let status = client
+            .get_transaction(request(Some(("x-api-key", "wrong"))))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::Unauthenticated);
+
+        // Valid keys are accepted as a bearer token or in the x-api-key metadata
+        let response = client
+            .get_transaction(request(Some(("authorization", "Bearer secret"))))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(hex::encode(&response.raw_tx), raw_tx);
+        let block = response.block.expect("confirmed transaction");
+        assert_eq!(block.height, 102);
+        assert_eq!(block.hash, serialize(&blockhash));
+        client
+            .get_transaction(request(Some(("x-api-key", "secret"))))
+            .await
+            .unwrap();
+
+        // Keys with a rate limit are rejected once it's exhausted
+        client
+            .get_transaction(request(Some(("x-api-key", "limited"))))
+            .await
+            .unwrap();
+        let status = client
+            .get_transaction(request(Some(("x-api-key", "limited"))))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    });
+
+    grpc_handle.stop();
+    Ok(())
+}
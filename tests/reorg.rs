@@ -0,0 +1,245 @@
+use bitcoind::bitcoincore_rpc::RpcApi;
+use serde_json::Value;
+
+use electrs::chain::BlockHash;
+use electrs::new_index::db::DBFlush;
+use electrs::util::bincode;
+
+pub mod common;
+
+use common::Result;
+
+#[test]
+fn test_reorg() -> Result<()> {
+    let (rest_handle, rest_addr, mut tester) = common::init_rest_tester().unwrap();
+
+    let get_json = |path: &str| -> Result<Value> { common::rest_get_json(&rest_addr, path) };
+
+    // Confirm a transaction, then replace the block that confirmed it
+    let addr1 = tester.newaddress()?;
+    let txid = tester.send(&addr1, "0.4217 BTC".parse().unwrap())?;
+    let orphaned_hash = tester.mine()?;
+
+    let res = get_json(&format!("/tx/{}/status", txid))?;
+    assert_eq!(
+        res["block_hash"].as_str(),
+        Some(&orphaned_hash.to_string()[..])
+    );
+    let confirmed_height = res["block_height"].as_u64().unwrap();
+
+    let new_hashes = tester.reorg(1)?;
+    assert_eq!(new_hashes.len(), 2);
+
+    // The tip follows the new chain
+    let tip_height = get_json("/blocks/tip/height")?.as_u64().unwrap();
+    assert_eq!(tip_height, confirmed_height + 1);
+    let tip_hash = common::rest_get_plain(&rest_addr, "/blocks/tip/hash")?;
+    assert_eq!(tip_hash, new_hashes[1].to_string());
+
+    // The orphaned block is no longer part of the best chain
+    let res = get_json(&format!("/block/{}/status", orphaned_hash))?;
+    assert_eq!(res["in_best_chain"].as_bool(), Some(false));
+
+    // The transaction got confirmed again at the same height in the replacement block
+    let res = get_json(&format!("/tx/{}/status", txid))?;
+    assert_eq!(res["confirmed"].as_bool(), Some(true));
+    assert_eq!(res["block_height"].as_u64(), Some(confirmed_height));
+    assert_eq!(
+        res["block_hash"].as_str(),
+        Some(&new_hashes[0].to_string()[..])
+    );
+
+    // History entries left over from the orphaned block must not be counted twice
+    let res = get_json(&format!("/address/{}", addr1))?;
+    assert_eq!(res["chain_stats"]["tx_count"].as_u64(), Some(1));
+    assert_eq!(res["chain_stats"]["funded_txo_count"].as_u64(), Some(1));
+    assert_eq!(
+        res["chain_stats"]["funded_txo_sum"].as_u64(),
+        Some(42170000)
+    );
+    assert_eq!(res["mempool_stats"]["tx_count"].as_u64(), Some(0));
+
+    let res = get_json(&format!("/address/{}/txs", addr1))?;
+    let txs = res.as_array().expect("array of transactions");
+    assert_eq!(txs.len(), 1);
+    assert_eq!(txs[0]["txid"].as_str(), Some(&txid.to_string()[..]));
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_verify_repair() -> Result<()> {
+    let (rest_handle, rest_addr, mut tester) = common::init_rest_tester().unwrap();
+
+    let get_json = |path: &str| -> Result<Value> { common::rest_get_json(&rest_addr, path) };
+
+    // Confirm a transaction in a block that gets buried below the tip
+    let addr1 = tester.newaddress()?;
+    let txid = tester.send(&addr1, "0.31 BTC".parse().unwrap())?;
+    let blockhash = tester.mine()?;
+    tester.mine_blocks(2)?;
+    let store = tester.query().chain().store();
+    assert!(store.verify(false, false).is_consistent());
+
+    // Lose the txids row of the block, as an interrupted write could
+    let txids_key = [b"X", &blockhash[..]].concat();
+    store
+        .txstore_db()
+        .write_and_delete(vec![], vec![txids_key.clone()], DBFlush::Enable);
+    let report = store.verify(false, false);
+    assert_eq!(report.broken_blocks, vec![blockhash]);
+    assert!(!report.repaired);
+
+    // The repair gets the block added and indexed again by the next update
+    let report = store.verify(false, true);
+    assert!(report.repaired);
+    tester.sync()?;
+    let store = tester.query().chain().store();
+    assert!(store.txstore_db().get(&txids_key).is_some());
+    assert!(store.verify(false, false).is_consistent());
+
+    let txids = get_json(&format!("/block/{}/txids", blockhash))?;
+    assert!(txids
+        .as_array()
+        .expect("array of txids")
+        .contains(&Value::String(txid.to_string())));
+    let tip_height = get_json("/blocks/tip/height")?.as_u64().unwrap();
+    assert_eq!(tip_height, 104);
+
+    // Its done markers are restored in both DBs
+    let done_key = [b"D", &blockhash[..]].concat();
+    assert!(store.txstore_db().get(&done_key).is_some());
+    assert!(store.history_db().get(&done_key).is_some());
+
+    // Lose the added marker of the tip block, leaving it indexed but not added
+    let tip_hash = tester.node_client().get_best_block_hash()?;
+    let tip_done_key = [b"D", &tip_hash[..]].concat();
+    store
+        .txstore_db()
+        .write_and_delete(vec![], vec![tip_done_key.clone()], DBFlush::Enable);
+    let report = store.verify(false, true);
+    assert_eq!(report.broken_blocks, vec![tip_hash]);
+    assert!(store.history_db().get(&tip_done_key).is_none());
+
+    // The running indexer adds and indexes it again along with the next blocks, without
+    // counting the history of the repaired blocks twice
+    let txid2 = tester.send(&addr1, "0.12 BTC".parse().unwrap())?;
+    tester.mine()?;
+    let store = tester.query().chain().store();
+    assert!(store.txstore_db().get(&tip_done_key).is_some());
+    assert!(store.history_db().get(&tip_done_key).is_some());
+    assert!(store.verify(false, false).is_consistent());
+
+    let tip_height = get_json("/blocks/tip/height")?.as_u64().unwrap();
+    assert_eq!(tip_height, 105);
+    let res = get_json(&format!("/address/{}", addr1))?;
+    assert_eq!(res["chain_stats"]["tx_count"].as_u64(), Some(2));
+    assert_eq!(
+        res["chain_stats"]["funded_txo_sum"].as_u64(),
+        Some(43_000_000)
+    );
+    let res = get_json(&format!("/tx/{}/status", txid2))?;
+    assert_eq!(res["block_height"].as_u64(), Some(105));
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_headers_chain_restore() -> Result<()> {
+    let mut tester = common::TestRunner::new()?;
+
+    // The headers chain is persisted in chunks of 2016 headers
+    let chunks = |tester: &common::TestRunner| -> Vec<Vec<BlockHash>> {
+        let txstore_db = tester.query().chain().store().txstore_db();
+        txstore_db
+            .iter_scan(b"h")
+            .map(|row| {
+                let (hashes, _headers): (Vec<BlockHash>, Vec<Vec<u8>>) =
+                    bincode::deserialize_little(&row.value).unwrap();
+                hashes
+            })
+            .collect()
+    };
+    let node_chain = |tester: &common::TestRunner| -> Result<Vec<BlockHash>> {
+        let client = tester.node_client();
+        let tip_height = client.get_block_count()?;
+        Ok((0..=tip_height)
+            .map(|height| client.get_block_hash(height).unwrap())
+            .collect())
+    };
+    let indexed_chain = |tester: &common::TestRunner| -> Vec<BlockHash> {
+        let chain = tester.query().chain();
+        let tip_height = chain.best_height();
+        (0..=tip_height)
+            .map(|height| *chain.header_by_height(height).unwrap().hash())
+            .collect()
+    };
+
+    // Grow the chain into a second chunk
+    tester.mine_blocks(1920)?;
+    let chain = node_chain(&tester)?;
+    assert_eq!(chain.len(), 2022);
+    assert_eq!(chunks(&tester).concat(), chain);
+    assert_eq!(chunks(&tester).len(), 2);
+
+    // The chain is restored as it was on restart
+    let mut tester = tester.restart()?;
+    assert_eq!(indexed_chain(&tester), chain);
+
+    // Reorg to a shorter chain that ends in the first chunk
+    let client = tester.node_client();
+    let fork_hash = client.get_block_hash(2014)?;
+    client.call::<Value>("invalidateblock", &[fork_hash.to_string().into()])?;
+    tester.mine_blocks(1)?;
+    let chain = node_chain(&tester)?;
+    assert_eq!(chain.len(), 2015);
+    assert_eq!(indexed_chain(&tester), chain);
+
+    // The chunk past the new tip is dropped, so that the chain can be restored again
+    assert_eq!(chunks(&tester), vec![chain.clone()]);
+    let mut tester = tester.restart()?;
+    assert_eq!(indexed_chain(&tester), chain);
+
+    // and it keeps getting extended from there
+    tester.mine()?;
+    assert_eq!(indexed_chain(&tester), node_chain(&tester)?);
+
+    Ok(())
+}
+
+#[test]
+fn test_phase_cursors() -> Result<()> {
+    let mut tester = common::TestRunner::new()?;
+
+    // The add and index cursors are kept in the txstore and history dbs respectively, as the
+    // height and hash of the block up to which the phase is done
+    let cursors = |tester: &common::TestRunner| -> Vec<(u32, BlockHash)> {
+        let store = tester.query().chain().store();
+        [store.txstore_db(), store.history_db()]
+            .iter()
+            .map(|db| bincode::deserialize_little(&db.get(b"c").expect("missing cursor")).unwrap())
+            .collect()
+    };
+    let tip = |tester: &common::TestRunner| -> Result<(u32, BlockHash)> {
+        let client = tester.node_client();
+        Ok((
+            client.get_block_count()? as u32,
+            client.get_best_block_hash()?,
+        ))
+    };
+
+    assert_eq!(cursors(&tester), vec![tip(&tester)?; 2]);
+
+    // Both move up to the new tip after an update
+    tester.mine_blocks(3)?;
+    assert_eq!(cursors(&tester), vec![tip(&tester)?; 2]);
+    assert_eq!(cursors(&tester)[0].0, 104);
+
+    // and follow the new chain after a reorg
+    let new_hashes = tester.reorg(2)?;
+    assert_eq!(cursors(&tester), vec![(105, new_hashes[2]); 2]);
+
+    Ok(())
+}
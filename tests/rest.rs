@@ -1,8 +1,16 @@
 use bitcoind::bitcoincore_rpc::RpcApi;
 use serde_json::Value;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+#[cfg(not(feature = "liquid"))]
+use std::io::Read;
+use std::time::Duration;
 
-use electrs::chain::Txid;
+use bitcoin::hashes::hex::FromHex;
+use electrs::chain::{Address, BlockHash, Txid};
+use electrs::config::RouteTimeout;
+use electrs::events::WebhookRegistry;
+use electrs::new_index::{compute_script_hash, meta};
+use electrs::util::bincode;
 
 pub mod common;
 
@@ -12,17 +20,8 @@ use common::Result;
 fn test_rest() -> Result<()> {
     let (rest_handle, rest_addr, mut tester) = common::init_rest_tester().unwrap();
 
-    let get_json = |path: &str| -> Result<Value> {
-        Ok(ureq::get(&format!("http://{}{}", rest_addr, path))
-            .call()?
-            .into_json::<Value>()?)
-    };
-
-    let get_plain = |path: &str| -> Result<String> {
-        Ok(ureq::get(&format!("http://{}{}", rest_addr, path))
-            .call()?
-            .into_string()?)
-    };
+    let get_json = |path: &str| -> Result<Value> { common::rest_get_json(&rest_addr, path) };
+    let get_plain = |path: &str| -> Result<String> { common::rest_get_plain(&rest_addr, path) };
 
     // Send transaction and confirm it
     let addr1 = tester.newaddress()?;
@@ -40,6 +39,88 @@ fn test_rest() -> Result<()> {
         vout["scriptpubkey_address"].as_str() == Some(&addr1.to_string())
             && vout["value"].as_u64() == Some(119123000)
     }));
+    let weight = res["weight"].as_u64().expect("tx weight");
+    assert_eq!(res["vsize"].as_u64(), Some((weight + 3) / 4));
+    let fee = res["fee"].as_u64().expect("tx fee");
+    assert!(fee > 0);
+    let vsize = res["vsize"].as_u64().unwrap();
+    assert_eq!(res["feerate"].as_f64(), Some(fee as f64 / vsize as f64));
+    #[cfg(not(feature = "liquid"))]
+    assert!(res["sigop_cost"].as_u64().expect("tx sigop cost") > 0);
+    // the wallet sets the locktime to the current height for anti-fee-sniping
+    if res["locktime"].as_u64() != Some(0) {
+        assert_eq!(res["locktime_info"]["height"], res["locktime"]);
+    }
+    assert!(res["rbf"].is_boolean());
+
+    // Test GET /wtxid/:wtxid and GET /tx/:txid/raw?stripped=true
+    let wtxid = res["wtxid"].as_str().expect("wtxid");
+    assert_eq!(
+        get_plain(&format!("/wtxid/{}", wtxid))?,
+        txid1_confirmed.to_string()
+    );
+    #[cfg(not(feature = "liquid"))]
+    {
+        let mut raw = vec![];
+        ureq::get(&format!(
+            "http://{}/tx/{}/raw?stripped=true",
+            rest_addr, txid1_confirmed
+        ))
+        .call()?
+        .into_reader()
+        .read_to_end(&mut raw)?;
+        let stripped: bitcoin::Transaction = bitcoin::consensus::deserialize(&raw).unwrap();
+        assert_eq!(stripped.txid(), txid1_confirmed);
+        assert!(stripped.input.iter().all(|txin| txin.witness.is_empty()));
+    }
+
+    // Test GET /tx/:txid/proof-bundle
+    #[cfg(not(feature = "liquid"))]
+    {
+        let mut raw = vec![];
+        ureq::get(&format!(
+            "http://{}/tx/{}/proof-bundle?checkpoint=100",
+            rest_addr, txid1_confirmed
+        ))
+        .call()?
+        .into_reader()
+        .read_to_end(&mut raw)?;
+        let mut cursor = std::io::Cursor::new(&raw);
+        let headers: Vec<bitcoin::block::Header> =
+            bitcoin::consensus::Decodable::consensus_decode(&mut cursor).unwrap();
+        let _pos: u32 = bitcoin::consensus::Decodable::consensus_decode(&mut cursor).unwrap();
+        let _branch: Vec<bitcoin::TxMerkleNode> =
+            bitcoin::consensus::Decodable::consensus_decode(&mut cursor).unwrap();
+        let rawtx: Vec<u8> = bitcoin::consensus::Decodable::consensus_decode(&mut cursor).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers[1].prev_blockhash, headers[0].block_hash());
+        let tx: bitcoin::Transaction = bitcoin::consensus::deserialize(&rawtx).unwrap();
+        assert_eq!(tx.txid(), txid1_confirmed);
+    }
+
+    // Test GET /blocks/raw/:start/:count
+    #[cfg(not(feature = "liquid"))]
+    {
+        let mut raw = vec![];
+        ureq::get(&format!("http://{}/blocks/raw/101/2", rest_addr))
+            .call()?
+            .into_reader()
+            .read_to_end(&mut raw)?;
+        let mut blocks: Vec<bitcoin::Block> = vec![];
+        let mut rest = &raw[..];
+        while !rest.is_empty() {
+            let (len, block) = rest.split_at(4);
+            let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+            blocks.push(bitcoin::consensus::deserialize(&block[..len]).unwrap());
+            rest = &block[len..];
+        }
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[1].header.prev_blockhash, blocks[0].block_hash());
+        assert!(blocks[1]
+            .txdata
+            .iter()
+            .any(|tx| tx.txid() == txid1_confirmed));
+    }
 
     // Test GET /tx/:txid/status
     let res = get_json(&format!("/tx/{}/status", txid1_confirmed))?;
@@ -50,6 +131,28 @@ fn test_rest() -> Result<()> {
     assert_eq!(res["confirmed"].as_bool(), Some(false));
     assert_eq!(res["block_height"].as_u64(), None);
 
+    // Test GET and POST /txs/status
+    let res = get_json(&format!(
+        "/txs/status?txids={},{}",
+        txid1_confirmed, txid2_mempool
+    ))?;
+    let statuses = res.as_array().expect("array of statuses");
+    assert_eq!(statuses.len(), 2);
+    assert_eq!(
+        statuses[0]["txid"].as_str(),
+        Some(txid1_confirmed.to_string().as_str())
+    );
+    assert_eq!(statuses[0]["confirmed"].as_bool(), Some(true));
+    assert_eq!(statuses[0]["height"].as_u64(), Some(102));
+    assert!(statuses[0]["blockhash"].is_string());
+    assert_eq!(statuses[1]["confirmed"].as_bool(), Some(false));
+    assert!(statuses[1]["height"].is_null());
+    let res: Value = ureq::post(&format!("http://{}/txs/status", rest_addr))
+        .send_json(serde_json::json!([txid2_mempool, txid1_confirmed]))?
+        .into_json()?;
+    assert_eq!(res[0]["confirmed"].as_bool(), Some(false));
+    assert_eq!(res[1]["height"].as_u64(), Some(102));
+
     // Test GET /address/:address
     let res = get_json(&format!("/address/{}", addr1))?;
     assert_eq!(res["chain_stats"]["funded_txo_count"].as_u64(), Some(1));
@@ -64,6 +167,49 @@ fn test_rest() -> Result<()> {
         res["mempool_stats"]["funded_txo_sum"].as_u64(),
         Some(71130000)
     );
+    #[cfg(not(feature = "liquid"))]
+    {
+        assert_eq!(res["chain_stats"]["balance"].as_i64(), Some(119123000));
+        assert_eq!(res["mempool_stats"]["balance"].as_i64(), Some(71130000));
+    }
+    // funded by both the confirmed and the mempool transaction
+    assert_eq!(res["funding_tx_count"].as_u64(), Some(2));
+    assert_eq!(res["reused"].as_bool(), Some(true));
+
+    // Test GET /address/:address?cached=true
+    let res = get_json(&format!("/address/{}?cached=true", addr1))?;
+    assert_eq!(res["chain_stats"]["funded_txo_count"].as_u64(), Some(1));
+    assert_eq!(res["mempool_stats"]["funded_txo_count"].as_u64(), Some(1));
+    // only set when the chain stats were found in the cache
+    if let Some(height) = res["chain_stats_height"].as_u64() {
+        assert!(height <= 102);
+    }
+
+    // Test the --history-bloom markers of the funded scripthashes
+    let unused_addr = tester.newaddress()?;
+    let chain = tester.query().chain();
+    assert!(chain.may_have_history(&compute_script_hash(&addr1.script_pubkey())));
+    assert!(!chain.may_have_history(&compute_script_hash(&unused_addr.script_pubkey())));
+    let res = get_json(&format!("/address/{}", unused_addr))?;
+    assert_eq!(res["chain_stats"]["tx_count"].as_u64(), Some(0));
+    let res = get_json(&format!("/address/{}/txs", unused_addr))?;
+    assert_eq!(res.as_array().map(Vec::len), Some(0));
+
+    // Test GET /address/:address/balance-at/:height
+    let res = get_json(&format!("/address/{}/balance-at/101", addr1))?;
+    assert_eq!(res["chain_stats"]["tx_count"].as_u64(), Some(0));
+    let res = get_json(&format!("/address/{}/balance-at/102", addr1))?;
+    assert_eq!(res["chain_stats"]["tx_count"].as_u64(), Some(1));
+    #[cfg(not(feature = "liquid"))]
+    assert_eq!(res["balance"].as_u64(), Some(119123000));
+
+    // Test GET /address/:address/activity
+    let res = get_json(&format!("/address/{}/activity?granularity=month", addr1))?;
+    let periods = res.as_array().expect("array of periods");
+    assert_eq!(periods.len(), 1);
+    assert_eq!(periods[0]["tx_count"].as_u64(), Some(1));
+    #[cfg(not(feature = "liquid"))]
+    assert_eq!(periods[0]["received"].as_u64(), Some(119123000));
 
     // Test GET /address/:address/txs
     let res = get_json(&format!("/address/{}/txs", addr1))?;
@@ -76,6 +222,109 @@ fn test_rest() -> Result<()> {
     assert!(txids.remove(&txid2_mempool));
     assert!(txids.is_empty());
 
+    // Test GET /address/:address/txs.csv
+    #[cfg(not(feature = "liquid"))]
+    {
+        let csv = get_plain(&format!("/address/{}/txs.csv", addr1))?;
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("timestamp,txid,direction,amount,fee,balance")
+        );
+        let row: Vec<&str> = lines.next().expect("confirmed tx row").split(',').collect();
+        assert_eq!(row[1], txid1_confirmed.to_string());
+        assert_eq!(row[2], "incoming");
+        assert_eq!(row[3], "119123000");
+        assert_eq!(row[5], "119123000");
+        // the mempool transaction is not exported
+        assert_eq!(lines.next(), None);
+
+        // the streamed export is compressed chunk by chunk
+        let resp = ureq::get(&format!("http://{}/address/{}/txs.csv", rest_addr, addr1))
+            .set("Accept-Encoding", "gzip")
+            .call()?;
+        assert_eq!(resp.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(resp.header("ETag"), None);
+        let mut compressed = vec![];
+        resp.into_reader().read_to_end(&mut compressed)?;
+        let mut decompressed = String::new();
+        flate2::read::GzDecoder::new(&compressed[..]).read_to_string(&mut decompressed)?;
+        assert_eq!(decompressed, csv);
+    }
+
+    // Test GET /address/:address/utxo
+    let res = get_json(&format!("/address/{}/utxo", addr1))?;
+    let utxos = res.as_array().expect("array of utxos");
+    assert_eq!(utxos.len(), 2);
+    let confirmed_utxo = utxos
+        .iter()
+        .find(|utxo| utxo["status"]["confirmed"].as_bool() == Some(true))
+        .expect("confirmed utxo");
+    assert_eq!(confirmed_utxo["confirmations"].as_u64(), Some(1));
+    assert_eq!(confirmed_utxo["age_blocks"].as_u64(), Some(0));
+    #[cfg(not(feature = "liquid"))]
+    assert_eq!(confirmed_utxo["is_dust"].as_bool(), Some(false));
+    assert_eq!(confirmed_utxo["reused"].as_bool(), Some(true));
+    assert_eq!(confirmed_utxo["is_coinbase"].as_bool(), Some(false));
+    assert!(confirmed_utxo["spendable_at_height"].is_null());
+    assert!(confirmed_utxo["scriptpubkey"].is_null());
+
+    // Test GET /address/:address/utxo?include_script=true
+    let res = get_json(&format!("/address/{}/utxo?include_script=true", addr1))?;
+    let tx = get_json(&format!("/tx/{}", txid1_confirmed))?;
+    let txout = tx["vout"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|txout| txout["scriptpubkey_address"].as_str() == Some(&addr1.to_string()))
+        .expect("funding output");
+    for utxo in res.as_array().unwrap() {
+        assert_eq!(utxo["scriptpubkey"], txout["scriptpubkey"]);
+        assert_eq!(utxo["scriptpubkey_asm"], txout["scriptpubkey_asm"]);
+        assert_eq!(utxo["scriptpubkey_type"], txout["scriptpubkey_type"]);
+        assert_eq!(
+            utxo["scriptpubkey_address"].as_str(),
+            Some(addr1.to_string().as_str())
+        );
+    }
+
+    // Test GET /address/:address/utxo with an immature coinbase output
+    #[cfg(not(feature = "liquid"))]
+    {
+        let tip_hash = get_plain("/blocks/tip/hash")?;
+        let tip_height: u64 = get_plain("/blocks/tip/height")?.parse().unwrap();
+        let coinbase_txid = get_plain(&format!("/block/{}/txid/0", tip_hash))?;
+        let coinbase = get_json(&format!("/tx/{}", coinbase_txid))?;
+        let coinbase_addr = coinbase["vout"][0]["scriptpubkey_address"]
+            .as_str()
+            .unwrap();
+        let res = get_json(&format!("/address/{}/utxo", coinbase_addr))?;
+        let coinbase_utxo = res
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|utxo| utxo["txid"].as_str() == Some(&coinbase_txid))
+            .expect("coinbase utxo");
+        assert_eq!(coinbase_utxo["is_coinbase"].as_bool(), Some(true));
+        assert_eq!(
+            coinbase_utxo["spendable_at_height"].as_u64(),
+            Some(tip_height + 100)
+        );
+    }
+
+    // Test GET /address/:address/utxo/summary
+    #[cfg(not(feature = "liquid"))]
+    {
+        let res = get_json(&format!("/address/{}/utxo/summary", addr1))?;
+        assert_eq!(res["count"].as_u64(), Some(2));
+        assert_eq!(res["value"].as_u64(), Some(119123000 + 71130000));
+        assert_eq!(res["confirmed_count"].as_u64(), Some(1));
+        assert_eq!(res["dust_count"].as_u64(), Some(0));
+        let bands = res["bands"].as_array().expect("array of bands");
+        assert_eq!(bands[5]["count"].as_u64(), Some(1));
+        assert_eq!(bands[6]["count"].as_u64(), Some(1));
+    }
+
     // Test GET /address-prefix/:prefix
     let addr1_prefix = &addr1.to_string()[0..8];
     let res = get_json(&format!("/address-prefix/{}", addr1_prefix))?;
@@ -83,6 +332,18 @@ fn test_rest() -> Result<()> {
     assert_eq!(found.len(), 1);
     assert_eq!(found[0].as_str(), Some(addr1.to_string().as_str()));
 
+    // bech32 addresses are also found when searched for in uppercase, or without their HRP
+    let res = get_json(&format!("/address-prefix/{}", addr1_prefix.to_uppercase()))?;
+    let found = res.as_array().expect("array of matching addresses");
+    assert_eq!(found.len(), 1);
+    assert_eq!(found[0].as_str(), Some(addr1.to_string().as_str()));
+
+    let addr1_str = addr1.to_string();
+    let data_prefix = &addr1_str[addr1_str.rfind('1').unwrap() + 1..][0..8];
+    let res = get_json(&format!("/address-prefix/{}", data_prefix))?;
+    let found = res.as_array().expect("array of matching addresses");
+    assert!(found.iter().any(|a| a.as_str() == Some(addr1_str.as_str())));
+
     // Test GET /blocks/tip/hash
     let bestblockhash = tester.node_client().get_best_block_hash()?;
     let res = get_plain("/blocks/tip/hash")?;
@@ -92,6 +353,99 @@ fn test_rest() -> Result<()> {
     let res = get_plain("/blocks/tip/hash")?;
     assert_eq!(res, bestblockhash.to_string());
 
+    // Test GET /address/:address/txs/chain?order=asc|desc
+    let chain_txids = |order: &str| -> Result<Vec<Txid>> {
+        let res = get_json(&format!("/address/{}/txs/chain?order={}", addr1, order))?;
+        Ok(res
+            .as_array()
+            .expect("array of transactions")
+            .iter()
+            .map(|tx| tx["txid"].as_str().unwrap().parse().unwrap())
+            .collect())
+    };
+    assert_eq!(chain_txids("asc")?, vec![txid1_confirmed, txid2_mempool]);
+    assert_eq!(chain_txids("desc")?, vec![txid2_mempool, txid1_confirmed]);
+    let res = get_json(&format!(
+        "/address/{}/txs/chain/{}?order=asc",
+        addr1, txid1_confirmed
+    ))?;
+    assert_eq!(res.as_array().unwrap().len(), 1);
+    assert_eq!(
+        res[0]["txid"].as_str(),
+        Some(txid2_mempool.to_string().as_str())
+    );
+
+    // Test GET /address/:address/txs/chain?prevouts=false
+    let res = get_json(&format!("/address/{}/txs/chain?prevouts=false", addr1))?;
+    for tx in res.as_array().expect("array of transactions") {
+        assert!(tx["fee"].as_u64().expect("tx fee") > 0);
+        assert!(tx["vin"]
+            .as_array()
+            .expect("array of inputs")
+            .iter()
+            .all(|vin| vin["prevout"].is_null()));
+    }
+
+    // Test that the history of transactions confirmed in the same block follows their position
+    let addr_same_block = tester.newaddress()?;
+    let mut sent = vec![];
+    for _ in 0..5 {
+        sent.push(tester.send(&addr_same_block, "0.01 BTC".parse().unwrap())?);
+    }
+    let blockhash = tester.mine()?;
+    let block_txids: Vec<Txid> = get_json(&format!("/block/{}/txids", blockhash))?
+        .as_array()
+        .expect("array of txids")
+        .iter()
+        .map(|txid| txid.as_str().unwrap().parse().unwrap())
+        .filter(|txid| sent.contains(txid))
+        .collect();
+    assert_eq!(block_txids.len(), sent.len());
+    for order in ["asc", "desc"] {
+        let res = get_json(&format!(
+            "/address/{}/txs/chain?order={}",
+            addr_same_block, order
+        ))?;
+        let mut txids: Vec<Txid> = res
+            .as_array()
+            .expect("array of transactions")
+            .iter()
+            .map(|tx| tx["txid"].as_str().unwrap().parse().unwrap())
+            .collect();
+        if order == "desc" {
+            txids.reverse();
+        }
+        assert_eq!(txids, block_txids);
+
+        // the pages resume right after the position of the last seen tx within the block
+        let mut paged: Vec<Txid> = vec![];
+        loop {
+            let path = match paged.last() {
+                Some(last_seen) => format!(
+                    "/address/{}/txs/chain/{}?order={}&limit=2",
+                    addr_same_block, last_seen, order
+                ),
+                None => format!(
+                    "/address/{}/txs/chain?order={}&limit=2",
+                    addr_same_block, order
+                ),
+            };
+            let page = get_json(&path)?;
+            let page = page.as_array().expect("array of transactions");
+            if page.is_empty() {
+                break;
+            }
+            paged.extend(
+                page.iter()
+                    .map(|tx| tx["txid"].as_str().unwrap().parse::<Txid>().unwrap()),
+            );
+        }
+        if order == "desc" {
+            paged.reverse();
+        }
+        assert_eq!(paged, block_txids);
+    }
+
     // Test GET /blocks/tip/height
     let bestblockheight = tester.node_client().get_block_count()?;
     let res = get_plain("/blocks/tip/height")?;
@@ -100,10 +454,226 @@ fn test_rest() -> Result<()> {
         bestblockheight
     );
 
+    // Test the tip headers
+    let resp = ureq::get(&format!("http://{}/address/{}/txs", rest_addr, addr1)).call()?;
+    assert_eq!(
+        resp.header("X-Tip-Height"),
+        Some(bestblockheight.to_string().as_str())
+    );
+    assert_eq!(
+        resp.header("X-Tip-Hash"),
+        Some(bestblockhash.to_string().as_str())
+    );
+
     // Test GET /block-height/:height
     let res = get_plain(&format!("/block-height/{}", bestblockheight))?;
     assert_eq!(res, bestblockhash.to_string());
 
+    // Test the chainwork and difficulty epoch of GET /block/:hash, and GET /difficulty-adjustment
+    #[cfg(not(feature = "liquid"))]
+    {
+        let res = get_json(&format!("/block/{}", bestblockhash))?;
+        let header_info = tester
+            .node_client()
+            .call::<Value>("getblockheader", &[bestblockhash.to_string().into()])?;
+        assert_eq!(res["chainwork"], header_info["chainwork"]);
+        assert_eq!(res["epoch_position"].as_u64(), Some(bestblockheight % 2016));
+
+        let res = get_json("/difficulty-adjustment")?;
+        assert_eq!(res["position"].as_u64(), Some(bestblockheight % 2016));
+        assert_eq!(
+            res["next_retarget_height"].as_u64(),
+            Some(bestblockheight - bestblockheight % 2016 + 2016)
+        );
+    }
+
+    // Test GET /headers/:start_height/:count
+    #[cfg(not(feature = "liquid"))]
+    {
+        let mut raw = vec![];
+        ureq::get(&format!(
+            "http://{}/headers/{}/5",
+            rest_addr,
+            bestblockheight - 1
+        ))
+        .call()?
+        .into_reader()
+        .read_to_end(&mut raw)?;
+        // only the two last headers exist
+        assert_eq!(raw.len(), 2 * 80);
+        let tip_header: bitcoin::block::Header =
+            bitcoin::consensus::deserialize(&raw[80..]).unwrap();
+        assert_eq!(
+            tip_header.block_hash().to_string(),
+            bestblockhash.to_string()
+        );
+    }
+
+    // Test the query cost headers
+    let resp = ureq::get(&format!("http://{}/address/{}/txs", rest_addr, addr1)).call()?;
+    let rows_scanned = resp
+        .header("X-Query-Cost-Rows-Scanned")
+        .expect("rows scanned header");
+    assert!(rows_scanned.parse::<u64>().unwrap() > 0);
+    assert!(resp.header("X-Query-Cost-Txs-Deserialized").is_some());
+    assert!(resp.header("X-Query-Cost-Daemon-Rpcs").is_some());
+
+    // Webhooks can't be registered without API key authentication
+    {
+        let err = ureq::post(&format!("http://{}/hooks", rest_addr))
+            .send_json(serde_json::json!({
+                "url": "http://192.0.2.1/notify",
+                "scripthashes": ["01".repeat(32)],
+            }))
+            .unwrap_err();
+        assert!(matches!(err, ureq::Error::Status(403, _)));
+    }
+
+    // Test that labels can't be changed without API key authentication
+    {
+        let label_url = format!("http://{}/labels/{}", rest_addr, "02".repeat(32));
+        let err = ureq::put(&label_url)
+            .send_json(serde_json::json!({ "name": "Cold Storage" }))
+            .unwrap_err();
+        assert!(matches!(err, ureq::Error::Status(403, _)));
+        let err = ureq::delete(&label_url).call().unwrap_err();
+        assert!(matches!(err, ureq::Error::Status(403, _)));
+    }
+
+    // Test the metadata merged into the address and transaction responses
+    {
+        let meta_db = tester.query().chain().store().meta_db();
+        let scripthash = "03".repeat(32);
+        let tag = serde_json::json!({ "exchange": "Example Exchange" });
+        meta::set(meta_db, "tags", &scripthash, &tag)?;
+        meta::set(meta_db, "tags", &txid1_confirmed.to_string(), &tag)?;
+        assert!(meta::set(meta_db, "tags/x", &scripthash, &tag).is_err());
+
+        let res = get_json(&format!("/scripthash/{}", scripthash))?;
+        assert_eq!(res["meta"]["tags"], tag);
+        let res = get_json(&format!("/tx/{}", txid1_confirmed))?;
+        assert_eq!(res["meta"]["tags"], tag);
+        assert_eq!(
+            res["txid"].as_str(),
+            Some(txid1_confirmed.to_string().as_str())
+        );
+
+        assert!(meta::delete(meta_db, "tags", &scripthash));
+        let res = get_json(&format!("/scripthash/{}", scripthash))?;
+        assert!(res.get("meta").is_none());
+    }
+
+    // Test POST /psbt/decode
+    #[cfg(not(feature = "liquid"))]
+    {
+        let addr = tester.newaddress()?;
+        let funded = tester.node_client().call::<Value>(
+            "walletcreatefundedpsbt",
+            &[
+                serde_json::json!([]),
+                serde_json::json!([{ addr.to_string(): 0.5 }]),
+            ],
+        )?;
+        let psbt = funded["psbt"].as_str().unwrap();
+        let expected_fee = (funded["fee"].as_f64().unwrap() * 100_000_000.0).round() as u64;
+
+        let res: Value = ureq::post(&format!("http://{}/psbt/decode", rest_addr))
+            .send_string(psbt)?
+            .into_json()?;
+        assert_eq!(res["fee"].as_u64(), Some(expected_fee));
+        assert_eq!(res["finalized"].as_bool(), Some(false));
+        assert!(res["feerate"].is_null());
+        let vin = res["vin"].as_array().expect("array of inputs");
+        assert!(!vin.is_empty());
+        for input in vin {
+            assert!(input["prevout"]["value"].as_u64().is_some());
+            assert_eq!(input["signatures"].as_u64(), Some(0));
+            assert_eq!(input["signatures_required"].as_u64(), Some(1));
+            assert_eq!(input["missing_signatures"].as_u64(), Some(1));
+        }
+
+        let signed = tester
+            .node_client()
+            .call::<Value>("walletprocesspsbt", &[psbt.into()])?;
+        let res: Value = ureq::post(&format!("http://{}/psbt/decode", rest_addr))
+            .send_string(signed["psbt"].as_str().unwrap())?
+            .into_json()?;
+        assert_eq!(res["finalized"].as_bool(), Some(true));
+        assert_eq!(res["vin"][0]["missing_signatures"].as_u64(), Some(0));
+        assert!(res["feerate"].as_f64().unwrap() > 0.0);
+
+        let err = ureq::post(&format!("http://{}/psbt/decode", rest_addr))
+            .send_string("not a psbt")
+            .unwrap_err();
+        assert!(matches!(err, ureq::Error::Status(400, _)));
+    }
+
+    // Test POST /tx/preview
+    #[cfg(not(feature = "liquid"))]
+    {
+        let addr = tester.newaddress()?;
+        let funded = tester.node_client().call::<Value>(
+            "walletcreatefundedpsbt",
+            &[
+                serde_json::json!([]),
+                serde_json::json!([{ addr.to_string(): 0.3 }]),
+            ],
+        )?;
+        let expected_fee = (funded["fee"].as_f64().unwrap() * 100_000_000.0).round() as u64;
+        let signed = tester
+            .node_client()
+            .call::<Value>("walletprocesspsbt", &[funded["psbt"].clone()])?;
+        let finalized = tester
+            .node_client()
+            .call::<Value>("finalizepsbt", &[signed["psbt"].clone()])?;
+        let txhex = finalized["hex"].as_str().unwrap();
+
+        let preview_url = format!("http://{}/tx/preview", rest_addr);
+        let res: Value = ureq::post(&preview_url).send_string(txhex)?.into_json()?;
+        assert_eq!(res["fee"].as_u64(), Some(expected_fee));
+        assert_eq!(res["standard"].as_bool(), Some(true));
+        assert_eq!(res["known"].as_bool(), Some(false));
+        assert!(res["errors"].as_array().unwrap().is_empty());
+        assert!(res["conflicts"].as_array().unwrap().is_empty());
+        assert!(res["sigops"].as_u64().unwrap() > 0);
+
+        // nothing was broadcast
+        let txid = res["txid"].as_str().unwrap();
+        let err = ureq::get(&format!("http://{}/tx/{}", rest_addr, txid))
+            .call()
+            .unwrap_err();
+        match err {
+            ureq::Error::Status(404, resp) => {
+                let body: Value = resp.into_json()?;
+                assert_eq!(body["code"].as_str(), Some("tx-not-found"));
+            }
+            err => panic!("unexpected error {:?}", err),
+        }
+
+        tester
+            .node_client()
+            .call::<Value>("sendrawtransaction", &[txhex.into()])?;
+        tester.sync()?;
+        let res: Value = ureq::post(&preview_url).send_string(txhex)?.into_json()?;
+        assert_eq!(res["known"].as_bool(), Some(true));
+    }
+
+    // Test GET /stats/chain
+    let res = get_json("/stats/chain")?;
+    let days = res.as_array().expect("array of days");
+    let block_count: u64 = days
+        .iter()
+        .map(|day| day["block_count"].as_u64().unwrap())
+        .sum();
+    let tx_count: u64 = days
+        .iter()
+        .map(|day| day["tx_count"].as_u64().unwrap())
+        .sum();
+    let tip_height = get_plain("/blocks/tip/height")?.parse::<u64>().unwrap();
+    assert_eq!(block_count, tip_height + 1);
+    assert!(tx_count > block_count);
+    assert!(days.last().unwrap()["utxo_count"].as_u64().unwrap() > 0);
+
     // Test GET /blocks
     let res = get_json("/blocks")?;
     let last_blocks = res.as_array().unwrap();
@@ -132,17 +702,94 @@ fn test_rest() -> Result<()> {
         Some(tester.node_client().get_block_count()?)
     );
     assert_eq!(res["tx_count"].as_u64(), Some(2));
+    #[cfg(not(feature = "liquid"))]
+    assert!(res["sigop_cost"].as_u64().expect("block sigop cost") > 0);
 
     // Test GET /block/:hash/txs
     let res = get_json(&format!("/block/{}/txs", blockhash))?;
     let block_txs = res.as_array().expect("list of txs");
     assert_eq!(block_txs.len(), 2);
     assert_eq!(block_txs[0]["vin"][0]["is_coinbase"].as_bool(), Some(true));
+
+    // Test GET /block/:hash/coinbase
+    #[cfg(not(feature = "liquid"))]
+    {
+        let res = get_json(&format!("/block/{}/coinbase", blockhash))?;
+        assert_eq!(res["txid"], block_txs[0]["txid"]);
+        assert_eq!(
+            res["height"].as_u64(),
+            Some(tester.node_client().get_block_count()?)
+        );
+        assert!(res["total_value"].as_u64().unwrap() > 0);
+        assert!(!res["vout"].as_array().unwrap().is_empty());
+    }
+
+    // Test the coin days destroyed of GET /block/:hash and its transactions
+    #[cfg(not(feature = "liquid"))]
+    {
+        let res = get_json(&format!("/block/{}", blockhash))?;
+        let block_cdd = res["coin_days_destroyed"].as_f64().expect("block cdd");
+        // the coinbase doesn't spend any coins
+        assert!(block_txs[0]["coin_days_destroyed"].is_null());
+        let tx_cdd = block_txs[1]["coin_days_destroyed"]
+            .as_f64()
+            .expect("tx cdd");
+        assert_eq!(block_cdd, tx_cdd);
+        let days = get_json("/stats/chain")?;
+        let last_day = days.as_array().unwrap().last().unwrap();
+        assert!(last_day["coin_days_destroyed"].as_f64().unwrap() >= block_cdd);
+    }
+
+    // Test the first-seen times of the block and of its transaction, seen in the mempool first
+    {
+        let res = get_json(&format!("/block/{}", blockhash))?;
+        let block_seen_at = res["seen_at"].as_u64().expect("block seen_at");
+        assert!(block_txs[1]["seen_at"].as_u64().expect("tx seen_at") <= block_seen_at);
+    }
+
+    // Test GET /block/:hash/utxo-muhash against bitcoind's UTXO set hash
+    #[cfg(not(feature = "liquid"))]
+    {
+        let txoutsetinfo: Value = tester
+            .node_client()
+            .call("gettxoutsetinfo", &["muhash".into()])?;
+        assert_eq!(
+            txoutsetinfo["bestblock"].as_str(),
+            Some(blockhash.to_string().as_str())
+        );
+        assert_eq!(
+            get_plain(&format!("/block/{}/utxo-muhash", blockhash))?,
+            txoutsetinfo["muhash"].as_str().expect("muhash")
+        );
+    }
+
+    // Test the script type counts of GET /block/:hash
+    let script_types = &get_json(&format!("/block/{}", blockhash))?["script_types"];
+    let counted: u64 = script_types
+        .as_object()
+        .expect("script type counts")
+        .values()
+        .map(|count| count.as_u64().unwrap())
+        .sum();
+    let outputs: usize = block_txs
+        .iter()
+        .map(|tx| tx["vout"].as_array().unwrap().len())
+        .sum();
+    assert_eq!(counted, outputs as u64);
     assert_eq!(
         block_txs[1]["txid"].as_str(),
         Some(txid.to_string().as_str())
     );
 
+    // Test the fee totals of GET /block/:hash
+    #[cfg(not(feature = "liquid"))]
+    {
+        let tx_fee = block_txs[1]["fee"].as_u64().expect("tx fee");
+        let res = get_json(&format!("/block/{}", blockhash))?;
+        assert_eq!(res["fees"].as_u64(), Some(tx_fee));
+        assert_eq!(res["subsidy"].as_u64(), Some(50 * 100_000_000));
+    }
+
     // Test GET /block/:hash/txid/:index
     let res = get_plain(&format!("/block/{}/txid/1", blockhash))?;
     assert_eq!(res, txid.to_string());
@@ -168,6 +815,79 @@ fn test_rest() -> Result<()> {
     tester.mine()?;
     assert_eq!(get_json("/mempool")?["count"].as_u64(), Some(0));
 
+    // Test GET /outpoint/:txid:vout/conflicts with a transaction replaced by RBF
+    #[cfg(not(feature = "liquid"))]
+    {
+        let replaced_txid = tester.send(&addr1, "0.5 BTC".parse().unwrap())?;
+        let res = get_json(&format!("/tx/{}", replaced_txid))?;
+        let outpoint = format!(
+            "{}:{}",
+            res["vin"][0]["txid"].as_str().unwrap(),
+            res["vin"][0]["vout"]
+        );
+        let res = get_json(&format!("/outpoint/{}/conflicts", outpoint))?;
+        assert_eq!(
+            res["spend"]["txid"].as_str(),
+            Some(replaced_txid.to_string().as_str())
+        );
+        assert_eq!(res["conflicts"].as_array().map(Vec::len), Some(0));
+
+        let bumped = tester
+            .node_client()
+            .call::<Value>("bumpfee", &[replaced_txid.to_string().into()])?;
+        let replacement_txid = bumped["txid"].as_str().unwrap().to_string();
+        tester.sync()?;
+        let res = get_json(&format!("/outpoint/{}/conflicts", outpoint))?;
+        assert_eq!(
+            res["spend"]["txid"].as_str(),
+            Some(replacement_txid.as_str())
+        );
+        assert_eq!(res["spend"]["status"]["confirmed"].as_bool(), Some(false));
+        let conflicts = res["conflicts"].as_array().expect("array of conflicts");
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(
+            conflicts[0]["txid"].as_str(),
+            Some(replaced_txid.to_string().as_str())
+        );
+        assert_eq!(conflicts[0]["vin"].as_u64(), Some(0));
+
+        // still reported once the replacement confirms
+        tester.mine()?;
+        let res = get_json(&format!("/outpoint/{}/conflicts", outpoint))?;
+        assert_eq!(res["spend"]["status"]["confirmed"].as_bool(), Some(true));
+        assert_eq!(res["conflicts"].as_array().map(Vec::len), Some(1));
+    }
+
+    // Test GET /pubkey/:xonly/txs
+    #[cfg(not(feature = "liquid"))]
+    {
+        let tr_addr = tester
+            .node_client()
+            .get_new_address(
+                None,
+                Some(bitcoind::bitcoincore_rpc::json::AddressType::Bech32m),
+            )?
+            .assume_checked();
+        let output_key =
+            bitcoin::key::XOnlyPublicKey::from_slice(&tr_addr.script_pubkey().as_bytes()[2..34])
+                .unwrap();
+        let tr_txid = tester.send(&tr_addr, "0.5 BTC".parse().unwrap())?;
+        tester.mine()?;
+
+        let res = get_json(&format!("/pubkey/{}/txs", output_key))?;
+        let txs = res.as_array().expect("array of transactions");
+        assert_eq!(txs.len(), 1);
+        assert_eq!(txs[0]["txid"].as_str(), Some(tr_txid.to_string().as_str()));
+
+        let resp = ureq::get(&format!(
+            "http://{}/pubkey/{}/txs",
+            rest_addr,
+            "00".repeat(31)
+        ))
+        .call();
+        assert!(matches!(resp, Err(ureq::Error::Status(400, _))));
+    }
+
     // Elements-only tests
     #[cfg(feature = "liquid")]
     {
@@ -292,6 +1012,63 @@ fn test_rest() -> Result<()> {
             assert!(vout["value"].is_null());
             assert!(vout["assetcommitment"].is_string());
             assert!(vout["valuecommitment"].is_string());
+
+            // Add an explicit policy asset output to the same script
+            let txid = tester.send(&uc_addr, "1 BTC".parse().unwrap())?;
+            let tx = get_json(&format!("/tx/{}", txid))?;
+            let policy_asset = tx["vout"]
+                .as_array()
+                .expect("array of outs")
+                .iter()
+                .find(|vout| vout["scriptpubkey_address"].as_str() == Some(&uc_addr.to_string()))
+                .and_then(|vout| vout["asset"].as_str())
+                .expect("explicit policy asset")
+                .to_string();
+
+            // Test GET /address/:address/utxo?asset=
+            let utxos = get_json(&format!("/address/{}/utxo", uc_addr))?;
+            assert_eq!(utxos.as_array().map(Vec::len), Some(3));
+
+            // the blinded output of the same asset is not matched
+            let utxos = get_json(&format!("/address/{}/utxo?asset={}", uc_addr, assetid))?;
+            let utxos = utxos.as_array().expect("array of utxos");
+            assert_eq!(utxos.len(), 1);
+            assert_eq!(utxos[0]["asset"].as_str(), Some(assetid));
+            assert_eq!(utxos[0]["value"].as_u64(), Some(30000000));
+
+            let utxos = get_json(&format!("/address/{}/utxo?asset={}", uc_addr, policy_asset))?;
+            let utxos = utxos.as_array().expect("array of utxos");
+            assert_eq!(utxos.len(), 1);
+            assert_eq!(utxos[0]["txid"].as_str(), Some(txid.to_string().as_str()));
+            assert_eq!(utxos[0]["value"].as_u64(), Some(100000000));
+
+            let utxos = get_json(&format!(
+                "/address/{}/utxo?asset={}",
+                uc_addr,
+                "11".repeat(32)
+            ))?;
+            assert_eq!(utxos.as_array().map(Vec::len), Some(0));
+
+            // Test GET /address/:address?asset=
+            let stats = get_json(&format!("/address/{}?asset={}", uc_addr, assetid))?;
+            assert_eq!(stats["asset_stats"]["asset"].as_str(), Some(assetid));
+            assert_eq!(stats["asset_stats"]["utxo_count"].as_u64(), Some(1));
+            assert_eq!(stats["asset_stats"]["utxo_sum"].as_u64(), Some(30000000));
+
+            let stats = get_json(&format!("/address/{}?asset={}", uc_addr, policy_asset))?;
+            assert_eq!(stats["asset_stats"]["utxo_count"].as_u64(), Some(1));
+            assert_eq!(stats["asset_stats"]["utxo_sum"].as_u64(), Some(100000000));
+
+            let stats = get_json(&format!("/address/{}", uc_addr))?;
+            assert!(stats["asset_stats"].is_null());
+
+            for path in [
+                format!("/address/{}", uc_addr),
+                format!("/address/{}/utxo", uc_addr),
+            ] {
+                let resp = ureq::get(&format!("http://{}{}?asset=nope", rest_addr, path)).call();
+                assert!(matches!(resp, Err(ureq::Error::Status(400, _))));
+            }
         }
 
         // Test GET /block/:hash
@@ -318,3 +1095,591 @@ fn test_rest() -> Result<()> {
     rest_handle.stop();
     Ok(())
 }
+
+#[test]
+fn test_rest_auth() -> Result<()> {
+    let keys_file = tempfile::NamedTempFile::new()?;
+    std::fs::write(keys_file.path(), "secret\nlimited:2\n")?;
+    let keys_path = keys_file.path().to_path_buf();
+    let (rest_handle, rest_addr, _tester) =
+        common::init_rest_tester_with(|config| config.auth_keys_file = Some(keys_path))?;
+
+    let url = format!("http://{}/blocks/tip/height", rest_addr);
+
+    // Missing and unknown keys are rejected
+    let err = ureq::get(&url).call().unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(401, _)));
+    let err = ureq::get(&url)
+        .set("X-API-Key", "wrong")
+        .call()
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(401, _)));
+    let err = ureq::get(&url)
+        .set("Authorization", "Bearer wrong")
+        .call()
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(401, _)));
+
+    // Valid keys are accepted as a bearer token or in the X-API-Key header
+    let height = ureq::get(&url)
+        .set("Authorization", "Bearer secret")
+        .call()?
+        .into_string()?;
+    assert_eq!(height, "101");
+    ureq::get(&url).set("X-API-Key", "secret").call()?;
+
+    // Health checks don't require a key
+    ureq::get(&format!("http://{}/healthz", rest_addr)).call()?;
+
+    // Keys with a rate limit are rejected once it's exhausted, without affecting the others
+    for _ in 0..2 {
+        ureq::get(&url).set("X-API-Key", "limited").call()?;
+    }
+    let err = ureq::get(&url)
+        .set("X-API-Key", "limited")
+        .call()
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(429, _)));
+    ureq::get(&url).set("X-API-Key", "secret").call()?;
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_rest_route_timeouts() -> Result<()> {
+    let (rest_handle, rest_addr, _tester) = common::init_rest_tester_with(|config| {
+        config.http_route_timeouts = vec![RouteTimeout {
+            path: "/blocks/tip".to_string(),
+            timeout: Duration::from_nanos(1),
+        }]
+    })?;
+
+    // the handler can't complete within the overridden timeout
+    let err = ureq::get(&format!("http://{}/blocks/tip/height", rest_addr))
+        .call()
+        .unwrap_err();
+    match err {
+        ureq::Error::Status(504, resp) => {
+            let body: Value = resp.into_json()?;
+            assert_eq!(body["code"].as_str(), Some("timeout"));
+        }
+        err => panic!("unexpected error {:?}", err),
+    }
+
+    // the other routes keep the default timeout
+    let hash = common::rest_get_plain(&rest_addr, "/block-height/101")?;
+    assert_eq!(hash.len(), 64);
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_rest_auth_reload() -> Result<()> {
+    let keys_file = tempfile::NamedTempFile::new()?;
+    std::fs::write(keys_file.path(), "first:2\n")?;
+    let keys_path = keys_file.path().to_path_buf();
+    let (rest_handle, rest_addr, tester) =
+        common::init_rest_tester_with(|config| config.auth_keys_file = Some(keys_path))?;
+
+    let url = format!("http://{}/blocks/tip/height", rest_addr);
+    ureq::get(&url).set("X-API-Key", "first").call()?;
+
+    // A malformed file keeps the current keys
+    std::fs::write(keys_file.path(), "first:many\n")?;
+    assert!(tester.query().reload_api_keys().is_err());
+    ureq::get(&url).set("X-API-Key", "first").call()?;
+
+    // Removed keys are rejected, and the usage of the remaining ones is kept
+    std::fs::write(keys_file.path(), "first:2\nsecond\n")?;
+    tester.query().reload_api_keys()?;
+    ureq::get(&url).set("X-API-Key", "second").call()?;
+    let err = ureq::get(&url)
+        .set("X-API-Key", "first")
+        .call()
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(429, _)));
+
+    std::fs::write(keys_file.path(), "second\n")?;
+    tester.query().reload_api_keys()?;
+    let err = ureq::get(&url)
+        .set("X-API-Key", "first")
+        .call()
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(401, _)));
+    ureq::get(&url).set("X-API-Key", "second").call()?;
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_rest_labels() -> Result<()> {
+    let keys_file = tempfile::NamedTempFile::new()?;
+    std::fs::write(keys_file.path(), "secret\n")?;
+    let keys_path = keys_file.path().to_path_buf();
+    let (rest_handle, rest_addr, _tester) =
+        common::init_rest_tester_with(|config| config.auth_keys_file = Some(keys_path))?;
+
+    let url = |path: &str| format!("http://{}{}", rest_addr, path);
+    let get_json = |path: &str| -> Result<Value> {
+        Ok(ureq::get(&url(path))
+            .set("X-API-Key", "secret")
+            .call()?
+            .into_json::<Value>()?)
+    };
+    let scripthash = "02".repeat(32);
+    let label_path = format!("/labels/{}", scripthash);
+    let put = || ureq::put(&url(&label_path)).set("X-API-Key", "secret");
+
+    // Unauthenticated writes are refused
+    let err = ureq::put(&url(&label_path))
+        .send_json(serde_json::json!({ "name": "Cold Storage" }))
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(401, _)));
+
+    put().send_json(serde_json::json!({
+        "name": "Cold Storage",
+        "color": "#ff8800",
+    }))?;
+
+    let res = get_json(&label_path)?;
+    assert_eq!(res["name"].as_str(), Some("Cold Storage"));
+    assert_eq!(res["color"].as_str(), Some("#ff8800"));
+
+    // merged into the address response
+    let res = get_json(&format!("/scripthash/{}", scripthash))?;
+    assert_eq!(res["label"]["name"].as_str(), Some("Cold Storage"));
+    assert_eq!(res["funding_tx_count"].as_u64(), Some(0));
+    assert_eq!(res["reused"].as_bool(), Some(false));
+
+    let res = get_json("/labels?q=cold")?;
+    assert_eq!(res.as_array().unwrap().len(), 1);
+    assert_eq!(res[0]["scripthash"].as_str(), Some(scripthash.as_str()));
+    let res = get_json("/labels?q=hot")?;
+    assert!(res.as_array().unwrap().is_empty());
+
+    let err = put()
+        .send_json(serde_json::json!({ "name": "Cold Storage", "color": "orange" }))
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(400, _)));
+    let err = put()
+        .send_json(serde_json::json!({ "name": "x".repeat(2000) }))
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(400, _)));
+
+    let err = ureq::delete(&url(&label_path)).call().unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(401, _)));
+    ureq::delete(&url(&label_path))
+        .set("X-API-Key", "secret")
+        .call()?;
+    let res = get_json(&format!("/scripthash/{}", scripthash))?;
+    assert!(res.get("label").is_none());
+    let err = ureq::get(&url(&label_path))
+        .set("X-API-Key", "secret")
+        .call()
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(404, _)));
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_rest_webhooks() -> Result<()> {
+    let keys_file = tempfile::NamedTempFile::new()?;
+    std::fs::write(keys_file.path(), "secret\n")?;
+    let keys_path = keys_file.path().to_path_buf();
+    let (rest_handle, rest_addr, mut tester) =
+        common::init_rest_tester_with(|config| config.auth_keys_file = Some(keys_path))?;
+
+    let url = |path: &str| format!("http://{}{}", rest_addr, path);
+    let get = |path: &str| ureq::get(&url(path)).set("X-API-Key", "secret");
+    let post = |path: &str| ureq::post(&url(path)).set("X-API-Key", "secret");
+    let delete = |path: &str| ureq::delete(&url(path)).set("X-API-Key", "secret");
+
+    let addr = tester.newaddress()?;
+    let txid = tester.send(&addr, "0.5 BTC".parse().unwrap())?;
+    tester.mine()?;
+
+    // Scripthash webhooks. The callbacks are never reached, their deliveries just fail.
+    let scripthash = "01".repeat(32);
+    let res: Value = post("/hooks")
+        .send_json(serde_json::json!({
+            "url": "http://192.0.2.1/notify",
+            "scripthashes": [scripthash],
+            "secret": "s3cret",
+        }))?
+        .into_json()?;
+    let hook_id = res["id"].as_str().expect("hook id").to_string();
+
+    let res: Value = get(&format!("/hooks/{}", hook_id)).call()?.into_json()?;
+    assert_eq!(res["url"].as_str(), Some("http://192.0.2.1/notify"));
+    assert_eq!(res["scripthashes"][0].as_str(), Some(scripthash.as_str()));
+    assert!(res["secret"].is_null());
+
+    delete(&format!("/hooks/{}", hook_id)).call()?;
+    let err = get(&format!("/hooks/{}", hook_id)).call().unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(404, _)));
+
+    // Only http:// callbacks to public addresses are accepted
+    for callback in &[
+        "ftp://192.0.2.1/notify",
+        "http://127.0.0.1:3000/notify",
+        "http://localhost/notify",
+        "http://10.0.0.1/notify",
+        "http://169.254.169.254/latest/meta-data",
+        "http://[::1]/notify",
+    ] {
+        let err = post("/hooks")
+            .send_json(serde_json::json!({
+                "url": callback,
+                "scripthashes": [scripthash],
+            }))
+            .unwrap_err();
+        assert!(matches!(err, ureq::Error::Status(400, _)), "{}", callback);
+    }
+
+    // Confirmation watches, with the default targets
+    let res: Value = post("/hooks/confirmations")
+        .send_json(serde_json::json!({
+            "url": "http://192.0.2.1/confirmed",
+            "txid": txid.to_string(),
+        }))?
+        .into_json()?;
+    let watch_id = res["id"].as_str().expect("watch id").to_string();
+
+    let res: Value = get(&format!("/hooks/confirmations/{}", watch_id))
+        .call()?
+        .into_json()?;
+    assert_eq!(res["txid"].as_str(), Some(txid.to_string().as_str()));
+    assert_eq!(res["confirmations"], serde_json::json!([1, 3, 6]));
+    assert_eq!(res["notified"].as_u64(), Some(0));
+
+    delete(&format!("/hooks/confirmations/{}", watch_id)).call()?;
+    let err = get(&format!("/hooks/confirmations/{}", watch_id))
+        .call()
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(404, _)));
+
+    let err = post("/hooks/confirmations")
+        .send_json(serde_json::json!({
+            "url": "http://192.0.2.1/confirmed",
+            "txid": txid.to_string(),
+            "confirmations": [0],
+        }))
+        .unwrap_err();
+    assert!(matches!(err, ureq::Error::Status(400, _)));
+
+    // Registrations survive a restart, they're reloaded from the db
+    let res: Value = post("/hooks")
+        .send_json(serde_json::json!({
+            "url": "http://192.0.2.1/notify",
+            "scripthashes": [scripthash],
+        }))?
+        .into_json()?;
+    let hook_id = res["id"].as_str().expect("hook id").to_string();
+    let registry = WebhookRegistry::load(tester.query().chain().store().cache_db());
+    let hook_id = <[u8; 32]>::from_hex(&hook_id).unwrap();
+    assert!(registry.hook(&hook_id).is_some());
+
+    rest_handle.stop();
+    Ok(())
+}
+
+/// Test the outputs indexed with --script-dictionary, where reused scripts are replaced by an id
+#[cfg_attr(not(feature = "liquid"), test)]
+#[cfg_attr(feature = "liquid", allow(dead_code))]
+fn test_script_dictionary() -> Result<()> {
+    let (rest_handle, rest_addr, mut tester) =
+        common::init_rest_tester_with(|config| config.script_dictionary = true)?;
+    let get_json = |path: &str| -> Result<Value> { common::rest_get_json(&rest_addr, path) };
+    let in_dictionary = |tester: &common::TestRunner, addr: &Address| {
+        let scripthash = compute_script_hash(&addr.script_pubkey());
+        let txstore_db = tester.query().chain().store().txstore_db();
+        txstore_db.get(&[b"S", &scripthash[..]].concat()).is_some()
+    };
+
+    // A script is added to the dictionary once paid to again, the other ones are kept inline
+    let reused_addr = tester.newaddress()?;
+    let single_addr = tester.newaddress()?;
+    let txid1 = tester.send(&reused_addr, "1.5 BTC".parse().unwrap())?;
+    tester.send(&single_addr, "0.4 BTC".parse().unwrap())?;
+    tester.mine()?;
+    assert!(!in_dictionary(&tester, &reused_addr));
+    let txid2 = tester.send(&reused_addr, "2.5 BTC".parse().unwrap())?;
+    tester.mine()?;
+    assert!(in_dictionary(&tester, &reused_addr));
+    assert!(!in_dictionary(&tester, &single_addr));
+
+    // Outputs are resolved the same way whether they're encoded or not
+    let find_vout = |txid: &Txid| -> Result<(u64, Value)> {
+        let tx = get_json(&format!("/tx/{}", txid))?;
+        let vouts = tx["vout"].as_array().expect("array of outs");
+        let vout = vouts
+            .iter()
+            .position(|vout| vout["scriptpubkey_address"] == reused_addr.to_string())
+            .expect("output paying to the address");
+        Ok((vout as u64, vouts[vout].clone()))
+    };
+    let (vout1, out1) = find_vout(&txid1)?;
+    let (vout2, out2) = find_vout(&txid2)?;
+    assert_eq!(out1["value"].as_u64(), Some(150000000));
+    assert_eq!(out2["value"].as_u64(), Some(250000000));
+    assert_eq!(out1["scriptpubkey"], out2["scriptpubkey"]);
+
+    let utxos = get_json(&format!("/address/{}/utxo", reused_addr))?;
+    assert_eq!(utxos.as_array().map(Vec::len), Some(2));
+
+    // Spend both outputs, which looks up their previous output in the txstore
+    let client = tester.node_client();
+    let dest = tester.newaddress()?;
+    let inputs = serde_json::json!([
+        { "txid": txid1, "vout": vout1 },
+        { "txid": txid2, "vout": vout2 },
+    ]);
+    let outputs = serde_json::json!({ dest.to_string(): 3.99 });
+    let unsigned: String = client.call("createrawtransaction", &[inputs, outputs])?;
+    let signed: Value = client.call("signrawtransactionwithwallet", &[unsigned.into()])?;
+    let spend_txid: Txid = client.call("sendrawtransaction", &[signed["hex"].clone()])?;
+    tester.mine()?;
+
+    let spend = get_json(&format!("/tx/{}", spend_txid))?;
+    for vin in spend["vin"].as_array().expect("array of ins") {
+        assert_eq!(
+            vin["prevout"]["scriptpubkey_address"],
+            reused_addr.to_string()
+        );
+    }
+    assert_eq!(spend["fee"].as_u64(), Some(1000000));
+
+    let stats = get_json(&format!("/address/{}", reused_addr))?;
+    assert_eq!(
+        stats["chain_stats"]["funded_txo_sum"].as_u64(),
+        Some(400000000)
+    );
+    assert_eq!(
+        stats["chain_stats"]["spent_txo_sum"].as_u64(),
+        Some(400000000)
+    );
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "liquid"), test)]
+#[cfg_attr(feature = "liquid", allow(dead_code))]
+fn test_rest_mempool_full() -> Result<()> {
+    let (rest_handle, rest_addr, mut tester) = common::init_rest_tester().unwrap();
+    let get_json = |path: &str| -> Result<Value> { common::rest_get_json(&rest_addr, path) };
+
+    // Mature enough coinbase outputs for the payments not to chain up in the mempool
+    tester.mine_blocks(60)?;
+
+    // The net balance delta covers more unconfirmed transactions than are listed
+    let addr = tester.newaddress()?;
+    for _ in 0..51 {
+        tester.send(&addr, "0.01 BTC".parse().unwrap())?;
+    }
+    let res = get_json(&format!("/address/{}/txs/mempool/full", addr))?;
+    let txs = res["txs"].as_array().expect("array of transactions");
+    assert_eq!(txs.len(), 50);
+    assert!(txs
+        .iter()
+        .all(|tx| tx["balance_delta"].as_i64() == Some(1000000)));
+    assert_eq!(res["balance_delta"].as_i64(), Some(51000000));
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_rest_mempool_template() -> Result<()> {
+    let (rest_handle, rest_addr, mut tester) = common::init_rest_tester().unwrap();
+    let get_json = |path: &str| -> Result<Value> { common::rest_get_json(&rest_addr, path) };
+    let template_id =
+        || -> Result<u64> { Ok(get_json("/mempool/template")?["id"].as_u64().unwrap()) };
+
+    // An unconfirmed transaction gets projected into the next block
+    let id = template_id()?;
+    let addr = tester.newaddress()?;
+    let txid = tester.send(&addr, "0.5 BTC".parse().unwrap())?;
+    let position = get_json(&format!("/tx/{}/mempool-position", txid))?;
+    assert_eq!(position["position"]["block"].as_u64(), Some(0));
+    assert_eq!(position["position"]["vsize_ahead"].as_u64(), Some(0));
+    let template = get_json("/mempool/template")?;
+    assert_eq!(template["id"].as_u64(), Some(id + 1));
+    assert_eq!(template["txids"], serde_json::json!([txid]));
+    let diff = get_json("/mempool/template/diff")?;
+    assert_eq!(diff["added"], serde_json::json!([txid]));
+    assert_eq!(diff["removed"], serde_json::json!([]));
+
+    // And is removed from the template once confirmed
+    tester.mine()?;
+    assert_eq!(template_id()?, id + 2);
+    let diff = get_json("/mempool/template/diff")?;
+    assert_eq!(diff["added"], serde_json::json!([]));
+    assert_eq!(diff["removed"], serde_json::json!([txid]));
+    assert!(get_json(&format!("/tx/{}/mempool-position", txid)).is_err());
+
+    // Diffing against an older template spans all the changes since
+    let diff = get_json(&format!("/mempool/template/diff/{}", id))?;
+    assert_eq!(diff["base_id"].as_u64(), Some(id));
+    assert_eq!(diff["added"], serde_json::json!([]));
+    assert_eq!(diff["removed"], serde_json::json!([]));
+
+    // The full template is returned when the base isn't known anymore
+    let mut txids = vec![];
+    for _ in 0..11 {
+        txids.push(tester.send(&addr, "0.01 BTC".parse().unwrap())?.to_string());
+    }
+    let template_id = template_id()?;
+    assert!(template_id >= id + 2 + 11);
+    let diff = get_json(&format!("/mempool/template/diff/{}", id + 2))?;
+    assert!(diff.get("added").is_none());
+    let mut full: Vec<String> = serde_json::from_value(diff["txids"].clone())?;
+    full.sort();
+    txids.sort();
+    assert_eq!(full, txids);
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_rest_history_paging() -> Result<()> {
+    let (rest_handle, rest_addr, mut tester) = common::init_rest_tester().unwrap();
+    let get_json = |path: &str| -> Result<Value> { common::rest_get_json(&rest_addr, path) };
+
+    // More confirmed transactions than fit in a page, sent in batches to keep the mempool
+    // chains of the wallet short
+    let addr = tester.newaddress()?;
+    let mut sent = HashSet::new();
+    for _ in 0..2 {
+        for _ in 0..15 {
+            sent.insert(tester.send(&addr, "0.01 BTC".parse().unwrap())?);
+        }
+        tester.mine()?;
+    }
+
+    // Every page is served from a snapshot, and together they cover the history exactly once
+    let mut paged: Vec<Txid> = vec![];
+    loop {
+        let path = match paged.last() {
+            None => format!("/address/{}/txs/chain", addr),
+            Some(last_seen) => format!("/address/{}/txs/chain/{}", addr, last_seen),
+        };
+        let page: Vec<Txid> = get_json(&path)?
+            .as_array()
+            .expect("array of transactions")
+            .iter()
+            .map(|tx| tx["txid"].as_str().unwrap().parse().unwrap())
+            .collect();
+        if page.is_empty() {
+            break;
+        }
+        assert!(page.len() <= 25);
+        paged.extend(page);
+    }
+    assert_eq!(paged.len(), 30);
+    assert_eq!(paged.into_iter().collect::<HashSet<_>>(), sent);
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[test]
+fn test_rest_utxo_checkpoint() -> Result<()> {
+    let (rest_handle, rest_addr, mut tester) = common::init_rest_tester_with(|config| {
+        config.runtime.write().unwrap().utxos_limit = 1000;
+    })?;
+    let get_utxos = |addr: &Address| -> Result<usize> {
+        let utxos = common::rest_get_json(&rest_addr, &format!("/address/{}/utxo", addr))?;
+        Ok(utxos.as_array().expect("array of utxos").len())
+    };
+
+    // Enough history for the utxo set to be checkpointed, at a height deep enough for the 1000th
+    // block to be the checkpoint
+    let addr = tester.newaddress()?;
+    tester
+        .node_client()
+        .call::<Value>("generatetoaddress", &[110.into(), addr.to_string().into()])?;
+    tester.mine_blocks(900)?;
+    assert_eq!(get_utxos(&addr)?, 110);
+
+    let scripthash = compute_script_hash(&addr.script_pubkey());
+    let checkpoint_key = [b"o", &scripthash[..]].concat();
+    let cache_db = || tester.query().chain().store().cache_db();
+    let mut attempts = 0;
+    let checkpoint = loop {
+        if let Some(checkpoint) = cache_db().get(&checkpoint_key) {
+            break checkpoint;
+        }
+        attempts += 1;
+        assert!(attempts < 50, "the utxo checkpoint wasn't written");
+        std::thread::sleep(Duration::from_millis(100));
+    };
+
+    // Empty the checkpointed set, so that resuming from it only finds the utxos that follow it
+    type CachedUtxoMap = HashMap<(Txid, u32), (u32, electrs::chain::Value)>;
+    let (utxos, height, blockhash): (CachedUtxoMap, u32, BlockHash) =
+        bincode::deserialize_little(&checkpoint).unwrap();
+    assert_eq!((utxos.len(), height), (110, 1000));
+    let emptied = bincode::serialize_little(&(CachedUtxoMap::new(), height, blockhash)).unwrap();
+    cache_db().put(&checkpoint_key, &emptied);
+
+    tester.send(&addr, "0.01 BTC".parse().unwrap())?;
+    tester.mine()?;
+    assert_eq!(get_utxos(&addr)?, 1);
+
+    // Clearing the caches drops the checkpoint but keeps the compatibility marker of the db
+    let store = tester.query().chain().store();
+    assert!(store.clear_caches() > 0);
+    assert!(store.cache_db().get(&checkpoint_key).is_none());
+    assert!(store.cache_db().get(b"V").is_some());
+    assert_eq!(get_utxos(&addr)?, 111);
+
+    rest_handle.stop();
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "liquid"), test)]
+#[cfg_attr(feature = "liquid", allow(dead_code))]
+fn test_missing_txids_cache() -> Result<()> {
+    let mut tester = common::TestRunner::new()?;
+    let addr = tester.newaddress()?;
+    let txid = tester.send(&addr, "0.1 BTC".parse().unwrap())?;
+
+    // The misses of unconfirmed txs are cached until the next block
+    {
+        let rawtx = tester.query().mempool().lookup_raw_txn(&txid).unwrap();
+        let chain = tester.query().chain();
+        assert!(chain.lookup_txn(&txid, None).is_none());
+        assert!(chain.tx_confirming_block(&txid).is_none());
+        let tx_key = [&b"T"[..], &txid[..]].concat();
+        chain.store().txstore_db().put(&tx_key, &rawtx);
+        assert!(chain.lookup_txn(&txid, None).is_none());
+    }
+
+    // A new tip invalidates them, even if the tx is still unconfirmed
+    let miner = tester.newaddress()?.to_string();
+    tester
+        .node_client()
+        .call::<Value>("generateblock", &[miner.into(), serde_json::json!([])])?;
+    tester.sync()?;
+    {
+        let chain = tester.query().chain();
+        assert_eq!(
+            chain.lookup_txn(&txid, None).map(|tx| tx.txid()),
+            Some(txid)
+        );
+        assert!(chain.tx_confirming_block(&txid).is_none());
+    }
+
+    tester.mine()?;
+    let blockid = tester.query().chain().tx_confirming_block(&txid);
+    assert_eq!(blockid.map(|blockid| blockid.height), Some(103));
+
+    Ok(())
+}